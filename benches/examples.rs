@@ -0,0 +1,74 @@
+//! Benchmarks the interpreter by running the example scripts
+use criterion::{criterion_group, criterion_main, Criterion};
+use haru::compiler::Compiler;
+use haru::grammar;
+use haru::hanayo;
+use haru::harumachine::value::Value;
+use haru::harumachine::vm::{execute_vm, Vm, VmOpcode};
+use haru::harumachine::vmerror::VmError;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// stands in for print so the results don't flood the benchmark's output
+fn discard(vm: Rc<RefCell<Vm>>, nargs: u16) {
+    let mut vm = vm.borrow_mut();
+    for _ in 0..nargs {
+        vm.stack.pop();
+    }
+    vm.stack.push(Value::Nil);
+}
+
+fn run(src: &str) {
+    let prog = grammar::parser_start(src).unwrap();
+    let mut c = Compiler::new(true);
+    for stmt in prog {
+        stmt.emit(&mut c).unwrap();
+    }
+    c.cpushop(VmOpcode::Halt);
+
+    let vm = c.get_vm();
+    hanayo::init(Rc::clone(&vm));
+    vm.borrow_mut()
+        .mut_global()
+        .insert("print".into(), Value::NativeFn(discard));
+    vm.borrow().gc_enable();
+
+    execute_vm(Rc::clone(&vm));
+    assert!(vm.borrow().error == VmError::ERROR_NO_ERROR);
+}
+
+fn fib(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fib");
+    group.sample_size(10);
+    group.bench_function("fib.hana", |b| {
+        b.iter(|| run(include_str!("../examples_haru/fib.hana")))
+    });
+    group.bench_function("fib_tail_call.hana", |b| {
+        b.iter(|| run(include_str!("../examples_haru/fib_tail_call.hana")))
+    });
+    group.finish();
+}
+
+fn arithmetic(c: &mut Criterion) {
+    let mut group = c.benchmark_group("arithmetic");
+    group.sample_size(10);
+    group.bench_function("sieve.hana", |b| {
+        b.iter(|| run(include_str!("../examples_haru/sieve.hana")))
+    });
+    group.bench_function("mandelbrot.hana", |b| {
+        b.iter(|| run(include_str!("../examples_haru/mandelbrot.hana")))
+    });
+    group.finish();
+}
+
+fn members(c: &mut Criterion) {
+    let mut group = c.benchmark_group("members");
+    group.sample_size(10);
+    group.bench_function("vectors.hana", |b| {
+        b.iter(|| run(include_str!("../examples_haru/vectors.hana")))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, fib, arithmetic, members);
+criterion_main!(benches);
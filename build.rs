@@ -15,8 +15,11 @@ const OS: &str = "MacOs";
 const OS: &str = "Windows";
 
 fn main() {
-    // increase the size of the stack so that it is not too short
-    println!("cargo:rustc-link-arg=/stack:{}", 20 * 1024 * 1024);
+    // increase the size of the stack so that it is not too short,
+    // /stack: is only understood by the MSVC linker
+    if env::var("CARGO_CFG_TARGET_ENV").as_deref() == Ok("msvc") {
+        println!("cargo:rustc-link-arg=/stack:{}", 20 * 1024 * 1024);
+    }
 
     if env::var("CARGO_RUN_BUILD").is_ok() {
         let output = Command::new("rustc")
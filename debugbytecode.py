@@ -36,7 +36,7 @@ VmOpcode = {
     'SetLocal': 31,
     'SetLocalFunctionDef': 32,
     'GetLocal': 33,
-    'GetLocalUp': 34,
+    'GetUpvalue': 34,
     'SetGlobal': 35,
     'GetGlobal': 36,
     'DefFunctionPush': 37,
@@ -64,6 +64,8 @@ VmOpcode = {
     'ForIn': 59,
     'Swap': 60, 
     'Use': 61,
+    'SetUpvalue': 62,
+    'Capture': 63,
 }
 
 VmOpcode_from_byte = {
@@ -31,10 +31,10 @@ macro_rules! ast_impl {
 macro_rules! emit_begin {
     ($self:ident, $c:ident) => {{
         let mut modules_info = $c.modules_info.borrow_mut();
-        let len = modules_info.files.len();
+        let fileno = modules_info.fileno;
         modules_info.smap.push(compiler::SourceMap {
             file: $self.span().clone(),
-            fileno: if len == 0 { 0 } else { len - 1 },
+            fileno,
             bytecode: ($c.clen(), 0),
         });
     }};
@@ -84,8 +84,21 @@ pub enum CodeGenError {
     InvalidLeftHandSide,
     ExpectedIdentifier,
     ExpectedInFunction,
+    ExpectedInLoop,
     NilString,
 }
+
+impl fmt::Display for CodeGenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodeGenError::InvalidLeftHandSide => write!(f, "invalid left hand side of assignment"),
+            CodeGenError::ExpectedIdentifier => write!(f, "expected identifier"),
+            CodeGenError::ExpectedInFunction => write!(f, "statement must be inside a function"),
+            CodeGenError::ExpectedInLoop => write!(f, "statement must be inside a loop"),
+            CodeGenError::NilString => write!(f, "string must not contain a nul byte"),
+        }
+    }
+}
 pub type CodeGenResult = Result<(), CodeGenError>;
 
 /// Span of the Asrt node, represented by a tuple of (from, to) indexes
@@ -116,7 +129,7 @@ impl Ast for Identifier {
     fn emit(&self, c: &mut compiler::Compiler) -> CodeGenResult {
         emit_begin!(self, c);
         let _smap_begin = smap_begin!(c);
-        c.emit_get_var(self.val.clone());
+        c.emit_get_var(self.val.clone(), self._span);
         emit_end!(c, _smap_begin);
         Ok(())
     }
@@ -273,15 +286,15 @@ impl Ast for FunctionDefinition {
         let function_end = c.reserve_label16();
 
         if let Some(id) = &self.id {
-            c.set_local(id.clone());
+            c.set_local(id.clone(), self._span);
         }
-        c.scope();
+        c.scope(self.id.clone());
 
         // body
         c.cpushop(VmOpcode::EnvNew);
         let nslot_label = c.reserve_label16();
         for arg in &self.args {
-            c.set_local(arg.clone());
+            c.set_local(arg.clone(), self._span);
         }
         self.stmt.emit(c)?;
         if let Some(id) = &self.id {
@@ -290,22 +303,16 @@ impl Ast for FunctionDefinition {
             modules_info.symbol.insert(len, id.clone());
         }
 
-        let op = c.ctop();
-        let u = VmOpcode::from_u8(op).expect("there is a calculation error in the operators");
-
-        match u {
-            VmOpcode::Ret => {}
-            VmOpcode::RetCall => {}
-            _ => {
-                c.cpushop(VmOpcode::PushNil);
-                c.cpushop(VmOpcode::Ret);
-            }
-        };
+        // the last byte may be an operand rather than an opcode, so
+        // always return nil in case the body falls through
+        c.cpushop(VmOpcode::PushNil);
+        c.cpushop(VmOpcode::Ret);
 
         // end
-        let nslots = c.unscope();
+        let (nslots, captures) = c.unscope();
         c.fill_label16(nslot_label, nslots);
         c.fill_label16(function_end, (c.clen() - function_end) as u16);
+        c.emit_captures(&captures);
         emit_end!(c, _smap_begin);
         Ok(())
     }
@@ -554,7 +561,7 @@ impl Ast for BinExpr {
                 let any = self.left.as_any();
                 if let Some(id) = any.downcast_ref::<Identifier>() {
                     self.right.emit(c)?;
-                    c.emit_set_var(id.val.clone(), false);
+                    c.emit_set_var(id.val.clone(), false, id._span);
                 } else if let Some(memexpr) = any.downcast_ref::<MemExpr>() {
                     self.right.emit(c)?;
                     memexpr._emit(c, MemExprEmit::SetOp)?;
@@ -564,27 +571,25 @@ impl Ast for BinExpr {
                     c.cpush16(callexpr.args.len() as u16);
                     let function_end = c.reserve_label16();
 
-                    c.set_local(
-                        if let Some(callee) = callexpr.callee.as_any().downcast_ref::<Identifier>()
-                        {
-                            callee.val.clone()
-                        } else {
-                            return Err(CodeGenError::ExpectedIdentifier);
-                        },
-                    );
-                    c.scope();
+                    let callee = if let Some(callee) =
+                        callexpr.callee.as_any().downcast_ref::<Identifier>()
+                    {
+                        callee
+                    } else {
+                        return Err(CodeGenError::ExpectedIdentifier);
+                    };
+                    c.set_local(callee.val.clone(), callee._span);
+                    c.scope(Some(callee.val.clone()));
 
                     // body
                     c.cpushop(VmOpcode::EnvNew);
                     let nslot_label = c.reserve_label16();
                     for arg in &callexpr.args {
-                        c.set_local(
-                            if let Some(arg) = arg.as_any().downcast_ref::<Identifier>() {
-                                arg.val.clone()
-                            } else {
-                                return Err(CodeGenError::ExpectedIdentifier);
-                            },
-                        );
+                        if let Some(arg) = arg.as_any().downcast_ref::<Identifier>() {
+                            c.set_local(arg.val.clone(), arg._span);
+                        } else {
+                            return Err(CodeGenError::ExpectedIdentifier);
+                        }
                     }
 
                     if let Some(expr) = self.right.as_any().downcast_ref::<CallExpr>() {
@@ -598,19 +603,14 @@ impl Ast for BinExpr {
                     }
 
                     // end
-                    let nslots = c.unscope();
+                    let (nslots, captures) = c.unscope();
                     c.fill_label16(nslot_label, nslots);
                     c.fill_label16(function_end, (c.clen() - function_end) as u16);
+                    c.emit_captures(&captures);
 
-                    let id =
-                        if let Some(id) = &callexpr.callee.as_any().downcast_ref::<Identifier>() {
-                            id.val.clone()
-                        } else {
-                            return Err(CodeGenError::ExpectedIdentifier);
-                        };
-                    if id != "_" {
+                    if callee.val != "_" {
                         // _ for id is considered a anonymous function decl
-                        c.emit_set_var(id, true);
+                        c.emit_set_var(callee.val.clone(), true, callee._span);
                     }
                 } else {
                     return Err(CodeGenError::InvalidLeftHandSide);
@@ -628,7 +628,7 @@ impl Ast for BinExpr {
                 let any = self.left.as_any();
                 //let mut in_place_addr = std::usize::MAX;
                 if let Some(id) = any.downcast_ref::<Identifier>() {
-                    c.emit_get_var(id.val.clone());
+                    c.emit_get_var(id.val.clone(), id._span);
                     self.right.emit(c)?;
                     c.cpushop(opcode);
                     /*
@@ -640,7 +640,7 @@ impl Ast for BinExpr {
                         _ => {}
                     };
                     */
-                    c.emit_set_var(id.val.clone(), false);
+                    c.emit_set_var(id.val.clone(), false, id._span);
                 } else if let Some(memexpr) = any.downcast_ref::<MemExpr>() {
                     memexpr.left.emit(c)?;
 
@@ -1022,6 +1022,7 @@ impl Ast for ForInStatement {
         //  set id
         //  [body]
         //  jmp [next_it]
+        //  break: pop, pop
         //  [end]
         emit_begin!(self, c);
         let _smap_begin = smap_begin!(c);
@@ -1030,11 +1031,17 @@ impl Ast for ForInStatement {
         let next_it_label = c.clen();
         c.cpushop(VmOpcode::ForIn);
         let end_label = c.reserve_label16();
-        c.emit_set_var(self.id.clone(), false);
+        c.emit_set_var(self.id.clone(), false, self._span);
         c.cpushop(VmOpcode::Pop);
+        c.loop_start();
         self.stmt.emit(c)?;
         c.cpushop(VmOpcode::Jmp);
         c.cpush16((next_it_label as isize - c.clen() as isize) as u16);
+        // break leaves the array and its iterator on the stack
+        let break_label = c.clen();
+        c.cpushop(VmOpcode::Pop);
+        c.cpushop(VmOpcode::Pop);
+        c.loop_end(next_it_label, break_label);
         c.fill_label16(end_label, (c.clen() - end_label) as u16);
 
         emit_end!(c, _smap_begin);
@@ -1056,6 +1063,9 @@ impl Ast for ContinueStatement {
     fn emit(&self, c: &mut compiler::Compiler) -> CodeGenResult {
         emit_begin!(self, c);
         let _smap_begin = smap_begin!(c);
+        if !c.is_in_loop() {
+            return Err(CodeGenError::ExpectedInLoop);
+        }
         c.cpushop(VmOpcode::Jmp);
         c.loop_continue();
         emit_end!(c, _smap_begin);
@@ -1077,6 +1087,9 @@ impl Ast for BreakStatement {
     fn emit(&self, c: &mut compiler::Compiler) -> CodeGenResult {
         emit_begin!(self, c);
         let _smap_begin = smap_begin!(c);
+        if !c.is_in_loop() {
+            return Err(CodeGenError::ExpectedInLoop);
+        }
         c.cpushop(VmOpcode::Jmp);
         c.loop_break();
         emit_end!(c, _smap_begin);
@@ -1111,7 +1124,7 @@ impl Ast for FunctionStatement {
         self.def.emit(c)?;
 
         // set var
-        c.emit_set_var(self.def.id.as_ref().unwrap().clone(), true);
+        c.emit_set_var(self.def.id.as_ref().unwrap().clone(), true, self._span);
         c.cpushop(VmOpcode::Pop);
         Ok(())
     }
@@ -1179,7 +1192,7 @@ impl Ast for RecordStatement {
         self.def.emit(c)?;
 
         // set var
-        c.emit_set_var(self.def.id.as_ref().unwrap().clone(), false);
+        c.emit_set_var(self.def.id.as_ref().unwrap().clone(), false, self._span);
         c.cpushop(VmOpcode::Pop);
         Ok(())
     }
@@ -1212,20 +1225,16 @@ impl Ast for TryStatement {
             let body_start = c.reserve_label16();
             // id
             if let Some(id) = &case.id {
-                let id = id
-                    .as_any()
-                    .downcast_ref::<Identifier>()
-                    .unwrap()
-                    .val
-                    .clone();
-                c.emit_set_var(id, false);
+                let id = id.as_any().downcast_ref::<Identifier>().unwrap();
+                c.emit_set_var(id.val.clone(), false, id._span);
                 c.cpushop(VmOpcode::Pop);
             }
             // body
             for s in &case.stmts {
                 s.emit(c)?;
             }
-            c.cpushop(VmOpcode::ExframeRet);
+            // the exception frame was left when the value was raised
+            c.cpushop(VmOpcode::Jmp);
             cases_to_fill.push(c.reserve_label16());
             // end
             c.fill_label16(body_start, (c.clen() - body_start) as u16);
@@ -1236,6 +1245,10 @@ impl Ast for TryStatement {
         for s in &self.stmts {
             s.emit(c)?;
         }
+        // leave the exception frame if the body finished without raising
+        c.cpushop(VmOpcode::ExframeRet);
+        let body_end = c.reserve_label16();
+        c.fill_label16(body_end, (c.clen() - body_end) as u16);
         for hole in cases_to_fill {
             c.fill_label16(hole, (c.clen() - hole) as u16);
         }
@@ -1,4 +1,6 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::harumachine::vm::DEFAULT_MAX_CALL_DEPTH;
 
 #[derive(Parser)]
 #[clap(
@@ -8,6 +10,9 @@ use clap::Parser;
     about = "Interpreter Implemententation for the Hana Programming Language"
 )]
 pub(crate) struct CliArgs {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(
         short,
         long,
@@ -29,10 +34,127 @@ pub(crate) struct CliArgs {
     #[arg(short, long, help = "prints ast and without run")]
     pub print_ast: bool,
 
+    #[arg(
+        long,
+        help = "maximum number of nested function calls before a StackOverflowError is raised",
+        value_name = "DEPTH",
+        default_value_t = DEFAULT_MAX_CALL_DEPTH
+    )]
+    pub max_call_depth: usize,
+
+    #[arg(
+        long,
+        help = "maximum number of instructions to execute",
+        value_name = "INSTRUCTIONS"
+    )]
+    pub fuel: Option<u64>,
+
+    #[arg(
+        long,
+        help = "maximum number of milliseconds to run for",
+        value_name = "MILLISECONDS"
+    )]
+    pub time_limit: Option<u64>,
+
+    #[arg(
+        long,
+        help = "maximum size of the garbage collected heap",
+        value_name = "BYTES"
+    )]
+    pub max_heap_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "profiles the script, writing the instructions run in every call stack in the folded format flamegraph tools read",
+        value_name = "FILE"
+    )]
+    pub profile: Option<String>,
+
+    #[arg(
+        long,
+        help = "weighs the call stacks written by --profile by the microseconds spent in them instead",
+        requires = "profile"
+    )]
+    pub profile_time: bool,
+
+    #[arg(
+        long,
+        help = "profiles the script, writing the self and total time of every function",
+        value_name = "FILE"
+    )]
+    pub profile_report: Option<String>,
+
+    #[arg(
+        long,
+        help = "writes how many times every line of the script and its modules ran, in the lcov format",
+        value_name = "FILE"
+    )]
+    pub coverage: Option<String>,
+
+    #[arg(
+        long,
+        help = "logs every instruction the virtual machine runs to stderr, with its source position and the top of the stack"
+    )]
+    pub trace: bool,
+
+    #[arg(
+        long = "trace-function",
+        help = "only traces the instructions of functions with the name, can be given more than once",
+        value_name = "NAME",
+        requires = "trace"
+    )]
+    pub trace_functions: Vec<String>,
+
+    #[arg(
+        long = "trace-file",
+        help = "only traces the instructions of the file, or files whose path ends with it, can be given more than once",
+        value_name = "FILE",
+        requires = "trace"
+    )]
+    pub trace_files: Vec<String>,
+
+    #[arg(
+        long,
+        help = "number of values shown from the top of the stack when tracing",
+        value_name = "N",
+        default_value_t = 3
+    )]
+    pub trace_stack: usize,
+
+    #[cfg(feature = "debuger")]
+    #[arg(long, help = "runs the script in the debugger")]
+    pub debug: bool,
+
+    #[cfg(feature = "debuger")]
+    #[arg(
+        long,
+        help = "serves the Debug Adapter Protocol on stdin and stdout, the script is given by the launch request"
+    )]
+    pub dap: bool,
+
+    #[cfg(feature = "lsp")]
+    #[arg(long, help = "serves the Language Server Protocol on stdin and stdout")]
+    pub lsp: bool,
+
     #[arg(help = "The name of the file to compile")]
     pub filename: Option<String>,
 }
 
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    #[command(about = "formats Hana source files in place, keeping their comments")]
+    Fmt {
+        #[arg(
+            long,
+            help = "only lists the files that aren't formatted, exiting with an error if there are any"
+        )]
+        check: bool,
+
+        #[arg(help = "The files to format", required = true)]
+        files: Vec<String>,
+    },
+}
+
 impl CliArgs {
     pub(crate) fn parse_args() -> CliArgs {
         CliArgs::parse()
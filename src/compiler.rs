@@ -5,7 +5,7 @@
 //! ```
 //! use haru::{ast, grammar};
 //! use haru::compiler::Compiler;
-//! use haru::vmbindings::vm::{Vm, VmOpcode};
+//! use haru::harumachine::vm::VmOpcode;
 //! let mut c = Compiler::new(true);
 //! let prog = grammar::parser_start("print('Hello World')\n").unwrap();
 //! for stmt in prog {
@@ -15,19 +15,23 @@
 //! ```
 
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 
+use crate::ast;
+use crate::grammar;
 use crate::harumachine::interned_string_map::InternedStringMap;
 use crate::harumachine::vm::{initialize_vm, Vm, VmOpcode};
 
 struct Scope {
     vars: Vec<String>,
-}
-impl Scope {
-    fn new() -> Scope {
-        Scope { vars: Vec::new() }
-    }
+    // variables of the enclosing functions the function uses
+    captures: Vec<CapturedVar>,
+    // where the function's code starts and its name, if it has one
+    start: usize,
+    name: Option<String>,
+    // tells apart the variables of different functions
+    id: usize,
 }
 
 struct LoopStatement {
@@ -45,6 +49,55 @@ pub struct SourceMap {
     pub fileno: usize,
 }
 
+/// Variable a function captures from the one it's defined in
+#[derive(Clone)]
+pub struct CapturedVar {
+    pub name: String,
+    /// Whether it's a local variable of the enclosing function rather than
+    /// one the enclosing function captured itself
+    pub is_local: bool,
+    /// Slot of the local variable or index of the captured one
+    pub index: u16,
+}
+
+/// Local variables of a function, by slot
+#[derive(Clone)]
+pub struct ScopeInfo {
+    pub bytecode: ArrayIndexRange,
+    pub name: Option<String>,
+    pub vars: Vec<String>,
+    /// Names of the variables the function captures, by index
+    pub captures: Vec<String>,
+}
+
+/// Variable a name refers to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Binding {
+    /// Slot of a function's local variables, the functions are numbered
+    /// in the order they're compiled
+    Local(usize, u16),
+    Global(String),
+}
+
+/// Node reading or setting a variable
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct VariableUse {
+    /// Source range of the node, which can be larger than the name
+    pub span: ArrayIndexRange,
+    pub name: String,
+    pub binding: Binding,
+}
+
+/// Where variables are defined and used in the compiled source, collected
+/// once [Compiler::track_definitions] is called
+#[derive(Default)]
+pub struct DefinitionTable {
+    /// Source range of the node each variable is first set by
+    pub definitions: HashMap<Binding, ArrayIndexRange>,
+    pub uses: Vec<VariableUse>,
+}
+
 /// Loaded modules info
 #[derive(Default)]
 pub struct ModulesInfo {
@@ -53,12 +106,76 @@ pub struct ModulesInfo {
     pub modules_loaded: std::collections::HashSet<std::path::PathBuf>,
     pub symbol: BTreeMap<usize, String>,
     pub sources: Vec<String>,
+    /// Index of the file being compiled into `files`
+    pub fileno: usize,
+    /// Scopes of every compiled function, inner functions come first
+    pub scopes: Vec<ScopeInfo>,
+    // entries of files by the hash of their name and source
+    file_ids: HashMap<u64, usize>,
 }
 
 impl ModulesInfo {
     pub fn new() -> ModulesInfo {
         ModulesInfo::default()
     }
+
+    /// Makes the source the file being compiled, returning its index
+    ///
+    /// A source compiled under the same name before keeps its entry,
+    /// so evaluating the same string over and over doesn't grow them.
+    pub fn add_file(&mut self, file: String, source: String) -> usize {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (&file, &source).hash(&mut hasher);
+        let hash = hasher.finish();
+        self.fileno = match self.file_ids.get(&hash) {
+            Some(&fileno) if self.files[fileno] == file && self.sources[fileno] == source => {
+                fileno
+            }
+            _ => {
+                self.files.push(file);
+                self.sources.push(source);
+                self.file_ids.insert(hash, self.files.len() - 1);
+                self.files.len() - 1
+            }
+        };
+        self.fileno
+    }
+
+    /// Finds the most specific source range the bytecode index was emitted from
+    pub fn lookup_smap(&self, bc_idx: usize) -> Option<SourceMap> {
+        // TODO: fix this and maybe use binary search?
+        let mut last_found: Option<SourceMap> = None;
+        for smap in self.smap.iter() {
+            if (smap.bytecode.0..=smap.bytecode.1).contains(&bc_idx) {
+                // this is so that the lookup gets more "specific"
+                last_found = Some((*smap).clone());
+            }
+        }
+        last_found
+    }
+
+    /// Describes where the bytecode index is in the source, as in
+    /// `function@file:line:col`, for backtraces
+    pub fn location(&self, bc_idx: usize) -> String {
+        if let Some(smap) = self.lookup_smap(bc_idx) {
+            let src = &self.sources[smap.fileno];
+            let (line, col) = ast::pos_to_line(src, smap.file.0);
+            format!(
+                "{}{}:{}:{}",
+                if let Some(sym) = self.symbol.get(&bc_idx) {
+                    sym.clone() + "@"
+                } else {
+                    "".to_string()
+                },
+                self.files[smap.fileno],
+                line,
+                col
+            )
+        } else {
+            format!("bytecode index {}", bc_idx)
+        }
+    }
 }
 
 /// Compiler for processing Ast nodes and
@@ -69,6 +186,8 @@ pub struct Compiler {
     code: Option<Vec<u8>>,
     pub interned_strings: Option<InternedStringMap>,
     pub modules_info: Rc<RefCell<ModulesInfo>>,
+    pub definitions: Option<DefinitionTable>,
+    nscopes: usize,
 }
 impl Compiler {
     pub fn new(interned_strings_enabled: bool) -> Compiler {
@@ -82,6 +201,8 @@ impl Compiler {
                 None
             },
             modules_info: Rc::new(RefCell::new(ModulesInfo::new())),
+            definitions: None,
+            nscopes: 0,
         }
     }
 
@@ -96,9 +217,18 @@ impl Compiler {
             code: Some(code),
             interned_strings: Some(interned_strings),
             modules_info,
+            definitions: None,
+            nscopes: 0,
         }
     }
 
+    /// Records where the variables of the code compiled from now on are
+    /// defined and used, in [Compiler::definitions]
+    #[allow(dead_code)]
+    pub fn track_definitions(&mut self) {
+        self.definitions = Some(DefinitionTable::default());
+    }
+
     // TODO: create
     pub fn get_vm(&mut self) -> Rc<RefCell<Vm>> {
         initialize_vm(
@@ -119,9 +249,6 @@ impl Compiler {
     }
 
     // #region code
-    pub fn ctop(&self) -> u8 {
-        *self.code.as_ref().unwrap().last().unwrap()
-    }
     pub fn clen(&self) -> usize {
         self.code.as_ref().unwrap().len()
     }
@@ -200,19 +327,88 @@ impl Compiler {
         None
     }
 
-    pub fn set_local(&mut self, var: String) -> Option<(u16, u16)> {
+    pub fn set_local(&mut self, var: String, span: ArrayIndexRange) -> Option<(u16, u16)> {
         if let Some(last) = self.scopes.last_mut() {
             last.vars.push(var);
             let idx = last.vars.len() - 1;
+            let binding = Binding::Local(last.id, idx as u16);
+            if self.definitions.is_some() {
+                let name = last.vars[idx].clone();
+                self.track(binding, name, span, true);
+            }
             return Some((idx as u16, 0));
         }
         None
     }
 
+    // variable a local found by get_local refers to
+    fn local_binding(&self, (slot, relascope): (u16, u16)) -> Binding {
+        let scope = &self.scopes[self.scopes.len() - 1 - relascope as usize];
+        Binding::Local(scope.id, slot)
+    }
+
+    // index of a local found by get_local in the captures of the current
+    // function, the functions in between capture it too
+    fn capture(&mut self, var: &str, (slot, relascope): (u16, u16)) -> u16 {
+        self.capture_in(self.scopes.len() - 1, var, slot, relascope)
+    }
+
+    fn capture_in(&mut self, scope: usize, var: &str, slot: u16, relascope: u16) -> u16 {
+        let (is_local, index) = if relascope == 1 {
+            (true, slot)
+        } else {
+            (false, self.capture_in(scope - 1, var, slot, relascope - 1))
+        };
+        let captures = &mut self.scopes[scope].captures;
+        if let Some(idx) = captures
+            .iter()
+            .position(|c| c.is_local == is_local && c.index == index)
+        {
+            return idx as u16;
+        }
+        captures.push(CapturedVar {
+            name: var.to_string(),
+            is_local,
+            index,
+        });
+        (captures.len() - 1) as u16
+    }
+
+    // index of the variable in the captures of the current function when
+    // its enclosing functions weren't entered, see [Compiler::enter_scope]
+    fn captured(&self, var: &str) -> Option<u16> {
+        let scope = self.scopes.last()?;
+        let idx = scope.captures.iter().position(|c| c.name == var)?;
+        Some(idx as u16)
+    }
+
+    fn track(
+        &mut self,
+        binding: Binding,
+        name: String,
+        span: ArrayIndexRange,
+        is_definition: bool,
+    ) {
+        if let Some(table) = self.definitions.as_mut() {
+            if is_definition {
+                table.definitions.entry(binding.clone()).or_insert(span);
+            }
+            table.uses.push(VariableUse {
+                span,
+                name,
+                binding,
+            });
+        }
+    }
+
     // emit set var
-    pub fn emit_set_var(&mut self, var: String, is_function: bool) {
+    pub fn emit_set_var(&mut self, var: String, is_function: bool, span: ArrayIndexRange) {
         if var.starts_with('$') || self.scopes.is_empty() {
             // set global
+            if self.definitions.is_some() {
+                let name = var.strip_prefix('$').unwrap_or(var.as_str()).to_string();
+                self.track(Binding::Global(name), var.clone(), span, true);
+            }
             self.cpushop(VmOpcode::SetGlobal);
             self.cpushs(var.strip_prefix('$').unwrap_or(var.as_str()))
                 .unwrap();
@@ -221,8 +417,20 @@ impl Compiler {
             let mut slot = local.0;
             let relascope = local.1;
             if relascope != 0 {
-                let local = self.set_local(var.clone()).unwrap();
+                if !is_function {
+                    // set the variable captured from the enclosing function
+                    if self.definitions.is_some() {
+                        self.track(self.local_binding(local), var.clone(), span, false);
+                    }
+                    let idx = self.capture(&var, local);
+                    self.cpushop(VmOpcode::SetUpvalue);
+                    self.cpush16(idx);
+                    return;
+                }
+                let local = self.set_local(var.clone(), span).unwrap();
                 slot = local.0;
+            } else if self.definitions.is_some() {
+                self.track(self.local_binding(local), var.clone(), span, false);
             }
             if is_function {
                 self.cpushop(VmOpcode::SetLocalFunctionDef);
@@ -231,32 +439,46 @@ impl Compiler {
                 self.cpushop(VmOpcode::SetLocal);
                 self.cpush16(slot);
             }
+        } else if let Some(idx) = self.captured(&var).filter(|_| !is_function) {
+            self.cpushop(VmOpcode::SetUpvalue);
+            self.cpush16(idx);
         } else {
-            let local = self.set_local(var.clone()).unwrap();
+            let local = self.set_local(var.clone(), span).unwrap();
             let slot = local.0;
             self.cpushop(VmOpcode::SetLocal);
             self.cpush16(slot);
         }
     }
 
-    pub fn emit_get_var(&mut self, var: String) {
+    pub fn emit_get_var(&mut self, var: String, span: ArrayIndexRange) {
         let local = self.get_local(&var);
-        if var.starts_with('$') || local.is_none() {
+        let captured = self.captured(&var);
+        if let (None, Some(idx)) = (local, captured) {
+            self.cpushop(VmOpcode::GetUpvalue);
+            self.cpush16(idx);
+        } else if var.starts_with('$') || local.is_none() {
             // set global
+            if self.definitions.is_some() {
+                let name = var.strip_prefix('$').unwrap_or(var.as_str()).to_string();
+                self.track(Binding::Global(name), var.clone(), span, false);
+            }
             self.cpushop(VmOpcode::GetGlobal);
             self.cpushs(var.strip_prefix('$').unwrap_or(var.as_str()))
                 .unwrap();
         } else {
             let local = local.unwrap();
+            if self.definitions.is_some() {
+                self.track(self.local_binding(local), var.clone(), span, false);
+            }
             let slot = local.0;
             let relascope = local.1;
             if relascope == 0 {
                 self.cpushop(VmOpcode::GetLocal);
                 self.cpush16(slot);
             } else {
-                self.cpushop(VmOpcode::GetLocalUp);
-                self.cpush16(slot);
-                self.cpush16(relascope);
+                let idx = self.capture(&var, local);
+                self.cpushop(VmOpcode::GetUpvalue);
+                self.cpush16(idx);
             }
         }
     }
@@ -272,15 +494,75 @@ impl Compiler {
     }
 
     // scopes
-    pub fn scope(&mut self) {
-        self.scopes.push(Scope::new());
+    pub fn scope(&mut self, name: Option<String>) {
+        self.nscopes += 1;
+        self.scopes.push(Scope {
+            vars: Vec::new(),
+            captures: Vec::new(),
+            start: self.clen(),
+            name,
+            id: self.nscopes,
+        });
+    }
+    /// Leaves the function's scope, returning its number of slots
+    /// and the variables it captures
+    pub fn unscope(&mut self) -> (u16, Vec<CapturedVar>) {
+        let scope = self.scopes.pop().unwrap();
+        let size = scope.vars.len();
+        self.modules_info.borrow_mut().scopes.push(ScopeInfo {
+            bytecode: (scope.start, self.clen()),
+            name: scope.name,
+            vars: scope.vars,
+            captures: scope.captures.iter().map(|c| c.name.clone()).collect(),
+        });
+        (size as u16, scope.captures)
+    }
+    /// Fills in the captured variables of the function that was just defined
+    pub fn emit_captures(&mut self, captures: &[CapturedVar]) {
+        if captures.is_empty() {
+            return;
+        }
+        self.cpushop(VmOpcode::Capture);
+        self.cpush16(captures.len() as u16);
+        for captured in captures {
+            self.cpush8(captured.is_local as u8);
+            self.cpush16(captured.index);
+        }
+    }
+    /// Enters the scope of a function compiled before, so that the code
+    /// emitted next can refer to its variables
+    ///
+    /// Only the variables the function captured are there out of
+    /// the enclosing ones, so it's entered on its own.
+    #[allow(dead_code)]
+    pub fn enter_scope(&mut self, info: &ScopeInfo) {
+        self.nscopes += 1;
+        self.scopes.push(Scope {
+            vars: info.vars.clone(),
+            captures: info
+                .captures
+                .iter()
+                .map(|name| CapturedVar {
+                    name: name.clone(),
+                    is_local: false,
+                    index: 0,
+                })
+                .collect(),
+            start: info.bytecode.0,
+            name: info.name.clone(),
+            id: self.nscopes,
+        });
     }
-    pub fn unscope(&mut self) -> u16 {
-        let size = self.scopes.pop().unwrap().vars.len();
-        size as u16
+    /// Number of local variables in the innermost scope
+    #[allow(dead_code)]
+    pub fn nlocals(&self) -> usize {
+        self.scopes.last().map_or(0, |scope| scope.vars.len())
     }
 
     // loops
+    pub fn is_in_loop(&self) -> bool {
+        !self.loop_stmts.is_empty()
+    }
     pub fn loop_start(&mut self) {
         self.loop_stmts.push(LoopStatement {
             fill_continue: Vec::new(),
@@ -299,8 +581,9 @@ impl Compiler {
     }
     pub fn loop_end(&mut self, next_it_pos: usize, end_pos: usize) {
         let ls = self.loop_stmts.pop().unwrap();
+        // continue jumps back in for..in loops
         for label in ls.fill_continue {
-            self.fill_label16(label, (next_it_pos - label) as u16);
+            self.fill_label16(label, (next_it_pos as isize - label as isize) as u16);
         }
         for label in ls.fill_break {
             self.fill_label16(label, (end_pos - label) as u16);
@@ -309,19 +592,111 @@ impl Compiler {
 
     // source map
     pub fn lookup_smap(&self, bc_idx: usize) -> Option<SourceMap> {
-        // TODO: fix this and maybe use binary search?
-        let mut last_found: Option<SourceMap> = None;
-        let modules_info = self.modules_info.borrow();
-        for smap in modules_info.smap.iter() {
-            if (smap.bytecode.0..=smap.bytecode.1).contains(&bc_idx) {
-                // this is so that the lookup gets more "specific"
-                last_found = Some((*smap).clone());
-            }
+        self.modules_info.borrow().lookup_smap(bc_idx)
+    }
+}
+
+/// Why a source couldn't be appended to the vm's code
+#[derive(Debug)]
+pub enum SourceError {
+    /// The source couldn't be parsed
+    Syntax {
+        message: String,
+        line: usize,
+        column: usize,
+    },
+    /// Bytecode couldn't be generated for the node at the position
+    CodeGen {
+        error: ast::CodeGenError,
+        line: usize,
+        column: usize,
+    },
+}
+
+/// Parses the source and appends its bytecode to the vm's code under the
+/// file name, returning the ip it starts at
+///
+/// The value of the last expression statement is left on the stack (nil if
+/// there is none), then the code ends with the opcode: `Halt`, or `Ret` for
+/// it to run as the body of a function called with no arguments.
+pub fn append_source(
+    vm: &Rc<RefCell<Vm>>,
+    src: &str,
+    file: String,
+    end: VmOpcode,
+) -> Result<u32, SourceError> {
+    let prog = grammar::parser_start(src).map_err(|err| {
+        let expected: Vec<String> = err.expected.iter().map(|x| x.to_string()).collect();
+        SourceError::Syntax {
+            message: format!("expected {}", expected.join(", ")),
+            line: err.line,
+            column: err.column,
         }
-        if last_found.is_some() {
-            last_found
-        } else {
-            None
+    })?;
+
+    let modules_info = vm
+        .borrow()
+        .modules_info
+        .clone()
+        .unwrap_or_else(|| Rc::new(RefCell::new(ModulesInfo::new())));
+    // register the source so runtime errors inside of it have a position,
+    // remembering the file being compiled before us
+    let enclosing = {
+        let mut modules_info = modules_info.borrow_mut();
+        let enclosing = modules_info.fileno;
+        modules_info.add_file(file, src.to_string());
+        enclosing
+    };
+
+    let target_ip = vm.borrow().code.len() as u32;
+    let code = std::mem::take(&mut vm.borrow_mut().code);
+    let interned_strings = vm.borrow_mut().interned_strings.take().unwrap_or_default();
+    let mut c = Compiler::new_append(code, Rc::clone(&modules_info), interned_strings);
+
+    let result = (|| -> ast::CodeGenResult {
+        if end == VmOpcode::Ret {
+            c.cpushop(VmOpcode::EnvNew);
+            c.cpush16(0);
         }
+        let mut prog = prog;
+        match prog.pop() {
+            Some(last) => {
+                for stmt in prog {
+                    stmt.emit(&mut c)?;
+                }
+                if let Some(expr_stmt) = last.as_any().downcast_ref::<ast::ExprStatement>() {
+                    expr_stmt.expr.emit(&mut c)?;
+                } else {
+                    last.emit(&mut c)?;
+                    c.cpushop(VmOpcode::PushNil);
+                }
+            }
+            None => c.cpushop(VmOpcode::PushNil),
+        }
+        c.cpushop(end);
+        Ok(())
+    })();
+
+    vm.borrow_mut().interned_strings = c.interned_strings.take();
+    modules_info.borrow_mut().fileno = enclosing;
+    let mut code = c.into_code();
+    if let Err(error) = result {
+        // the code that's already there is still needed
+        code.truncate(target_ip as usize);
+        vm.borrow_mut().code = code;
+        // the innermost node being emitted is the last one in the source map
+        let pos = modules_info
+            .borrow()
+            .smap
+            .last()
+            .map_or(0, |smap| smap.file.0);
+        let (line, column) = ast::pos_to_line(src, pos);
+        return Err(SourceError::CodeGen {
+            error,
+            line,
+            column,
+        });
     }
+    vm.borrow_mut().code = code;
+    Ok(target_ip)
 }
@@ -24,7 +24,29 @@ extern crate quote;
 use proc_macro::TokenStream;
 use syn::{self, spanned::Spanned};
 
+// whether the type is written like Value::Int
+fn is_value_variant(path: &syn::Path) -> bool {
+    path.segments.len() == 2 && path.segments[0].ident == "Value"
+}
+
+// the last segment of the type's path, if the type has the name
+fn type_named<'a>(ty: &'a syn::Type, name: &str) -> Option<&'a syn::PathSegment> {
+    match ty {
+        syn::Type::Path(x) => x.path.segments.last().filter(|segment| segment.ident == name),
+        _ => None,
+    }
+}
 
+// the type parameter of a type like Vec<T>
+fn type_argument(segment: &syn::PathSegment) -> Option<&syn::Type> {
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
 
 /// Generates a native function callable from haru's virtual machine.
 ///
@@ -63,13 +85,50 @@ use syn::{self, spanned::Spanned};
 ///     vm.stack.push(result.wrap());
 /// }
 /// ```
+///
+/// Arguments and the return value can also be Rust types, which get
+/// converted through the `FromValue` and `IntoValue` traits
+/// (`haru::harumachine::convert`):
+///
+/// ```rust,text
+/// #[hana_function()]
+/// fn repeat(s: &str, times: i64) -> String {
+///     s.repeat(times as usize)
+/// }
+/// ```
+///
+/// Trailing `Option` arguments can be left out by the caller, and a last
+/// `Vec` argument marked with `#[rest]` collects the remaining arguments.
+/// Returning `Err` from a function returning a `Result` raises the error
+/// value. Arguments of the wrong type raise an InvalidArgumentError:
+///
+/// ```rust,text
+/// #[hana_function()]
+/// fn join(sep: Option<String>, #[rest] parts: Vec<String>) -> Result<String, String> {
+///     if parts.is_empty() {
+///         return Err("nothing to join".to_string());
+///     }
+///     Ok(parts.join(sep.as_deref().unwrap_or("")))
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn hana_function(_args: TokenStream, item: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(item as syn::ItemFn);
     let name = &input.sig.ident;
     let body = &input.block;
+    let fname = syn::LitStr::new(name.to_string().as_str(), name.span());
+
+    // raises an InvalidArgumentError with the reason in `why`
+    let raise_invalid_argument = quote!({
+        let err = std::cell::RefCell::borrow(&vm).invalid_argument_error(why);
+        return Vm::raise_value(&vm, err);
+    });
 
     let mut args_setup = Vec::new();
+    let mut nparams: u16 = 0;
+    // arguments after the last one that isn't an Option can be left out
+    let mut nrequired: u16 = 0;
+    let mut rest = false;
     // deprecated code: decl.inputs.iter() {
     for arg in input.sig.inputs.iter() {
         match *arg {
@@ -78,20 +137,81 @@ pub fn hana_function(_args: TokenStream, item: TokenStream) -> TokenStream {
                     syn::Pat::Ident(x) => x,
                     _ => panic!("expected identifier argument!"),
                 };
-                let path = match *cap.ty.clone() {
-                    syn::Type::Path(x) => x.path.segments,
-                    _ => panic!("expected type for {:?} to be path!", pattern),
-                };
-                // match and unwrap type from value variant
-                // also panics if unexpected type
-                let atype = path.last().unwrap().ident.to_string();
-                //.into_value().ident.to_string();
-                let atypes = syn::LitStr::new(atype.as_str(), atype.span()); // quote::__rt::Span::call_site());
+                if rest {
+                    panic!("expected rest argument to be the last one!");
+                }
                 let argname = syn::LitStr::new(
                     pattern.ident.to_string().as_str(),
                     pattern.ident.span(),
                     //quote::__rt::Span::call_site(),
                 );
+                let idx = nparams;
+                let value = format_ident!("{}_value", pattern.ident);
+
+                // #[rest] args: Vec<T> takes every argument that's left
+                if cap.attrs.iter().any(|attr| attr.path().is_ident("rest")) {
+                    let elem = type_named(&cap.ty, "Vec")
+                        .and_then(type_argument)
+                        .unwrap_or_else(|| panic!("expected rest argument to be a Vec!"));
+                    args_setup.push(quote!(
+                        let #value: Vec<Value> = (#idx..nargs)
+                            .map(|_| vm.borrow_mut().stack.pop().unwrap())
+                            .collect();
+                        let #pattern: Vec<#elem> = {
+                            let mut args = Vec::with_capacity(#value.len());
+                            for val in #value.iter() {
+                                match val.convert_arg(#fname, #argname) {
+                                    Ok(x) => args.push(x),
+                                    Err(why) => #raise_invalid_argument
+                                }
+                            }
+                            args
+                        };
+                    ));
+                    rest = true;
+                    continue;
+                }
+
+                nparams += 1;
+                let ty = &cap.ty;
+                let path = match *cap.ty.clone() {
+                    syn::Type::Path(x) if is_value_variant(&x.path) => x.path.segments,
+                    // any other type gets converted through FromValue
+                    _ => {
+                        let optional = type_named(ty, "Option").is_some();
+                        if !optional {
+                            nrequired = nparams;
+                        }
+                        let pop = if optional {
+                            quote!(if nargs > #idx {
+                                vm.borrow_mut().stack.pop().unwrap()
+                            } else {
+                                Value::Nil
+                            })
+                        } else {
+                            quote!(vm.borrow_mut().stack.pop().unwrap())
+                        };
+                        args_setup.push(quote!(
+                            let #value = #pop;
+                            let #pattern: #ty = match #value.convert_arg(#fname, #argname) {
+                                Ok(x) => x,
+                                Err(why) => #raise_invalid_argument
+                            };
+                        ));
+                        continue;
+                    }
+                };
+                nrequired = nparams;
+                // match and unwrap type from value variant
+                // also raises if unexpected type
+                let atype = path.last().unwrap().ident.to_string();
+                //.into_value().ident.to_string();
+                let atypes = match atype.as_str() {
+                    "Str" => "String",
+                    "Fn" | "NativeFn" => "Function",
+                    atype => atype,
+                };
+                let atypes = syn::LitStr::new(atypes, atype.span()); // quote::__rt::Span::call_site());
                 let match_arm = match atype.as_str() {
                     "Int" | "Float" | "NativeFn" | "Fn" | "Str" | "Record" | "Array" => {
                         quote!(#path(x) => x)
@@ -102,12 +222,18 @@ pub fn hana_function(_args: TokenStream, item: TokenStream) -> TokenStream {
                 args_setup.push(match atype.as_str() {
                     "Any" => quote!(let #pattern = vm.borrow_mut().stack.pop().unwrap() ;),
                     _ => quote!(
+                        let #value = vm.borrow_mut().stack.pop().unwrap();
                         let #pattern = {
-                            match  vm.borrow_mut().stack.pop().unwrap()  {
+                            match #value {
                                 #match_arm,
-                                _ => panic!("expected argument {} to be type {}",
-                                    #argname,
-                                    #atypes)
+                                val => {
+                                    let why = format!("expected argument {} of {} to be {}, found {}",
+                                        #argname,
+                                        #fname,
+                                        #atypes,
+                                        val.type_name());
+                                    #raise_invalid_argument
+                                }
                             }
                         };
                     ),
@@ -117,28 +243,75 @@ pub fn hana_function(_args: TokenStream, item: TokenStream) -> TokenStream {
         }
     }
 
-    let arglen = syn::LitInt::new(
-        input.sig.inputs.len().to_string().as_str(),
-        input.sig.inputs.span(),
-    );
+    let unsuffixed = |n: u16| syn::LitInt::new(n.to_string().as_str(), input.sig.inputs.span());
+    let (min, max) = (unsuffixed(nrequired), unsuffixed(nparams));
+    let (mismatch, expected) = match (nrequired, rest) {
+        (_, false) if nrequired == nparams => (quote!(nargs != #max), quote!(#max)),
+        (0, false) => (quote!(nargs > #max), quote!(#max)),
+        (_, false) => (
+            quote!(!(#min..=#max).contains(&nargs)),
+            quote!(if nargs < #min { #min } else { #max }),
+        ),
+        (_, true) => (quote!(nargs < #min), quote!(#min)),
+    };
+    let arity_check = if rest && nrequired == 0 {
+        quote!()
+    } else {
+        quote!(
+            if #mismatch {
+                vm.borrow_mut().mismatch_arguments(#expected);
+                return;
+            }
+        )
+    };
+    // the argument count is only needed to pop optional and rest arguments
+    let nargs = if rest || nrequired != nparams {
+        quote!(nargs)
+    } else {
+        quote!(_nargs)
+    };
+
+    // values are returned as is, anything else gets converted through IntoValue,
+    // errors returned from a Result get raised
+    let (output, result) = match &input.sig.output {
+        syn::ReturnType::Default => (
+            quote!(Value),
+            quote!(Value::from_rust(result, &std::cell::RefCell::borrow(&vm))),
+        ),
+        syn::ReturnType::Type(_, ty) if type_named(ty, "Result").is_some() => (
+            quote!(#ty),
+            quote!(match result {
+                Ok(val) => Value::from_rust(val, &std::cell::RefCell::borrow(&vm)),
+                Err(err) => {
+                    let err = Value::from_rust(err, &std::cell::RefCell::borrow(&vm));
+                    Vm::raise_value(&vm, err)
+                }
+            }),
+        ),
+        syn::ReturnType::Type(_, ty) => (
+            quote!(#ty),
+            quote!(Value::from_rust(result, &std::cell::RefCell::borrow(&vm))),
+        ),
+    };
 
     quote!(
         pub fn #name(vm: std::rc::Rc<std::cell::RefCell<Vm>>, nargs : u16) {
 
-            if nargs != #arglen {
-                use super::VmError;
-                vm.borrow_mut().error = VmError::ERROR_MISMATCH_ARGUMENTS;
-                vm.borrow_mut().error_expected = #arglen;
-                return;
-            }
+            #arity_check
 
             #[inline(always)]
-            fn #name(vm: std::rc::Rc<std::cell::RefCell<Vm>>) -> Value {
+            fn #name(vm: std::rc::Rc<std::cell::RefCell<Vm>>, #nargs: u16) -> Value {
                 #(#args_setup)*
-                #body
+                // the body can take the vm, it's still needed for the result
+                let result = {
+                    let vm = std::rc::Rc::clone(&vm);
+                    #[allow(clippy::redundant_closure_call)]
+                    (|| -> #output #body)()
+                };
+                #result
             }
 
-            let result = #name(std::rc::Rc::clone(&vm));
+            let result = #name(std::rc::Rc::clone(&vm), nargs);
             match result {
                 Value::PropagateError => (),
                 _ => unsafe{ vm.borrow_mut().stack_push_gray(result) },
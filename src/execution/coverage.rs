@@ -0,0 +1,82 @@
+//! Line coverage for scripts run with --coverage, written in the lcov format
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::compiler::ModulesInfo;
+use crate::harumachine::debug::LineTable;
+use crate::harumachine::vm::{Vm, VmHook};
+
+/// Number of times every instruction ran
+#[derive(Default)]
+pub struct Coverage {
+    hits: Vec<u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Hit counts of the lines with code of every file in the lcov format,
+    /// a line counts as many hits as its instruction that ran the most
+    ///
+    /// Code that isn't from a file, like the one given to eval, is left out.
+    pub fn lcov(&self, info: &ModulesInfo) -> String {
+        let mut lines = LineTable::new();
+        let mut files: BTreeMap<&str, BTreeMap<usize, u64>> = BTreeMap::new();
+        let len = info
+            .smap
+            .iter()
+            .map(|smap| smap.bytecode.1)
+            .max()
+            .unwrap_or(0);
+        for ip in 0..len {
+            let Some(line) = lines.line_at(info, ip) else {
+                continue;
+            };
+            let file = &info.files[line.fileno];
+            if file.starts_with('[') {
+                continue;
+            }
+            // files show up again after the code eval compiles in them
+            let hits = files.entry(file).or_default().entry(line.line).or_default();
+            *hits = (*hits).max(self.hits.get(ip).copied().unwrap_or(0));
+        }
+
+        let mut out = String::new();
+        for (file, lines) in files {
+            writeln!(out, "TN:\nSF:{}", file).unwrap();
+            for (line, hits) in lines.iter() {
+                writeln!(out, "DA:{},{}", line, hits).unwrap();
+            }
+            let nhit = lines.values().filter(|&&hits| hits > 0).count();
+            writeln!(out, "LH:{}\nLF:{}\nend_of_record", nhit, lines.len()).unwrap();
+        }
+        out
+    }
+}
+
+/// Hook counting the instructions the script runs
+pub struct CoverageHook {
+    coverage: Rc<RefCell<Coverage>>,
+}
+
+impl CoverageHook {
+    pub fn new(coverage: Rc<RefCell<Coverage>>) -> CoverageHook {
+        CoverageHook { coverage }
+    }
+}
+
+impl VmHook for CoverageHook {
+    fn on_instruction(&mut self, vm: &Rc<RefCell<Vm>>) {
+        let ip = vm.borrow().ip() as usize;
+        let hits = &mut self.coverage.borrow_mut().hits;
+        if ip >= hits.len() {
+            hits.resize(ip + 1, 0);
+        }
+        hits[ip] += 1;
+    }
+}
@@ -0,0 +1,547 @@
+//! Debug Adapter Protocol server, lets editors debug scripts with
+//! `haru --dap`
+//!
+//! Messages are read from stdin and written to stdout, the output of the
+//! script is sent to the client as output events.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value as Json};
+
+use super::{protocol, ExecutionKind, ParserFlag, ScriptExecutor};
+use crate::compiler::ModulesInfo;
+use crate::grammar;
+use crate::harumachine::debug::{self, PauseReason, StepMode, Stepper};
+use crate::harumachine::value::Value;
+use crate::harumachine::vm::{Vm, VmHook};
+
+// scripts only ever run on one thread
+const THREAD_ID: i64 = 1;
+
+/// Connection to the client, shared with the threads forwarding the output
+#[derive(Clone)]
+struct Connection {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    seq: Arc<AtomicI64>,
+}
+
+impl Connection {
+    fn send(&self, mut msg: Json) {
+        msg["seq"] = json!(self.seq.fetch_add(1, Ordering::Relaxed));
+        protocol::write_message(&mut *self.out.lock().unwrap(), &msg);
+    }
+
+    fn event(&self, event: &str, body: Json) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn respond(&self, request: &Json, body: Json) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }));
+    }
+
+    fn respond_error(&self, request: &Json, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }));
+    }
+
+    /// Reads the next request, None once the client has gone away
+    fn read(&self) -> Option<Json> {
+        protocol::read_message(&mut io::stdin().lock())
+    }
+}
+
+/// Sends what the script writes to stdout and stderr to the client, the
+/// messages themselves go to the original stdout
+#[cfg(unix)]
+struct OutputRedirect {
+    // redirected descriptor and a copy of what it was before
+    saved: Vec<(i32, i32)>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+#[cfg(unix)]
+impl OutputRedirect {
+    fn new() -> (Connection, OutputRedirect) {
+        use std::fs::File;
+        use std::os::unix::io::FromRawFd;
+
+        let conn = Connection {
+            out: Arc::new(Mutex::new(Box::new(unsafe {
+                File::from_raw_fd(libc::dup(libc::STDOUT_FILENO))
+            }))),
+            seq: Arc::new(AtomicI64::new(1)),
+        };
+        let mut redirect = OutputRedirect {
+            saved: Vec::new(),
+            threads: Vec::new(),
+        };
+        for (fd, category) in [
+            (libc::STDOUT_FILENO, "stdout"),
+            (libc::STDERR_FILENO, "stderr"),
+        ] {
+            let mut fds = [0; 2];
+            unsafe {
+                if libc::pipe(fds.as_mut_ptr()) != 0 {
+                    continue;
+                }
+                redirect.saved.push((fd, libc::dup(fd)));
+                libc::dup2(fds[1], fd);
+                libc::close(fds[1]);
+            }
+            let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+            let conn = conn.clone();
+            redirect.threads.push(std::thread::spawn(move || {
+                let mut buf = [0; 4096];
+                while let Ok(n @ 1..) = pipe.read(&mut buf) {
+                    conn.event(
+                        "output",
+                        json!({
+                            "category": category,
+                            "output": String::from_utf8_lossy(&buf[..n]),
+                        }),
+                    );
+                }
+            }));
+        }
+        (conn, redirect)
+    }
+
+    /// Restores stdout and stderr once everything written to them is sent
+    fn finish(self) {
+        let _ = io::stdout().flush();
+        for (fd, saved) in self.saved {
+            unsafe {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct OutputRedirect;
+
+#[cfg(not(unix))]
+impl OutputRedirect {
+    fn new() -> (Connection, OutputRedirect) {
+        let conn = Connection {
+            out: Arc::new(Mutex::new(Box::new(io::stdout()))),
+            seq: Arc::new(AtomicI64::new(1)),
+        };
+        (conn, OutputRedirect)
+    }
+
+    fn finish(self) {}
+}
+
+// replaces the breakpoints of a file
+fn set_breakpoints(conn: &Connection, stepper: &mut Stepper, info: &ModulesInfo, request: &Json) {
+    let args = &request["arguments"];
+    let Some(path) = args["source"]["path"].as_str() else {
+        conn.respond_error(request, "breakpoints can only be set in files");
+        return;
+    };
+    stepper.breakpoints.retain(|bp| bp.file != path);
+    let lines: Vec<u64> = match args["breakpoints"].as_array() {
+        Some(breakpoints) => breakpoints
+            .iter()
+            .filter_map(|bp| bp["line"].as_u64())
+            .collect(),
+        None => args["lines"]
+            .as_array()
+            .map(|lines| lines.iter().filter_map(Json::as_u64).collect())
+            .unwrap_or_default(),
+    };
+    let breakpoints: Vec<Json> = lines
+        .into_iter()
+        .map(
+            |line| match stepper.add_breakpoint(info, path.to_string(), line as usize) {
+                Ok(line) => json!({ "verified": true, "line": line }),
+                Err(why) => json!({ "verified": false, "line": line, "message": why }),
+            },
+        )
+        .collect();
+    conn.respond(request, json!({ "breakpoints": breakpoints }));
+}
+
+fn respond_threads(conn: &Connection, request: &Json) {
+    conn.respond(
+        request,
+        json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+    );
+}
+
+// what a variables reference handed to the client points to
+enum Variables {
+    Locals(usize),
+    Globals,
+    Children(Value),
+}
+
+/// Hook answering the client's requests while the script is paused
+struct DapHook {
+    conn: Connection,
+    stepper: Stepper,
+    // the first pause is on entry
+    entry: bool,
+    // globals defined before the script started, hidden from the client
+    builtins: Option<HashSet<String>>,
+    // valid until the script resumes, referenced by their index + 1
+    variables: Vec<Variables>,
+}
+
+impl DapHook {
+    fn reference(&mut self, variables: Variables) -> usize {
+        self.variables.push(variables);
+        self.variables.len()
+    }
+
+    // reference to the members of records and arrays, 0 for other values
+    fn value_reference(&mut self, val: &Value) -> usize {
+        match val {
+            Value::Record(rec) if rec.as_ref().iter().next().is_some() => {
+                self.reference(Variables::Children(val.clone()))
+            }
+            Value::Array(arr) if !arr.as_ref().is_empty() => {
+                self.reference(Variables::Children(val.clone()))
+            }
+            _ => 0,
+        }
+    }
+
+    fn variable(&mut self, name: String, val: &Value) -> Json {
+        json!({
+            "name": name,
+            "value": format!("{:?}", val),
+            "variablesReference": self.value_reference(val),
+        })
+    }
+
+    // prompts for requests until one of them resumes the script
+    fn pause(&mut self, vm: &Rc<RefCell<Vm>>, info: &Rc<RefCell<ModulesInfo>>, reason: &str) {
+        let _ = io::stdout().flush();
+        self.conn.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        );
+
+        loop {
+            let Some(request) = self.conn.read() else {
+                std::process::exit(0);
+            };
+            let args = &request["arguments"];
+            let depth = vm.borrow().localenv().len();
+            let mode = match request["command"].as_str().unwrap_or("") {
+                "continue" => StepMode::Continue,
+                "next" => StepMode::Next(depth),
+                "stepIn" => StepMode::Step,
+                "stepOut" if depth == 0 => StepMode::Continue,
+                "stepOut" => StepMode::Finish(depth),
+                "threads" => {
+                    respond_threads(&self.conn, &request);
+                    continue;
+                }
+                "setBreakpoints" => {
+                    set_breakpoints(&self.conn, &mut self.stepper, &info.borrow(), &request);
+                    continue;
+                }
+                "stackTrace" => {
+                    let info = info.borrow();
+                    let frames: Vec<Json> = debug::frames(&vm.borrow())
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, frame)| {
+                            let mut json = json!({
+                                "id": idx,
+                                "name": frame.name(&info),
+                                "line": 0,
+                                "column": 0,
+                            });
+                            match self.stepper.lines.line_at(&info, frame.ip) {
+                                Some(line) => {
+                                    let file = &info.files[line.fileno];
+                                    json["source"] = json!({
+                                        "name": Path::new(file).file_name().map_or(
+                                            file.clone(),
+                                            |name| name.to_string_lossy().into_owned()
+                                        ),
+                                        "path": file,
+                                    });
+                                    json["line"] = json!(line.line);
+                                    json["column"] = json!(1);
+                                }
+                                None => json["presentationHint"] = json!("subtle"),
+                            }
+                            json
+                        })
+                        .collect();
+                    self.conn.respond(
+                        &request,
+                        json!({ "stackFrames": frames, "totalFrames": frames.len() }),
+                    );
+                    continue;
+                }
+                "scopes" => {
+                    let frame = args["frameId"].as_u64().unwrap_or(0) as usize;
+                    let locals = self.reference(Variables::Locals(frame));
+                    let globals = self.reference(Variables::Globals);
+                    self.conn.respond(
+                        &request,
+                        json!({ "scopes": [
+                            { "name": "Locals", "variablesReference": locals, "expensive": false },
+                            { "name": "Globals", "variablesReference": globals, "expensive": false },
+                        ] }),
+                    );
+                    continue;
+                }
+                "variables" => {
+                    let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+                    let values: Vec<(String, Value)> =
+                        match self.variables.get(reference.wrapping_sub(1)) {
+                            Some(Variables::Locals(frame)) => debug::frames(&vm.borrow())
+                                .get(*frame)
+                                .map(|frame| frame.locals(&info.borrow()))
+                                .unwrap_or_default(),
+                            Some(Variables::Globals) => {
+                                let vm = vm.borrow();
+                                let builtins = self.builtins.as_ref();
+                                let mut globals: Vec<(String, Value)> = vm
+                                    .global()
+                                    .iter()
+                                    .filter(|(key, _)| {
+                                        !builtins.is_some_and(|builtins| {
+                                            builtins.contains(key.as_str())
+                                        })
+                                    })
+                                    .map(|(key, val)| (key.to_string(), val.clone()))
+                                    .collect();
+                                globals.sort_by(|(x, _), (y, _)| x.cmp(y));
+                                globals
+                            }
+                            Some(Variables::Children(Value::Record(rec))) => rec
+                                .as_ref()
+                                .iter()
+                                .map(|(key, val)| (key.to_string(), val.clone()))
+                                .collect(),
+                            Some(Variables::Children(Value::Array(arr))) => arr
+                                .as_ref()
+                                .iter()
+                                .enumerate()
+                                .map(|(idx, val)| (idx.to_string(), val.clone()))
+                                .collect(),
+                            _ => Vec::new(),
+                        };
+                    let variables: Vec<Json> = values
+                        .into_iter()
+                        .map(|(name, val)| self.variable(name, &val))
+                        .collect();
+                    self.conn
+                        .respond(&request, json!({ "variables": variables }));
+                    continue;
+                }
+                "evaluate" => {
+                    if args["frameId"].as_u64().is_some_and(|frame| frame != 0) {
+                        self.conn.respond_error(
+                            &request,
+                            "expressions can only be evaluated in the innermost frame",
+                        );
+                        continue;
+                    }
+                    let expression = args["expression"].as_str().unwrap_or("");
+                    match debug::eval_in_frame(vm, expression) {
+                        Ok(val) => {
+                            let reference = self.value_reference(&val);
+                            self.conn.respond(
+                                &request,
+                                json!({
+                                    "result": format!("{:?}", val),
+                                    "variablesReference": reference,
+                                }),
+                            );
+                        }
+                        Err(why) => self.conn.respond_error(&request, &why),
+                    }
+                    continue;
+                }
+                "disconnect" => {
+                    self.conn.respond(&request, json!({}));
+                    std::process::exit(0);
+                }
+                command => {
+                    self.conn
+                        .respond_error(&request, &format!("unsupported request {}", command));
+                    continue;
+                }
+            };
+            self.stepper.mode = mode;
+            self.variables.clear();
+            self.conn
+                .respond(&request, json!({ "allThreadsContinued": true }));
+            return;
+        }
+    }
+}
+
+impl VmHook for DapHook {
+    fn on_instruction(&mut self, vm: &Rc<RefCell<Vm>>) {
+        // the hook first runs before the script does
+        if self.builtins.is_none() {
+            let vm = vm.borrow();
+            self.builtins = Some(vm.global().keys().map(|key| key.to_string()).collect());
+        }
+        let info = vm.borrow().modules_info.clone().unwrap();
+        let (ip, depth) = {
+            let vm = vm.borrow();
+            (vm.ip() as usize, vm.localenv().len())
+        };
+        let Some(reason) = self.stepper.should_pause(&info.borrow(), ip, depth) else {
+            return;
+        };
+        let reason = match reason {
+            _ if self.entry => "entry",
+            PauseReason::Step => "step",
+            PauseReason::Breakpoint => "breakpoint",
+        };
+        self.entry = false;
+        self.pause(vm, &info, reason);
+    }
+}
+
+// reads and compiles the program a launch request points to
+fn launch(request: &Json, flag: ParserFlag) -> Result<ScriptExecutor, String> {
+    let program = request["arguments"]["program"]
+        .as_str()
+        .ok_or("no program to launch")?;
+    // breakpoints are set by absolute paths
+    let program = std::fs::canonicalize(program)
+        .map_err(|err| format!("error opening {}: {}", program, err))?;
+    let program = program.to_string_lossy().into_owned();
+    let mut executor = ScriptExecutor::new(ExecutionKind::File(program), flag);
+    executor.load_script();
+    let prog = grammar::parser_start(executor.script()).map_err(|err| {
+        let expected: Vec<String> = err.expected.iter().map(|x| x.to_string()).collect();
+        format!(
+            "parser error at {}:{}: expected {}",
+            err.line,
+            err.column,
+            expected.join(", ")
+        )
+    })?;
+    executor.emit_bytecode(prog);
+    Ok(executor)
+}
+
+/// Serves the Debug Adapter Protocol until the client disconnects
+pub fn run_dap(flag: ParserFlag) {
+    let (conn, redirect) = OutputRedirect::new();
+    let mut flag = Some(flag);
+    let mut executor: Option<ScriptExecutor> = None;
+    let mut stepper = Stepper::new(StepMode::Continue);
+    let mut stop_on_entry = false;
+
+    // configuration, until the client is done setting breakpoints
+    loop {
+        let Some(request) = conn.read() else {
+            return;
+        };
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => conn.respond(
+                &request,
+                json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                }),
+            ),
+            "launch" => {
+                let Some(flag) = flag.take() else {
+                    conn.respond_error(&request, "a program is already launched");
+                    continue;
+                };
+                match launch(&request, flag) {
+                    Ok(launched) => {
+                        stop_on_entry = request["arguments"]["stopOnEntry"]
+                            .as_bool()
+                            .unwrap_or(false);
+                        executor = Some(launched);
+                        conn.respond(&request, json!({}));
+                        conn.event("initialized", json!({}));
+                    }
+                    Err(why) => {
+                        conn.respond_error(&request, &why);
+                        conn.event("terminated", json!({}));
+                    }
+                }
+            }
+            "setBreakpoints" => {
+                let info = executor.as_ref().map(|executor| executor.modules_info());
+                match info {
+                    Some(info) => set_breakpoints(&conn, &mut stepper, &info.borrow(), &request),
+                    None => set_breakpoints(&conn, &mut stepper, &ModulesInfo::new(), &request),
+                }
+            }
+            "setExceptionBreakpoints" => conn.respond(&request, json!({})),
+            "threads" => respond_threads(&conn, &request),
+            "configurationDone" => {
+                conn.respond(&request, json!({}));
+                if executor.is_some() {
+                    break;
+                }
+            }
+            "disconnect" => {
+                conn.respond(&request, json!({}));
+                return;
+            }
+            command => conn.respond_error(&request, &format!("unsupported request {}", command)),
+        }
+    }
+
+    let mut executor = executor.unwrap();
+    if stop_on_entry {
+        stepper.mode = StepMode::Step;
+    }
+    executor.set_hook(Box::new(DapHook {
+        conn: conn.clone(),
+        stepper,
+        entry: stop_on_entry,
+        builtins: None,
+        variables: Vec::new(),
+    }));
+    let failed = executor.execute();
+    redirect.finish();
+    conn.event("exited", json!({ "exitCode": failed as i32 }));
+    conn.event("terminated", json!({}));
+
+    while let Some(request) = conn.read() {
+        match request["command"].as_str().unwrap_or("") {
+            "disconnect" => {
+                conn.respond(&request, json!({}));
+                return;
+            }
+            "threads" => respond_threads(&conn, &request),
+            command => conn.respond_error(
+                &request,
+                &format!("the program has exited, can't {}", command),
+            ),
+        }
+    }
+}
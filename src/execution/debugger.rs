@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::compiler::ModulesInfo;
+use crate::harumachine::debug::{self, SourceLine, StepMode, Stepper};
+use crate::harumachine::vm::{Vm, VmHook};
+use ansi_term::Color as ac;
+use rustyline::{error::ReadlineError, history::DefaultHistory, Editor};
+
+const HELP: &str = "\
+break [FILE:]LINE  (b)   pause when the line is reached
+delete [N]         (d)   delete breakpoint N, or all of them
+breakpoints              list the breakpoints
+step               (s)   run to the next line, stepping into function calls
+next               (n)   run to the next line of the current function
+finish             (f)   run until the current function returns
+continue           (c)   run until a breakpoint is reached
+locals                   show the local variables of the current function
+globals                  show the global variables set by the script
+backtrace          (bt)  show the call stack
+print EXPR         (p)   evaluate the expression in the current function
+list               (l)   show the source around the current line
+quit               (q)   stop the program";
+
+// lines shown around the current line by list
+const LIST_CONTEXT: usize = 5;
+
+/// Debugger prompt, pausing the virtual machine before it runs a new line
+pub(crate) struct Debugger {
+    editor: Editor<(), DefaultHistory>,
+    stepper: Stepper,
+    // globals defined before the script started, hidden by globals
+    builtins: HashSet<String>,
+    last_command: String,
+}
+
+impl Debugger {
+    /// Creates a debugger that pauses on the first line of the script
+    pub(crate) fn new(vm: &Vm) -> Debugger {
+        println!("haru debugger, type help for a list of commands");
+        Debugger {
+            editor: Editor::new().unwrap(),
+            stepper: Stepper::new(StepMode::Step),
+            builtins: vm.global().keys().map(|key| key.to_string()).collect(),
+            last_command: String::new(),
+        }
+    }
+
+    // prompts for commands until one of them resumes the program
+    fn pause(&mut self, vm: &Rc<RefCell<Vm>>, info: &Rc<RefCell<ModulesInfo>>) {
+        let ip = vm.borrow().ip() as usize;
+        if let Some(line) = self.stepper.lines.line_at(&info.borrow(), ip) {
+            let info = info.borrow();
+            println!(
+                "{} {}:{}",
+                ac::Blue.bold().paint("paused at"),
+                info.files[line.fileno],
+                line.line
+            );
+            print_lines(&info, line, line.line, line.line);
+        }
+
+        loop {
+            let command = match self.editor.readline("(hdb) ") {
+                Ok(command) => command,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(_) => std::process::exit(0),
+            };
+            let command = if command.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                let _ = self.editor.add_history_entry(command.as_str());
+                self.last_command = command.clone();
+                command
+            };
+            let command = command.trim();
+            let (name, arg) = command
+                .split_once(char::is_whitespace)
+                .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+            let depth = vm.borrow().localenv().len();
+            match name {
+                "" => {}
+                "help" | "h" => println!("{}", HELP),
+                "step" | "s" => {
+                    self.stepper.mode = StepMode::Step;
+                    return;
+                }
+                "next" | "n" => {
+                    self.stepper.mode = StepMode::Next(depth);
+                    return;
+                }
+                "finish" | "f" => {
+                    if depth == 0 {
+                        println!("not in a function");
+                        continue;
+                    }
+                    self.stepper.mode = StepMode::Finish(depth);
+                    return;
+                }
+                "continue" | "c" => {
+                    self.stepper.mode = StepMode::Continue;
+                    return;
+                }
+                "break" | "b" => self.add_breakpoint(&info.borrow(), ip, arg),
+                "delete" | "d" => {
+                    if arg.is_empty() {
+                        self.stepper.breakpoints.clear();
+                    } else {
+                        match arg.parse::<usize>() {
+                            Ok(n) if n >= 1 && n <= self.stepper.breakpoints.len() => {
+                                self.stepper.breakpoints.remove(n - 1);
+                            }
+                            _ => println!("no breakpoint {}", arg),
+                        }
+                    }
+                }
+                "breakpoints" => {
+                    for (idx, bp) in self.stepper.breakpoints.iter().enumerate() {
+                        println!("{}: {}:{}", idx + 1, bp.file, bp.line);
+                    }
+                }
+                "locals" => {
+                    let frames = debug::frames(&vm.borrow());
+                    for (name, val) in frames[0].locals(&info.borrow()) {
+                        println!("{} = {:?}", name, val);
+                    }
+                }
+                "globals" => {
+                    let vm = vm.borrow();
+                    let mut globals: Vec<_> = vm
+                        .global()
+                        .iter()
+                        .filter(|(key, _)| !self.builtins.contains(key.as_str()))
+                        .collect();
+                    globals.sort_by(|(x, _), (y, _)| x.as_str().cmp(y.as_str()));
+                    for (name, val) in globals {
+                        println!("{} = {:?}", name.as_str(), val);
+                    }
+                }
+                "backtrace" | "bt" => {
+                    let info = info.borrow();
+                    for (idx, frame) in debug::frames(&vm.borrow()).iter().enumerate() {
+                        match self.stepper.lines.line_at(&info, frame.ip) {
+                            Some(line) => println!(
+                                "#{} {} at {}:{}",
+                                idx,
+                                frame.name(&info),
+                                info.files[line.fileno],
+                                line.line
+                            ),
+                            None => println!("#{} {} [native code]", idx, frame.name(&info)),
+                        }
+                    }
+                }
+                "print" | "p" => match debug::eval_in_frame(vm, arg) {
+                    Ok(val) => println!("{:?}", val),
+                    Err(why) => println!("{} {}", ac::Red.bold().paint("error:"), why),
+                },
+                "list" | "l" => {
+                    if let Some(line) = self.stepper.lines.line_at(&info.borrow(), ip) {
+                        let first = line.line.saturating_sub(LIST_CONTEXT).max(1);
+                        print_lines(&info.borrow(), line, first, line.line + LIST_CONTEXT);
+                    }
+                }
+                "quit" | "q" => std::process::exit(0),
+                _ => println!("unknown command {}, type help for a list of commands", name),
+            }
+        }
+    }
+
+    fn add_breakpoint(&mut self, info: &ModulesInfo, ip: usize, arg: &str) {
+        let (file, line) = match arg.rsplit_once(':') {
+            Some((file, line)) => (file.to_string(), line),
+            // a line of the current file
+            None => match self.stepper.lines.line_at(info, ip) {
+                Some(at) => (info.files[at.fileno].clone(), arg),
+                None => {
+                    println!("usage: break [FILE:]LINE");
+                    return;
+                }
+            },
+        };
+        let Ok(line) = line.parse::<usize>() else {
+            println!("usage: break [FILE:]LINE");
+            return;
+        };
+        match self.stepper.add_breakpoint(info, file.clone(), line) {
+            Ok(line) => println!(
+                "breakpoint {} at {}:{}",
+                self.stepper.breakpoints.len(),
+                file,
+                line
+            ),
+            Err(why) => println!("{}", why),
+        }
+    }
+}
+
+// prints the lines from first to last of the file, marking the current one
+fn print_lines(info: &ModulesInfo, current: SourceLine, first: usize, last: usize) {
+    let src = &info.sources[current.fileno];
+    for (idx, text) in src.lines().enumerate().take(last).skip(first - 1) {
+        let lineno = idx + 1;
+        let marker = if lineno == current.line { ">" } else { " " };
+        println!(
+            "{}{}",
+            ac::Blue.bold().paint(format!("{}{:>4} | ", marker, lineno)),
+            text
+        );
+    }
+}
+
+impl VmHook for Debugger {
+    fn on_instruction(&mut self, vm: &Rc<RefCell<Vm>>) {
+        let info = vm.borrow().modules_info.clone().unwrap();
+        let (ip, depth) = {
+            let vm = vm.borrow();
+            (vm.ip() as usize, vm.localenv().len())
+        };
+        if self
+            .stepper
+            .should_pause(&info.borrow(), ip, depth)
+            .is_some()
+        {
+            self.pause(vm, &info);
+        }
+    }
+}
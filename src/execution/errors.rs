@@ -6,6 +6,9 @@ use crate::{
 };
 use ansi_term::Color as ac;
 
+// number of frames shown at both ends of a long backtrace
+const BACKTRACE_EDGE: usize = 10;
+
 pub(crate) fn handle_error(vm: Rc<RefCell<Vm>>, c: &compiler::Compiler) -> bool {
     if (*vm).borrow().error != VmError::ERROR_NO_ERROR {
         if let Some(smap) = c.lookup_smap((*vm).borrow().ip() as usize) {
@@ -38,28 +41,21 @@ pub(crate) fn handle_error(vm: Rc<RefCell<Vm>>, c: &compiler::Compiler) -> bool
 
         if !(*vm).borrow().localenv().is_empty() {
             eprintln!("{}", ac::Red.bold().paint("backtrace:"));
-            for some_env in (*vm).borrow().localenv().iter() {
-                if let Some(env) = some_env.borrow().as_ref() {
-                    let ip = env.retip as usize;
-                    if let Some(smap) = c.lookup_smap(ip) {
-                        let modules_info = c.modules_info.borrow();
-                        let src = &modules_info.sources[smap.fileno];
-                        let (line, col) = ast::pos_to_line(src, smap.file.0);
-                        eprintln!(
-                            " from {}{}:{}:{}",
-                            if let Some(sym) = modules_info.symbol.get(&ip) {
-                                sym.clone() + "@"
-                            } else {
-                                "".to_string()
-                            },
-                            modules_info.files[smap.fileno],
-                            line,
-                            col
-                        );
-                    } else {
-                        eprintln!(" from bytecode index {}", ip);
+            let vm = (*vm).borrow();
+            let localenv = vm.localenv();
+            for (idx, env) in localenv.iter().enumerate() {
+                // deep recursion would print thousands of frames, only the
+                // outermost and innermost ones are shown
+                if localenv.len() > BACKTRACE_EDGE * 2
+                    && (BACKTRACE_EDGE..localenv.len() - BACKTRACE_EDGE).contains(&idx)
+                {
+                    if idx == BACKTRACE_EDGE {
+                        eprintln!(" ... {} more", localenv.len() - BACKTRACE_EDGE * 2);
                     }
+                    continue;
                 }
+                let location = c.modules_info.borrow().location(env.retip as usize);
+                eprintln!(" from {}", location);
             }
         }
         true
@@ -0,0 +1,581 @@
+//! Source formatter for `haru fmt`, printing scripts back with a canonical
+//! layout: four spaces of indentation, spaces around binary operators and
+//! blocks written with `begin ... end` rather than `then begin ... end`
+//!
+//! The parser drops comments, so they're found by scanning the source and
+//! put back before the statement they precede, or after the code they
+//! follow on the same line. The formatted source is compiled again and its
+//! bytecode has to match the original's before anything is written.
+
+use std::fs;
+
+use super::errors::print_error;
+use crate::ast::{
+    ArrayExpr, Ast, BinExpr, BinOp, BlockStatement, BreakStatement, CallExpr, CondExpr,
+    ContinueStatement, ExprStatement, FloatLiteral, ForInStatement, FunctionDefinition,
+    FunctionStatement, Identifier, IfStatement, IntLiteral, MemExpr, RaiseStatement,
+    RecordDefinition, RecordStatement, ReturnStatement, StrLiteral, TryStatement, UnaryExpr,
+    UnaryOp, UseStatement, WhileStatement,
+};
+use crate::compiler::Compiler;
+use crate::grammar;
+
+const INDENT: &str = "    ";
+
+// precedence of expressions, an operand binding looser than its operator
+// gets parenthesized
+const ASSIGN: u8 = 0;
+const COND: u8 = 1;
+const BITWISE: u8 = 2;
+const LOGICAL: u8 = 3;
+const COMPARE: u8 = 4;
+const OF: u8 = 5;
+const SUM: u8 = 6;
+const PRODUCT: u8 = 7;
+const UNARY: u8 = 8;
+const POSTFIX: u8 = 9;
+const ATOM: u8 = 10;
+
+/// Why a source couldn't be formatted
+pub enum FormatError {
+    Parse(grammar::ParseError),
+    /// The formatted source doesn't compile to the same program, which is a
+    /// bug of the formatter
+    Changed,
+}
+
+/// Formats the files in place, or only checks they're formatted with
+/// `check`, returning whether all of them were
+pub(crate) fn run_fmt(files: Vec<String>, check: bool) -> bool {
+    let mut ok = true;
+    for file in files {
+        let src = match fs::read_to_string(&file) {
+            Ok(src) => src,
+            Err(err) => {
+                eprintln!("error reading {}: {}", file, err);
+                ok = false;
+                continue;
+            }
+        };
+        let formatted = match format_source(&src) {
+            Ok(formatted) => formatted,
+            Err(FormatError::Parse(err)) => {
+                eprintln!("error formatting {}:", file);
+                print_error(
+                    &src,
+                    err.line,
+                    err.column,
+                    err.line,
+                    err.column,
+                    "parser error:",
+                    &format!("expected {}", {
+                        let expected: Vec<String> =
+                            err.expected.iter().map(|x| x.to_string()).collect();
+                        expected.join(", ")
+                    }),
+                );
+                ok = false;
+                continue;
+            }
+            Err(FormatError::Changed) => {
+                eprintln!(
+                    "error formatting {}: the formatted script doesn't do the same, it was left as it is",
+                    file
+                );
+                ok = false;
+                continue;
+            }
+        };
+        if formatted == src {
+            continue;
+        }
+        if check {
+            println!("{} isn't formatted", file);
+            ok = false;
+        } else if let Err(err) = fs::write(&file, formatted) {
+            eprintln!("error writing {}: {}", file, err);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Formats the source of a script
+pub fn format_source(src: &str) -> Result<String, FormatError> {
+    let prog = grammar::parser_start(src).map_err(FormatError::Parse)?;
+    let formatted = Formatter::new(src).program(&prog);
+    let reparsed = grammar::parser_start(&formatted).map_err(|_| FormatError::Changed)?;
+    if bytecode(prog) != bytecode(reparsed) {
+        return Err(FormatError::Changed);
+    }
+    Ok(formatted)
+}
+
+// code the program compiles to, None if it doesn't
+fn bytecode(prog: grammar::Program) -> Option<Vec<u8>> {
+    let mut c = Compiler::new(false);
+    for stmt in prog {
+        stmt.emit(&mut c).ok()?;
+    }
+    Some(c.take_code())
+}
+
+// byte ranges of the comments in the source, the strings in it skipped
+fn find_comments(src: &str) -> Vec<(usize, usize)> {
+    let bytes = src.as_bytes();
+    let mut comments = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'"' | b'\'' => {
+                let quote = bytes[pos];
+                pos += 1;
+                while pos < bytes.len() && bytes[pos] != quote {
+                    pos += if bytes[pos] == b'\\' { 2 } else { 1 };
+                }
+                pos += 1;
+            }
+            b'\\' => pos += 2,
+            b'/' if bytes.get(pos + 1) == Some(&b'/') => {
+                let end = src[pos..].find('\n').map_or(src.len(), |idx| pos + idx);
+                comments.push((pos, pos + src[pos..end].trim_end().len()));
+                pos = end;
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                let end = src[pos + 2..]
+                    .find("*/")
+                    .map_or(src.len(), |idx| pos + idx + 4);
+                comments.push((pos, end));
+                pos = end;
+            }
+            _ => pos += 1,
+        }
+    }
+    comments
+}
+
+struct Formatter<'a> {
+    src: &'a str,
+    comments: Vec<(usize, usize)>,
+    // first comment that isn't printed yet
+    next_comment: usize,
+    out: String,
+    indent: usize,
+    // end of the code or comment printed last, blank lines after it are kept
+    last: usize,
+    // nothing was printed in the block yet, so no blank line goes first
+    block_start: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(src: &'a str) -> Formatter<'a> {
+        Formatter {
+            src,
+            comments: find_comments(src),
+            next_comment: 0,
+            out: String::new(),
+            indent: 0,
+            last: 0,
+            block_start: true,
+        }
+    }
+
+    fn program(mut self, prog: &[Box<dyn Ast>]) -> String {
+        if self.src.starts_with("#!") {
+            let end = self.src.find('\n').unwrap_or(self.src.len());
+            self.out.push_str(self.src[..end].trim_end());
+            self.out.push('\n');
+            self.last = end;
+            self.block_start = false;
+            // the line is skipped by the parser, comments in it included
+            while self
+                .comments
+                .get(self.next_comment)
+                .is_some_and(|comment| comment.0 < end)
+            {
+                self.next_comment += 1;
+            }
+        }
+        self.statements(prog, self.src.len());
+        self.out
+    }
+
+    // prints the statements a line each, followed by the comments before
+    // the end of the block
+    fn statements(&mut self, stmts: &[Box<dyn Ast>], end: usize) {
+        for stmt in stmts {
+            let span = *stmt.span();
+            self.comments_before(span.0);
+            self.start_line(span.0);
+            self.statement(stmt.as_ref());
+            self.out.push('\n');
+            self.last = self.code_end(span.1);
+            self.block_start = false;
+        }
+        self.comments_before(end);
+    }
+
+    // prints an indented block after its opening keyword, ending at the
+    // indentation of the keyword closing it
+    fn block(&mut self, stmts: &[Box<dyn Ast>], end: usize) {
+        self.out.push('\n');
+        self.indent += 1;
+        self.block_start = true;
+        self.statements(stmts, end);
+        self.indent -= 1;
+        self.block_start = false;
+        self.push_indent();
+    }
+
+    fn push_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    // indents a new line, after a blank one if the source has one before pos
+    fn start_line(&mut self, pos: usize) {
+        if !self.block_start && self.blank_line_between(self.last, pos) {
+            self.out.push('\n');
+        }
+        self.push_indent();
+    }
+
+    fn blank_line_between(&self, from: usize, to: usize) -> bool {
+        if from >= to {
+            return false;
+        }
+        let lines: Vec<&str> = self.src[from..to].split('\n').collect();
+        lines.len() > 2
+            && lines[1..lines.len() - 1]
+                .iter()
+                .any(|line| line.trim().is_empty())
+    }
+
+    // prints the comments before pos, the ones following code on their line
+    // are put at the end of the last line printed
+    fn comments_before(&mut self, pos: usize) {
+        while let Some(&(start, end)) = self.comments.get(self.next_comment) {
+            if start >= pos {
+                break;
+            }
+            let line_start = self.src[..start].rfind('\n').map_or(0, |idx| idx + 1);
+            let follows_code = !self.src[line_start..start].trim().is_empty();
+            if follows_code && self.out.ends_with('\n') {
+                self.out.pop();
+                self.out.push(' ');
+            } else {
+                self.start_line(start);
+                self.block_start = false;
+            }
+            self.out.push_str(&self.src[start..end]);
+            self.out.push('\n');
+            self.last = end;
+            self.next_comment += 1;
+        }
+    }
+
+    // end of the code before pos, skipping the whitespace and comments after it
+    fn code_end(&self, pos: usize) -> usize {
+        let mut pos = pos;
+        loop {
+            pos = self.src[..pos]
+                .trim_end_matches(|ch: char| ch.is_whitespace() || ch == ';')
+                .len();
+            match self.comments.iter().find(|comment| comment.1 == pos) {
+                Some(comment) => pos = comment.0,
+                None => return pos,
+            }
+        }
+    }
+
+    // start of the keyword closing a block that ends at pos
+    fn end_keyword(&self, pos: usize) -> usize {
+        self.code_end(pos) - "end".len()
+    }
+
+    fn verbatim(&mut self, node: &dyn Ast) {
+        let span = node.span();
+        self.out.push_str(&self.src[span.0..span.1]);
+    }
+
+    fn statement(&mut self, node: &dyn Ast) {
+        let any = node.as_any();
+        if let Some(stmt) = any.downcast_ref::<ExprStatement>() {
+            self.expr(stmt.expr.as_ref());
+        } else if let Some(stmt) = any.downcast_ref::<ReturnStatement>() {
+            self.out.push_str("return");
+            if let Some(expr) = stmt.expr.as_ref() {
+                self.out.push(' ');
+                self.expr(expr.as_ref());
+            }
+        } else if any.downcast_ref::<ContinueStatement>().is_some() {
+            self.out.push_str("continue");
+        } else if any.downcast_ref::<BreakStatement>().is_some() {
+            self.out.push_str("break");
+        } else if let Some(stmt) = any.downcast_ref::<RaiseStatement>() {
+            self.out.push_str("raise ");
+            self.expr(stmt.expr.as_ref());
+        } else if let Some(stmt) = any.downcast_ref::<UseStatement>() {
+            self.out.push_str("use ");
+            self.out.push_str(&quote(&stmt.path));
+        } else if let Some(stmt) = any.downcast_ref::<BlockStatement>() {
+            self.out.push_str("begin");
+            self.block(&stmt.stmts, self.end_keyword(stmt._span.1));
+            self.out.push_str("end");
+        } else if let Some(stmt) = any.downcast_ref::<IfStatement>() {
+            self.out.push_str("if ");
+            self.expr(stmt.expr.as_ref());
+            self.body(stmt.then.as_ref());
+            if let Some(alt) = stmt.alt.as_ref() {
+                self.out.push('\n');
+                self.comments_before(alt.span().0);
+                self.push_indent();
+                self.out.push_str("else ");
+                self.statement(alt.as_ref());
+            }
+        } else if let Some(stmt) = any.downcast_ref::<WhileStatement>() {
+            self.out.push_str("while ");
+            self.expr(stmt.expr.as_ref());
+            self.body(stmt.then.as_ref());
+        } else if let Some(stmt) = any.downcast_ref::<ForInStatement>() {
+            self.out.push_str("for ");
+            self.out.push_str(&stmt.id);
+            self.out.push_str(" in ");
+            self.expr(stmt.expr.as_ref());
+            self.body(stmt.stmt.as_ref());
+        } else if let Some(stmt) = any.downcast_ref::<TryStatement>() {
+            let end = self.end_keyword(stmt._span.1);
+            let case_starts: Vec<usize> = stmt.cases.iter().map(|case| case._span.0).collect();
+            self.out.push_str("try");
+            self.block(&stmt.stmts, case_starts.first().copied().unwrap_or(end));
+            for (idx, case) in stmt.cases.iter().enumerate() {
+                self.out.push_str("case ");
+                self.expr(case.etype.as_ref());
+                if let Some(id) = case.id.as_ref() {
+                    self.out.push_str(" as ");
+                    self.expr(id.as_ref());
+                }
+                self.block(
+                    &case.stmts,
+                    case_starts.get(idx + 1).copied().unwrap_or(end),
+                );
+            }
+            self.out.push_str("end");
+        } else if let Some(stmt) = any.downcast_ref::<FunctionStatement>() {
+            let def = stmt.def();
+            self.out.push_str("func ");
+            self.out.push_str(def.id.as_deref().unwrap_or_default());
+            self.function(def);
+        } else if let Some(stmt) = any.downcast_ref::<RecordStatement>() {
+            let def = stmt.def();
+            self.out.push_str("record ");
+            self.out.push_str(def.id.as_deref().unwrap_or_default());
+            self.block(&def.stmts, self.end_keyword(stmt._span.1));
+            self.out.push_str("end");
+        } else {
+            self.verbatim(node);
+        }
+    }
+
+    // body of an if, while or for statement
+    fn body(&mut self, node: &dyn Ast) {
+        if let Some(block) = node.as_any().downcast_ref::<BlockStatement>() {
+            self.out.push_str(" begin");
+            self.block(&block.stmts, self.end_keyword(block._span.1));
+            self.out.push_str("end");
+        } else {
+            self.out.push_str(" then ");
+            self.statement(node);
+        }
+    }
+
+    // arguments and body of a function, after its name
+    fn function(&mut self, def: &FunctionDefinition) {
+        self.out.push('(');
+        self.out.push_str(&def.args.join(", "));
+        self.out.push(')');
+        let stmts = match def.stmt.as_any().downcast_ref::<BlockStatement>() {
+            Some(block) => &block.stmts[..],
+            None => std::slice::from_ref(&def.stmt),
+        };
+        self.block(stmts, self.end_keyword(def._span.1));
+        self.out.push_str("end");
+    }
+
+    fn expr(&mut self, node: &dyn Ast) {
+        let any = node.as_any();
+        if let Some(expr) = any.downcast_ref::<Identifier>() {
+            self.out.push_str(&expr.val);
+        } else if let Some(expr) = any.downcast_ref::<ArrayExpr>() {
+            self.out.push('[');
+            self.list(&expr.exprs);
+            self.out.push(']');
+        } else if let Some(def) = any.downcast_ref::<FunctionDefinition>() {
+            self.out.push_str("fn");
+            self.function(def);
+        } else if let Some(def) = any.downcast_ref::<RecordDefinition>() {
+            self.out.push_str("record");
+            self.block(&def.stmts, self.end_keyword(def._span.1));
+            self.out.push_str("end");
+        } else if let Some(expr) = any.downcast_ref::<UnaryExpr>() {
+            self.out.push_str(match expr.op {
+                UnaryOp::Not => "not ",
+                UnaryOp::Neg => "-",
+            });
+            self.operand(expr.val.as_ref(), precedence(expr.val.as_ref()) < ATOM);
+        } else if let Some(expr) = any.downcast_ref::<CondExpr>() {
+            for (idx, operand) in [&expr.cond, &expr.then, &expr.alt].into_iter().enumerate() {
+                if idx > 0 {
+                    self.out.push_str(if idx == 1 { " ? " } else { " : " });
+                }
+                self.operand(operand.as_ref(), precedence(operand.as_ref()) <= COND);
+            }
+        } else if let Some(expr) = any.downcast_ref::<BinExpr>() {
+            let prec = precedence(node);
+            self.operand(expr.left.as_ref(), precedence(expr.left.as_ref()) < prec);
+            self.out.push(' ');
+            self.out.push_str(operator(&expr.op));
+            self.out.push(' ');
+            self.operand(expr.right.as_ref(), precedence(expr.right.as_ref()) <= prec);
+        } else if let Some(expr) = any.downcast_ref::<MemExpr>() {
+            let right = expr.right.as_any();
+            let dot = !expr.is_expr && right.downcast_ref::<Identifier>().is_some();
+            // 1.x would be read as the float 1. followed by x
+            let int_before_dot =
+                dot && !expr.is_namespace && expr.left.as_any().is::<IntLiteral>();
+            self.operand(
+                expr.left.as_ref(),
+                needs_parens_before_postfix(expr.left.as_ref()) || int_before_dot,
+            );
+            if !dot {
+                self.out.push('[');
+                self.expr(expr.right.as_ref());
+                self.out.push(']');
+            } else {
+                self.out
+                    .push_str(if expr.is_namespace { "::" } else { "." });
+                self.expr(expr.right.as_ref());
+            }
+        } else if let Some(expr) = any.downcast_ref::<CallExpr>() {
+            self.operand(
+                expr.callee.as_ref(),
+                needs_parens_before_postfix(expr.callee.as_ref()),
+            );
+            self.out.push('(');
+            self.list(&expr.args);
+            self.out.push(')');
+        } else {
+            // literals are kept as they're written
+            self.verbatim(node);
+        }
+    }
+
+    fn operand(&mut self, node: &dyn Ast, parenthesize: bool) {
+        if parenthesize {
+            self.out.push('(');
+            self.expr(node);
+            self.out.push(')');
+        } else {
+            self.expr(node);
+        }
+    }
+
+    fn list(&mut self, exprs: &[Box<dyn Ast>]) {
+        for (idx, expr) in exprs.iter().enumerate() {
+            if idx > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(expr.as_ref());
+        }
+    }
+}
+
+fn precedence(node: &dyn Ast) -> u8 {
+    let any = node.as_any();
+    if let Some(expr) = any.downcast_ref::<BinExpr>() {
+        match expr.op {
+            BinOp::Assign
+            | BinOp::Adds
+            | BinOp::Subs
+            | BinOp::Muls
+            | BinOp::Divs
+            | BinOp::Mods => ASSIGN,
+            BinOp::BitwiseAnd | BinOp::BitwiseOr | BinOp::BitwiseXor => BITWISE,
+            BinOp::And | BinOp::Or => LOGICAL,
+            BinOp::Eq | BinOp::Neq | BinOp::Gt | BinOp::Lt | BinOp::Geq | BinOp::Leq => COMPARE,
+            BinOp::Of => OF,
+            BinOp::Add | BinOp::Sub => SUM,
+            BinOp::Mul | BinOp::Div | BinOp::Mod => PRODUCT,
+        }
+    } else if any.is::<CondExpr>() {
+        COND
+    } else if any.is::<UnaryExpr>() {
+        UNARY
+    } else if any.is::<MemExpr>() || any.is::<CallExpr>() {
+        POSTFIX
+    } else if any.is::<Identifier>()
+        || any.is::<IntLiteral>()
+        || any.is::<FloatLiteral>()
+        || any.is::<StrLiteral>()
+        || any.is::<ArrayExpr>()
+        || any.is::<FunctionDefinition>()
+        || any.is::<RecordDefinition>()
+    {
+        ATOM
+    } else {
+        ASSIGN
+    }
+}
+
+// fn and record literals called or indexed right away are kept in parentheses,
+// so the end of their block doesn't read like the end of a statement
+fn needs_parens_before_postfix(node: &dyn Ast) -> bool {
+    let any = node.as_any();
+    precedence(node) < UNARY || any.is::<FunctionDefinition>() || any.is::<RecordDefinition>()
+}
+
+fn operator(op: &BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Mod => "%",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+        BinOp::Eq => "==",
+        BinOp::Neq => "!=",
+        BinOp::Gt => ">",
+        BinOp::Lt => "<",
+        BinOp::Geq => ">=",
+        BinOp::Leq => "<=",
+        BinOp::Assign => "=",
+        BinOp::Adds => "+=",
+        BinOp::Subs => "-=",
+        BinOp::Muls => "*=",
+        BinOp::Divs => "/=",
+        BinOp::Mods => "%=",
+        BinOp::Of => "of",
+        BinOp::BitwiseAnd => "&",
+        BinOp::BitwiseOr => "|",
+        BinOp::BitwiseXor => "~",
+    }
+}
+
+// string literal with the value
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,729 @@
+//! Language Server Protocol server for Hana sources, started by `haru --lsp`
+//!
+//! Documents are parsed and compiled whenever they change: parser and
+//! compiler errors are published as diagnostics, and the definitions the
+//! compiler tracks answer go to definition and hover requests.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+
+use serde_json::{json, Value as Json};
+
+use super::protocol;
+use crate::ast::{
+    ArrayExpr, Ast, BinExpr, BinOp, BlockStatement, CallExpr, ExprStatement, FloatLiteral,
+    ForInStatement, FunctionDefinition, FunctionStatement, Identifier, IfStatement, IntLiteral,
+    RecordDefinition, RecordStatement, Span, StrLiteral, TryStatement, WhileStatement,
+};
+use crate::compiler::{Binding, Compiler, DefinitionTable};
+use crate::grammar;
+use crate::hanayo;
+use crate::harumachine::value::Value;
+
+// LSP enums
+const SEVERITY_ERROR: u8 = 1;
+const SYMBOL_METHOD: u8 = 6;
+const SYMBOL_FIELD: u8 = 8;
+const SYMBOL_FUNCTION: u8 = 12;
+const SYMBOL_STRUCT: u8 = 23;
+const COMPLETION_METHOD: u8 = 2;
+const COMPLETION_FUNCTION: u8 = 3;
+const COMPLETION_VARIABLE: u8 = 6;
+const COMPLETION_CLASS: u8 = 7;
+const COMPLETION_CONSTANT: u8 = 21;
+const METHOD_NOT_FOUND: i32 = -32601;
+
+/// Position in the document, as LSP counts them: the line and the UTF-16
+/// code unit in it
+fn position(src: &str, offset: usize) -> Json {
+    let offset = floor_char_boundary(src, offset);
+    let line_start = src[..offset].rfind('\n').map_or(0, |pos| pos + 1);
+    json!({
+        "line": src[..offset].matches('\n').count(),
+        "character": src[line_start..offset].encode_utf16().count(),
+    })
+}
+
+fn range(src: &str, span: Span) -> Json {
+    json!({ "start": position(src, span.0), "end": position(src, span.1) })
+}
+
+// byte offset of an LSP position
+fn offset(src: &str, position: &Json) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let Some(line_start) = (match line {
+        0 => Some(0),
+        _ => src
+            .match_indices('\n')
+            .nth(line - 1)
+            .map(|(pos, _)| pos + 1),
+    }) else {
+        return src.len();
+    };
+    let mut units = 0;
+    for (idx, ch) in src[line_start..].char_indices() {
+        if units >= character || ch == '\n' {
+            return line_start + idx;
+        }
+        units += ch.len_utf16();
+    }
+    src.len()
+}
+
+fn floor_char_boundary(src: &str, offset: usize) -> usize {
+    let mut offset = offset.min(src.len());
+    while !src.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+fn is_identifier_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_' || ch == '$' || ch == '?' || ch == '!'
+}
+
+// nodes defining a variable span more than its name, like the whole
+// function, this finds the name in them
+fn name_span(src: &str, span: Span, name: &str) -> Span {
+    let start = floor_char_boundary(src, span.0);
+    let end = floor_char_boundary(src, span.1).max(start);
+    let text = &src[start..end];
+    for (idx, _) in text.match_indices(name) {
+        let before = text[..idx].chars().next_back();
+        let after = text[idx + name.len()..].chars().next();
+        if !before.is_some_and(is_identifier_char) && !after.is_some_and(is_identifier_char) {
+            return (start + idx, start + idx + name.len());
+        }
+    }
+    (start, end)
+}
+
+// prototype of the value an expression makes, the callee of a call is
+// taken to be a record with a constructor
+fn type_of(expr: &dyn Ast) -> Option<String> {
+    let any = expr.as_any();
+    if any.is::<StrLiteral>() {
+        Some("String".to_string())
+    } else if any.is::<IntLiteral>() {
+        Some("Int".to_string())
+    } else if any.is::<FloatLiteral>() {
+        Some("Float".to_string())
+    } else if any.is::<ArrayExpr>() {
+        Some("Array".to_string())
+    } else {
+        let call = any.downcast_ref::<CallExpr>()?;
+        let callee = call.callee.as_any().downcast_ref::<Identifier>()?;
+        Some(callee.val.clone())
+    }
+}
+
+// offset of the bracket opening the one the text ends with
+fn opening_bracket(text: &str, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    for (idx, ch) in text.char_indices().rev() {
+        if ch == close {
+            depth += 1;
+        } else if ch == open {
+            depth -= 1;
+            if depth == 0 {
+                return Some(idx);
+            }
+        }
+    }
+    None
+}
+
+// prototype of the literal or constructor call the text ends with, as in
+// `"abc"`, `[1, 2]` or `Foo(x)`
+fn literal_type(text: &str) -> Option<String> {
+    let text = text.trim_end();
+    if text.ends_with('"') || text.ends_with('\'') {
+        Some("String".to_string())
+    } else if text.ends_with(']') {
+        // an index like a[0] could be anything
+        let open = opening_bracket(text, '[', ']')?;
+        let before = text[..open].trim_end().chars().next_back();
+        match before {
+            Some(ch) if is_identifier_char(ch) || ch == ')' || ch == ']' => None,
+            _ => Some("Array".to_string()),
+        }
+    } else if text.ends_with(')') {
+        let open = opening_bracket(text, '(', ')')?;
+        let callee = text[..open].trim_end_matches(is_identifier_char);
+        // a method call could return anything
+        if callee.len() == open || callee.ends_with('.') || callee.ends_with("::") {
+            return None;
+        }
+        Some(text[callee.len()..open].to_string())
+    } else {
+        None
+    }
+}
+
+/// Function defined in a document, for hovers
+struct FunctionInfo {
+    // where the compiler defines its name
+    spans: Vec<Span>,
+    name: String,
+    args: Vec<String>,
+}
+
+impl FunctionInfo {
+    fn signature(&self) -> String {
+        let arity = match self.args.len() {
+            1 => "1 argument".to_string(),
+            n => format!("{} arguments", n),
+        };
+        format!(
+            "```hana\nfunc {}({})\n```\ntakes {}",
+            self.name,
+            self.args.join(", "),
+            arity
+        )
+    }
+}
+
+/// What's known about a document after parsing and compiling it
+#[derive(Default)]
+struct Analysis {
+    parsed: bool,
+    diagnostics: Vec<Json>,
+    symbols: Vec<Json>,
+    functions: Vec<FunctionInfo>,
+    definitions: DefinitionTable,
+    /// Members of the records defined in the document, by name
+    records: HashMap<String, Vec<String>>,
+    /// Prototypes of the values assigned to each variable, like `String`
+    /// for `x = "a"` or `Foo` for `x = Foo()`
+    types: HashMap<String, BTreeSet<String>>,
+}
+
+impl Analysis {
+    fn new(src: &str) -> Analysis {
+        let mut analysis = Analysis::default();
+        let prog = match grammar::parser_start(src) {
+            Ok(prog) => {
+                analysis.parsed = true;
+                prog
+            }
+            Err(err) => {
+                let mut expected: Vec<&str> = err.expected.iter().copied().collect();
+                expected.sort_unstable();
+                analysis.diagnostics.push(json!({
+                    "range": range(src, (err.offset, err.offset)),
+                    "severity": SEVERITY_ERROR,
+                    "source": "haru",
+                    "message": format!("expected {}", expected.join(", ")),
+                }));
+                return analysis;
+            }
+        };
+
+        let mut symbols = Vec::new();
+        for stmt in prog.iter() {
+            analysis.collect(src, stmt.as_ref(), false, &mut symbols);
+        }
+        analysis.symbols = symbols;
+
+        let mut c = Compiler::new(true);
+        c.track_definitions();
+        // the innermost node being emitted is the last one in the source map
+        let last_span = |c: &Compiler, stmt: &dyn Ast| {
+            c.modules_info
+                .borrow()
+                .smap
+                .last()
+                .map_or(*stmt.span(), |smap| smap.file)
+        };
+        for stmt in prog.iter() {
+            // a bug in the compiler mustn't take the server down with it
+            let emitted = panic::catch_unwind(AssertUnwindSafe(|| stmt.emit(&mut c)));
+            let message = match emitted {
+                Ok(Ok(())) => continue,
+                Ok(Err(err)) => err.to_string(),
+                Err(_) => "internal compiler error".to_string(),
+            };
+            analysis.diagnostics.push(json!({
+                "range": range(src, last_span(&c, stmt.as_ref())),
+                "severity": SEVERITY_ERROR,
+                "source": "haru",
+                "message": message,
+            }));
+            break;
+        }
+        analysis.definitions = c.definitions.take().unwrap_or_default();
+        analysis
+    }
+
+    // adds the symbols of the statement and the ones nested in it
+    fn collect(&mut self, src: &str, node: &dyn Ast, in_record: bool, out: &mut Vec<Json>) {
+        let any = node.as_any();
+        let kind = if in_record {
+            SYMBOL_METHOD
+        } else {
+            SYMBOL_FUNCTION
+        };
+        let mut nested: Vec<&dyn Ast> = Vec::new();
+        if let Some(stmt) = any.downcast_ref::<FunctionStatement>() {
+            let def = stmt.def();
+            let name = def.id.clone().unwrap_or_default();
+            let spans = vec![*stmt.span(), def._span];
+            out.push(self.function(src, name, &def.args, spans, kind, Some(def.stmt.as_ref())));
+        } else if let Some(stmt) = any.downcast_ref::<RecordStatement>() {
+            let def = stmt.def();
+            let name = def.id.clone().unwrap_or_default();
+            out.push(self.record(src, name, *stmt.span(), def));
+        } else if let Some(stmt) = any.downcast_ref::<ExprStatement>() {
+            out.extend(self.assignment(src, stmt, in_record));
+        } else if let Some(stmt) = any.downcast_ref::<BlockStatement>() {
+            nested.extend(stmt.stmts.iter().map(|stmt| stmt.as_ref()));
+        } else if let Some(stmt) = any.downcast_ref::<IfStatement>() {
+            nested.push(stmt.then.as_ref());
+            nested.extend(stmt.alt.as_deref());
+        } else if let Some(stmt) = any.downcast_ref::<WhileStatement>() {
+            nested.push(stmt.then.as_ref());
+        } else if let Some(stmt) = any.downcast_ref::<ForInStatement>() {
+            nested.push(stmt.stmt.as_ref());
+        } else if let Some(stmt) = any.downcast_ref::<TryStatement>() {
+            nested.extend(stmt.stmts.iter().map(|stmt| stmt.as_ref()));
+            for case in stmt.cases.iter() {
+                nested.extend(case.stmts.iter().map(|stmt| stmt.as_ref()));
+            }
+        }
+        for stmt in nested {
+            self.collect(src, stmt, in_record, out);
+        }
+    }
+
+    fn function(
+        &mut self,
+        src: &str,
+        name: String,
+        args: &[String],
+        spans: Vec<Span>,
+        kind: u8,
+        body: Option<&dyn Ast>,
+    ) -> Json {
+        let mut children = Vec::new();
+        if let Some(body) = body {
+            self.collect(src, body, false, &mut children);
+        }
+        let span = spans[0];
+        let symbol = json!({
+            "name": name,
+            "detail": format!("func {}({})", name, args.join(", ")),
+            "kind": kind,
+            "range": range(src, span),
+            "selectionRange": range(src, name_span(src, span, &name)),
+            "children": children,
+        });
+        self.functions.push(FunctionInfo {
+            spans,
+            name,
+            args: args.to_vec(),
+        });
+        symbol
+    }
+
+    fn record(&mut self, src: &str, name: String, span: Span, def: &RecordDefinition) -> Json {
+        let mut children = Vec::new();
+        for stmt in def.stmts.iter() {
+            self.collect(src, stmt.as_ref(), true, &mut children);
+        }
+        let members = children
+            .iter()
+            .filter_map(|child| child["name"].as_str())
+            .map(|member| member.to_string())
+            .collect();
+        self.records.insert(name.clone(), members);
+        json!({
+            "name": name,
+            "kind": SYMBOL_STRUCT,
+            "range": range(src, span),
+            "selectionRange": range(src, name_span(src, span, &name)),
+            "children": children,
+        })
+    }
+
+    // functions and records assigned to a name, and the fields of records
+    fn assignment(&mut self, src: &str, stmt: &ExprStatement, in_record: bool) -> Option<Json> {
+        let expr = stmt.expr.as_any().downcast_ref::<BinExpr>()?;
+        if !matches!(expr.op, BinOp::Assign) {
+            return None;
+        }
+        let span = *stmt.span();
+        let left = expr.left.as_any();
+        let kind = if in_record {
+            SYMBOL_METHOD
+        } else {
+            SYMBOL_FUNCTION
+        };
+        if let Some(call) = left.downcast_ref::<CallExpr>() {
+            // f(x) = expr
+            let callee = call.callee.as_any().downcast_ref::<Identifier>()?;
+            let args: Vec<String> = call
+                .args
+                .iter()
+                .filter_map(|arg| arg.as_any().downcast_ref::<Identifier>())
+                .map(|arg| arg.val.clone())
+                .collect();
+            let spans = vec![span, callee._span];
+            return Some(self.function(src, callee.val.clone(), &args, spans, kind, None));
+        }
+        let id = left.downcast_ref::<Identifier>()?;
+        if let Some(prototype) = type_of(expr.right.as_ref()) {
+            self.types
+                .entry(id.val.clone())
+                .or_default()
+                .insert(prototype);
+        }
+        let right = expr.right.as_any();
+        if let Some(def) = right.downcast_ref::<FunctionDefinition>() {
+            let spans = vec![span, id._span, def._span];
+            let body = Some(def.stmt.as_ref());
+            Some(self.function(src, id.val.clone(), &def.args, spans, kind, body))
+        } else if let Some(def) = right.downcast_ref::<RecordDefinition>() {
+            Some(self.record(src, id.val.clone(), span, def))
+        } else if in_record {
+            Some(json!({
+                "name": id.val,
+                "kind": SYMBOL_FIELD,
+                "range": range(src, span),
+                "selectionRange": range(src, id._span),
+            }))
+        } else {
+            None
+        }
+    }
+
+    /// Innermost node reading or setting a variable at the offset, with the
+    /// range of its name
+    fn use_at(&self, src: &str, offset: usize) -> Option<(Span, &str, &Binding)> {
+        self.definitions
+            .uses
+            .iter()
+            .map(|var| (name_span(src, var.span, &var.name), var))
+            .filter(|(span, _)| span.0 <= offset && offset <= span.1)
+            .min_by_key(|(span, _)| span.1 - span.0)
+            .map(|(span, var)| (span, var.name.as_str(), &var.binding))
+    }
+
+    fn function_at(&self, span: Span) -> Option<&FunctionInfo> {
+        self.functions
+            .iter()
+            .find(|function| function.spans.iter().any(|s| s.0 == span.0))
+    }
+}
+
+/// Globals `hanayo::init` defines and the methods of its records
+struct Stdlib {
+    globals: Vec<(String, Value)>,
+    methods: HashMap<String, Vec<String>>,
+}
+
+impl Stdlib {
+    fn new() -> Stdlib {
+        let vm = Compiler::new(true).get_vm();
+        hanayo::init(std::rc::Rc::clone(&vm));
+        let vm = vm.borrow();
+        let mut globals: Vec<(String, Value)> = vm
+            .global()
+            .iter()
+            .map(|(key, val)| (key.to_string(), val.clone()))
+            .collect();
+        globals.sort_by(|(x, _), (y, _)| x.cmp(y));
+        let methods = globals
+            .iter()
+            .filter_map(|(name, val)| match val {
+                Value::Record(rec) => {
+                    let mut keys: Vec<String> = rec
+                        .as_ref()
+                        .iter()
+                        .map(|(key, _)| key.to_string())
+                        .collect();
+                    keys.sort();
+                    Some((name.clone(), keys))
+                }
+                _ => None,
+            })
+            .collect();
+        Stdlib { globals, methods }
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.globals
+            .iter()
+            .find(|(global, _)| global == name)
+            .map(|(_, val)| val)
+    }
+}
+
+fn describe(val: &Value) -> (&'static str, u8) {
+    match val {
+        Value::NativeFn(_) | Value::Fn(_) => ("function", COMPLETION_FUNCTION),
+        Value::Record(_) => ("record", COMPLETION_CLASS),
+        _ => ("value", COMPLETION_CONSTANT),
+    }
+}
+
+struct Server {
+    documents: HashMap<String, String>,
+    analyses: HashMap<String, Analysis>,
+    stdlib: Stdlib,
+    shutdown: bool,
+}
+
+impl Server {
+    fn send(&self, msg: Json) {
+        protocol::write_message(&mut io::stdout().lock(), &msg);
+    }
+
+    fn respond(&self, request: &Json, result: Json) {
+        self.send(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }));
+    }
+
+    fn notify(&self, method: &str, params: Json) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn update(&mut self, uri: &str, text: String) {
+        let mut analysis = Analysis::new(&text);
+        // documents don't parse while they're being typed, what was found
+        // the last time they did is kept until then
+        if let Some(last) = self.analyses.remove(uri).filter(|_| !analysis.parsed) {
+            analysis.symbols = last.symbols;
+            analysis.functions = last.functions;
+            analysis.definitions = last.definitions;
+            analysis.records = last.records;
+            analysis.types = last.types;
+        }
+        self.notify(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": analysis.diagnostics }),
+        );
+        self.documents.insert(uri.to_string(), text);
+        self.analyses.insert(uri.to_string(), analysis);
+    }
+
+    // the document, its analysis and the offset the request points to
+    fn document<'a>(
+        &'a self,
+        params: &'a Json,
+    ) -> Option<(&'a str, &'a str, &'a Analysis, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let src = self.documents.get(uri)?;
+        let analysis = self.analyses.get(uri)?;
+        Some((uri, src, analysis, offset(src, &params["position"])))
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let Some((uri, src, analysis, offset)) = self.document(params) else {
+            return Json::Null;
+        };
+        let Some((_, name, binding)) = analysis.use_at(src, offset) else {
+            return Json::Null;
+        };
+        match analysis.definitions.definitions.get(binding) {
+            Some(span) => json!({ "uri": uri, "range": range(src, name_span(src, *span, name)) }),
+            None => Json::Null,
+        }
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let Some((_, src, analysis, offset)) = self.document(params) else {
+            return Json::Null;
+        };
+        let Some((span, name, binding)) = analysis.use_at(src, offset) else {
+            return Json::Null;
+        };
+        let contents = match analysis.definitions.definitions.get(binding) {
+            Some(def) => match analysis.function_at(*def) {
+                Some(function) => function.signature(),
+                None => format!("```hana\n{}\n```", name),
+            },
+            None => match (binding, self.stdlib.get(name)) {
+                (Binding::Global(_), Some(val)) => {
+                    format!("```hana\n{}\n```\nbuilt-in {}", name, describe(val).0)
+                }
+                _ => return Json::Null,
+            },
+        };
+        json!({
+            "contents": { "kind": "markdown", "value": contents },
+            "range": range(src, span),
+        })
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let Some((_, src, analysis, offset)) = self.document(params) else {
+            return json!([]);
+        };
+        // the word being typed and what comes before it
+        let line_start = src[..offset].rfind('\n').map_or(0, |pos| pos + 1);
+        let before = src[line_start..offset].trim_end_matches(is_identifier_char);
+        if let Some(left) = before
+            .strip_suffix("::")
+            .or_else(|| before.strip_suffix('.'))
+        {
+            return json!(self.members(analysis, left));
+        }
+
+        let mut items = Vec::new();
+        let mut seen = BTreeSet::new();
+        for var in analysis.definitions.uses.iter() {
+            if analysis.definitions.definitions.contains_key(&var.binding)
+                && seen.insert(var.name.as_str())
+            {
+                let kind =
+                    match analysis.function_at(analysis.definitions.definitions[&var.binding]) {
+                        Some(_) => COMPLETION_FUNCTION,
+                        None => COMPLETION_VARIABLE,
+                    };
+                items.push(json!({ "label": var.name, "kind": kind }));
+            }
+        }
+        for (name, val) in self.stdlib.globals.iter() {
+            if seen.insert(name.as_str()) {
+                let (detail, kind) = describe(val);
+                items.push(json!({ "label": name, "kind": kind, "detail": detail }));
+            }
+        }
+        json!(items)
+    }
+
+    // completions for the members of the value before a `.` or `::`: only
+    // the members of its prototype when it's known, or every method with
+    // the ones of the prototypes it may have first
+    fn members(&self, analysis: &Analysis, left: &str) -> Vec<Json> {
+        let mut prototypes = BTreeSet::new();
+        if let Some(prototype) = literal_type(left) {
+            prototypes.insert(prototype);
+        } else {
+            let start = left
+                .rfind(|ch: char| !is_identifier_char(ch))
+                .map_or(0, |pos| pos + 1);
+            let name = &left[start..];
+            if analysis.records.contains_key(name) || self.stdlib.methods.contains_key(name) {
+                prototypes.insert(name.to_string());
+            } else if let Some(types) = analysis.types.get(name) {
+                prototypes.extend(types.iter().cloned());
+            }
+        }
+        let members_of = |prototype: &String| {
+            analysis
+                .records
+                .get(prototype)
+                .or_else(|| self.stdlib.methods.get(prototype))
+        };
+        let likely: BTreeSet<&String> =
+            prototypes.iter().filter_map(members_of).flatten().collect();
+        let method = |label: &String, rank: u8| {
+            json!({
+                "label": label,
+                "kind": COMPLETION_METHOD,
+                "sortText": format!("{}{}", rank, label),
+            })
+        };
+        if prototypes.len() == 1 && prototypes.iter().all(|p| members_of(p).is_some()) {
+            return likely.into_iter().map(|label| method(label, 0)).collect();
+        }
+        let rest: BTreeSet<&String> = self
+            .stdlib
+            .methods
+            .values()
+            .flatten()
+            .filter(|label| !likely.contains(label))
+            .collect();
+        likely
+            .into_iter()
+            .map(|label| method(label, 0))
+            .chain(rest.into_iter().map(|label| method(label, 1)))
+            .collect()
+    }
+
+    fn handle(&mut self, msg: Json) {
+        let method = msg["method"].as_str().unwrap_or("");
+        let params = &msg["params"];
+        let is_request = msg.get("id").is_some();
+        match method {
+            "initialize" => self.respond(
+                &msg,
+                json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "documentSymbolProvider": true,
+                        "definitionProvider": true,
+                        "hoverProvider": true,
+                        "completionProvider": { "triggerCharacters": [".", ":"] },
+                    },
+                    "serverInfo": { "name": "haru", "version": env!("CARGO_PKG_VERSION") },
+                }),
+            ),
+            "shutdown" => {
+                self.shutdown = true;
+                self.respond(&msg, Json::Null);
+            }
+            "exit" => std::process::exit(if self.shutdown { 0 } else { 1 }),
+            "textDocument/didOpen" => {
+                let doc = &params["textDocument"];
+                if let (Some(uri), Some(text)) = (doc["uri"].as_str(), doc["text"].as_str()) {
+                    self.update(uri, text.to_string());
+                }
+            }
+            "textDocument/didChange" => {
+                // documents are synced in full, the last change is the whole text
+                let uri = params["textDocument"]["uri"].as_str();
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(uri), Some(text)) = (uri, text) {
+                    self.update(uri, text.to_string());
+                }
+            }
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                    self.analyses.remove(uri);
+                    self.notify(
+                        "textDocument/publishDiagnostics",
+                        json!({ "uri": uri, "diagnostics": [] }),
+                    );
+                }
+            }
+            "textDocument/documentSymbol" => {
+                let symbols = params["textDocument"]["uri"]
+                    .as_str()
+                    .and_then(|uri| self.analyses.get(uri))
+                    .map_or(json!([]), |analysis| json!(analysis.symbols));
+                self.respond(&msg, symbols);
+            }
+            "textDocument/definition" => self.respond(&msg, self.definition(params)),
+            "textDocument/hover" => self.respond(&msg, self.hover(params)),
+            "textDocument/completion" => self.respond(&msg, self.completion(params)),
+            // notifications the server doesn't handle are ignored
+            _ if !is_request => {}
+            _ => self.send(json!({
+                "jsonrpc": "2.0",
+                "id": msg["id"],
+                "error": { "code": METHOD_NOT_FOUND, "message": format!("unsupported method {}", method) },
+            })),
+        }
+    }
+}
+
+/// Serves the Language Server Protocol on stdin and stdout until the
+/// client exits
+pub fn run_lsp() {
+    let mut server = Server {
+        documents: HashMap::new(),
+        analyses: HashMap::new(),
+        stdlib: Stdlib::new(),
+        shutdown: false,
+    };
+    while let Some(msg) = protocol::read_message(&mut io::stdin().lock()) {
+        server.handle(msg);
+    }
+}
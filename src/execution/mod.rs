@@ -1,18 +1,66 @@
-use std::{io, rc::Rc};
+use std::{
+    cell::RefCell,
+    io,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use crate::{
     compiler, grammar, hanayo,
-    harumachine::vm::{execute_vm, VmOpcode},
+    harumachine::vm::{execute_vm, ExecutionLimits, VmHook, VmOpcode},
 };
 
+pub mod coverage;
+#[cfg(feature = "debuger")]
+pub mod dap;
+#[cfg(feature = "debuger")]
+pub mod debugger;
 pub mod errors;
+pub mod formatter;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod profiler;
+#[cfg(any(feature = "debuger", feature = "lsp"))]
+mod protocol;
 pub mod repl;
+pub mod trace;
 
 use std::io::{Read, Write};
 
 pub struct ParserFlag {
     pub dump_bytecode: bool,
     pub print_ast: bool,
+    pub max_call_depth: usize,
+    pub fuel: Option<u64>,
+    pub time_limit: Option<Duration>,
+    pub max_heap_size: Option<usize>,
+    /// File the folded call stacks of the profile are written to
+    pub profile: Option<String>,
+    /// Weighs the folded call stacks by time rather than instructions
+    pub profile_time: bool,
+    /// File the per-function report of the profile is written to
+    pub profile_report: Option<String>,
+    /// File the lcov line coverage of the script is written to
+    pub coverage: Option<String>,
+    /// Logs every instruction run to stderr
+    pub trace: bool,
+    /// Functions and files the instructions traced are limited to
+    pub trace_functions: Vec<String>,
+    pub trace_files: Vec<String>,
+    /// Number of values shown from the top of the stack when tracing
+    pub trace_stack: usize,
+    #[cfg(feature = "debuger")]
+    pub debug: bool,
+}
+
+impl ParserFlag {
+    // limits for running a program from now on
+    pub fn execution_limits(&self) -> ExecutionLimits {
+        ExecutionLimits {
+            fuel: self.fuel,
+            deadline: self.time_limit.map(|limit| Instant::now() + limit),
+        }
+    }
 }
 
 // command/file
@@ -27,6 +75,7 @@ pub struct ScriptExecutor {
     flag: ParserFlag,
     compiler: compiler::Compiler,
     script: String,
+    hook: Option<Box<dyn VmHook>>,
 }
 
 impl ScriptExecutor {
@@ -36,19 +85,30 @@ impl ScriptExecutor {
             flag,
             compiler: compiler::Compiler::new(true),
             script: String::new(),
+            hook: None,
         }
     }
 
+    /// Source of the script, once it's loaded
+    #[cfg(feature = "debuger")]
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+
+    #[cfg(feature = "debuger")]
+    pub fn modules_info(&self) -> Rc<std::cell::RefCell<compiler::ModulesInfo>> {
+        Rc::clone(&self.compiler.modules_info)
+    }
+
+    /// Hook the virtual machine runs the script with
+    #[cfg(feature = "debuger")]
+    pub fn set_hook(&mut self, hook: Box<dyn VmHook>) {
+        self.hook = Some(hook);
+    }
+
     pub fn load_script(&mut self) {
-        self.script = match self.arg.clone() {
-            ExecutionKind::Command(cmd) => {
-                self.compiler
-                    .modules_info
-                    .borrow_mut()
-                    .files
-                    .push("[cmdline]".to_string());
-                cmd.to_string()
-            }
+        let (file, script) = match self.arg.clone() {
+            ExecutionKind::Command(cmd) => ("[cmdline]".to_string(), cmd.to_string()),
             ExecutionKind::File(filename) => {
                 let mut script = String::new();
                 if &filename == "-" {
@@ -58,11 +118,7 @@ impl ScriptExecutor {
                             println!("error reading from stdin: {}", err);
                             std::process::exit(1);
                         });
-                    self.compiler
-                        .modules_info
-                        .borrow_mut()
-                        .files
-                        .push("[stdin]".to_string());
+                    ("[stdin]".to_string(), script)
                 } else {
                     let mut file = std::fs::File::open(&filename).unwrap_or_else(|err| {
                         println!("error opening file: {}", err);
@@ -72,15 +128,20 @@ impl ScriptExecutor {
                         println!("error reading file: {}", err);
                         std::process::exit(1);
                     });
-                    let mut modules_info = self.compiler.modules_info.borrow_mut();
-                    modules_info
+                    self.compiler
+                        .modules_info
+                        .borrow_mut()
                         .modules_loaded
                         .insert(std::path::Path::new(&filename).to_path_buf());
-                    modules_info.files.push(filename.to_string());
+                    (filename.to_string(), script)
                 }
-                script
             }
         };
+        self.compiler
+            .modules_info
+            .borrow_mut()
+            .add_file(file, script.clone());
+        self.script = script;
     }
 
     pub fn parse_script(&self) -> grammar::Program {
@@ -112,20 +173,85 @@ impl ScriptExecutor {
         self.compiler.cpushop(VmOpcode::Halt);
     }
 
-    pub fn execute(&mut self) {
-        self.compiler
-            .modules_info
-            .borrow_mut()
-            .sources
-            .push(self.script.clone());
-
+    /// Runs the compiled script, returning whether it ended with an error
+    pub fn execute(&mut self) -> bool {
         let vm = self.compiler.get_vm();
         hanayo::init(Rc::clone(&vm));
 
         vm.borrow_mut().gc_enable();
+        vm.borrow_mut().set_max_call_depth(self.flag.max_call_depth);
+        vm.borrow().set_max_heap_size(self.flag.max_heap_size);
+        vm.borrow_mut()
+            .set_execution_limits(self.flag.execution_limits());
+        let mut hooks: Vec<Box<dyn VmHook>> = self.hook.take().into_iter().collect();
+        #[cfg(feature = "debuger")]
+        if self.flag.debug {
+            hooks.push(Box::new(debugger::Debugger::new(&vm.borrow())));
+        }
+        let profile = if self.flag.profile.is_none() && self.flag.profile_report.is_none() {
+            None
+        } else if !hooks.is_empty() {
+            eprintln!("the script can't be profiled while it's being debugged");
+            None
+        } else {
+            let profile = Rc::new(RefCell::new(profiler::Profile::new()));
+            hooks.push(Box::new(profiler::Profiler::new(Rc::clone(&profile))));
+            Some(profile)
+        };
+        let coverage = self.flag.coverage.as_ref().map(|_| {
+            let coverage = Rc::new(RefCell::new(coverage::Coverage::new()));
+            hooks.push(Box::new(coverage::CoverageHook::new(Rc::clone(&coverage))));
+            coverage
+        });
+        if self.flag.trace {
+            hooks.push(Box::new(trace::Tracer::new(trace::TraceFilter {
+                functions: self.flag.trace_functions.clone(),
+                files: self.flag.trace_files.clone(),
+                stack: self.flag.trace_stack,
+            })));
+        }
+        match hooks.len() {
+            0 => (),
+            1 => {
+                vm.borrow_mut().set_hook(hooks.pop());
+            }
+            _ => {
+                vm.borrow_mut().set_hook(Some(Box::new(hooks)));
+            }
+        }
 
         execute_vm(Rc::clone(&vm));
-        errors::handle_error(Rc::clone(&vm), &self.compiler);
+        let failed = errors::handle_error(Rc::clone(&vm), &self.compiler);
+        vm.borrow_mut().set_hook(None);
+        if let Some(profile) = profile {
+            profile.borrow_mut().stop_timing();
+            self.write_profile(&profile.borrow());
+        }
+        if let (Some(path), Some(coverage)) = (&self.flag.coverage, coverage) {
+            let lcov = coverage.borrow().lcov(&self.compiler.modules_info.borrow());
+            if let Err(err) = std::fs::write(path, lcov) {
+                eprintln!("error writing coverage to {}: {}", path, err);
+            }
+        }
+        failed
+    }
+
+    fn write_profile(&self, profile: &profiler::Profile) {
+        let info = self.compiler.modules_info.borrow();
+        let outputs = [
+            (
+                &self.flag.profile,
+                profile.folded(&info, self.flag.profile_time),
+            ),
+            (&self.flag.profile_report, profile.report(&info)),
+        ];
+        for (path, contents) in outputs {
+            if let Some(path) = path {
+                if let Err(err) = std::fs::write(path, contents) {
+                    eprintln!("error writing profile to {}: {}", path, err);
+                }
+            }
+        }
     }
 
     pub fn run(&mut self) {
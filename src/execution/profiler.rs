@@ -0,0 +1,433 @@
+//! Profiler for scripts run with --profile: counts the instructions run in
+//! every call stack and the time spent in it, for flamegraphs and a report
+//! of the self and total time of every function
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::compiler::ModulesInfo;
+use crate::harumachine::env::Env;
+use crate::harumachine::value::{NativeFnData, Value};
+use crate::harumachine::vm::{Vm, VmHook, VmOpcode};
+
+/// Function a frame of the call stack is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum FrameId {
+    /// Top level of the script and its modules
+    Main,
+    /// Function written in hana, an index into [ModulesInfo::scopes]
+    Function(usize),
+    /// Native function, an index into the names of native functions
+    Native(usize),
+}
+
+// node of the call tree, standing for the call stack from the root to it
+struct Node {
+    frame: FrameId,
+    parent: usize,
+    children: HashMap<FrameId, usize>,
+    instructions: u64,
+    time: Duration,
+}
+
+// function call on the call stack with the node of its caller, where its
+// frame starts and its return address tell whether the call is still running
+struct Call {
+    base: usize,
+    retip: u32,
+    caller: usize,
+}
+
+fn is_call_of(call: &Call, env: &Env) -> bool {
+    call.base == env.base && call.retip == env.retip
+}
+
+/// Instructions run and time spent per call stack
+///
+/// The call tree is updated as functions get called and return rather
+/// than walking the whole call stack before every instruction. Calls to
+/// native functions get a node of their own, which the call instruction
+/// and the time until the next instruction are counted in.
+pub struct Profile {
+    nodes: Vec<Node>,
+    calls: Vec<Call>,
+    // native functions running, with the call depth they were called at
+    // and their node
+    natives: Vec<(usize, usize)>,
+    native_names: Vec<String>,
+    native_ids: HashMap<usize, usize>,
+    // number of globals native functions were last looked up in
+    nglobals: usize,
+    // innermost function of every instruction, updated when more
+    // functions get compiled
+    functions: Vec<Option<usize>>,
+    nscopes: usize,
+    // node the time since the last instruction is spent in
+    timed: usize,
+    last: Instant,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            nodes: vec![Node {
+                frame: FrameId::Main,
+                parent: 0,
+                children: HashMap::new(),
+                instructions: 0,
+                time: Duration::ZERO,
+            }],
+            calls: Vec::new(),
+            natives: Vec::new(),
+            native_names: Vec::new(),
+            native_ids: HashMap::new(),
+            nglobals: 0,
+            functions: Vec::new(),
+            nscopes: 0,
+            timed: 0,
+            last: Instant::now(),
+        }
+    }
+
+    fn on_instruction(&mut self, vm: &Vm) {
+        self.stop_timing();
+        let Some(modules_info) = vm.modules_info.as_ref() else {
+            return;
+        };
+        let info = modules_info.borrow();
+
+        let depth = vm.localenv().len();
+        while matches!(self.natives.last(), Some(&(native_depth, _)) if native_depth >= depth) {
+            self.natives.pop();
+        }
+        self.update_calls(vm, &info);
+
+        let ip = vm.ip() as usize;
+        let mut node = match self.calls.last() {
+            Some(call) => {
+                let caller = call.caller;
+                let frame = self.function_at(&info, ip);
+                self.child(caller, frame)
+            }
+            None => 0,
+        };
+        if let Some(native) = native_callee(vm) {
+            let frame = FrameId::Native(self.native_id(vm, native));
+            node = self.child(node, frame);
+            self.natives.push((depth, node));
+        }
+        self.nodes[node].instructions += 1;
+        self.timed = node;
+    }
+
+    /// Counts the time since the last instruction in its call stack, the
+    /// script has ended when it's called from outside the hook
+    pub fn stop_timing(&mut self) {
+        let now = Instant::now();
+        self.nodes[self.timed].time += now - self.last;
+        self.last = now;
+    }
+
+    // drops the calls that returned and adds the new ones
+    fn update_calls(&mut self, vm: &Vm, info: &ModulesInfo) {
+        let envs = vm.localenv();
+        self.calls.truncate(envs.len());
+        while let Some(call) = self.calls.last() {
+            let env = &envs[self.calls.len() - 1];
+            if is_call_of(call, env) {
+                break;
+            }
+            self.calls.pop();
+        }
+        while self.calls.len() < envs.len() {
+            let depth = self.calls.len();
+            let env = &envs[depth];
+            let retip = env.retip;
+            let caller = if retip == u32::MAX {
+                // called back by a native function
+                self.natives
+                    .iter()
+                    .rev()
+                    .find(|&&(native_depth, _)| native_depth == depth)
+                    .map_or(0, |&(_, node)| node)
+            } else if let Some(call) = self.calls.last() {
+                let caller = call.caller;
+                let frame = self.function_at(info, retip as usize);
+                self.child(caller, frame)
+            } else {
+                0
+            };
+            self.calls.push(Call {
+                base: env.base,
+                retip,
+                caller,
+            });
+        }
+    }
+
+    fn child(&mut self, parent: usize, frame: FrameId) -> usize {
+        if let Some(&node) = self.nodes[parent].children.get(&frame) {
+            return node;
+        }
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            frame,
+            parent,
+            children: HashMap::new(),
+            instructions: 0,
+            time: Duration::ZERO,
+        });
+        self.nodes[parent].children.insert(frame, node);
+        node
+    }
+
+    fn function_at(&mut self, info: &ModulesInfo, ip: usize) -> FrameId {
+        if info.scopes.len() != self.nscopes {
+            self.nscopes = info.scopes.len();
+            let len = info
+                .scopes
+                .iter()
+                .map(|scope| scope.bytecode.1)
+                .max()
+                .unwrap_or(0);
+            // scopes of inner functions come before the ones around them
+            self.functions = vec![None; len];
+            for (idx, scope) in info.scopes.iter().enumerate().rev() {
+                self.functions[scope.bytecode.0..scope.bytecode.1].fill(Some(idx));
+            }
+        }
+        match self.functions.get(ip).copied().flatten() {
+            Some(idx) => FrameId::Function(idx),
+            None => FrameId::Main,
+        }
+    }
+
+    // finds the name of the native function among the globals and the
+    // members of global records
+    fn native_id(&mut self, vm: &Vm, native: NativeFnData) -> usize {
+        let key = native as usize;
+        if !self.native_ids.contains_key(&key) && vm.global().len() != self.nglobals {
+            self.nglobals = vm.global().len();
+            let mut globals: Vec<(String, &Value)> = vm
+                .global()
+                .iter()
+                .map(|(name, val)| (name.to_string(), val))
+                .collect();
+            globals.sort_by(|(x, _), (y, _)| x.cmp(y));
+            let mut names: Vec<(usize, String)> = Vec::new();
+            for (name, val) in globals.iter() {
+                if let Value::NativeFn(native) = val {
+                    names.push((*native as usize, name.clone()));
+                }
+            }
+            for (name, val) in globals.iter() {
+                if let Value::Record(rec) = val {
+                    let mut members: Vec<(String, usize)> = rec
+                        .as_ref()
+                        .iter()
+                        .filter_map(|(key, val)| match val {
+                            Value::NativeFn(native) => Some((key.to_string(), *native as usize)),
+                            _ => None,
+                        })
+                        .collect();
+                    members.sort();
+                    for (member, native) in members {
+                        names.push((native, format!("{}.{}", name, member)));
+                    }
+                }
+            }
+            for (native, name) in names {
+                if !self.native_ids.contains_key(&native) {
+                    self.native_ids.insert(native, self.native_names.len());
+                    self.native_names.push(name);
+                }
+            }
+        }
+        *self.native_ids.entry(key).or_insert_with(|| {
+            self.native_names.push("<native>".to_string());
+            self.native_names.len() - 1
+        })
+    }
+
+    fn name(&self, info: &ModulesInfo, frame: FrameId) -> String {
+        match frame {
+            FrameId::Main => "<main>".to_string(),
+            FrameId::Function(idx) => info.scopes[idx]
+                .name
+                .clone()
+                .unwrap_or_else(|| "<anonymous>".to_string()),
+            FrameId::Native(idx) => self.native_names[idx].clone(),
+        }
+    }
+
+    /// Call stacks in the folded format flamegraph tools read, each one
+    /// with the instructions run in it or the microseconds spent in it
+    pub fn folded(&self, info: &ModulesInfo, time: bool) -> String {
+        let mut stacks: Vec<(String, u64)> = Vec::new();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let weight = if time {
+                node.time.as_micros() as u64
+            } else {
+                node.instructions
+            };
+            if weight == 0 {
+                continue;
+            }
+            let mut frames = vec![self.name(info, node.frame)];
+            let mut idx = idx;
+            while idx != 0 {
+                idx = self.nodes[idx].parent;
+                frames.push(self.name(info, self.nodes[idx].frame));
+            }
+            frames.reverse();
+            stacks.push((frames.join(";"), weight));
+        }
+        // functions with the same name end up in the same stack
+        stacks.sort();
+        stacks.dedup_by(|next, prev| {
+            if next.0 == prev.0 {
+                prev.1 += next.1;
+                true
+            } else {
+                false
+            }
+        });
+        let mut out = String::new();
+        for (stack, weight) in stacks {
+            writeln!(out, "{} {}", stack, weight).unwrap();
+        }
+        out
+    }
+
+    /// Self and total time and instructions of every function, native
+    /// functions listed on their own
+    pub fn report(&self, info: &ModulesInfo) -> String {
+        // time and instructions of every node along with its callees
+        let mut subtree: Vec<(Duration, u64)> = self
+            .nodes
+            .iter()
+            .map(|node| (node.time, node.instructions))
+            .collect();
+        for idx in (1..self.nodes.len()).rev() {
+            let (time, instructions) = subtree[idx];
+            let parent = self.nodes[idx].parent;
+            subtree[parent].0 += time;
+            subtree[parent].1 += instructions;
+        }
+
+        #[derive(Default)]
+        struct Stats {
+            self_time: Duration,
+            self_instructions: u64,
+            total_time: Duration,
+            total_instructions: u64,
+        }
+        let mut stats: HashMap<FrameId, Stats> = HashMap::new();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let entry = stats.entry(node.frame).or_default();
+            entry.self_time += node.time;
+            entry.self_instructions += node.instructions;
+            // recursive calls are already part of the outermost one
+            let mut ancestor = idx;
+            let recursive = loop {
+                if ancestor == 0 {
+                    break false;
+                }
+                ancestor = self.nodes[ancestor].parent;
+                if self.nodes[ancestor].frame == node.frame {
+                    break true;
+                }
+            };
+            if !recursive {
+                entry.total_time += subtree[idx].0;
+                entry.total_instructions += subtree[idx].1;
+            }
+        }
+
+        let (total_time, total_instructions) = subtree[0];
+        let percent = |time: Duration| {
+            if total_time.is_zero() {
+                0.0
+            } else {
+                time.as_secs_f64() / total_time.as_secs_f64() * 100.0
+            }
+        };
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:.3}ms, {} instructions",
+            total_time.as_secs_f64() * 1000.0,
+            total_instructions
+        )
+        .unwrap();
+        let mut stats: Vec<(FrameId, Stats)> = stats.into_iter().collect();
+        stats.sort_by(|(x, x_stats), (y, y_stats)| {
+            y_stats
+                .self_time
+                .cmp(&x_stats.self_time)
+                .then_with(|| self.name(info, *x).cmp(&self.name(info, *y)))
+        });
+        for (title, natives) in [("functions", false), ("native functions", true)] {
+            writeln!(
+                out,
+                "\n{}:\n{:>18} {:>18} {:>12} {:>12}  name",
+                title, "self time", "total time", "self instr", "total instr"
+            )
+            .unwrap();
+            for (frame, stats) in stats.iter() {
+                if matches!(frame, FrameId::Native(_)) != natives {
+                    continue;
+                }
+                writeln!(
+                    out,
+                    "{:>10.3}ms {:>5.1}% {:>10.3}ms {:>5.1}% {:>12} {:>12}  {}",
+                    stats.self_time.as_secs_f64() * 1000.0,
+                    percent(stats.self_time),
+                    stats.total_time.as_secs_f64() * 1000.0,
+                    percent(stats.total_time),
+                    stats.self_instructions,
+                    stats.total_instructions,
+                    self.name(info, *frame)
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+// native function the instruction is about to call
+fn native_callee(vm: &Vm) -> Option<NativeFnData> {
+    let op = *vm.code.get(vm.ip() as usize)?;
+    if VmOpcode::Call != op && VmOpcode::RetCall != op {
+        return None;
+    }
+    match vm.stack.last()? {
+        Value::NativeFn(native) => Some(native),
+        Value::Record(rec) => match rec.as_ref().get("constructor") {
+            Some(Value::NativeFn(native)) => Some(*native),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Hook collecting the profile of the script it runs
+pub struct Profiler {
+    profile: Rc<RefCell<Profile>>,
+}
+
+impl Profiler {
+    pub fn new(profile: Rc<RefCell<Profile>>) -> Profiler {
+        Profiler { profile }
+    }
+}
+
+impl VmHook for Profiler {
+    fn on_instruction(&mut self, vm: &Rc<RefCell<Vm>>) {
+        self.profile.borrow_mut().on_instruction(&vm.borrow());
+    }
+}
@@ -0,0 +1,35 @@
+//! Messages framed by a `Content-Length` header, as the Debug Adapter
+//! Protocol and the Language Server Protocol send them
+
+use std::io::{BufRead, Write};
+
+use serde_json::Value as Json;
+
+/// Reads the next message, None once the input is closed
+pub fn read_message(input: &mut impl BufRead) -> Option<Json> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; content_length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+pub fn write_message(out: &mut impl Write, msg: &Json) {
+    let body = msg.to_string();
+    let _ = write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = out.flush();
+}
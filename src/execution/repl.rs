@@ -1,121 +1,528 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Instant;
 
 use super::{
     errors::{handle_error, print_error},
     ParserFlag,
 };
 
-use crate::harumachine::vm::VmOpcode;
+use crate::harumachine::debug::disassemble;
+use crate::harumachine::inspect::{inspect, DEFAULT_DEPTH};
+use crate::harumachine::record::Record;
+use crate::harumachine::value::Value;
+use crate::harumachine::vm::{Vm, VmOpcode};
 use crate::harumachine::vmerror::VmError;
 use crate::{ast, grammar};
 use crate::{
     compiler, hanayo,
     harumachine::vm::{execute_vm, initialize_vm},
 };
-use rustyline::{error::ReadlineError, history::DefaultHistory, Editor};
+use ansi_term::Colour;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{error::ReadlineError, history::DefaultHistory, Context, Editor, Helper};
+
+const KEYWORDS: &[&str] = &[
+    "and", "as", "begin", "break", "case", "continue", "else", "end", "fn", "for", "func", "if",
+    "in", "match", "not", "of", "or", "raise", "record", "return", "then", "try", "use", "while",
+];
+
+// commands the repl takes after a colon
+const COMMANDS: &[&str] = &[
+    "ast", "dis", "globals", "help", "load", "reset", "time", "type",
+];
+
+fn is_id_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || "$_?!".contains(ch)
+}
+
+// splits the source into words and the text in between them, strings and
+// comments are never words
+fn split_words(text: &str) -> Vec<(&str, bool)> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(ch) = rest.chars().next() {
+        let (len, word) = if rest.starts_with("//") {
+            (rest.find('\n').unwrap_or(rest.len()), false)
+        } else if rest.starts_with("/*") {
+            (rest.find("*/").map_or(rest.len(), |idx| idx + 2), false)
+        } else if ch == '"' || ch == '\'' {
+            (string_len(rest, ch), false)
+        } else if is_id_char(ch) {
+            (
+                rest.find(|ch: char| !is_id_char(ch)).unwrap_or(rest.len()),
+                true,
+            )
+        } else {
+            (ch.len_utf8(), false)
+        };
+        parts.push((&rest[..len], word));
+        rest = &rest[len..];
+    }
+    parts
+}
+
+// number of blocks the source leaves without an end
+fn open_blocks(text: &str) -> isize {
+    split_words(text)
+        .into_iter()
+        .map(|part| match part {
+            ("begin" | "fn" | "func" | "record" | "try", true) => 1,
+            ("end", true) => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+// length of the string literal the text starts with, up to the end of
+// the text if it isn't closed
+fn string_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (idx, ch) in text.char_indices().skip(1) {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if ch == quote => return idx + 1,
+            _ => (),
+        }
+    }
+    text.len()
+}
+
+// file the history is kept in between sessions
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".haru_history"))
+}
+
+/// Completes and highlights the lines being typed, from the globals of
+/// the virtual machine the repl runs in
+struct ReplHelper {
+    vm: Rc<RefCell<Vm>>,
+}
+
+impl ReplHelper {
+    // value of a global followed by members, like `a.b.c`
+    fn resolve(&self, path: &str) -> Option<Value> {
+        let vm = self.vm.borrow();
+        let mut names = path.split('.');
+        let mut val = vm.global().get(names.next()?)?.clone();
+        for name in names {
+            val = member_record(&vm, &val)?.get(name)?.clone();
+        }
+        Some(val)
+    }
+}
+
+// record the members of the value are looked up in
+fn member_record<'a>(vm: &'a Vm, val: &'a Value) -> Option<&'a Record> {
+    match val {
+        Value::Record(rec) => Some(rec.as_ref()),
+        Value::Str(_) => vm.dstr.as_ref().map(|rec| rec.as_ref()),
+        Value::Int(_) => vm.dint.as_ref().map(|rec| rec.as_ref()),
+        Value::Float(_) => vm.dfloat.as_ref().map(|rec| rec.as_ref()),
+        Value::Array(_) => vm.darray.as_ref().map(|rec| rec.as_ref()),
+        _ => None,
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|ch: char| !is_id_char(ch))
+            .map_or(0, |idx| idx + 1);
+        let prefix = &before[start..];
+
+        if before[..start].trim_start() == ":" {
+            let candidates = COMMANDS
+                .iter()
+                .filter(|command| command.starts_with(prefix))
+                .map(|command| command.to_string())
+                .collect();
+            return Ok((start, candidates));
+        }
+
+        let mut candidates: Vec<String> = match before[..start]
+            .strip_suffix("::")
+            .or(before[..start].strip_suffix('.'))
+        {
+            // members of the value the path before the dot leads to
+            Some(receiver) => {
+                let path_start = receiver
+                    .rfind(|ch: char| !is_id_char(ch) && ch != '.')
+                    .map_or(0, |idx| idx + 1);
+                let Some(val) = self.resolve(&receiver[path_start..]) else {
+                    return Ok((start, Vec::new()));
+                };
+                let vm = self.vm.borrow();
+                let mut members = Vec::new();
+                let mut rec = member_record(&vm, &val);
+                while let Some(record) = rec {
+                    members.extend(record.iter().map(|(key, _)| key.to_string()));
+                    rec = record.prototype();
+                }
+                members
+            }
+            None => {
+                let vm = self.vm.borrow();
+                vm.global()
+                    .keys()
+                    .map(|key| key.to_string())
+                    .chain(KEYWORDS.iter().map(|keyword| keyword.to_string()))
+                    .collect()
+            }
+        };
+        candidates.retain(|candidate| candidate.starts_with(prefix));
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let mut out = String::with_capacity(line.len());
+        for (part, word) in split_words(line) {
+            if word && KEYWORDS.contains(&part) {
+                out.push_str(&Colour::Purple.bold().paint(part).to_string());
+            } else {
+                out.push_str(part);
+            }
+        }
+        Cow::Owned(out)
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
+        true
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+// reads lines for as long as the program is cut off inside of a block or
+// an expression, an empty line gives up on the expression
+fn read_input(rl: &mut Editor<ReplHelper, DefaultHistory>) -> rustyline::Result<String> {
+    let mut input = rl.readline(">> ")?;
+    while let Err(err) = grammar::parser_start(&input) {
+        let open_blocks = open_blocks(&input) > 0;
+        if !open_blocks && err.offset < input.trim_end().len() {
+            break;
+        }
+        let line = rl.readline(".. ")?;
+        if !open_blocks && line.trim().is_empty() {
+            break;
+        }
+        input.push('\n');
+        input.push_str(&line);
+    }
+    Ok(input)
+}
+
+const HELP: &str = "\
+:load file   runs the file in the current session
+:reset       starts over with a fresh virtual machine
+:ast expr    prints the syntax tree of the code
+:dis expr    prints the bytecode the code compiles to, without running it
+:time expr   runs the code and prints how long it took
+:globals     lists the globals defined in the session
+:type expr   prints the type of the value
+:help        prints this message";
+
+/// Compiler and virtual machine the repl runs code in
+struct Repl<'a> {
+    flag: &'a ParserFlag,
+    c: compiler::Compiler,
+    vm: Rc<RefCell<Vm>>,
+    // globals of the standard library
+    builtins: HashSet<String>,
+}
+
+impl<'a> Repl<'a> {
+    fn new(flag: &'a ParserFlag) -> Repl<'a> {
+        let c = compiler::Compiler::new(false);
+        c.modules_info
+            .borrow_mut()
+            .add_file("[repl]".to_string(), String::new());
+        let vm = initialize_vm(Vec::new(), Some(c.modules_info.clone()), None);
+        hanayo::init(Rc::clone(&vm));
+        vm.borrow_mut().set_max_call_depth(flag.max_call_depth);
+        vm.borrow().set_max_heap_size(flag.max_heap_size);
+        let builtins = vm
+            .borrow()
+            .global()
+            .keys()
+            .map(|key| key.to_string())
+            .collect();
+        Repl {
+            flag,
+            c,
+            vm,
+            builtins,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Repl::new(self.flag);
+    }
+
+    // input the repl reads code from next
+    fn set_source(&self, src: &str) {
+        self.c
+            .modules_info
+            .borrow_mut()
+            .add_file("[repl]".to_string(), src.to_string());
+    }
+
+    /// Runs the code in the virtual machine, returning the value of the
+    /// expression it ends with
+    fn eval(&mut self, src: &str) -> Option<Value> {
+        self.set_source(src);
+        let prog = parse(src)?;
+        if self.flag.print_ast {
+            println!("{:?}", prog);
+            return None;
+        }
+        self.run(prog)
+    }
+
+    fn run(&mut self, prog: grammar::Program) -> Option<Value> {
+        let vm = Rc::clone(&self.vm);
+        vm.borrow_mut().error = VmError::ERROR_NO_ERROR;
+        let len = vm.borrow().code.len() as u32;
+        self.c.receive_code(vm.borrow().code.clone());
+        let pop_print = match emit(&mut self.c, prog) {
+            Ok(pop_print) => pop_print,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                return None;
+            }
+        };
+        if self.c.clen() as u32 == len {
+            return None;
+        }
+        self.c.cpushop(VmOpcode::Halt);
+        vm.borrow_mut().code = self.c.take_code();
+        vm.borrow_mut().jmp(len);
+        vm.borrow_mut()
+            .set_execution_limits(self.flag.execution_limits());
+        execute_vm(Rc::clone(&vm));
+        if !handle_error(Rc::clone(&vm), &self.c) && pop_print {
+            return vm.borrow_mut().stack.pop();
+        }
+        None
+    }
+
+    fn echo(&self, val: &Value) {
+        println!("=> {}", inspect(&self.vm.borrow(), val, DEFAULT_DEPTH));
+    }
+
+    fn command(&mut self, line: &str) {
+        let (name, arg) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(name, arg)| (name, arg.trim()));
+        match name {
+            "load" => self.load(arg),
+            "reset" => self.reset(),
+            "ast" => {
+                if let Some(prog) = parse(arg) {
+                    println!("{:?}", prog);
+                }
+            }
+            "dis" => self.disassemble(arg),
+            "time" => {
+                let start = Instant::now();
+                let val = self.eval(arg);
+                let elapsed = start.elapsed();
+                if let Some(val) = val {
+                    self.echo(&val);
+                }
+                println!("took {:?}", elapsed);
+            }
+            "globals" => {
+                let vm = self.vm.borrow();
+                let mut globals: Vec<(String, &Value)> = vm
+                    .global()
+                    .iter()
+                    .map(|(key, val)| (key.to_string(), val))
+                    .filter(|(key, _)| !self.builtins.contains(key))
+                    .collect();
+                globals.sort_by(|(x, _), (y, _)| x.cmp(y));
+                for (key, val) in globals {
+                    println!("{} = {}", key, inspect(&vm, val, DEFAULT_DEPTH));
+                }
+            }
+            "type" => {
+                if let Some(val) = self.eval(arg) {
+                    println!("{}", self.type_of(&val));
+                }
+            }
+            "help" => println!("{}", HELP),
+            _ => eprintln!("unknown command :{}, :help lists them", name),
+        }
+    }
+
+    fn load(&mut self, path: &str) {
+        let src = match std::fs::read_to_string(path) {
+            Ok(src) => src,
+            Err(err) => {
+                eprintln!("error reading {}: {}", path, err);
+                return;
+            }
+        };
+        self.c
+            .modules_info
+            .borrow_mut()
+            .add_file(path.to_string(), src.clone());
+        if let Some(prog) = parse(&src) {
+            self.run(prog);
+        }
+    }
+
+    // prints the code the source compiles to, which is then thrown away
+    fn disassemble(&mut self, src: &str) {
+        self.set_source(src);
+        let Some(prog) = parse(src) else {
+            return;
+        };
+        let (nsmaps, nscopes) = {
+            let modules_info = self.c.modules_info.borrow();
+            (modules_info.smap.len(), modules_info.scopes.len())
+        };
+        let start = self.vm.borrow().code.len();
+        self.c.receive_code(self.vm.borrow().code.clone());
+        let result = emit(&mut self.c, prog);
+        let code = self.c.take_code();
+        {
+            let mut modules_info = self.c.modules_info.borrow_mut();
+            modules_info.smap.truncate(nsmaps);
+            modules_info.scopes.truncate(nscopes);
+            modules_info.symbol.split_off(&start);
+        }
+        if let Err(e) = result {
+            eprintln!("{:?}", e);
+            return;
+        }
+        let mut ip = start;
+        while ip < code.len() {
+            let (text, len) = disassemble(&code, ip);
+            println!("{:>6}: {}", ip, text);
+            ip += len;
+        }
+    }
+
+    // name of the record the value was made from, or of its type
+    fn type_of(&self, val: &Value) -> String {
+        if let Value::Record(rec) = val {
+            if let Some(prototype) = rec.as_ref().prototype() {
+                let vm = self.vm.borrow();
+                let name = vm.global().iter().find_map(|(key, global)| match global {
+                    Value::Record(global) if std::ptr::eq(global.as_ref(), prototype) => {
+                        Some(key.to_string())
+                    }
+                    _ => None,
+                });
+                if let Some(name) = name {
+                    return name;
+                }
+            }
+        }
+        val.type_name().to_string()
+    }
+}
+
+// parses the source, printing where it went wrong if it can't be
+fn parse(src: &str) -> Option<grammar::Program> {
+    match grammar::parser_start(src) {
+        Ok(prog) => Some(prog),
+        Err(err) => {
+            print_error(
+                src,
+                err.line,
+                err.column,
+                err.line,
+                err.column,
+                "parser error:",
+                &format!("expected {}", {
+                    let expected: Vec<String> =
+                        err.expected.iter().map(|x| x.to_string()).collect();
+                    expected.join(", ")
+                }),
+            );
+            None
+        }
+    }
+}
+
+// emits the statements, leaving the value of the last one on the stack if
+// it's an expression, which is returned
+fn emit(
+    c: &mut compiler::Compiler,
+    mut prog: grammar::Program,
+) -> Result<bool, ast::CodeGenError> {
+    if let Some(stmt) = prog.pop() {
+        for stmt in prog {
+            stmt.emit(c)?;
+        }
+        if let Some(expr_stmt) = stmt.as_any().downcast_ref::<ast::ExprStatement>() {
+            expr_stmt.expr.emit(c)?;
+            return Ok(true);
+        }
+        stmt.emit(c)?;
+    }
+    Ok(false)
+}
 
 // repl
 pub(crate) fn run_repl(flag: ParserFlag) {
-    let mut rl = Editor::<(), DefaultHistory>::new().unwrap();
-    let mut c = compiler::Compiler::new(false);
-    {
-        let mut modules_info = c.modules_info.borrow_mut();
-        modules_info.files.push("[repl]".to_string());
-        modules_info.sources.push(String::new());
+    let mut repl = Repl::new(&flag);
+
+    let mut rl = Editor::<ReplHelper, DefaultHistory>::new().unwrap();
+    rl.set_helper(Some(ReplHelper {
+        vm: Rc::clone(&repl.vm),
+    }));
+    let history = history_path();
+    if let Some(history) = history.as_ref() {
+        // there's no history yet the first time
+        let _ = rl.load_history(history);
     }
-    let vm = initialize_vm(Vec::new(), Some(c.modules_info.clone()), None);
-    hanayo::init(Rc::clone(&vm));
 
     loop {
-        let readline = rl.readline(">> ");
+        let readline = read_input(&mut rl);
         match readline {
             Ok(s) => {
                 rl.add_history_entry(s.as_str()).unwrap();
-                c.modules_info.borrow_mut().sources[0] = s.clone();
-                match grammar::parser_start(&s) {
-                    Ok(mut prog) => {
-                        if flag.print_ast {
-                            println!("{:?}", prog);
-                            continue;
-                        }
-                        let gencode =
-                            |c: &mut compiler::Compiler| -> Result<bool, ast::CodeGenError> {
-                                if prog.last().is_some() {
-                                    let stmt = prog.pop().unwrap();
-                                    for stmt in prog {
-                                        stmt.emit(c)?;
-                                    }
-                                    if let Some(expr_stmt) =
-                                        stmt.as_any().downcast_ref::<ast::ExprStatement>()
-                                    {
-                                        expr_stmt.expr.emit(c)?;
-                                        return Ok(true);
-                                    } else {
-                                        stmt.emit(c)?;
-                                    }
-                                } else {
-                                    for stmt in prog {
-                                        stmt.emit(c)?;
-                                    }
-                                }
-                                Ok(false)
-                            };
-                        // setup
-
-                        let pop_print: bool; // false
-                        if (*vm).borrow().code.is_empty() {
-                            match gencode(&mut c) {
-                                Ok(pop_print_) => {
-                                    pop_print = pop_print_;
-                                    c.cpushop(VmOpcode::Halt);
-                                    vm.borrow_mut().code = c.take_code();
-                                    execute_vm(Rc::clone(&vm));
-                                }
-                                Err(e) => {
-                                    eprintln!("{:?}", e);
-                                    continue;
-                                }
-                            }
-                        } else {
-                            vm.borrow_mut().error = VmError::ERROR_NO_ERROR;
-                            let len = (*vm).borrow().code.len() as u32;
-                            c.receive_code((*vm).borrow().code.clone());
-                            match gencode(&mut c) {
-                                Ok(pop_print_) => {
-                                    pop_print = pop_print_;
-                                    if c.clen() as u32 == len {
-                                        continue;
-                                    }
-                                    c.cpushop(VmOpcode::Halt);
-                                    vm.borrow_mut().code = c.take_code();
-                                    vm.borrow_mut().jmp(len);
-                                    execute_vm(Rc::clone(&vm));
-                                }
-                                Err(e) => {
-                                    eprintln!("{:?}", e);
-                                    continue;
-                                }
-                            }
-                        }
-                        if !handle_error(Rc::clone(&vm), &c) && pop_print {
-                            println!("=> {:?}", vm.borrow_mut().stack.pop().unwrap());
-                        }
-                    }
-                    Err(err) => {
-                        print_error(
-                            &s,
-                            err.line,
-                            err.column,
-                            err.line,
-                            err.column,
-                            "parser error:",
-                            &format!("expected {}", {
-                                let expected: Vec<String> =
-                                    err.expected.iter().map(|x| x.to_string()).collect();
-                                expected.join(", ")
-                            }),
-                        );
+                if let Some(history) = history.as_ref() {
+                    let _ = rl.save_history(history);
+                }
+                if let Some(command) = s.trim_start().strip_prefix(':') {
+                    let vm = Rc::clone(&repl.vm);
+                    repl.command(command.trim_end());
+                    if !Rc::ptr_eq(&vm, &repl.vm) {
+                        rl.set_helper(Some(ReplHelper {
+                            vm: Rc::clone(&repl.vm),
+                        }));
                     }
+                } else if let Some(val) = repl.eval(&s) {
+                    repl.echo(&val);
                 }
             }
             Err(ReadlineError::Interrupted) => continue,
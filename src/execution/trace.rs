@@ -0,0 +1,136 @@
+//! Tracing for scripts run with --trace: logs every instruction the virtual
+//! machine dispatches to stderr, along with where it comes from and the
+//! values on top of the stack
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::compiler::ModulesInfo;
+use crate::harumachine::debug::{disassemble, file_matches, scopes_at, LineTable};
+use crate::harumachine::value::Value;
+use crate::harumachine::vm::{Vm, VmHook};
+
+// longest string shown on the stack
+const MAX_STRING_LEN: usize = 32;
+
+/// Which instructions get traced
+pub struct TraceFilter {
+    /// Names of the functions to trace, every one if empty
+    pub functions: Vec<String>,
+    /// Files to trace, or the ends of their paths, every one if empty
+    pub files: Vec<String>,
+    /// Number of values shown from the top of the stack
+    pub stack: usize,
+}
+
+/// Hook logging the instructions that pass its filter
+pub struct Tracer {
+    filter: TraceFilter,
+    lines: LineTable,
+}
+
+impl Tracer {
+    pub fn new(filter: TraceFilter) -> Tracer {
+        Tracer {
+            filter,
+            lines: LineTable::new(),
+        }
+    }
+
+    // position and function of the instruction, None if it's filtered out
+    fn locate(&mut self, info: &ModulesInfo, vm: &Vm) -> Option<(String, String)> {
+        let ip = vm.ip() as usize;
+        let line = self.lines.line_at(info, ip);
+        let file = line.map(|line| info.files[line.fileno].as_str());
+        if !self.filter.files.is_empty()
+            && !file.is_some_and(|file| {
+                self.filter
+                    .files
+                    .iter()
+                    .any(|pattern| file_matches(file, pattern))
+            })
+        {
+            return None;
+        }
+
+        let function = if vm.localenv().is_empty() {
+            None
+        } else {
+            scopes_at(info, ip)
+                .first()
+                .map(|scope| scope.name.as_deref().unwrap_or("<anonymous>"))
+        };
+        if !self.filter.functions.is_empty()
+            && !function
+                .is_some_and(|function| self.filter.functions.iter().any(|f| f == function))
+        {
+            return None;
+        }
+
+        let position = match (file, line) {
+            (Some(file), Some(line)) => format!("{}:{}", file, line.line),
+            _ => "?".to_string(),
+        };
+        Some((position, function.unwrap_or("<main>").to_string()))
+    }
+}
+
+impl VmHook for Tracer {
+    fn on_instruction(&mut self, vm: &Rc<RefCell<Vm>>) {
+        let vm = vm.borrow();
+        let Some(modules_info) = vm.modules_info.as_ref() else {
+            return;
+        };
+        let Some((position, function)) = self.locate(&modules_info.borrow(), &vm) else {
+            return;
+        };
+
+        let ip = vm.ip() as usize;
+        let shown = vm.stack.len().min(self.filter.stack);
+        let mut stack: Vec<String> = vm.stack.slots()[vm.stack.len() - shown..]
+            .iter()
+            .map(|val| describe(&val.borrow()))
+            .collect();
+        if shown < vm.stack.len() {
+            stack.insert(0, "..".to_string());
+        }
+        let _ = writeln!(
+            std::io::stderr().lock(),
+            "[{} in {}] {:>6}: {:<32} [{}]",
+            position,
+            function,
+            ip,
+            disassemble(&vm.code, ip).0,
+            stack.join(", ")
+        );
+    }
+}
+
+/// Short description of the value, values only the virtual machine sees
+/// included
+pub fn describe(val: &Value) -> String {
+    match val {
+        Value::Nil => "nil".to_string(),
+        Value::True => "true".to_string(),
+        Value::False => "false".to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::Float(n) => format!("{:?}", n),
+        Value::NativeFn(_) => "[native fn]".to_string(),
+        Value::Fn(f) => format!("[fn {:p}]", f.to_raw()),
+        Value::Str(s) => {
+            let s: &str = s.as_ref();
+            if s.chars().count() > MAX_STRING_LEN {
+                let s: String = s.chars().take(MAX_STRING_LEN).collect();
+                format!("{:?}..", s)
+            } else {
+                format!("{:?}", s)
+            }
+        }
+        Value::Record(r) => format!("[record {:p}]", r.to_raw()),
+        Value::Array(a) => format!("[array of {}]", a.as_ref().len()),
+        Value::InterpreterError => "[interpreter error]".to_string(),
+        Value::PropagateError => "[propagate error]".to_string(),
+        Value::Iterator => "[iterator]".to_string(),
+    }
+}
@@ -1,33 +1,18 @@
 //! Provides Array record for handling arrays
-use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::rc::Rc;
 
 //use crate::harumachine::nativeval::NativeValue;
 use crate::harumachine::value::Value;
 use crate::harumachine::{
+    gc::write_barrier,
     operations::{value_eq, value_gt, value_lt},
     vm::{call as vm_call, Vm},
 };
 
-/// # Safety
-///
-/// This function needs to be unsafe for internal compatibility between multiple languages.
-pub fn constructor(vm: Rc<RefCell<Vm>>, nargs: u16) {
-    if nargs == 0 {
-        let new_array = Value::Array(vm.borrow().malloc(Vec::new()));
-        vm.borrow_mut().stack.push(new_array);
-        return;
-    }
-
-    let nargs = nargs as usize;
-    let mut array = (*vm).borrow().malloc(Vec::with_capacity(nargs));
-    for _i in 0..nargs {
-        let val = vm.borrow_mut().stack.pop().unwrap();
-        array.inner_mut_ptr().push(val);
-    }
-
-    vm.borrow_mut().stack.push(Value::Array(array));
+#[hana_function()]
+fn constructor(#[rest] values: Vec<Value>) -> Vec<Value> {
+    values
 }
 
 #[hana_function()]
@@ -37,7 +22,9 @@ fn length(array: Value::Array) -> Value {
 
 #[hana_function()]
 fn insert_(mut array: Value::Array, pos: Value::Int, elem: Value::Any) -> Value {
+    write_barrier(&elem);
     array.inner_mut_ptr().insert(pos as usize, elem);
+    (*vm).borrow().gc_resize(&array);
     Value::Int(array.as_ref().len() as i64)
 }
 
@@ -52,7 +39,9 @@ fn delete_(mut array: Value::Array, from_pos: Value::Int, nelems: Value::Int) ->
 // stack manipulation
 #[hana_function()]
 fn push(mut array: Value::Array, elem: Value::Any) -> Value {
+    write_barrier(&elem);
     array.inner_mut_ptr().push(elem);
+    (*vm).borrow().gc_resize(&array);
     Value::Nil
 }
 
@@ -62,8 +51,8 @@ fn pop(mut array: Value::Array) -> Value {
 }
 
 // sorting
-fn value_cmp(left: Value, right: Value) -> Ordering {
-    match value_gt(left.clone(), right.clone()) {
+fn value_cmp(left: &Value, right: &Value) -> Ordering {
+    match value_gt(left, right) {
         Value::Int(1) => Ordering::Greater,
         _ => match value_lt(left, right) {
             Value::Int(1) => Ordering::Less,
@@ -76,14 +65,14 @@ fn value_cmp(left: Value, right: Value) -> Ordering {
 fn sort(array: Value::Array) -> Value {
     let mut new_array = (*vm).borrow().malloc(array.as_ref().clone());
     let slice = new_array.inner_mut_ptr().as_mut_slice();
-    slice.sort_by(|left, right| value_cmp(left.clone(), right.clone()));
+    slice.sort_by(value_cmp);
     Value::Array(new_array)
 }
 
 #[hana_function()]
 fn sort_(mut array: Value::Array) -> Value {
     let slice = array.inner_mut_ptr().as_mut_slice();
-    slice.sort_by(|left, right| value_cmp(left.clone(), right.clone()));
+    slice.sort_by(value_cmp);
     Value::Array(array)
 }
 
@@ -99,11 +88,14 @@ fn map(array: Value::Array, fun: Value::Any) -> Value {
         args.push(val.clone());
 
         if let Some(val) = vm_call(Rc::clone(&vm), fun.clone(), &args) {
+            // the new array might have been marked while calling
+            write_barrier(&val);
             new_array.inner_mut_ptr().push(val);
         } else {
             return Value::PropagateError;
         }
     }
+    (*vm).borrow().gc_resize(&new_array);
     Value::Array(new_array)
 }
 
@@ -117,12 +109,14 @@ fn filter(array: Value::Array, fun: Value::Any) -> Value {
 
         if let Some(filter) = vm_call(Rc::clone(&vm), fun.clone(), &args) {
             if filter.is_true() {
+                write_barrier(val);
                 new_array.inner_mut_ptr().push(val.clone());
             }
         } else {
             return Value::PropagateError;
         }
     }
+    (*vm).borrow().gc_resize(&new_array);
     Value::Array(new_array)
 }
 
@@ -150,7 +144,7 @@ fn index(array: Value::Array, elem: Value::Any) -> Value {
     let array = array.as_ref();
     // NOTE: array.len() -1
     for (i, item) in array.iter().enumerate() {
-        if let Value::Int(1) = value_eq(item.clone(), elem.clone()) {
+        if let Value::Int(1) = value_eq(item, &elem) {
             return Value::Int(i as i64);
         }
     }
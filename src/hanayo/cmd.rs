@@ -1,5 +1,5 @@
 //! Provides Cmd record for executing and handling commands
-use crate::harumachine::{record::Record, value::Value, vm::Vm, vmerror::VmError};
+use crate::harumachine::{record::Record, value::Value, vm::Vm};
 use std::borrow::Borrow;
 use std::cell::RefCell;
 use std::io::Write;
@@ -35,5 +35,6 @@ fn ls(dir: Value::Record) -> Value {
                 .push(Value::Str((*vm).borrow().malloc(path.to_string().into())));
         }
     }
+    (*vm).borrow().gc_resize(&entries);
     Value::Array(entries)
 }
@@ -1,33 +1,19 @@
 //! Provides Env record for getting and setting environment variables
-use crate::harumachine::{record::Record, value::Value, vm::Vm};
-use std::{borrow::Borrow, env};
+use crate::harumachine::{value::Value, vm::Vm};
+use std::{collections::HashMap, env};
 
 #[hana_function]
-fn get(key: Value::Str) -> Value {
-    match env::var(key.as_ref().borrow() as &String) {
-        Ok(value) => Value::Str((*vm).borrow().malloc(value.into())),
-        Err(_) => Value::Nil,
-    }
+fn get(key: &str) -> Option<String> {
+    env::var(key).ok()
 }
 
 #[hana_function]
-fn set(key: Value::Str, val: Value::Str) -> Value {
-    env::set_var(
-        key.as_ref().borrow() as &String,
-        val.as_ref().borrow() as &String,
-    );
-
+fn set(key: &str, val: &str) -> Value {
+    env::set_var(key, val);
     Value::Nil
 }
 
 #[hana_function]
-fn vars() -> Value {
-    let mut record = (*vm).borrow().malloc(Record::new());
-    for (key, value) in env::vars() {
-        record
-            .inner_mut_ptr()
-            .insert(key, Value::Str((*vm).borrow().malloc(value.into())));
-    }
-
-    Value::Record(record)
+fn vars() -> HashMap<String, String> {
+    env::vars().collect()
 }
@@ -1,39 +1,192 @@
-//! Provides eval function for dynamically evaluating source code
-use crate::compiler::{Compiler, ModulesInfo};
-#[allow(unused_imports)]
-use crate::harumachine::interned_string_map::InternedStringMap;
+//! Provides eval and compile functions for dynamically evaluating source code
+use crate::compiler::{append_source, SourceError};
+use crate::harumachine::function::Function;
+use crate::harumachine::gc::Gc;
+use crate::harumachine::hmap::HaruHashMap;
+use crate::harumachine::record::Record;
 use crate::harumachine::value::Value;
-use crate::harumachine::vm::VmOpcode;
-use crate::harumachine::vm::{execute_vm, Vm};
+use crate::harumachine::vm::{execute_vm, Vm, VmOpcode};
+use crate::harumachine::vmerror::VmError;
 use std::cell::RefCell;
 use std::rc::Rc;
 
-#[hana_function]
-fn eval(s: Value::Str) -> Value {
-    let s = s.as_ref();
-    if let Ok(prog) = crate::grammar::parser_start(s) {
-        let target_ip = (*vm).borrow().code.len() as u32;
-        let mut c = Compiler::new_append(
-            (*vm).borrow().code.clone(),
-            Rc::new(RefCell::new(ModulesInfo::new())),
-            vm.borrow_mut().interned_strings.take().unwrap(),
-        );
-        // generate code
-        for stmt in prog {
-            if stmt.emit(&mut c).is_err() {
-                return Value::False;
+const EVAL_FILENAME: &str = "[eval]";
+
+/// Either the start of the generated bytecode or the SyntaxError to raise.
+type CompileResult = Result<u32, Value>;
+
+fn syntax_error(vm: &Rc<RefCell<Vm>>, why: String, line: usize, column: usize) -> Value {
+    let mut rec = (*vm).borrow().malloc(Record::new());
+    rec.inner_mut_ptr().insert(
+        "prototype",
+        Value::Record((*vm).borrow().stdlib.as_ref().unwrap().syntax_error.clone()),
+    );
+    rec.inner_mut_ptr()
+        .insert("why", Value::Str((*vm).borrow().malloc(why.into())));
+    rec.inner_mut_ptr().insert(
+        "where",
+        Value::Str(
+            (*vm)
+                .borrow()
+                .malloc(format!("{}:{}:{}", EVAL_FILENAME, line, column).into()),
+        ),
+    );
+    rec.inner_mut_ptr().insert("line", Value::Int(line as i64));
+    rec.inner_mut_ptr()
+        .insert("column", Value::Int(column as i64));
+    Value::Record(rec)
+}
+
+fn invalid_argument(vm: &Rc<RefCell<Vm>>, arg: &str, expected: &str, val: &Value) -> Value {
+    (*vm).borrow().invalid_argument_error(format!(
+        "expected argument {} of eval to be {}, found {}",
+        arg,
+        expected,
+        val.type_name()
+    ))
+}
+
+// Parses and appends the source to the vm's code, see append_source
+fn compile_source(vm: &Rc<RefCell<Vm>>, src: &str, end: VmOpcode) -> CompileResult {
+    append_source(vm, src, EVAL_FILENAME.to_string(), end).map_err(|err| match err {
+        SourceError::Syntax {
+            message,
+            line,
+            column,
+        } => syntax_error(vm, message, line, column),
+        SourceError::CodeGen {
+            error,
+            line,
+            column,
+        } => syntax_error(vm, error.to_string(), line, column),
+    })
+}
+
+// Runs the code at target_ip in a fresh execution context and returns
+// the resulting value, or the value raised from inside of it.
+fn execute_source(vm: &Rc<RefCell<Vm>>, target_ip: u32) -> Result<Value, Value> {
+    let ctx = vm.borrow_mut().new_exec_ctx();
+    vm.borrow_mut().jmp(target_ip);
+    execute_vm(Rc::clone(vm));
+
+    let error_ip = (*vm).borrow().ip();
+    let error = std::mem::replace(&mut vm.borrow_mut().error, VmError::ERROR_NO_ERROR);
+    let result = match error {
+        VmError::ERROR_NO_ERROR => Ok(vm.borrow_mut().stack.pop().unwrap()),
+        // given back to the enclosing script to handle
+        VmError::ERROR_UNHANDLED_EXCEPTION => Err(vm.borrow_mut().stack.pop().unwrap()),
+        _ => {
+            vm.borrow_mut().error = error;
+            Ok(Value::PropagateError)
+        }
+    };
+    vm.borrow_mut().restore_exec_ctx(ctx);
+
+    match result {
+        Ok(Value::PropagateError) => {
+            // point the error at the evaluated source
+            vm.borrow_mut().jmp(error_ip);
+            Ok(Value::PropagateError)
+        }
+        result => result,
+    }
+}
+
+// Evaluates using the bindings of the record as globals,
+// writing every binding that was set back into it.
+fn execute_source_in(
+    vm: &Rc<RefCell<Vm>>,
+    target_ip: u32,
+    mut env: Gc<Record>,
+) -> Result<Value, Value> {
+    let mut globals = (*vm).borrow().global().clone();
+    for (key, value) in env.as_ref().iter() {
+        globals.insert(key.clone(), value.clone());
+    }
+    let outer: HaruHashMap = vm.borrow_mut().replace_global(globals);
+
+    let result = execute_source(vm, target_ip);
+
+    let globals = vm.borrow_mut().replace_global(outer);
+    let vm_ref = (*vm).borrow();
+    for (key, value) in globals.into_iter() {
+        let is_binding = env.as_ref().get(&key).is_some();
+        if is_binding || vm_ref.global().get(&key) != Some(&value) {
+            env.inner_mut_ptr().insert(key, value);
+        }
+    }
+    result
+}
+
+/// Evaluates the source string, returning the value of its last expression
+///
+/// `eval(source)` evaluates in the global scope, `eval(source, record)` evaluates
+/// with the record's keys as variables, storing any assignments into the record.
+pub fn eval(vm: Rc<RefCell<Vm>>, nargs: u16) {
+    if nargs != 1 && nargs != 2 {
+        vm.borrow_mut().error = VmError::ERROR_MISMATCH_ARGUMENTS;
+        vm.borrow_mut().error_expected = 1;
+        return;
+    }
+
+    fn eval(vm: Rc<RefCell<Vm>>, nargs: u16) -> Value {
+        let src = vm.borrow_mut().stack.pop().unwrap();
+        let src = match src {
+            Value::Str(s) => s,
+            val => {
+                hana_raise!(vm, invalid_argument(&vm, "src", "String", &val));
+            }
+        };
+        let env = if nargs == 2 {
+            let env = vm.borrow_mut().stack.pop().unwrap();
+            match env {
+                Value::Record(env) => Some(env),
+                val => {
+                    hana_raise!(vm, invalid_argument(&vm, "env", "Record", &val));
+                }
+            }
+        } else {
+            None
+        };
+
+        let target_ip = match compile_source(&vm, src.as_ref(), VmOpcode::Halt) {
+            Ok(target_ip) => target_ip,
+            Err(err) => {
+                hana_raise!(vm, err);
+            }
+        };
+        let result = match env {
+            Some(env) => execute_source_in(&vm, target_ip, env),
+            None => execute_source(&vm, target_ip),
+        };
+        match result {
+            Ok(value) => value,
+            Err(raised) => {
+                hana_raise!(vm, raised);
             }
         }
+    }
+
+    let result = eval(Rc::clone(&vm), nargs);
+    match result {
+        Value::PropagateError => (),
+        _ => unsafe { vm.borrow_mut().stack_push_gray(result) },
+    }
+}
 
-        c.cpushop(VmOpcode::Halt);
-        //panic!("{:?}", c.interned_strings);
-        vm.borrow_mut().interned_strings = c.interned_strings.take();
-        vm.borrow_mut().code = c.into_code();
-        let ctx = vm.borrow_mut().new_exec_ctx();
-        vm.borrow_mut().jmp(target_ip);
-        execute_vm(Rc::clone(&vm));
-        vm.borrow_mut().restore_exec_ctx(ctx);
-        return Value::True;
+/// Compiles the source string into a function taking no arguments
+///
+/// Calling the function evaluates the source like `eval` would,
+/// returning the value of the last expression.
+#[hana_function()]
+fn compile(src: Value::Str) -> Value {
+    match compile_source(&vm, src.as_ref(), VmOpcode::Ret) {
+        Ok(ip) => {
+            let fun = unsafe { Function::new(ip, 0, Rc::default()) };
+            Value::Fn((*vm).borrow().malloc(fun))
+        }
+        Err(err) => {
+            hana_raise!(vm, err);
+        }
     }
-    Value::False
 }
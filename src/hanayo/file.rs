@@ -4,7 +4,7 @@ use std::boxed::Box;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 
-use crate::harumachine::{record::Record, value::Value, vm::Vm, vmerror::VmError};
+use crate::harumachine::{record::Record, value::Value, vm::Vm};
 
 #[hana_function]
 fn constructor(path: Value::Str, mode: Value::Str) -> Value {
@@ -4,7 +4,6 @@ use std::str::FromStr;
 use crate::harumachine::record::Record;
 use crate::harumachine::value::Value;
 use crate::harumachine::vm::Vm;
-use crate::harumachine::vmerror::VmError;
 
 #[hana_function]
 fn constructor(val: Value::Any) -> Value {
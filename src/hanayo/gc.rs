@@ -0,0 +1,87 @@
+//! Provides Gc record for controlling the garbage collector
+use crate::harumachine::record::Record;
+use crate::harumachine::value::Value;
+use crate::harumachine::vm::Vm;
+
+#[hana_function()]
+fn collect() -> Value {
+    let bytes_allocated = (*vm).borrow().gc_stats().bytes_allocated;
+    // arguments of the native function calling this are held by native references
+    unsafe {
+        (*vm).borrow().gc_collect();
+    }
+    let freed = bytes_allocated - (*vm).borrow().gc_stats().bytes_allocated;
+    Value::Int(freed as i64)
+}
+
+#[hana_function()]
+fn stats() -> Value {
+    let stats = (*vm).borrow().gc_stats();
+    let mut objects = (*vm).borrow().malloc(Record::new());
+    for (name, count) in stats.objects.iter() {
+        objects
+            .inner_mut_ptr()
+            .insert(*name, Value::Int(*count as i64));
+    }
+    let mut rec = (*vm).borrow().malloc(Record::new());
+    rec.inner_mut_ptr()
+        .insert("bytes_allocated", Value::Int(stats.bytes_allocated as i64));
+    rec.inner_mut_ptr()
+        .insert("threshold", Value::Int(stats.threshold as i64));
+    rec.inner_mut_ptr()
+        .insert("objects", Value::Record(objects));
+    rec.inner_mut_ptr()
+        .insert("collections", Value::Int(stats.collections as i64));
+    rec.inner_mut_ptr().insert(
+        "last_pause_micros",
+        Value::Int(stats.last_pause.as_micros() as i64),
+    );
+    rec.inner_mut_ptr()
+        .insert("enabled", Value::Int(stats.enabled as i64));
+    Value::Record(rec)
+}
+
+#[hana_function()]
+fn enable() -> Value {
+    (*vm).borrow().gc_enable();
+    Value::Nil
+}
+
+#[hana_function()]
+fn disable() -> Value {
+    (*vm).borrow().gc_disable();
+    Value::Nil
+}
+
+#[hana_function()]
+fn set_threshold(threshold: Value::Int) -> Value {
+    if threshold < 0 {
+        hana_raise!(vm, {
+            let mut rec = (*vm).borrow().malloc(Record::new());
+            rec.inner_mut_ptr().insert(
+                "prototype",
+                Value::Record(
+                    (*vm)
+                        .borrow()
+                        .stdlib
+                        .as_ref()
+                        .unwrap()
+                        .invalid_argument_error
+                        .clone(),
+                ),
+            );
+            rec.inner_mut_ptr().insert(
+                "why",
+                Value::Str(
+                    (*vm)
+                        .borrow()
+                        .malloc("Threshold can't be negative".to_string().into()),
+                ),
+            );
+            rec.inner_mut_ptr().insert("where", Value::Int(0));
+            Value::Record(rec)
+        });
+    }
+    (*vm).borrow().gc_set_threshold(threshold as usize);
+    Value::Nil
+}
@@ -2,7 +2,6 @@
 use crate::harumachine::record::Record;
 use crate::harumachine::value::Value;
 use crate::harumachine::vm::Vm;
-use crate::harumachine::vmerror::VmError;
 use std::str::FromStr;
 
 #[hana_function]
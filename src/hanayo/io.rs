@@ -1,22 +1,39 @@
-//! Provides print, input and exit functions
-use std::cell::RefCell;
+//! Provides print, inspect, input and exit functions
+use std::collections::HashMap;
 use std::io::Write;
-use std::rc::Rc;
 
+use crate::harumachine::inspect::DEFAULT_DEPTH;
 use crate::harumachine::value::Value;
 use crate::harumachine::vm::Vm;
 
-/// # Safety
-///
-/// This function needs to be unsafe for internal compatibility between multiple languages.
-pub fn print(vm: Rc<RefCell<Vm>>, nargs: u16) {
-    for _ in 0..nargs {
-        let val = vm.borrow_mut().stack.pop().unwrap();
+#[hana_function()]
+fn print(#[rest] values: Vec<Value>) -> Value {
+    for val in values.iter() {
         std::print!("{}", val);
     }
 
     std::io::stdout().flush().unwrap();
-    vm.borrow_mut().stack.push(Value::Nil);
+    Value::Nil
+}
+
+#[hana_function()]
+fn inspect(val: Value, options: Option<HashMap<String, Value>>) -> Result<String, Value> {
+    let depth = match options.as_ref().and_then(|options| options.get("depth")) {
+        None | Some(Value::Nil) => DEFAULT_DEPTH,
+        Some(Value::Int(depth)) => (*depth).max(0) as usize,
+        Some(depth) => {
+            let why = format!(
+                "expected option depth of inspect to be Int, found {}",
+                depth.type_name()
+            );
+            return Err((*vm).borrow().invalid_argument_error(why));
+        }
+    };
+    Ok(crate::harumachine::inspect::inspect(
+        &vm.borrow(),
+        &val,
+        depth,
+    ))
 }
 
 #[hana_function()]
@@ -7,19 +7,14 @@ use crate::harumachine::gc::Gc;
 use crate::harumachine::record::Record;
 use crate::harumachine::value::*;
 use crate::harumachine::vm::Vm;
-use crate::harumachine::vmerror::VmError;
 
 // TODO: move this somewhere else
 #[macro_export]
 macro_rules! hana_raise {
     ($vm:ident, $rec:expr) => {
-        $vm.borrow_mut().stack.push($rec);
-        return if $crate::harumachine::vm::raise(std::rc::Rc::clone(&$vm)) {
-            Value::PropagateError
-        } else {
-            $vm.borrow_mut().error = VmError::ERROR_UNHANDLED_EXCEPTION;
-            Value::PropagateError
-        };
+        // the record is built before borrowing the vm, building it needs the vm too
+        let rec = $rec;
+        return $crate::harumachine::vm::Vm::raise_value(&$vm, rec);
     };
 }
 
@@ -28,11 +23,13 @@ pub mod dir;
 pub mod env;
 pub mod eval;
 pub mod file;
+pub mod gc;
 pub mod io;
 pub mod math;
 pub mod proc;
 pub mod sys;
 pub mod time;
+pub mod weakref;
 cfg_if! {
     if #[cfg(feature="cffi")] {
         pub mod cffi;
@@ -55,11 +52,15 @@ pub struct HanayoCtx {
     pub cmd_rec: Gc<Record>,
     pub proc_rec: Gc<Record>,
     pub time_rec: Gc<Record>,
+    pub weak_ref_rec: Gc<Record>,
 
     // errors
     pub invalid_argument_error: Gc<Record>,
     pub io_error: Gc<Record>,
     pub utf8_decoding_error: Gc<Record>,
+    pub syntax_error: Gc<Record>,
+    pub stack_overflow_error: Gc<Record>,
+    pub memory_error: Gc<Record>,
 }
 
 /// Initialises hanayo for the virtual machine
@@ -85,9 +86,11 @@ pub fn init(vm: Rc<RefCell<Vm>>) {
 
     // builtin functions
     set_var!("print", Value::NativeFn(io::print));
+    set_var!("inspect", Value::NativeFn(io::inspect));
     set_var!("input", Value::NativeFn(io::input));
     set_var!("exit", Value::NativeFn(io::exit));
     set_var!("eval", Value::NativeFn(eval::eval));
+    set_var!("compile", Value::NativeFn(eval::compile));
 
     // maths
     set_var!("sqrt", Value::NativeFn(math::sqrt));
@@ -237,6 +240,27 @@ pub fn init(vm: Rc<RefCell<Vm>>) {
     set_var!("Time", Value::Record(time.clone()));
     // #endregion
 
+    // #region weakref
+    let mut weak_ref = (*vm).borrow().malloc(Record::new());
+    set_obj_var!(
+        weak_ref,
+        "constructor",
+        Value::NativeFn(weakref::constructor)
+    );
+    set_obj_var!(weak_ref, "get", Value::NativeFn(weakref::get));
+    set_var!("WeakRef", Value::Record(weak_ref.clone()));
+    // #endregion
+
+    // #region gc
+    let mut gc = (*vm).borrow().malloc(Record::new());
+    set_obj_var!(gc, "collect", Value::NativeFn(gc::collect));
+    set_obj_var!(gc, "stats", Value::NativeFn(gc::stats));
+    set_obj_var!(gc, "enable", Value::NativeFn(gc::enable));
+    set_obj_var!(gc, "disable", Value::NativeFn(gc::disable));
+    set_obj_var!(gc, "set_threshold", Value::NativeFn(gc::set_threshold));
+    set_var!("Gc", Value::Record(gc));
+    // #endregion
+
     cffi_load(Rc::clone(&vm));
 
     // #region errors
@@ -280,6 +304,40 @@ pub fn init(vm: Rc<RefCell<Vm>>) {
         "Utf8DecodingError",
         Value::Record(utf8_decoding_error.clone())
     );
+
+    // SyntaxError
+    let mut syntax_error = (*vm).borrow().malloc(Record::new());
+    set_obj_var!(
+        syntax_error,
+        "what",
+        Value::Str((*vm).borrow().malloc("Syntax error".to_string().into()))
+    );
+    set_var!("SyntaxError", Value::Record(syntax_error.clone()));
+
+    // StackOverflowError
+    let mut stack_overflow_error = (*vm).borrow().malloc(Record::new());
+    set_obj_var!(
+        stack_overflow_error,
+        "what",
+        Value::Str(
+            (*vm)
+                .borrow()
+                .malloc("Stack overflow error".to_string().into())
+        )
+    );
+    set_var!(
+        "StackOverflowError",
+        Value::Record(stack_overflow_error.clone())
+    );
+
+    // MemoryError
+    let mut memory_error = (*vm).borrow().malloc(Record::new());
+    set_obj_var!(
+        memory_error,
+        "what",
+        Value::Str((*vm).borrow().malloc("Memory error".to_string().into()))
+    );
+    set_var!("MemoryError", Value::Record(memory_error.clone()));
     // #endregion
 
     vm.borrow_mut().stdlib = Some(HanayoCtx {
@@ -288,10 +346,14 @@ pub fn init(vm: Rc<RefCell<Vm>>) {
         cmd_rec: cmd,
         proc_rec: proc,
         time_rec: time,
+        weak_ref_rec: weak_ref,
 
         // errors
         invalid_argument_error,
         io_error,
         utf8_decoding_error,
+        syntax_error,
+        stack_overflow_error,
+        memory_error,
     });
 }
@@ -7,7 +7,6 @@ use std::rc::Rc;
 use crate::harumachine::record::Record;
 use crate::harumachine::value::Value;
 use crate::harumachine::vm::Vm;
-use crate::harumachine::vmerror::VmError;
 
 // inputs
 #[hana_function()]
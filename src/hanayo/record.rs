@@ -16,6 +16,7 @@ fn keys(rec: Value::Record) -> Value {
             .inner_mut_ptr()
             .push(Value::Str((*vm).borrow().malloc(key.clone())));
     }
+    (*vm).borrow().gc_resize(&array);
 
     Value::Array(array)
 }
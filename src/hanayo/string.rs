@@ -120,6 +120,7 @@ fn copy(s: Value::Str, from_pos: Value::Int, nchars: Value::Int) -> Value {
 fn insert_(mut dst: Value::Str, from_pos: Value::Int, src: Value::Str) -> Value {
     if let Some((i, _)) = dst.as_ref().grapheme_indices(true).nth(from_pos as usize) {
         dst.inner_mut_ptr().insert_str(i, src.as_ref().as_str());
+        (*vm).borrow().gc_resize(&dst);
     }
 
     Value::Str(dst)
@@ -135,6 +136,7 @@ fn split(s: Value::Str, delim: Value::Str) -> Value {
             .inner_mut_ptr()
             .push(Value::Str((*vm).borrow().malloc(ss.to_string().into())));
     }
+    (*vm).borrow().gc_resize(&array);
 
     Value::Array(array)
 }
@@ -166,6 +168,7 @@ fn chars(s: Value::Str) -> Value {
     for ch in s.as_ref().graphemes(true) {
         array_ref.push(Value::Str((*vm).borrow().malloc(ch.to_string().into())));
     }
+    (*vm).borrow().gc_resize(&array);
 
     Value::Array(array)
 }
@@ -2,7 +2,6 @@
 use crate::harumachine::record::Record;
 use crate::harumachine::value::Value;
 use crate::harumachine::vm::Vm;
-use crate::harumachine::vmerror::VmError;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::thread::sleep as nsleep;
@@ -0,0 +1,31 @@
+//! Provides WeakRef record for referring to values without keeping them alive
+use crate::harumachine::gc::WeakRef;
+use crate::harumachine::record::Record;
+use crate::harumachine::value::Value;
+use crate::harumachine::vm::Vm;
+
+#[hana_function()]
+fn constructor(val: Value::Any) -> Value {
+    let weak_ref = (*vm).borrow().gc_weak_ref(&val);
+    let mut rec = (*vm).borrow().malloc(Record::new());
+    rec.inner_mut_ptr().native_field = Some(Box::new(weak_ref));
+    rec.inner_mut_ptr().insert(
+        "prototype",
+        Value::Record((*vm).borrow().stdlib.as_ref().unwrap().weak_ref_rec.clone()),
+    );
+    Value::Record(rec)
+}
+
+#[hana_function()]
+fn get(weak_ref: Value::Record) -> Value {
+    let field = weak_ref.as_ref().native_field.as_ref();
+    let Some(weak_ref) = field.and_then(|field| field.downcast_ref::<WeakRef>()) else {
+        hana_raise!(
+            vm,
+            (*vm).borrow().invalid_argument_error(
+                "expected argument weak_ref of get to be a WeakRef, found Record".to_string()
+            )
+        );
+    };
+    weak_ref.get().unwrap_or(Value::Nil)
+}
@@ -0,0 +1,226 @@
+//! Provides conversions between values and Rust types
+
+use super::record::Record;
+use super::value::Value;
+use super::vm::Vm;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+
+/// Rust type that can be read from a value
+///
+/// The lifetime lets types like `&str` borrow from the value.
+pub trait FromValue<'a>: Sized {
+    /// Name of the value's type for errors
+    const TYPE_NAME: &'static str;
+
+    /// Converts the value, returns None if it has the wrong type
+    fn from_value(val: &'a Value) -> Option<Self>;
+}
+
+/// Rust type that can be turned into a value
+pub trait IntoValue {
+    /// Converts into a value, allocating it in the vm if needed
+    fn into_value(self, vm: &Vm) -> Value;
+}
+
+impl Value {
+    /// Converts the value into a Rust type, see [FromValue]
+    #[allow(dead_code)]
+    pub fn convert<'a, T: FromValue<'a>>(&'a self) -> Option<T> {
+        T::from_value(self)
+    }
+
+    /// Converts an argument of a native function, returning why it
+    /// has the wrong type for an InvalidArgumentError otherwise
+    pub fn convert_arg<'a, T: FromValue<'a>>(&'a self, fun: &str, arg: &str) -> Result<T, String> {
+        T::from_value(self).ok_or_else(|| {
+            format!(
+                "expected argument {} of {} to be {}, found {}",
+                arg,
+                fun,
+                T::TYPE_NAME,
+                self.type_name()
+            )
+        })
+    }
+
+    /// Converts the Rust value into a value, see [IntoValue]
+    pub fn from_rust<T: IntoValue>(val: T, vm: &Vm) -> Value {
+        val.into_value(vm)
+    }
+}
+
+// #region from value
+impl<'a> FromValue<'a> for Value {
+    const TYPE_NAME: &'static str = "any value";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        Some(val.clone())
+    }
+}
+
+impl<'a> FromValue<'a> for i64 {
+    const TYPE_NAME: &'static str = "Int";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        match val {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for f64 {
+    const TYPE_NAME: &'static str = "Float";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        match val {
+            Value::Float(n) => Some(*n),
+            Value::Int(n) => Some(*n as f64),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for bool {
+    const TYPE_NAME: &'static str = "Int";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        match val {
+            Value::Int(n) => Some(*n != 0),
+            Value::True => Some(true),
+            Value::False => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for &'a str {
+    const TYPE_NAME: &'static str = "String";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        match val {
+            Value::Str(s) => Some((s.as_ref().borrow() as &String).as_str()),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for String {
+    const TYPE_NAME: &'static str = "String";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        <&str>::from_value(val).map(str::to_string)
+    }
+}
+
+impl<'a, T: FromValue<'a>> FromValue<'a> for Vec<T> {
+    const TYPE_NAME: &'static str = "Array";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        match val {
+            Value::Array(array) => array.as_ref().iter().map(T::from_value).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, T: FromValue<'a>> FromValue<'a> for HashMap<String, T> {
+    const TYPE_NAME: &'static str = "Record";
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        match val {
+            Value::Record(record) => record
+                .as_ref()
+                .iter()
+                .map(|(key, val)| Some(((key.borrow() as &String).clone(), T::from_value(val)?)))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+// nil converts into None
+impl<'a, T: FromValue<'a>> FromValue<'a> for Option<T> {
+    const TYPE_NAME: &'static str = T::TYPE_NAME;
+
+    fn from_value(val: &'a Value) -> Option<Self> {
+        match val {
+            Value::Nil => Some(None),
+            _ => T::from_value(val).map(Some),
+        }
+    }
+}
+// #endregion
+
+// #region into value
+impl IntoValue for Value {
+    fn into_value(self, _: &Vm) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _: &Vm) -> Value {
+        Value::Nil
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self, _: &Vm) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &Vm) -> Value {
+        Value::Float(self)
+    }
+}
+
+// same as the true and false globals
+impl IntoValue for bool {
+    fn into_value(self, _: &Vm) -> Value {
+        Value::Int(self as i64)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, vm: &Vm) -> Value {
+        Value::Str(vm.malloc(self.into()))
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, vm: &Vm) -> Value {
+        Value::Str(vm.malloc(self.into()))
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, vm: &Vm) -> Value {
+        let array: Vec<Value> = self.into_iter().map(|x| x.into_value(vm)).collect();
+        Value::Array(vm.malloc(array))
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self, vm: &Vm) -> Value {
+        let mut record = Record::with_capacity(self.len());
+        for (key, val) in self {
+            record.insert(key, val.into_value(vm));
+        }
+        Value::Record(vm.malloc(record))
+    }
+}
+
+// None converts into nil
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, vm: &Vm) -> Value {
+        match self {
+            Some(val) => val.into_value(vm),
+            None => Value::Nil,
+        }
+    }
+}
+// #endregion
@@ -0,0 +1,462 @@
+//! Provides support for debuggers: the source line of instructions,
+//! the call stack with its local variables and evaluating code in it
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use super::inside::inside_execute;
+use super::value::Value;
+use super::vm::{Vm, VmOpcode};
+use super::vmerror::VmError;
+use crate::ast;
+use crate::compiler::{Compiler, ModulesInfo, ScopeInfo};
+use crate::grammar;
+
+/// Line of a source file, the file is an index into [ModulesInfo::files]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine {
+    pub fileno: usize,
+    pub line: usize,
+}
+
+/// Source line of every instruction, found through [ModulesInfo::smap] and
+/// updated whenever more code gets compiled
+#[derive(Default)]
+pub struct LineTable {
+    lines: Vec<Option<SourceLine>>,
+    // number of source maps and sources the lines were found from
+    nsmaps: usize,
+    nsources: usize,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable::default()
+    }
+
+    /// Line the instruction was compiled from, None for instructions that
+    /// don't belong to a line like the ones around function bodies
+    pub fn line_at(&mut self, info: &ModulesInfo, ip: usize) -> Option<SourceLine> {
+        self.update(info);
+        self.lines.get(ip).copied().flatten()
+    }
+
+    /// Whether the instruction is the first one of its line, rather than
+    /// one the line continues at after calling a function
+    pub fn is_line_start(&mut self, info: &ModulesInfo, ip: usize) -> bool {
+        let line = self.line_at(info, ip);
+        line.is_some() && (ip == 0 || self.lines[ip - 1] != line)
+    }
+
+    /// Finds the line a breakpoint on the line ends up at: the line itself
+    /// or the next one in the file that has code
+    pub fn resolve_line(
+        &mut self,
+        info: &ModulesInfo,
+        fileno: usize,
+        line: usize,
+    ) -> Option<usize> {
+        self.update(info);
+        self.lines
+            .iter()
+            .flatten()
+            .filter(|source_line| source_line.fileno == fileno && source_line.line >= line)
+            .map(|source_line| source_line.line)
+            .min()
+    }
+
+    fn update(&mut self, info: &ModulesInfo) {
+        if info.smap.len() == self.nsmaps && info.sources.len() == self.nsources {
+            return;
+        }
+        self.nsmaps = info.smap.len();
+        self.nsources = info.sources.len();
+
+        let len = info
+            .smap
+            .iter()
+            .map(|smap| smap.bytecode.1)
+            .max()
+            .unwrap_or(0);
+        // source maps of inner nodes come after the ones of outer nodes
+        let mut innermost: Vec<Option<usize>> = vec![None; len];
+        for (idx, smap) in info.smap.iter().enumerate() {
+            if smap.bytecode.0 < smap.bytecode.1 {
+                innermost[smap.bytecode.0..smap.bytecode.1].fill(Some(idx));
+            }
+        }
+        // code emitted around the function's body belongs to its definition
+        for scope in info.scopes.iter() {
+            for slot in innermost[scope.bytecode.0..scope.bytecode.1.min(len)].iter_mut() {
+                if matches!(slot, Some(idx) if info.smap[*idx].bytecode.0 < scope.bytecode.0) {
+                    *slot = None;
+                }
+            }
+        }
+
+        let newlines: Vec<Vec<usize>> = info
+            .sources
+            .iter()
+            .map(|src| src.match_indices('\n').map(|(pos, _)| pos).collect())
+            .collect();
+        self.lines = innermost
+            .into_iter()
+            .map(|idx| {
+                let smap = &info.smap[idx?];
+                let newlines = newlines.get(smap.fileno)?;
+                Some(SourceLine {
+                    fileno: smap.fileno,
+                    line: newlines.partition_point(|&pos| pos < smap.file.0) + 1,
+                })
+            })
+            .collect();
+    }
+}
+
+/// How far the program runs before it pauses again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepMode {
+    /// Until a breakpoint is reached
+    Continue,
+    /// To the next line, stepping into function calls
+    Step,
+    /// To the next line of the function at the call depth
+    Next(usize),
+    /// Until the function at the call depth returns
+    Finish(usize),
+}
+
+/// Why [Stepper::should_pause] paused the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseReason {
+    Step,
+    Breakpoint,
+}
+
+/// Breakpoint on a line, the file can be given by the end of its path
+pub struct Breakpoint {
+    pub file: String,
+    pub line: usize,
+}
+
+impl Breakpoint {
+    pub fn matches(&self, info: &ModulesInfo, at: SourceLine) -> bool {
+        self.line == at.line && file_matches(&info.files[at.fileno], &self.file)
+    }
+}
+
+/// Whether the file is the one given by the pattern, or its path ends with it
+pub fn file_matches(file: &str, pattern: &str) -> bool {
+    file == pattern || Path::new(file).ends_with(pattern)
+}
+
+/// Decides when a debugger pauses the program, from its breakpoints and
+/// the last stepping command
+pub struct Stepper {
+    pub lines: LineTable,
+    pub breakpoints: Vec<Breakpoint>,
+    pub mode: StepMode,
+}
+
+impl Stepper {
+    pub fn new(mode: StepMode) -> Stepper {
+        Stepper {
+            lines: LineTable::new(),
+            breakpoints: Vec::new(),
+            mode,
+        }
+    }
+
+    /// Whether to pause before running the instruction, depth is the
+    /// number of function calls on the stack
+    pub fn should_pause(
+        &mut self,
+        info: &ModulesInfo,
+        ip: usize,
+        depth: usize,
+    ) -> Option<PauseReason> {
+        let line = self.lines.line_at(info, ip)?;
+        if let StepMode::Finish(finish_depth) = self.mode {
+            if depth < finish_depth {
+                return Some(PauseReason::Step);
+            }
+        }
+        if !self.lines.is_line_start(info, ip) {
+            return None;
+        }
+        match self.mode {
+            StepMode::Step => Some(PauseReason::Step),
+            StepMode::Next(next_depth) if depth <= next_depth => Some(PauseReason::Step),
+            _ if self.breakpoints.iter().any(|bp| bp.matches(info, line)) => {
+                Some(PauseReason::Breakpoint)
+            }
+            _ => None,
+        }
+    }
+
+    /// Adds a breakpoint on the line, or the next one with code if the file
+    /// is loaded, returning the line it ends up at
+    pub fn add_breakpoint(
+        &mut self,
+        info: &ModulesInfo,
+        file: String,
+        line: usize,
+    ) -> Result<usize, String> {
+        let mut line = line;
+        // modules that aren't loaded yet can't be checked
+        if let Some(fileno) = info.files.iter().position(|f| file_matches(f, &file)) {
+            line = self
+                .lines
+                .resolve_line(info, fileno, line)
+                .ok_or_else(|| format!("no code at or after {}:{}", file, line))?;
+        }
+        self.breakpoints.push(Breakpoint { file, line });
+        Ok(line)
+    }
+}
+
+/// Scopes of the functions the instruction is in, innermost first
+pub fn scopes_at(info: &ModulesInfo, ip: usize) -> Vec<&ScopeInfo> {
+    let mut scopes: Vec<&ScopeInfo> = info
+        .scopes
+        .iter()
+        .filter(|scope| (scope.bytecode.0..scope.bytecode.1).contains(&ip))
+        .collect();
+    scopes.sort_by_key(|scope| scope.bytecode.1 - scope.bytecode.0);
+    scopes
+}
+
+/// Function call on the call stack
+pub struct Frame {
+    /// Instruction the function is at, u32::MAX if it was called from a
+    /// native function
+    pub ip: usize,
+    /// Values on the stack from the start of the function's local
+    /// variables, None for the top level of the script
+    pub slots: Option<Vec<Value>>,
+}
+
+impl Frame {
+    /// Name of the function the frame belongs to
+    pub fn name(&self, info: &ModulesInfo) -> String {
+        if self.slots.is_none() {
+            return "<main>".to_string();
+        }
+        scopes_at(info, self.ip)
+            .first()
+            .and_then(|scope| scope.name.clone())
+            .unwrap_or_else(|| "<anonymous>".to_string())
+    }
+
+    /// Local variables of the function with their names
+    pub fn locals(&self, info: &ModulesInfo) -> Vec<(String, Value)> {
+        let Some(slots) = self.slots.as_ref() else {
+            return Vec::new();
+        };
+        let scopes = scopes_at(info, self.ip);
+        let names = scopes.first().map_or(&[][..], |scope| &scope.vars[..]);
+        // the values after the local variables are temporaries
+        names.iter().cloned().zip(slots.iter().cloned()).collect()
+    }
+}
+
+/// Call stack of the virtual machine, innermost frame first
+pub fn frames(vm: &Vm) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut ip = vm.ip() as usize;
+    let mut end = vm.stack.len();
+    for env in vm.localenv().iter().rev() {
+        let base = env.base.min(end);
+        frames.push(Frame {
+            ip,
+            slots: Some(vm.stack.to_vec(base..end)),
+        });
+        ip = env.retip as usize;
+        end = base;
+    }
+    frames.push(Frame { ip, slots: None });
+    frames
+}
+
+/// Evaluates the expressions in the innermost frame, where they can use
+/// its local variables, returning the value of the last one
+///
+/// The state of the virtual machine is left as it was, exceptions that
+/// aren't handled by the expressions are returned as errors.
+pub fn eval_in_frame(vm: &Rc<RefCell<Vm>>, src: &str) -> Result<Value, String> {
+    let mut prog = grammar::parser_start(src).map_err(|err| {
+        let expected: Vec<String> = err.expected.iter().map(|x| x.to_string()).collect();
+        format!("expected {}", expected.join(", "))
+    })?;
+    // statements like return could leave the frame
+    if prog
+        .iter()
+        .any(|stmt| stmt.as_any().downcast_ref::<ast::ExprStatement>().is_none())
+    {
+        return Err("only expressions can be evaluated".to_string());
+    }
+    let last = prog.pop().ok_or("nothing to evaluate")?;
+
+    let modules_info = vm.borrow().modules_info.clone().unwrap();
+    // the variables of the enclosing functions it can use were captured by it
+    let scope: Option<ScopeInfo> = if vm.borrow().localenv().is_empty() {
+        None
+    } else {
+        let info = modules_info.borrow();
+        scopes_at(&info, vm.borrow().ip() as usize)
+            .first()
+            .map(|&scope| scope.clone())
+    };
+    {
+        let mut modules_info = modules_info.borrow_mut();
+        modules_info.add_file("[debugger]".to_string(), src.to_string());
+    }
+
+    // compile
+    let target_ip = vm.borrow().code.len() as u32;
+    let code = std::mem::take(&mut vm.borrow_mut().code);
+    let interned_strings = vm.borrow_mut().interned_strings.take().unwrap_or_default();
+    let mut c = Compiler::new_append(code, Rc::clone(&modules_info), interned_strings);
+    if let Some(scope) = &scope {
+        c.enter_scope(scope);
+    }
+    let result = (|| -> ast::CodeGenResult {
+        for stmt in prog {
+            stmt.emit(&mut c)?;
+        }
+        let expr_stmt = last.as_any().downcast_ref::<ast::ExprStatement>().unwrap();
+        expr_stmt.expr.emit(&mut c)?;
+        c.cpushop(VmOpcode::Halt);
+        Ok(())
+    })()
+    .map_err(|err| err.to_string())
+    .and_then(|()| match &scope {
+        // the frame's slots are followed by its temporaries on the stack
+        Some(scope) if c.nlocals() > scope.vars.len() => {
+            Err("new local variables can't be defined in a frame".to_string())
+        }
+        _ => Ok(()),
+    });
+    vm.borrow_mut().interned_strings = c.interned_strings.take();
+    let mut code = c.into_code();
+    if let Err(err) = result {
+        code.truncate(target_ip as usize);
+        vm.borrow_mut().code = code;
+        return Err(err);
+    }
+    vm.borrow_mut().code = code;
+
+    // run
+    let (ip, stack_len, localenv, exframes, exframe_fallthrough) = {
+        let mut vm = vm.borrow_mut();
+        let saved = (
+            vm.ip,
+            vm.stack.len(),
+            vm.localenv.clone(),
+            std::mem::take(vm.mut_exframes()),
+            vm.exframe_fallthrough.take(),
+        );
+        vm.ip = target_ip;
+        vm.native_call_depth += 1;
+        saved
+    };
+    inside_execute(Rc::clone(vm));
+    let error = std::mem::replace(&mut vm.borrow_mut().error, VmError::ERROR_NO_ERROR);
+    let result = match error {
+        VmError::ERROR_NO_ERROR => Ok(vm.borrow_mut().stack.pop().unwrap()),
+        VmError::ERROR_UNHANDLED_EXCEPTION => Err(format!(
+            "unhandled exception {:?}",
+            vm.borrow().stack.last().unwrap()
+        )),
+        _ => match unsafe { error.hint(Rc::clone(vm)) } {
+            Some(hint) => Err(format!("{} ({})", error, hint)),
+            None => Err(error.to_string()),
+        },
+    };
+    let mut vm = vm.borrow_mut();
+    vm.ip = ip;
+    vm.stack.truncate(stack_len);
+    vm.truncate_envs(localenv.len());
+    vm.localenv = localenv;
+    *vm.mut_exframes() = exframes;
+    vm.exframe_fallthrough = exframe_fallthrough;
+    vm.native_call_depth -= 1;
+    result
+}
+
+/// The instruction at ip with its operands decoded, jumps show the
+/// instruction they go to, along with the length of the instruction
+pub fn disassemble(code: &[u8], ip: usize) -> (String, usize) {
+    let Some(op) = code.get(ip).copied().and_then(VmOpcode::from_u8) else {
+        return (format!("[invalid opcode {:?}]", code.get(ip)), 1);
+    };
+    let bytes = |at: usize, len: usize| code.get(ip + at..ip + at + len);
+    let u16_at = |at: usize| bytes(at, 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let i16_at = |at: usize| bytes(at, 2).map(|b| i16::from_be_bytes([b[0], b[1]]));
+    let u32_at = |at: usize| bytes(at, 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+    let u64_at = |at: usize| bytes(at, 8).map(|b| u64::from_be_bytes(b.try_into().unwrap()));
+    // strings are null terminated
+    let string_len = code.get(ip + 1..).map_or(0, |rest| {
+        rest.iter().position(|&c| c == 0).unwrap_or(rest.len())
+    });
+    let string = || {
+        code.get(ip + 1..ip + 1 + string_len)
+            .map(|bytes| format!("{:?}", String::from_utf8_lossy(bytes)))
+    };
+
+    use VmOpcode::*;
+    let (operands, len) = match op {
+        Push8 => (code.get(ip + 1).map(|n| n.to_string()), 2),
+        Push16 => (u16_at(1).map(|n| n.to_string()), 3),
+        Push32 => (u32_at(1).map(|n| n.to_string()), 5),
+        Push64 => (u64_at(1).map(|n| (n as i64).to_string()), 9),
+        Pushf64 => (
+            bytes(1, 8).map(|b| f64::from_ne_bytes(b.try_into().unwrap()).to_string()),
+            9,
+        ),
+        PushStr | SetGlobal | GetGlobal | MemberGet | MemberGetNoPop | MemberSet | Use => {
+            (string(), string_len + 2)
+        }
+        PushStrInterned | EnvNew | SetLocal | SetLocalFunctionDef | GetLocal | SetUpvalue
+        | GetUpvalue | Call | RetCall => (u16_at(1).map(|n| n.to_string()), 3),
+        // local slots or upvalues of the enclosing function
+        Capture => {
+            let count = u16_at(1).unwrap_or(0) as usize;
+            let captures: Option<Vec<String>> = (0..count)
+                .map(|i| {
+                    let at = 3 + i * 3;
+                    let kind = match code.get(ip + at)? {
+                        0 => "up",
+                        _ => "local",
+                    };
+                    u16_at(at + 1).map(|idx| format!("{} {}", kind, idx))
+                })
+                .collect();
+            (captures.map(|captures| captures.join(", ")), 3 + count * 3)
+        }
+        // the body of the function comes right after
+        DefFunctionPush => (
+            u16_at(1)
+                .zip(u16_at(3))
+                .map(|(nargs, len)| format!("{} args, ends at {}", nargs, ip + 3 + len as usize)),
+            5,
+        ),
+        Jmp | JCond | JNcond | JCondNoPop | JNcondNoPop => (
+            i16_at(1).map(|pos| format!("-> {}", ip as i64 + 1 + pos as i64)),
+            3,
+        ),
+        JmpLong => (u32_at(1).map(|pos| format!("-> {}", pos)), 5),
+        ExframeRet | ForIn => (
+            u16_at(1).map(|pos| format!("-> {}", ip + 1 + pos as usize)),
+            3,
+        ),
+        _ => (Some(String::new()), 1),
+    };
+    let text = match operands {
+        Some(operands) if operands.is_empty() => format!("{:?}", op),
+        Some(operands) => format!("{:?} {}", op, operands),
+        None => format!("{:?} [truncated]", op),
+    };
+    (text, len)
+}
@@ -1,8 +1,7 @@
 //! Provides the stack frame for the virtual machine
 
-use super::value::Value;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use super::gc::Gc;
+use super::upvalue::Upvalue;
 use std::rc::Rc;
 
 #[repr(C)]
@@ -15,64 +14,24 @@ pub struct Env {
     /// Instruction pointer to return to on Ret
     pub retip: u32,
 
-    /// Local variable storage
+    /// Index of the first local variable in the value stack
     ///
-    /// Slot indexes access SHOULD be bounded
-    /// whenever the script is compiled to bytecode
-    pub slots: HashMap<u16, Value>,
+    /// Slot indexes are assigned densely by the compiler, the arguments
+    /// take up the first slots and EnvNew makes room for the rest of the
+    /// function's slots right above them.
+    pub base: usize,
 
-    /// Lexical parent of the current environment
-    ///
-    /// This is used for getting values on the previous stack frame.
-    pub lexical_parent: Rc<RefCell<Option<Env>>>,
+    /// Variables the function captured from the ones it's defined in
+    pub upvalues: Rc<[Gc<Upvalue>]>,
 }
 
 impl Env {
-    pub fn new(retip: u32, lexical_parent: Rc<RefCell<Option<Env>>>, nargs: u16) -> Env {
+    pub fn new(retip: u32, upvalues: Rc<[Gc<Upvalue>]>, nargs: u16, base: usize) -> Env {
         Env {
-            slots: HashMap::new(),
             nargs,
-            lexical_parent,
             retip,
+            base,
+            upvalues,
         }
     }
-
-    pub fn copy(other: Rc<RefCell<Option<Env>>>) -> Env {
-        let reference = Rc::clone(&other);
-
-        let mut slots = HashMap::new();
-        let mut lexical_parent = Rc::new(RefCell::new(None));
-
-        if let Some(base) = reference.borrow().as_ref() {
-            slots = base.slots.clone();
-            lexical_parent = base.lexical_parent.clone();
-        }
-
-        Env {
-            slots,
-            nargs: 0,
-            lexical_parent,
-            retip: u32::MAX,
-        }
-    }
-
-    pub fn get(&self, idx: u16) -> Option<Value> {
-        self.slots.get(&idx).cloned()
-    }
-
-    pub fn get_up(&self, up: u16, idx: u16) -> Option<Value> {
-        if let Some(lexical_parent) = self.lexical_parent.borrow().as_ref() {
-            if up == 1 {
-                lexical_parent.get(idx)
-            } else {
-                lexical_parent.get_up(up - 1, idx)
-            }
-        } else {
-            None
-        }
-    }
-
-    pub fn set(&mut self, idx: u16, val: Value) {
-        self.slots.insert(idx, val);
-    }
 }
@@ -3,7 +3,6 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::rc::Rc;
 
-use super::env::Env;
 use super::function::Function;
 use super::gc::Gc;
 use super::record::Record;
@@ -15,11 +14,9 @@ use super::vm::Vm;
 pub struct ExFrame {
     /// Exception frame handlers
     handlers: BTreeMap<Option<Gc<Record>>, Function>,
-    /// The target call stack frame to rewind to
-    #[allow(dead_code)]
-    pub unwind_env: Option<Rc<RefCell<Option<Env>>>>,
+    /// The number of call stack frames to rewind to
+    pub unwind_depth: usize,
     /// The target virtual machine stack index to rewind to
-    #[allow(dead_code)]
     pub unwind_stack: usize,
     /// How many native functions to return until we can call this?
     pub unwind_native_call_depth: usize,
@@ -27,13 +24,13 @@ pub struct ExFrame {
 
 impl ExFrame {
     pub fn new(
-        unwind_env: Option<Rc<RefCell<Option<Env>>>>,
+        unwind_depth: usize,
         unwind_stack: usize,
         unwind_native_call_depth: usize,
     ) -> ExFrame {
         ExFrame {
             handlers: BTreeMap::new(),
-            unwind_env,
+            unwind_depth,
             unwind_stack,
             unwind_native_call_depth,
         }
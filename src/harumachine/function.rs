@@ -1,8 +1,8 @@
 //! Provides a function value in Hana
 
-use super::env::Env;
-use crate::harumachine::gc::{push_gray_body, GcNode, GcTraceable};
-use std::{cell::RefCell, rc::Rc};
+use super::gc::{push_gray_body, Gc, GcNode, GcTraceable};
+use super::upvalue::Upvalue;
+use std::rc::Rc;
 
 // TODO: add a name type attribute to know the name of the function
 #[repr(C)]
@@ -14,47 +14,38 @@ pub struct Function {
     pub nargs: u16,
 
     // internal rust properties:
-    /// Represents the current local environment
-    /// at the time the function is declared.
+    /// Variables of the enclosing functions the function uses, in the
+    /// order the compiler numbered them
     ///
-    /// This will be COPIED into another struct env whenever OP_CALL is issued.
+    /// They're shared rather than copied, so the function sees assignments
+    /// made by the defining scope (and by other closures declared in it)
+    /// even after the declaration.
     ///
-    /// Wwe use this to implement closures.
-    pub bound: Rc<RefCell<Option<Env>>>,
+    /// We use this to implement closures.
+    pub upvalues: Rc<[Gc<Upvalue>]>,
 }
 
 impl Function {
-    pub unsafe fn new(ip: u32, nargs: u16, env: Rc<RefCell<Option<Env>>>) -> Function {
-        let bound = match env.borrow().is_some() {
-            true => Env::copy(Rc::clone(&env)),
-            false => Env::new(0, Rc::default(), nargs),
-        };
-
+    pub unsafe fn new(ip: u32, nargs: u16, upvalues: Rc<[Gc<Upvalue>]>) -> Function {
         Function {
             ip,
             nargs,
-            bound: Rc::new(RefCell::new(Some(bound))),
+            upvalues,
         }
     }
 
-    pub fn get_bound(&self) -> Rc<RefCell<Option<Env>>> {
-        Rc::clone(&self.bound)
+    pub fn get_upvalues(&self) -> Rc<[Gc<Upvalue>]> {
+        Rc::clone(&self.upvalues)
     }
 }
 
 // gc traceable
 impl GcTraceable for Function {
-    unsafe fn trace(&self, gray_nodes: &mut Vec<*mut GcNode>) {
-        let Some(bound) = self.bound.borrow_mut().take() else {
-            return;
-        };
+    const NAME: &'static str = "Function";
 
-        for val in bound.slots.values() {
-            if let Some(ptr) = val.as_gc_pointer() {
-                push_gray_body(gray_nodes, ptr);
-            }
+    unsafe fn trace(&self, gray_nodes: &mut Vec<*mut GcNode>) {
+        for upvalue in self.upvalues.iter() {
+            push_gray_body(gray_nodes, upvalue.to_raw() as *mut _);
         }
-
-        *self.bound.borrow_mut() = Some(bound);
     }
 }
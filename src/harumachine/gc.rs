@@ -1,9 +1,14 @@
-//! Basic implementation of a mark and sweep garbage collector
+//! Basic implementation of an incremental mark and sweep garbage collector
 
 pub use libc::c_void;
 use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::mem::ManuallyDrop;
 use std::ptr::{drop_in_place, null_mut, NonNull};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use super::value::Value;
 use super::vm::Vm;
@@ -19,7 +24,14 @@ enum GcNodeColor {
 pub struct GcNode {
     next: *mut GcNode,
     size: usize,
+    // bytes the body owns outside of the node, see GcTraceable::payload_size
+    payload: usize,
     color: GcNodeColor,
+    // id of the manager that allocated the node
+    manager: u32,
+    // index of the node's type in the manager's kinds
+    kind: u16,
+    flags: u8,
     native_refs: usize,
     // tracer gets called on the marking phase
     tracer: GenericTraceFunction,
@@ -28,6 +40,11 @@ pub struct GcNode {
     finalizer: GenericFunction,
 }
 
+// the node's finalizer already ran in the vm, it's freed the next time around
+const NODE_FINALIZED: u8 = 1;
+// the node has weak references to clear once it's freed
+const NODE_WEAKLY_REFERENCED: u8 = 2;
+
 impl GcNode {
     pub fn alloc_size<T: Sized>() -> usize {
         // number of bytes needed to allocate node for <T>
@@ -37,6 +54,7 @@ impl GcNode {
 }
 
 type GenericFunction = unsafe fn(*mut c_void);
+type GenericPredicate = unsafe fn(*const c_void) -> bool;
 // a generic function that takes in some pointer
 // this might be a finalizer or a tracer function
 // TODO maybe replace this with Any
@@ -44,24 +62,90 @@ type GenericFunction = unsafe fn(*mut c_void);
 // manager
 const INITIAL_THRESHOLD: usize = 4096;
 const USED_SPACE_RATIO: f64 = 0.7;
+// a full collection has to free up at least 1/n of the maximum heap size
+const MIN_FREE_RATIO: usize = 8;
+// number of nodes marked or swept on each allocation while collecting,
+// this keeps the pauses bounded no matter how big the heap is
+const STEP_SIZE: usize = 256;
+
+thread_local! {
+    static NEXT_MANAGER_ID: Cell<u32> = const { Cell::new(0) };
+    // nodes shaded by write barriers, by the id of their manager
+    static SHADED_NODES: RefCell<HashMap<u32, Vec<*mut GcNode>>> = RefCell::new(HashMap::new());
+}
+
+enum GcPhase {
+    // waiting for the heap to grow past the threshold
+    Idle,
+    Marking,
+    // sweeping the nodes after the cursor, prev is the node before it
+    Sweeping {
+        prev: *mut GcNode,
+        node: *mut GcNode,
+    },
+}
+
+// a type of values allocated by the manager
+struct GcKind {
+    name: &'static str,
+    count: usize,
+    needs_finalize: GenericPredicate,
+}
+
+/// Statistics about the garbage collected heap
+pub struct GcStats {
+    pub bytes_allocated: usize,
+    pub threshold: usize,
+    /// Number of live objects for each type
+    pub objects: Vec<(&'static str, usize)>,
+    /// Number of collections that ran to completion
+    pub collections: usize,
+    /// How long the last collection step paused the program for
+    pub last_pause: Duration,
+    pub enabled: bool,
+}
+
 pub struct GcManager {
+    id: u32,
     first_node: *mut GcNode,
     last_node: *mut GcNode,
     bytes_allocated: usize,
     gray_nodes: Vec<*mut GcNode>,
+    phase: GcPhase,
     threshold: usize,
+    max_heap_size: Option<usize>,
     enabled: bool,
+    kinds: Vec<GcKind>,
+    // nodes waiting for their finalizers to run in the vm,
+    // each of them holds a native reference until then
+    finalizable_nodes: Vec<*mut GcNode>,
+    weak_refs: HashMap<*mut GcNode, Rc<Cell<bool>>>,
+    // stats
+    collections: usize,
+    last_pause: Duration,
 }
 
 impl GcManager {
     pub fn new() -> GcManager {
         GcManager {
+            id: NEXT_MANAGER_ID.with(|id| {
+                let next = id.get();
+                id.set(next.wrapping_add(1));
+                next
+            }),
             first_node: null_mut(),
             last_node: null_mut(),
             bytes_allocated: 0,
             gray_nodes: Vec::new(),
+            phase: GcPhase::Idle,
             threshold: INITIAL_THRESHOLD,
+            max_heap_size: None,
             enabled: false,
+            kinds: Vec::new(),
+            finalizable_nodes: Vec::new(),
+            weak_refs: HashMap::new(),
+            collections: 0,
+            last_pause: Duration::default(),
         }
     }
 
@@ -72,8 +156,10 @@ impl GcManager {
         finalizer: GenericFunction,
     ) -> *mut T {
         let size = GcNode::alloc_size::<T>();
-        let node: *mut GcNode = self
-            .cycle(vm, size)
+        let payload = x.payload_size();
+        let fitting_node = self.cycle(vm, size);
+        self.check_heap_size(vm, size + payload);
+        let node: *mut GcNode = fitting_node
             .unwrap_or_else(|| {
                 let layout = Layout::from_size_align(size, 2).unwrap();
                 NonNull::new(alloc_zeroed(layout) as *mut GcNode).unwrap()
@@ -90,13 +176,28 @@ impl GcManager {
             self.last_node = node;
         }
         (*node).native_refs = 1;
+        (*node).manager = self.id;
+        (*node).kind = match self.kinds.iter().position(|kind| kind.name == T::NAME) {
+            Some(kind) => kind,
+            None => {
+                self.kinds.push(GcKind {
+                    name: T::NAME,
+                    count: 0,
+                    needs_finalize: |ptr| (*(ptr as *const T)).needs_finalize(),
+                });
+                self.kinds.len() - 1
+            }
+        } as u16;
+        self.kinds[(*node).kind as usize].count += 1;
+        (*node).flags = 0;
         (*node).tracer = std::mem::transmute::<
             *mut libc::c_void,
             unsafe fn(*mut libc::c_void, *mut libc::c_void),
         >(T::trace as *mut c_void);
         (*node).finalizer = finalizer;
         (*node).size = size;
-        self.bytes_allocated += (*node).size;
+        (*node).payload = payload;
+        self.bytes_allocated += size + payload;
         // gray out the node
         // TODO: we currently move the write barrier forward rather than backwards
         // this probably is less efficient than setting the newly allocated node
@@ -108,6 +209,40 @@ impl GcManager {
         node.add(1) as *mut T
     }
 
+    // collects if growing the heap by the size would take it past its
+    // maximum size. the allocation still has to go through since callers
+    // can't handle failing, the interpreter raises a MemoryError instead
+    unsafe fn check_heap_size(&mut self, vm: &Vm, size: usize) {
+        if let Some(max_heap_size) = self.max_heap_size {
+            if self.enabled && self.bytes_allocated + size > max_heap_size {
+                self.collect(vm);
+                // a collection that leaves little room would just run again
+                // on the next few allocations
+                if self.bytes_allocated + size > max_heap_size - max_heap_size / MIN_FREE_RATIO {
+                    vm.out_of_memory.set(true);
+                }
+            }
+        }
+    }
+
+    /// Accounts for the value's payload after it grew or shrank in place
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to the body of a live node
+    pub unsafe fn resize<T: Sized + GcTraceable>(&mut self, vm: &Vm, ptr: *const T) {
+        let node: *mut GcNode = (ptr as *mut GcNode).sub(1);
+        if (*node).manager != self.id {
+            return;
+        }
+        let payload = (*ptr).payload_size();
+        if payload > (*node).payload {
+            self.check_heap_size(vm, payload - (*node).payload);
+        }
+        self.bytes_allocated = self.bytes_allocated - (*node).payload + payload;
+        (*node).payload = payload;
+    }
+
     pub fn malloc<T: Sized + GcTraceable>(&mut self, vm: &Vm, val: T) -> Gc<T> {
         Gc {
             ptr: NonNull::new(unsafe {
@@ -125,92 +260,313 @@ impl GcManager {
     pub fn enable(&mut self) {
         self.enabled = true;
     }
-    #[allow(dead_code)]
     pub fn disable(&mut self) {
         self.enabled = false;
     }
 
-    // gc algorithm
+    pub fn max_heap_size(&self) -> Option<usize> {
+        self.max_heap_size
+    }
+
+    pub fn set_max_heap_size(&mut self, max_heap_size: Option<usize>) {
+        self.max_heap_size = max_heap_size;
+        if let Some(max_heap_size) = max_heap_size {
+            self.threshold = self.threshold.min(max_heap_size);
+        }
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            bytes_allocated: self.bytes_allocated,
+            threshold: self.threshold,
+            objects: self
+                .kinds
+                .iter()
+                .map(|kind| (kind.name, kind.count))
+                .collect(),
+            collections: self.collections,
+            last_pause: self.last_pause,
+            enabled: self.enabled,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = match self.max_heap_size {
+            Some(max_heap_size) => threshold.min(max_heap_size),
+            None => threshold,
+        };
+    }
+
+    // gc algorithm, does a bounded step of the collection
     unsafe fn cycle(&mut self, vm: &Vm, size: usize) -> Option<NonNull<GcNode>> {
-        if !self.enabled || self.bytes_allocated < self.threshold {
+        if !self.enabled {
             return None;
         }
-        // marking phase
-        let gray_nodes = std::mem::take(&mut self.gray_nodes);
-        for node in gray_nodes.iter() {
+        if let GcPhase::Idle = self.phase {
+            if self.bytes_allocated < self.threshold {
+                return None;
+            }
+            self.phase = GcPhase::Marking;
+        }
+        let start = Instant::now();
+        let fitting_node = match self.phase {
+            GcPhase::Sweeping { .. } => self.sweep(vm, size),
+            _ => {
+                self.mark(vm);
+                None
+            }
+        };
+        self.last_pause = start.elapsed();
+        fitting_node
+    }
+
+    /// Does a full collection, even if the collector is disabled
+    ///
+    /// # Safety
+    ///
+    /// Every value that's still in use must be reachable from the vm
+    /// or held by a native reference
+    pub unsafe fn collect(&mut self, vm: &Vm) {
+        let start = Instant::now();
+        // shaded nodes might get freed here, they're all grayed out below anyway
+        self.take_shaded_nodes();
+        // values inside of other nodes hold native references too, so nodes
+        // are visited newest first: nodes that are only referred to by newer
+        // nodes get freed in the same pass as them
+        let mut nodes = Vec::new();
+        let mut node = self.first_node;
+        while !node.is_null() {
+            nodes.push(node);
+            node = (*node).next;
+        }
+        for node in nodes.iter_mut().rev() {
+            if (**node).native_refs == 0 && !self.resurrect(vm, *node) {
+                self.release(*node);
+                let layout = Layout::from_size_align((**node).size, 2).unwrap();
+                dealloc(*node as *mut u8, layout);
+                *node = null_mut();
+            }
+        }
+        // relink what's left and start a new marking phase
+        self.first_node = null_mut();
+        self.last_node = null_mut();
+        self.gray_nodes.clear();
+        for &node in nodes.iter().filter(|node| !node.is_null()) {
+            (*node).next = null_mut();
+            if self.last_node.is_null() {
+                self.first_node = node;
+            } else {
+                (*self.last_node).next = node;
+            }
+            self.last_node = node;
+            if (*node).native_refs != 0 {
+                (*node).color = GcNodeColor::Gray;
+                self.gray_nodes.push(node);
+            } else {
+                (*node).color = GcNodeColor::White;
+            }
+        }
+        vm.trace(&mut self.gray_nodes);
+        self.phase = GcPhase::Idle;
+        self.collections += 1;
+        self.last_pause = start.elapsed();
+    }
+
+    // keeps a dead node alive for its finalizer to run in the vm if it has one,
+    // the finalizer only ever runs once, even if the node gets resurrected
+    unsafe fn resurrect(&mut self, vm: &Vm, node: *mut GcNode) -> bool {
+        if (*node).flags & NODE_FINALIZED != 0 {
+            return false;
+        }
+        let needs_finalize = self.kinds[(*node).kind as usize].needs_finalize;
+        if !needs_finalize(node.add(1) as *const c_void) {
+            return false;
+        }
+        (*node).flags |= NODE_FINALIZED;
+        (*node).native_refs += 1;
+        self.finalizable_nodes.push(node);
+        vm.finalizers_pending.set(true);
+        true
+    }
+
+    // accounts for a node that's about to be freed and drops its body
+    unsafe fn release(&mut self, node: *mut GcNode) {
+        self.bytes_allocated -= (*node).size + (*node).payload;
+        self.kinds[(*node).kind as usize].count -= 1;
+        if (*node).flags & NODE_WEAKLY_REFERENCED != 0 {
+            if let Some(alive) = self.weak_refs.remove(&node) {
+                alive.set(false);
+            }
+        }
+        // call finalizer
+        let finalizer = (*node).finalizer;
+        finalizer(node.add(1) as *mut c_void);
+    }
+
+    /// Takes the values that are waiting for their finalizers to run,
+    /// each of them holds a native reference that the caller now owns
+    pub fn take_finalizable(&mut self) -> Vec<*mut c_void> {
+        std::mem::take(&mut self.finalizable_nodes)
+            .into_iter()
+            .map(|node| unsafe { node.add(1) as *mut c_void })
+            .collect()
+    }
+
+    /// Creates a reference to the value that doesn't keep it alive
+    pub fn weak_ref(&mut self, val: &Value) -> WeakRef {
+        let alive = match val.as_gc_pointer() {
+            Some(ptr) => unsafe {
+                let node: *mut GcNode = (ptr as *mut GcNode).sub(1);
+                (*node).flags |= NODE_WEAKLY_REFERENCED;
+                Rc::clone(
+                    self.weak_refs
+                        .entry(node)
+                        .or_insert_with(|| Rc::new(Cell::new(true))),
+                )
+            },
+            None => Rc::new(Cell::new(true)),
+        };
+        let target = ManuallyDrop::new(val.clone());
+        // the copy doesn't count as a reference
+        if let Some(ptr) = target.as_gc_pointer() {
+            unsafe { ref_dec(ptr) }
+        }
+        WeakRef { alive, target }
+    }
+
+    // takes the nodes shaded by write barriers since the last call
+    fn take_shaded_nodes(&mut self) -> Vec<*mut GcNode> {
+        SHADED_NODES.with(|shaded| {
+            shaded
+                .borrow_mut()
+                .get_mut(&self.id)
+                .map(std::mem::take)
+                .unwrap_or_default()
+        })
+    }
+
+    // marks gray nodes until the step's budget runs out
+    unsafe fn mark(&mut self, vm: &Vm) {
+        self.mark_nodes(STEP_SIZE);
+        if !self.gray_nodes.is_empty() {
+            return;
+        }
+        // the roots might have changed since they were traced, and
+        // writes might have moved nodes out of the marked ones' reach,
+        // catch up with them in one go before sweeping
+        vm.trace(&mut self.gray_nodes);
+        self.mark_nodes(usize::MAX);
+        // nothing left to traverse, sweeping phase:
+        self.phase = GcPhase::Sweeping {
+            prev: null_mut(),
+            node: self.first_node,
+        };
+    }
+
+    // marks up to limit gray or shaded nodes
+    unsafe fn mark_nodes(&mut self, limit: usize) {
+        let mut marked = 0;
+        while marked < limit {
+            let node = match self.gray_nodes.pop() {
+                Some(node) => node,
+                None => match self.take_shaded_nodes() {
+                    shaded if shaded.is_empty() => return,
+                    shaded => {
+                        self.gray_nodes = shaded;
+                        continue;
+                    }
+                },
+            };
             let body = node.add(1) as *mut c_void;
-            (**node).color = GcNodeColor::Black;
+            (*node).color = GcNodeColor::Black;
 
-            let tracer_fn = (**node).tracer;
+            let tracer_fn = (*node).tracer;
             let mut_ptr =
                 &mut self.gray_nodes as *mut std::vec::Vec<*mut GcNode> as *mut libc::c_void;
 
             tracer_fn(body, mut_ptr);
+            marked += 1;
         }
-        // nothing left to traverse, sweeping phase:
-        if self.gray_nodes.is_empty() {
-            let mut prev: *mut GcNode = null_mut();
-            // sweep
-            let mut node = self.first_node;
-            let mut first_fitting_node: Option<NonNull<GcNode>> = None;
-            while !node.is_null() {
-                let next: *mut GcNode = (*node).next;
-                let mut freed = false;
-                if (*node).native_refs == 0 && (*node).color == GcNodeColor::White {
-                    freed = true;
-                    let body = node.add(1);
-
-                    // remove from ll
-                    if prev.is_null() {
-                        self.first_node = (*node).next;
-                    } else {
-                        (*prev).next = (*node).next;
-                    }
-                    if (*node).next.is_null() {
-                        self.last_node = prev;
-                    }
-                    self.bytes_allocated -= (*node).size;
-
-                    // call finalizer
-                    let finalizer = (*node).finalizer;
-                    finalizer(body as *mut c_void);
-
-                    // if this node fits then record it
-                    if (*node).size == size && first_fitting_node.is_none() {
-                        std::ptr::write_bytes(node as *mut u8, 0, (*node).size);
-                        first_fitting_node = Some(NonNull::new_unchecked(node));
-                    } else {
-                        // else just free it
-                        let layout = Layout::from_size_align((*node).size, 2).unwrap();
-                        dealloc(node as *mut u8, layout);
-                    }
-                } else if (*node).native_refs != 0 {
-                    self.gray_nodes.push(node);
+    }
+
+    // frees white nodes until the step's budget runs out, then starts
+    // a new marking phase from the vm's roots once every node is swept,
+    // returns a freed node of the given size for reuse if there's any
+    unsafe fn sweep(&mut self, vm: &Vm, size: usize) -> Option<NonNull<GcNode>> {
+        let (mut prev, mut node) = match self.phase {
+            GcPhase::Sweeping { prev, node } => (prev, node),
+            _ => return None,
+        };
+        let mut first_fitting_node: Option<NonNull<GcNode>> = None;
+        let mut swept = 0;
+        while !node.is_null() && swept < STEP_SIZE {
+            let next: *mut GcNode = (*node).next;
+            let mut freed = false;
+            if (*node).native_refs == 0
+                && (*node).color == GcNodeColor::White
+                && !self.resurrect(vm, node)
+            {
+                freed = true;
+
+                // remove from ll
+                if prev.is_null() {
+                    self.first_node = (*node).next;
                 } else {
-                    (*node).color = GcNodeColor::White;
+                    (*prev).next = (*node).next;
                 }
-                if !freed {
-                    prev = node;
+                if (*node).next.is_null() {
+                    self.last_node = prev;
                 }
-                node = next;
-            }
-            vm.trace(&mut self.gray_nodes);
+                self.release(node);
 
-            // we didn't collect enough, grow the ratio
-            if ((self.bytes_allocated as f64) / (self.threshold as f64)) > USED_SPACE_RATIO {
-                self.threshold = (self.bytes_allocated as f64 / USED_SPACE_RATIO) as usize;
+                // if this node fits then record it
+                if (*node).size == size && first_fitting_node.is_none() {
+                    std::ptr::write_bytes(node as *mut u8, 0, (*node).size);
+                    first_fitting_node = Some(NonNull::new_unchecked(node));
+                } else {
+                    // else just free it
+                    let layout = Layout::from_size_align((*node).size, 2).unwrap();
+                    dealloc(node as *mut u8, layout);
+                }
+            } else if (*node).native_refs != 0 {
+                (*node).color = GcNodeColor::Gray;
+                self.gray_nodes.push(node);
+            } else {
+                (*node).color = GcNodeColor::White;
             }
-
-            // return first fitting node if there is any
-            first_fitting_node
-        } else {
-            None
+            if !freed {
+                prev = node;
+            }
+            node = next;
+            swept += 1;
         }
+        if !node.is_null() {
+            self.phase = GcPhase::Sweeping { prev, node };
+            return first_fitting_node;
+        }
+        vm.trace(&mut self.gray_nodes);
+        self.phase = GcPhase::Idle;
+        self.collections += 1;
+
+        // we didn't collect enough, grow the ratio
+        if ((self.bytes_allocated as f64) / (self.threshold as f64)) > USED_SPACE_RATIO {
+            self.threshold = (self.bytes_allocated as f64 / USED_SPACE_RATIO) as usize;
+            if let Some(max_heap_size) = self.max_heap_size {
+                self.threshold = self.threshold.min(max_heap_size);
+            }
+        }
+
+        // return first fitting node if there is any
+        first_fitting_node
     }
 }
 
 impl std::ops::Drop for GcManager {
     fn drop(&mut self) {
+        SHADED_NODES.with(|shaded| shaded.borrow_mut().remove(&self.id));
+        for alive in self.weak_refs.values() {
+            alive.set(false);
+        }
         unsafe {
             let mut node: *mut GcNode = self.first_node;
             while !node.is_null() {
@@ -242,6 +598,29 @@ impl<T: Sized + GcTraceable> Gc<T> {
     pub unsafe fn into_raw(self) -> *mut T {
         self.ptr.as_ptr()
     }
+    /// # Safety
+    ///
+    /// The pointer must come from a value allocated by the gc that's still alive
+    pub unsafe fn from_raw(ptr: *mut T) -> Gc<T> {
+        ref_inc(ptr as *mut c_void);
+        Gc {
+            ptr: NonNull::new(ptr).unwrap(),
+        }
+    }
+
+    /// Gives up the handle without releasing its reference
+    pub(super) fn leak(self) -> NonNull<T> {
+        ManuallyDrop::new(self).ptr
+    }
+    /// Takes back a reference given up by [`Gc::leak`]
+    ///
+    /// # Safety
+    ///
+    /// The pointer must come from a handle that was leaked, and each leaked
+    /// handle can only be taken back once
+    pub(super) unsafe fn from_leaked(ptr: NonNull<T>) -> Gc<T> {
+        Gc { ptr }
+    }
 
     // refs with interior mutability
     pub fn inner_mut_ptr(&mut self) -> &mut T {
@@ -300,6 +679,20 @@ impl<T: Sized + GcTraceable> std::cmp::Eq for Gc<T> {}
 
 // #region traceable
 pub trait GcTraceable {
+    /// Name of the type in the gc's statistics
+    const NAME: &'static str = "Native";
+
+    /// Whether the vm has to run a finalizer before the value gets freed
+    fn needs_finalize(&self) -> bool {
+        false
+    }
+
+    /// Bytes the value owns outside of its node, they count towards
+    /// the heap's size
+    fn payload_size(&self) -> usize {
+        0
+    }
+
     unsafe fn trace(&self, manager: &mut Vec<*mut GcNode>);
 }
 
@@ -307,6 +700,12 @@ type GenericTraceFunction = unsafe fn(*mut c_void, *mut c_void);
 
 // native traceables
 impl GcTraceable for Vec<Value> {
+    const NAME: &'static str = "Array";
+
+    fn payload_size(&self) -> usize {
+        self.capacity() * std::mem::size_of::<Value>()
+    }
+
     unsafe fn trace(&self, gray_nodes: &mut Vec<*mut GcNode>) {
         for val in self.iter() {
             if let Some(ptr) = val.as_gc_pointer() {
@@ -339,6 +738,45 @@ pub unsafe fn ref_dec(ptr: *mut c_void) {
     (*node).native_refs -= 1;
 }
 
+/// Reference to a value that doesn't keep it alive
+pub struct WeakRef {
+    alive: Rc<Cell<bool>>,
+    target: ManuallyDrop<Value>,
+}
+
+impl WeakRef {
+    /// Gets the value if it hasn't been freed yet
+    pub fn get(&self) -> Option<Value> {
+        if self.alive.get() {
+            Some((*self.target).clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Write barrier, has to be called with values that get stored inside
+/// of other gc-allocated values or environments so that the incremental
+/// marking phase doesn't lose track of them
+pub fn write_barrier(val: &Value) {
+    if let Some(ptr) = val.as_gc_pointer() {
+        unsafe {
+            let node: *mut GcNode = (ptr as *mut GcNode).sub(1);
+            if (*node).color != GcNodeColor::White {
+                return;
+            }
+            (*node).color = GcNodeColor::Gray;
+            SHADED_NODES.with(|shaded| {
+                shaded
+                    .borrow_mut()
+                    .entry((*node).manager)
+                    .or_default()
+                    .push(node)
+            });
+        }
+    }
+}
+
 pub unsafe fn push_gray_body(gray_nodes: &mut Vec<*mut GcNode>, ptr: *mut c_void) {
     let node: *mut GcNode = (ptr as *mut GcNode).sub(1);
     //eprintln!("node: {:p}", node);
@@ -0,0 +1,110 @@
+//! Provides inline caches for member access instructions
+
+use super::record::Record;
+use super::string::HaruString;
+use super::value::Value;
+use std::borrow::Borrow;
+use std::hash::Hash;
+
+/// Where a member was found the last time an instruction was executed
+pub struct InlineCache {
+    /// Shape of the record the member was accessed on
+    shape: u64,
+    /// The record's prototype and its shape, if the member was found there
+    prototype: Option<(*const Record, u64)>,
+    slot: usize,
+    /// Instruction pointer right after the member's key
+    pub next_ip: u32,
+}
+
+impl InlineCache {
+    /// Caches the member of the record (or of its direct prototype),
+    /// members of records in dictionary mode aren't cached
+    pub fn new<T>(record: &Record, key: &T, next_ip: u32) -> Option<InlineCache>
+    where
+        HaruString: Borrow<T>,
+        T: Hash + Eq + ?Sized,
+    {
+        if record.is_dictionary() {
+            return None;
+        }
+        let (depth, slot) = record.lookup(key)?;
+        let prototype = match depth {
+            0 => None,
+            1 => {
+                let prototype = record.prototype()?;
+                if prototype.is_dictionary() {
+                    return None;
+                }
+                Some((prototype as *const Record, prototype.shape()))
+            }
+            _ => return None,
+        };
+        Some(InlineCache {
+            shape: record.shape(),
+            prototype,
+            slot,
+            next_ip,
+        })
+    }
+
+    /// Gets the cached member if the record still has the same shape
+    pub fn get<'a>(&self, record: &'a Record) -> Option<&'a Value> {
+        if record.shape() != self.shape {
+            return None;
+        }
+        match self.prototype {
+            None => Some(record.slot(self.slot)),
+            Some((ptr, shape)) => {
+                // same shape means the record still doesn't have the key
+                // itself, but it could have another prototype by now
+                let prototype = record.prototype()?;
+                if !std::ptr::eq(prototype, ptr) || prototype.shape() != shape {
+                    return None;
+                }
+                Some(prototype.slot(self.slot))
+            }
+        }
+    }
+
+    /// Sets the cached member if it belongs to the record and
+    /// the record still has the same shape
+    pub fn set(&self, record: &mut Record, val: Value) -> Result<(), Value> {
+        if record.shape() != self.shape || self.prototype.is_some() {
+            return Err(val);
+        }
+        record.set_slot(self.slot, val);
+        Ok(())
+    }
+}
+
+/// Inline caches for every member access instruction in the bytecode
+#[derive(Default)]
+pub struct InlineCaches {
+    // index + 1 into caches for each instruction pointer, 0 if there is none
+    indices: Vec<u32>,
+    caches: Vec<InlineCache>,
+}
+
+impl InlineCaches {
+    pub fn get(&self, ip: u32) -> Option<&InlineCache> {
+        match self.indices.get(ip as usize) {
+            Some(&idx) if idx != 0 => Some(&self.caches[idx as usize - 1]),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, ip: u32, cache: InlineCache) {
+        let ip = ip as usize;
+        if ip >= self.indices.len() {
+            self.indices.resize(ip + 1, 0);
+        }
+        match self.indices[ip] {
+            0 => {
+                self.caches.push(cache);
+                self.indices[ip] = self.caches.len() as u32;
+            }
+            idx => self.caches[idx as usize - 1] = cache,
+        }
+    }
+}
@@ -1,28 +1,34 @@
 use crate::harumachine::value::{value_is_true, Value::*};
 
+use crate::hanayo::HanayoCtx;
 #[allow(unused_imports)]
 use crate::harumachine::{
     self,
     exframe::ExFrame,
     function::Function,
-    gc::Gc,
+    gc::{write_barrier, Gc},
     operations::*,
     //  nativeval::{NativeValue, NativeValueType::TYPE_INTERPRETER_ERROR},
     value::Value,
-    vm::{Vm, VmOpcode, VmOpcode::*}, // vm_execute
+    vm::{run_finalizers, Vm, VmOpcode, VmOpcode::*}, // vm_execute
     vmerror::VmError::{
-        ERROR_CANNOT_ACCESS_NON_RECORD, ERROR_CASE_EXPECTS_DICT, ERROR_CONSTRUCTOR_NOT_FUNCTION,
-        ERROR_EXPECTED_CALLABLE, ERROR_EXPECTED_ITERABLE, ERROR_EXPECTED_RECORD_ARRAY,
-        ERROR_EXPECTED_RECORD_OF_EXPR, ERROR_KEY_NON_INT, ERROR_MISMATCH_ARGUMENTS,
-        ERROR_NO_ERROR, ERROR_OP_ADD, ERROR_OP_BITWISE_AND, ERROR_OP_BITWISE_OR,
-        ERROR_OP_BITWISE_XOR, ERROR_OP_DIV, ERROR_OP_EQ, ERROR_OP_GEQ, ERROR_OP_GT, ERROR_OP_LEQ,
-        ERROR_OP_LT, ERROR_OP_MOD, ERROR_OP_MUL, ERROR_OP_NEQ, ERROR_OP_SUB,
-        ERROR_RECORD_KEY_NON_STRING, ERROR_RECORD_NO_CONSTRUCTOR, ERROR_UNBOUNDED_ACCESS,
-        ERROR_UNDEFINED_GLOBAL_VAR, ERROR_UNHANDLED_EXCEPTION, ERROR_UNKNOWN_KEY,
+        self, ERROR_CANNOT_ACCESS_NON_RECORD, ERROR_CASE_EXPECTS_DICT,
+        ERROR_CONSTRUCTOR_NOT_FUNCTION, ERROR_EXPECTED_CALLABLE, ERROR_EXPECTED_ITERABLE,
+        ERROR_EXPECTED_RECORD_ARRAY, ERROR_EXPECTED_RECORD_OF_EXPR, ERROR_KEY_NON_INT,
+        ERROR_MISMATCH_ARGUMENTS, ERROR_NO_ERROR, ERROR_OP_ADD, ERROR_OP_BITWISE_AND,
+        ERROR_OP_BITWISE_OR, ERROR_OP_BITWISE_XOR, ERROR_OP_DIV, ERROR_OP_EQ, ERROR_OP_GEQ,
+        ERROR_OP_GT, ERROR_OP_LEQ, ERROR_OP_LT, ERROR_OP_MOD, ERROR_OP_MUL, ERROR_OP_NEQ,
+        ERROR_OP_SUB, ERROR_RECORD_KEY_NON_STRING, ERROR_RECORD_NO_CONSTRUCTOR,
+        ERROR_UNBOUNDED_ACCESS, ERROR_UNDEFINED_GLOBAL_VAR, ERROR_UNHANDLED_EXCEPTION,
+        ERROR_UNKNOWN_KEY,
     },
 };
-use crate::harumachine::{/*env::Env */ record, string::HaruString};
+use crate::harumachine::{
+    /*env::Env */ inline_cache::InlineCache, module, nanbox::NanBox, record,
+    string::HaruString,
+};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ops::Deref;
 use std::{borrow::Borrow, rc::Rc};
 use unicode_segmentation::UnicodeSegmentation;
@@ -30,12 +36,12 @@ use unicode_segmentation::UnicodeSegmentation;
 
 macro_rules! log_debug {
     () => (
-        #[cfg(feature = "debuger")]
+        #[cfg(feature = "log_instructions")]
         $crate::print!("\n")
     );
 
     ($($arg:tt)*) => ({
-        #[cfg(feature = "debuger")]
+        #[cfg(feature = "log_instructions")]
         println!($($arg)*);
     })
 }
@@ -86,1588 +92,1691 @@ pub(crate) fn get_prototype(vm: Rc<RefCell<Vm>>, val: Value) -> Option<Gc<record
     }
 }
 
-// pops a function/record constructor on top of the stack,
-// sets up necessary environment and calls it.
-#[inline(always)]
-pub(super) fn inside_execute(vm: Rc<RefCell<Vm>>) {
-    // println!("Ip: {}, {:?}", vm.ip, (*vm).borrow().code, vm.stack);
-    /*
-        vm.code: Instructions in the form of Bytecode
-        vm.ip: Global Parting with reference to vm.code (Current Instruction).
-        vm.stack: Stack/Saving of temporary values
-    */
-
-    //println!("Call me: {:?},  vm.code: {}", vm.code, vm.ip);
-
-    if Halt == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Halt, IP: {}", (*vm).borrow().ip);
-        return;
-    }
-
-    if Push8 == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        vm.borrow_mut().ip += 2;
-        log_debug!("Push8, IP: {} sum(2)", (*vm).borrow().ip);
-
-        let int = Int((*vm).borrow().code[(*vm).borrow().ip as usize - 1] as i64);
-        log_debug!("  int: {:?}", &int);
-
-        vm.borrow_mut().stack.push(int);
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
-    }
-
-    if Push16 == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Push16, IP: {}", (*vm).borrow().ip);
-        #[rustfmt::skip]
-        let i = i64::from_be_bytes([
-            0,0,0,0,0,0,
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-
-        vm.borrow_mut().stack.push(Int(i));
-        vm.borrow_mut().ip += 3;
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
-    }
-
-    if Push32 == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Push32, IP: {}", (*vm).borrow().ip);
-        #[rustfmt::skip]
-        let i = i64::from_be_bytes([
-            0, 0, 0, 0,
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
-        ]);
-
-        vm.borrow_mut().stack.push(Int(i));
-        vm.borrow_mut().ip += 5;
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
-    }
-
-    if Push64 == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Push64, IP: {}", (*vm).borrow().ip);
-        let i = i64::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 5],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 6],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 7],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 8],
-        ]);
-
-        vm.borrow_mut().stack.push(Int(i));
-        vm.borrow_mut().ip += 9;
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
-    }
-    // Push 32/64-bit float on to the stack
-    if Pushf64 == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Pushf64, IP: {}", (*vm).borrow().ip);
-
-        let bytes = [
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 5],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 6],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 7],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 8],
-        ];
-        vm.borrow_mut().ip += 9;
-        let f = f64::from_bits(u64::from_ne_bytes(bytes));
-        let float = Float(f);
-        vm.borrow_mut().stack.push(float);
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
-    }
-
-    if PushBool == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        unimplemented!("This is for the future");
-    }
-
-    // Push string on to the stack
-    if PushStr == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("PushStr, IP: {}", (*vm).borrow().ip);
-        let key: HaruString = generate_string(Rc::clone(&vm)).into();
-        log_debug!("  key: {:?}", key.to_string());
-        let key = (*vm).borrow().malloc(key);
-        vm.borrow_mut().stack.push(Str(key));
+// What happened when a native function called by the interpreter returned
+#[derive(PartialEq)]
+enum NativeReturn {
+    Returned,
+    // an exception raised inside of it was caught at this depth,
+    // so execution goes on from the handler
+    Caught,
+    // an error or an exception that's still unwinding to a caller
+    Stop,
+}
 
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
+fn native_call_returned(vm: &Rc<RefCell<Vm>>) -> NativeReturn {
+    let mut vm = vm.borrow_mut();
+    vm.native_call_depth -= 1;
+    if vm.error != ERROR_NO_ERROR {
+        return NativeReturn::Stop;
+    }
+    match &vm.exframe_fallthrough {
+        None => NativeReturn::Returned,
+        Some(exframe) if exframe.unwind_native_call_depth == vm.native_call_depth => {
+            vm.exframe_fallthrough = None;
+            NativeReturn::Caught
+        }
+        Some(_) => NativeReturn::Stop,
     }
+}
 
-    // Get a store string and push on the stack
-    if PushStrInterned == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("PushStrInterned, IP: {}", (*vm).borrow().ip);
+// Raises a StackOverflowError for a call past the maximum call depth,
+// the error keeps the location of every frame in its backtrace
+fn raise_stack_overflow(vm: &Rc<RefCell<Vm>>) -> bool {
+    let error = {
+        let vm = (**vm).borrow();
+        let why = format!("maximum call depth of {} exceeded", vm.max_call_depth());
+        let mut error = error_record(&vm, |stdlib| &stdlib.stack_overflow_error, why);
+        if let Some(info) = vm.modules_info.as_ref() {
+            let info = (**info).borrow();
+            // recursive calls return to the same few places
+            let mut locations: HashMap<u32, Value> = HashMap::new();
+            let backtrace: Vec<Value> = vm
+                .localenv()
+                .iter()
+                .map(|env| {
+                    locations
+                        .entry(env.retip)
+                        .or_insert_with(
+                            || Str(vm.malloc(info.location(env.retip as usize).into())),
+                        )
+                        .clone()
+                })
+                .collect();
+            error
+                .inner_mut_ptr()
+                .insert("backtrace", Array(vm.malloc(backtrace)));
+        }
+        error
+    };
+    raise_record(vm, error)
+}
 
-        let i = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
+// Rust stack that native functions calling back into the interpreter can
+// use up, the frames of the interpreter can be quite big in debug builds
+const NATIVE_STACK_SIZE: usize = 1024 * 1024;
 
-        vm.borrow_mut().ip += 3;
-        let i = (*vm).borrow().get_interned_string(i);
-        vm.borrow_mut().stack.push(Str((*vm).borrow().malloc(i)));
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
-    }
+fn native_stack_exhausted(vm: &Vm) -> bool {
+    let top = 0u8;
+    let top = std::hint::black_box(&top) as *const u8 as usize;
+    vm.native_stack_base != 0 && vm.native_stack_base.abs_diff(top) > NATIVE_STACK_SIZE
+}
 
-    // Push nil on the stack
-    if PushNil == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("PushNil, IP: {}", (*vm).borrow().ip);
-        // log!("Push Nil");
-        vm.borrow_mut().ip += 1;
-        vm.borrow_mut().stack.push(Nil);
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
+// Calls the hook, which is taken out of the vm so that code it runs doesn't call it
+fn run_hook(vm: &Rc<RefCell<Vm>>) {
+    let hook = vm.borrow_mut().hook.take();
+    if let Some(mut hook) = hook {
+        hook.on_instruction(vm);
+        let mut vm = vm.borrow_mut();
+        // the hook may have replaced itself
+        if vm.hook.is_none() {
+            vm.set_hook(Some(hook));
+        }
     }
+}
 
-    // frees top of the stack and pops the stack
-    if Pop == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        vm.borrow_mut().ip += 1;
-        let _value = vm.borrow_mut().stack.pop();
+// Makes an error record of the type picked from the stdlib
+fn error_record(
+    vm: &Vm,
+    error_type: fn(&HanayoCtx) -> &Gc<record::Record>,
+    why: String,
+) -> Gc<record::Record> {
+    let mut rec = vm.malloc(record::Record::new());
+    if let Some(stdlib) = vm.stdlib.as_ref() {
+        rec.inner_mut_ptr()
+            .insert("prototype", Record(error_type(stdlib).clone()));
+    }
+    rec.inner_mut_ptr()
+        .insert("why", Str(vm.malloc(why.into())));
+    rec
+}
 
-        log_debug!(
-            "Pop, IP: {} sum(1)\n  value: {:?}",
-            (*vm).borrow().ip,
-            _value
-        );
+// Raises an error of the type picked from the stdlib, returns whether it was caught
+fn raise_error(
+    vm: &Rc<RefCell<Vm>>,
+    error_type: fn(&HanayoCtx) -> &Gc<record::Record>,
+    why: String,
+) -> bool {
+    let error = error_record(&(**vm).borrow(), error_type, why);
+    raise_record(vm, error)
+}
 
-        debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
+// Raises the error record, returns whether it was caught
+fn raise_record(vm: &Rc<RefCell<Vm>>, error: Gc<record::Record>) -> bool {
+    vm.borrow_mut().stack.push(Record(error));
+    if harumachine::vm::raise(Rc::clone(vm)) {
+        true
+    } else {
+        vm.borrow_mut().error = ERROR_UNHANDLED_EXCEPTION;
+        false
     }
+}
 
-    // pops top of the stack, performs unary not and pushes the result
-    if Not == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Not, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        let val = vm.borrow_mut().stack.pop().unwrap();
-        vm.borrow_mut().stack.push(Int(!value_is_true(val) as i64));
+// Record a member of the value is looked up in
+#[inline(always)]
+fn member_record<'a>(vm: &'a Vm, val: &'a Value) -> Option<&'a record::Record> {
+    match val {
+        Record(reco) => Some(reco.as_ref()),
+        Str(..) => vm.dstr.as_ref().map(|r| r.as_ref()),
+        Int(..) => vm.dint.as_ref().map(|r| r.as_ref()),
+        Float(..) => vm.dfloat.as_ref().map(|r| r.as_ref()),
+        Array(..) => vm.darray.as_ref().map(|r| r.as_ref()),
+        _ => None,
     }
+}
 
-    // pops top of the stack, performs unary negation and pushes the result
-    if Negate == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Negate, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-
-        let val = vm.borrow_mut().stack.pop().unwrap();
-        match val {
-            Int(i) => vm.borrow_mut().stack.push(Int(-i)),
-            Float(f) => vm.borrow_mut().stack.push(Float(-f)),
-            _ => unreachable!(""),
-        }
-    }
+// Applies the binary operation to the two values on top of the stack,
+// replacing them with its result. The operands are borrowed in place
+// rather than popped, so heap values don't go through reference counting.
+// On failure the operands are left on the stack for the error's hint.
+#[inline(always)]
+fn binary_op(
+    vm: &Rc<RefCell<Vm>>,
+    error: VmError,
+    op: impl FnOnce(&Value, &Value, &Vm) -> Value,
+) -> bool {
+    let mut vm_mut = vm.borrow_mut();
+    vm_mut.ip += 1;
+    debug_assert!(vm_mut.stack.len() >= 2);
+
+    let result = match vm_mut.stack.slots() {
+        [.., left, right] => op(&left.borrow(), &right.borrow(), &vm_mut),
+        _ => unreachable!(),
+    };
+    if let Value::InterpreterError = result {
+        vm_mut.error = error;
+        vm_mut.ip -= 1;
+        return false;
+    }
+
+    let slots = vm_mut.stack.slots_mut();
+    slots.pop();
+    *slots.last_mut().unwrap() = result.into();
+    true
+}
 
-    // NOTE(xyz): IADD seems to me to be just for checking but it could have another
-    // purpose. IADD as u8 {
-    if Add == (*vm).borrow().code[(*vm).borrow().ip as usize]
-        || IAdd == (*vm).borrow().code[(*vm).borrow().ip as usize]
-    {
-        log_debug!("ADD/IADD, IP: {}", (*vm).borrow().ip);
-        let op = (*vm).borrow().code[(*vm).borrow().ip as usize];
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
+// pops a function/record constructor on top of the stack,
+// sets up necessary environment and calls it.
+#[inline(always)]
+pub(super) fn inside_execute(vm: Rc<RefCell<Vm>>) {
+    // println!("Ip: {}, {:?}", vm.ip, (*vm).borrow().code, vm.stack);
+    /*
+        vm.code: Instructions in the form of Bytecode
+        vm.ip: Global Parting with reference to vm.code (Current Instruction).
+        vm.stack: Stack/Saving of temporary values
+    */
 
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
+    //println!("Call me: {:?},  vm.code: {}", vm.code, vm.ip);
 
-        let result = value_add(left.clone(), right.clone(), Rc::clone(&vm));
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_ADD;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
+    loop {
+        let (op, out_of_memory, finalizers_pending) = {
+            let mut vm_mut = vm.borrow_mut();
+            if vm_mut.ticks >= vm_mut.ticks_until_check {
+                if !vm_mut.check_limits() {
+                    return;
+                }
+                if vm_mut.hook.is_some() {
+                    drop(vm_mut);
+                    run_hook(&vm);
+                    vm_mut = vm.borrow_mut();
+                    // the hook stops execution by setting an error
+                    if vm_mut.error != ERROR_NO_ERROR {
+                        return;
+                    }
+                }
+            }
+            let vm = &mut *vm_mut;
+            vm.ticks += 1;
+            (
+                vm.code[vm.ip as usize],
+                vm.out_of_memory.replace(false),
+                vm.finalizers_pending.replace(false),
+            )
+        };
 
+        if finalizers_pending && !run_finalizers(&vm) {
             return;
         }
 
-        if IAdd == op {
-            // Do task IADD...
+        // the last instruction allocated past the maximum heap size
+        if out_of_memory {
+            let why = format!(
+                "heap grew past its maximum size of {} bytes",
+                (*vm).borrow().max_heap_size().unwrap()
+            );
+            let caught = raise_error(&vm, |stdlib| &stdlib.memory_error, why);
+            // allocating the error itself can't raise another one
+            (*vm).borrow().out_of_memory.set(false);
+            if !caught || (*vm).borrow().exframe_fallthrough.is_some() {
+                return;
+            }
+            continue;
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
-
-    if Sub == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Sub, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_sub(left.clone(), right.clone(), Rc::clone(&vm));
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_SUB;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
+        if Halt == op {
+            log_debug!("Halt, IP: {}", (*vm).borrow().ip);
             return;
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        if Push8 == op {
+            vm.borrow_mut().ip += 2;
+            log_debug!("Push8, IP: {} sum(2)", (*vm).borrow().ip);
 
-    if Mul == (*vm).borrow().code[(*vm).borrow().ip as usize]
-        || IMul == (*vm).borrow().code[(*vm).borrow().ip as usize]
-    {
-        log_debug!("MUL/IMUL, IP: {}", (*vm).borrow().ip);
-        let op = (*vm).borrow().code[(*vm).borrow().ip as usize];
-        vm.borrow_mut().ip += 1;
-        debug_assert!(vm.borrow_mut().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_mul(left.clone(), right.clone(), Rc::clone(&vm));
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_MUL;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
-        }
+            let int = Int((*vm).borrow().code[(*vm).borrow().ip as usize - 1] as i64);
+            log_debug!("  int: {:?}", &int);
 
-        if IMul == op {
-            // Error: ERROR_OP_IMUL...
-            // Do task MUL...
+            vm.borrow_mut().stack.push(int);
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
         }
-        vm.borrow_mut().stack.push(result);
-    }
 
-    if Div == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("DIV, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_div(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_DIV;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        if Push16 == op {
+            log_debug!("Push16, IP: {}", (*vm).borrow().ip);
+            #[rustfmt::skip]
+            let i = i64::from_be_bytes([
+                0,0,0,0,0,0,
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+
+            vm.borrow_mut().stack.push(Int(i));
+            vm.borrow_mut().ip += 3;
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
         }
-        vm.borrow_mut().stack.push(result);
-    }
 
-    if Mod == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("MOD, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_mod(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_MOD;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        if Push32 == op {
+            log_debug!("Push32, IP: {}", (*vm).borrow().ip);
+            #[rustfmt::skip]
+            let i = i64::from_be_bytes([
+                0, 0, 0, 0,
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
+            ]);
+
+            vm.borrow_mut().stack.push(Int(i));
+            vm.borrow_mut().ip += 5;
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        if Push64 == op {
+            log_debug!("Push64, IP: {}", (*vm).borrow().ip);
+            let i = i64::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 5],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 6],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 7],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 8],
+            ]);
+
+            vm.borrow_mut().stack.push(Int(i));
+            vm.borrow_mut().ip += 9;
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
+        }
+        // Push 32/64-bit float on to the stack
+        if Pushf64 == op {
+            log_debug!("Pushf64, IP: {}", (*vm).borrow().ip);
+
+            let bytes = [
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 5],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 6],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 7],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 8],
+            ];
+            vm.borrow_mut().ip += 9;
+            let f = f64::from_bits(u64::from_ne_bytes(bytes));
+            let float = Float(f);
+            vm.borrow_mut().stack.push(float);
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
+        }
 
-    if BitwiseAnd == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("BITWISE_AND, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_bitwise_and(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_BITWISE_AND;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        if PushBool == op {
+            unimplemented!("This is for the future");
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        // Push string on to the stack
+        if PushStr == op {
+            log_debug!("PushStr, IP: {}", (*vm).borrow().ip);
+            let key: HaruString = generate_string(Rc::clone(&vm)).into();
+            log_debug!("  key: {:?}", key.to_string());
+            let key = (*vm).borrow().malloc(key);
+            vm.borrow_mut().stack.push(Str(key));
 
-    if BitwiseOr == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("BITWISE_OR, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_bitwise_or(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_BITWISE_OR;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        // Get a store string and push on the stack
+        if PushStrInterned == op {
+            log_debug!("PushStrInterned, IP: {}", (*vm).borrow().ip);
 
-    if BitwiseXOR == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("BITWISE_XOR, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_bitwise_xor(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_BITWISE_XOR;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+            let i = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+
+            vm.borrow_mut().ip += 3;
+            let i = (*vm).borrow().get_interned_string(i);
+            let i = Str((*vm).borrow().malloc(i));
+            vm.borrow_mut().stack.push(i);
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        // Push nil on the stack
+        if PushNil == op {
+            log_debug!("PushNil, IP: {}", (*vm).borrow().ip);
+            // log!("Push Nil");
+            vm.borrow_mut().ip += 1;
+            vm.borrow_mut().stack.push(Nil);
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
+        }
 
-    if Lt == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("LT, IP: {}", (*vm).borrow().ip);
+        // frees top of the stack and pops the stack
+        if Pop == op {
+            vm.borrow_mut().ip += 1;
+            let _value = vm.borrow_mut().stack.pop();
 
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
+            log_debug!(
+                "Pop, IP: {} sum(1)\n  value: {:?}",
+                (*vm).borrow().ip,
+                _value
+            );
 
-        let result = value_lt(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_LT;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+            debug_assert!((*vm).borrow().ip as usize <= (*vm).borrow().code.len());
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
-    if LEq == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("LEQ, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
-
-        let result = value_leq(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_LEQ;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        // pops top of the stack, performs unary not and pushes the result
+        if Not == op {
+            log_debug!("Not, IP: {}", (*vm).borrow().ip);
+            vm.borrow_mut().ip += 1;
+            let val = vm.borrow_mut().stack.pop().unwrap();
+            vm.borrow_mut().stack.push(Int(!value_is_true(&val) as i64));
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
-
-    if Gt == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Gt, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
+        // pops top of the stack, performs unary negation and pushes the result
+        if Negate == op {
+            log_debug!("Negate, IP: {}", (*vm).borrow().ip);
+            vm.borrow_mut().ip += 1;
 
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
+            let val = vm.borrow_mut().stack.pop().unwrap();
+            match val {
+                Int(i) => vm.borrow_mut().stack.push(Int(-i)),
+                Float(f) => vm.borrow_mut().stack.push(Float(-f)),
+                _ => unreachable!(""),
+            }
+        }
 
-        let result = value_gt(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_GT;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        // NOTE(xyz): IADD seems to me to be just for checking but it could have another
+        // purpose.
+        if Add == op || IAdd == op {
+            log_debug!("ADD/IADD, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_ADD, value_add) {
+                return;
+            }
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        if Sub == op {
+            log_debug!("Sub, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_SUB, |left, right, _| value_sub(left, right)) {
+                return;
+            }
+        }
 
-    if GEq == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("GEq, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
+        if Mul == op || IMul == op {
+            log_debug!("MUL/IMUL, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_MUL, value_mul) {
+                return;
+            }
+        }
 
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
+        if Div == op {
+            log_debug!("DIV, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_DIV, |left, right, _| value_div(left, right)) {
+                return;
+            }
+        }
 
-        let result = value_geq(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_GEQ;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        if Mod == op {
+            log_debug!("MOD, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_MOD, |left, right, _| value_mod(left, right)) {
+                return;
+            }
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        if BitwiseAnd == op {
+            log_debug!("BITWISE_AND, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_BITWISE_AND, |left, right, _| {
+                value_bitwise_and(left, right)
+            }) {
+                return;
+            }
+        }
 
-    if Eq == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Eq, IP: {}", (*vm).borrow().ip);
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
+        if BitwiseOr == op {
+            log_debug!("BITWISE_OR, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_BITWISE_OR, |left, right, _| {
+                value_bitwise_or(left, right)
+            }) {
+                return;
+            }
+        }
 
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
+        if BitwiseXOR == op {
+            log_debug!("BITWISE_XOR, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_BITWISE_XOR, |left, right, _| {
+                value_bitwise_xor(left, right)
+            }) {
+                return;
+            }
+        }
 
-        let result = value_eq(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_EQ;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        if Lt == op {
+            log_debug!("LT, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_LT, |left, right, _| value_lt(left, right)) {
+                return;
+            }
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        if LEq == op {
+            log_debug!("LEQ, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_LEQ, |left, right, _| value_leq(left, right)) {
+                return;
+            }
+        }
 
-    if NEq == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("NEq, IP: {}", (*vm).borrow().ip);
+        if Gt == op {
+            log_debug!("Gt, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_GT, |left, right, _| value_gt(left, right)) {
+                return;
+            }
+        }
 
-        vm.borrow_mut().ip += 1;
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
+        if GEq == op {
+            log_debug!("GEq, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_GEQ, |left, right, _| value_geq(left, right)) {
+                return;
+            }
+        }
 
-        let result = value_neq(left.clone(), right.clone());
-        if let Value::InterpreterError = result {
-            vm.borrow_mut().stack.append(&mut vec![right, left]); // tmp
-            vm.borrow_mut().error = ERROR_OP_NEQ;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-            return;
+        if Eq == op {
+            log_debug!("Eq, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_EQ, |left, right, _| value_eq(left, right)) {
+                return;
+            }
         }
 
-        vm.borrow_mut().stack.push(result);
-    }
+        if NEq == op {
+            log_debug!("NEq, IP: {}", (*vm).borrow().ip);
+            if !binary_op(&vm, ERROR_OP_NEQ, |left, right, _| value_neq(left, right)) {
+                return;
+            }
+        }
 
-    // matching (these require the stdlib to be loaded)
-    if Of == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Of, IP: {}", (*vm).borrow().ip);
-        //LOG("OF\n");
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        vm.borrow_mut().ip += 1;
+        // matching (these require the stdlib to be loaded)
+        if Of == op {
+            log_debug!("Of, IP: {}", (*vm).borrow().ip);
+            //LOG("OF\n");
+            debug_assert!((*vm).borrow().stack.len() >= 2);
+            vm.borrow_mut().ip += 1;
 
-        let right = vm.borrow_mut().stack.pop().unwrap();
-        let left = vm.borrow_mut().stack.pop().unwrap();
+            let right = vm.borrow_mut().stack.pop().unwrap();
+            let left = vm.borrow_mut().stack.pop().unwrap();
 
-        unsafe {
-            if let Record(rhs) = right {
-                let rhs = rhs.to_raw();
+            unsafe {
+                if let Record(rhs) = right {
+                    let rhs = rhs.to_raw();
 
-                if let Record(proto) = left {
-                    if rhs == (*vm).borrow().drec.as_ref().unwrap().to_raw() {
+                    if let Record(proto) = left {
+                        if rhs == (*vm).borrow().drec.as_ref().unwrap().to_raw() {
+                            vm.borrow_mut().stack.push(Int(1));
+                        } else {
+                            let proto = &*proto.into_raw();
+                            vm.borrow_mut()
+                                .stack
+                                .push(Int(proto.is_prototype_of(&*rhs) as i64));
+                        }
+                    } else if let Some(true) =
+                        get_prototype(Rc::clone(&vm), left).map(|reco| reco.to_raw() == rhs)
+                    {
                         vm.borrow_mut().stack.push(Int(1));
                     } else {
-                        let proto = &*proto.into_raw();
-                        vm.borrow_mut()
-                            .stack
-                            .push(Int(proto.is_prototype_of(&*rhs) as i64));
+                        vm.borrow_mut().stack.push(Int(0));
                     }
-                } else if let Some(true) =
-                    get_prototype(Rc::clone(&vm), left).map(|reco| reco.to_raw() == rhs)
-                {
-                    vm.borrow_mut().stack.push(Int(1));
                 } else {
-                    vm.borrow_mut().stack.push(Int(0));
+                    vm.borrow_mut().error = ERROR_EXPECTED_RECORD_OF_EXPR;
+                    vm.borrow_mut().ip -= 1;
+                    return;
                 }
-            } else {
-                vm.borrow_mut().error = ERROR_EXPECTED_RECORD_OF_EXPR;
-                vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                return;
             }
         }
-    }
 
-    // variables
-    // creates a new environment whenever a function is called
-    // the environment is initialized with a copy of the current environment's
-    if EnvNew == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("EnvNew, Ip: {} sum(3)", vm.ip);
+        // variables
+        // creates a new environment whenever a function is called
+        // the arguments on top of the stack become the first slots, then the
+        // stack makes room for the rest of the slots sized by the compiler
+        if EnvNew == op {
+            log_debug!("EnvNew, Ip: {} sum(3)", (*vm).borrow().ip);
         
-        let nslots = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-        vm.borrow_mut().ip += 3;
-
-        {
-            let mut vm_mut = vm.borrow_mut();
+            let nslots = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            vm.borrow_mut().ip += 3;
 
-            if let Some(last_entry) = vm_mut.localenv.last_mut() {
-                let mut env = last_entry.borrow_mut().take().unwrap();
-                debug_assert!(vm_mut.stack.len() >= env.nargs as usize);
-                debug_assert!(env.nargs <= nslots);
+            {
+                let mut vm_mut = vm.borrow_mut();
+                let vm_mut = &mut *vm_mut;
 
-                log_debug!("  send ars: {}", env.nargs);
+                if let Some(env) = vm_mut.localenv.last_mut() {
+                    debug_assert!(vm_mut.stack.len() >= env.nargs as usize);
+                    debug_assert!(env.nargs <= nslots);
 
-                for i in 0..env.nargs {
-                    let val = vm_mut.stack.pop().expect("the bytecode interpreter does not have all the arguments that the user requires.");
+                    log_debug!("  send ars: {}", env.nargs);
 
-                    env.set(i, val);
+                    // the last argument was pushed first
+                    env.base = vm_mut.stack.len() - env.nargs as usize;
+                    let slots = vm_mut.stack.slots_mut();
+                    slots[env.base..].reverse();
+                    slots.resize_with(env.base + nslots as usize, || NanBox::NIL);
                 }
-
-                *vm_mut.localenv.last_mut().unwrap().borrow_mut() = Some(env);
             }
         }
-    }
 
-    // variables
-    // sets the value of current environment's slot to the top of the stack
-    if SetLocal == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("SetLocal, IP: {}", vm.ip);
-        let nslots = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-        vm.borrow_mut().ip += 3;
-
-        {
-            let len_stack = (*vm).borrow().stack.len() - 1;
-            let new_value = (*vm).borrow().stack[len_stack].clone();
-
-            if let Some(last_entry) = vm.borrow_mut().localenv.last_mut() {
-                if let Some(env) = (**last_entry).borrow_mut().as_mut() {
-                    env.set(nslots, new_value);
-                }
+        // variables
+        // sets the value of current environment's slot to the top of the stack
+        if SetLocal == op {
+            log_debug!("SetLocal, IP: {}", (*vm).borrow().ip);
+            let slot = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            let mut vm_mut = vm.borrow_mut();
+            vm_mut.ip += 3;
+
+            if let Some(base) = vm_mut.localenv.last().map(|env| env.base) {
+                let slots = vm_mut.stack.slots_mut();
+                let new_value = slots.last().unwrap().clone();
+                write_barrier(&new_value.borrow());
+                slots[base + slot as usize] = new_value;
             }
         }
-    }
 
-    // this is for recursive function
-    if SetLocalFunctionDef == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("SetLocalFunctionDef, IP: {}", vm.ip);
-        let nslots = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-        vm.borrow_mut().ip += 3;
-
-        {
-            let len_stack = (*vm).borrow().stack.len() - 1;
-            let value = (*vm).borrow().stack[len_stack].clone();
-            let mut some_env = None;
-
-            let mut mut_vm = vm.borrow_mut();
-            if let Some(last_entry) = mut_vm.localenv.last_mut() {
-                if let Some(mut env) = (**last_entry).borrow_mut().take() {
-                    env.set(nslots, value.clone());
-
-                    let Fn(ref fun) = value else {
-                        unreachable!();
-                    };
+        // this is for recursive function
+        if SetLocalFunctionDef == op {
+            log_debug!("SetLocalFunctionDef, IP: {}", (*vm).borrow().ip);
+            let slot = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            let mut vm_mut = vm.borrow_mut();
+            vm_mut.ip += 3;
+
+            if let Some(base) = vm_mut.localenv.last().map(|env| env.base) {
+                // the function captures the slot, so it can see itself
+                let slots = vm_mut.stack.slots_mut();
+                let value = slots.last().unwrap().clone();
+                write_barrier(&value.borrow());
+                slots[base + slot as usize] = value;
+            }
+        }
 
-                    if let Some(bound) = fun.as_ref().bound.borrow_mut().as_mut() {
-                        bound.set(nslots, value.clone());
-                    }
+        // sets the value of a variable captured from an enclosing function
+        // to the top of the stack
+        if SetUpvalue == op {
+            log_debug!("SetUpvalue, IP: {}", (*vm).borrow().ip);
+            let idx = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            vm.borrow_mut().ip += 3;
 
-                    some_env = Some(env);
+            {
+                let mut vm_mut = vm.borrow_mut();
+                let vm_mut = &mut *vm_mut;
+                let new_value = vm_mut.stack.slots().last().cloned().unwrap();
+
+                let upvalue = vm_mut
+                    .localenv
+                    .last()
+                    .map(|env| env.upvalues[idx as usize].clone());
+                if let Some(mut upvalue) = upvalue {
+                    upvalue
+                        .inner_mut_ptr()
+                        .set(vm_mut.stack.slots_mut(), new_value);
                 }
-
-                *(**last_entry).borrow_mut() = some_env;
             }
         }
-    }
 
-    // pushes a copy of the value of current environment's slot
-    if GetLocal == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        let slot = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-        let mut vm_mut = vm.borrow_mut();
-        vm_mut.ip += 3;
-        log_debug!("GetLocal, IP: {} sum(3), slot {}", vm.ip, slot);
-        let mut env = None;
-        {
-            if let Some(last_entry) = vm_mut.localenv.last_mut() {
-                env = last_entry.borrow_mut().take();
-                if let Some(env) = &mut env {
-                    let value = env.get(slot).unwrap();
-                    log_debug!("  value: {:?}", &value);
-
-                    vm_mut.stack.push(value);
-                }
+        // pushes a copy of the value of current environment's slot
+        if GetLocal == op {
+            let slot = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            let mut vm_mut = vm.borrow_mut();
+            vm_mut.ip += 3;
+            log_debug!("GetLocal, IP: {} sum(3), slot {}", vm_mut.ip, slot);
+            let vm_mut = &mut *vm_mut;
+            if let Some(env) = vm_mut.localenv.last() {
+                let slots = vm_mut.stack.slots_mut();
+                let value = slots[env.base + slot as usize].clone();
+                log_debug!("  value: {:?}", &value);
+
+                slots.push(value);
             }
+        }
+
+        // pushes a copy of the value of a variable captured from an enclosing function
+        if GetUpvalue == op {
+            log_debug!("GetUpvalue, IP: {}", (*vm).borrow().ip);
+            let idx = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            vm.borrow_mut().ip += 3;
+
+            let value = {
+                let vm = (*vm).borrow();
+                vm.localenv
+                    .last()
+                    .map(|env| env.upvalues[idx as usize].as_ref().get(vm.stack.slots()))
+            };
 
-            if let Some(last_entry) = vm_mut.localenv.last_mut() {
-                *last_entry.borrow_mut() = env;
+            if let Some(value) = value {
+                vm.borrow_mut().stack.slots_mut().push(value);
             }
         }
-    }
 
-    if GetLocalUp == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("GetLocalUp, IP: {}", vm.ip);
-        let slot = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-
-        let relascope = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
-        ]);
-        vm.borrow_mut().ip += 5;
-
-        let mut value = None;
-        if let Some(last_entry) = vm.borrow_mut().localenv.last_mut() {
-            let some_env = (**last_entry).borrow_mut().take();
-            if let Some(env) = some_env {
-                value = Some(env.get_up(relascope, slot));
-                *(**last_entry).borrow_mut() = Some(env);
-            }
+        // sets the value of the global variable to the top of the stack
+        if SetGlobal == op {
+            log_debug!("SetGlobal, IP: {}", (*vm).borrow().ip);
+            let key = generate_string(Rc::clone(&vm));
+            log_debug!("  key: {}", &key);
+            let obj = (*vm).borrow().stack.last().unwrap();
+            vm.borrow_mut().mut_global().insert(key.into(), obj);
         }
 
-        if let Some(value) = value.flatten() {
-            vm.borrow_mut().stack.push(value);
+        // pushes a copy of the value of the global variable
+        // WARNING: This condition may not act as expected?
+        if GetGlobal == op {
+            log_debug!("GetGlobal, IP: {}", (*vm).borrow().ip);
+            let key = generate_string(Rc::clone(&vm));
+            log_debug!("  key: {}", &key);
+
+            let v = (*vm).borrow().global().get(&key).cloned();
+
+            if let Some(val) = v {
+                vm.borrow_mut().stack.push(val);
+            } else {
+                vm.borrow_mut().error = ERROR_UNDEFINED_GLOBAL_VAR;
+                vm.borrow_mut().ip -= key.len() as u32 + 2;
+                log_debug!("  IP: {}", (*vm).borrow().ip);
+                return;
+            }
         }
-    }
 
-    // sets the value of the global variable to the top of the stack
-    if SetGlobal == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("SetGlobal, IP: {}", vm.ip);
-        let key = generate_string(Rc::clone(&vm));
-        log_debug!("  key: {}", &key);
-        let obj = (*vm).borrow().stack[(*vm).borrow().stack.len() - 1].clone();
-        vm.borrow_mut().mut_global().insert(key.into(), obj);
-    }
+        // pushes a function with [name], that begins at the next instruction pointer
+        // to the stack and jumps to the [end address]
+        if DefFunctionPush == op {
+            log_debug!("DefFunctionPush, IP: {}", (*vm).borrow().ip);
+            // [opcode][end address]
+            let nargs = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            let pos = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
+            ]);
+
+            vm.borrow_mut().ip += 3;
+            log_debug!("  args: {}, pos: {} sum(3)", nargs, pos);
 
-    // pushes a copy of the value of the global variable
-    // WARNING: This condition may not act as expected?
-    if GetGlobal == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("GetGlobal, IP: {}", vm.ip);
-        let key = generate_string(Rc::clone(&vm));
-        log_debug!("  key: {}", &key);
-
-        let v = (*vm).borrow().global().get(&key).cloned();
-
-        if let Some(val) = v {
-            vm.borrow_mut().stack.push(val);
-        } else {
-            vm.borrow_mut().error = ERROR_UNDEFINED_GLOBAL_VAR;
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - (key.len() as i32 + 2)) as u32;
-            log_debug!("  IP: {}", &vm.ip);
-            return;
+            unsafe {
+                // variables the function captures get filled in by Capture
+                let ip = (*vm).borrow().ip + 2;
+                let new_fn = Fn((*vm)
+                    .borrow()
+                    .malloc(Function::new(ip, nargs, Rc::default())));
+                vm.borrow_mut().stack.push(new_fn);
+            }
+
+            vm.borrow_mut().ip += pos as u32;
         }
-    }
 
-    // pushes a function with [name], that begins at the next instruction pointer
-    // to the stack and jumps to the [end address]
-    if DefFunctionPush == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("DefFunctionPush, IP: {}", vm.ip);
-        // [opcode][end address]
-        let nargs = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-        let pos = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 4],
-        ]);
-
-        vm.borrow_mut().ip += 3;
-        log_debug!("  args: {}, pos: {} sum(3)", nargs, pos);
-
-        unsafe {
-            let env = match (*vm).borrow().localenv.last() {
-                Some(e) => Rc::clone(e),
-                None => Rc::default(),
-            };
+        // fills in the variables captured by the function on top of the stack
+        if Capture == op {
+            log_debug!("Capture, IP: {}", (*vm).borrow().ip);
+            // [opcode][count] then for each variable [is local][index]
+            let count = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            vm.borrow_mut().ip += 3;
+
+            let mut upvalues = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let ip = (*vm).borrow().ip as usize;
+                let is_local = (*vm).borrow().code[ip] != 0;
+                let idx =
+                    u16::from_be_bytes([(*vm).borrow().code[ip + 1], (*vm).borrow().code[ip + 2]]);
+                vm.borrow_mut().ip += 3;
+
+                let upvalue = if is_local {
+                    // local variable of the function it's defined in
+                    vm.borrow_mut().capture_local(idx)
+                } else {
+                    // which captured it itself
+                    let vm = (*vm).borrow();
+                    vm.localenv.last().unwrap().upvalues[idx as usize].clone()
+                };
+                upvalues.push(upvalue);
+            }
 
-            let ip = (*vm).borrow().ip + 2;
-            let new_fn = Fn((*vm).borrow().malloc(Function::new(ip, nargs, env)));
-            vm.borrow_mut().stack.push(new_fn);
+            if let Some(Fn(mut fun)) = (*vm).borrow().stack.last() {
+                fun.inner_mut_ptr().upvalues = upvalues.into();
+            }
         }
 
-        vm.borrow_mut().ip += pos as u32;
-    }
+        // flow control
+        // jmp [32-bit position] (jump to back)
+        if Jmp == op {
+            log_debug!("OP_JMP, IP: {}", (*vm).borrow().ip);
+            vm.borrow_mut().ip += 1;
+            let pos = i16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+            ]);
 
-    // flow control
-    // jmp [32-bit position] (jump to back)
-    if Jmp == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("OP_JMP, IP: {}", vm.ip);
-        vm.borrow_mut().ip += 1;
-        let pos = i16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-        ]);
-
-        let ip = ((*vm).borrow().ip as i32 + pos as i32) as u32;
-        vm.borrow_mut().ip = ip;
-    }
+            let ip = ((*vm).borrow().ip as i32 + pos as i32) as u32;
+            vm.borrow_mut().ip = ip;
+        }
 
-    // jmp [32-bit position]
-    if JmpLong == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("OP_JMP_LONG, IP: {}", vm.ip);
-        vm.borrow_mut().ip += 1;
-        let pos = u32::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
-        ]);
-
-        vm.borrow_mut().ip = pos;
-    }
+        // jmp [32-bit position]
+        if JmpLong == op {
+            log_debug!("OP_JMP_LONG, IP: {}", (*vm).borrow().ip);
+            vm.borrow_mut().ip += 1;
+            let pos = u32::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 3],
+            ]);
+
+            vm.borrow_mut().ip = pos;
+        }
 
-    // jmp if not true [32-bit position]
-    if [JCond as u8, JCondNoPop as u8].contains(&(*vm).borrow().code[(*vm).borrow().ip as usize]) {
-        log_debug!("OP_JCOND/OP_JCOND_NO_POP, IP: {}", vm.ip);
+        // jmp if not true [32-bit position]
+        if [JCond as u8, JCondNoPop as u8].contains(&op) {
+            log_debug!("OP_JCOND/OP_JCOND_NO_POP, IP: {}", (*vm).borrow().ip);
 
-        let val = if JCond == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-            vm.borrow_mut().stack.pop().unwrap()
-        } else {
-            (*vm).borrow().stack.last().cloned().unwrap()
-        };
+            let val = if JCond == op {
+                vm.borrow_mut().stack.pop().unwrap()
+            } else {
+                (*vm).borrow().stack.last().unwrap()
+            };
 
-        vm.borrow_mut().ip += 1;
-        let pos = i16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-        ]);
+            vm.borrow_mut().ip += 1;
+            let pos = i16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+            ]);
 
-        if value_is_true(val) {
-            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 + pos as i32) as u32;
-        } else {
-            vm.borrow_mut().ip += 2;
+            if value_is_true(&val) {
+                let ip = ((*vm).borrow().ip as i32 + pos as i32) as u32;
+                vm.borrow_mut().ip = ip;
+            } else {
+                vm.borrow_mut().ip += 2;
+            }
         }
-    }
 
-    // jump (to back) if true [32-bit position]
-    // Jump-Condition JMP
-    if [JNcond as u8, JNcondNoPop as u8].contains(&(*vm).borrow().code[(*vm).borrow().ip as usize])
-    {
-        log_debug!("OP_JNCOND/OP_JNCOND_NO_POP");
-        let val = if JNcond == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-            vm.borrow_mut().stack.pop().unwrap()
-        } else {
-            (*vm).borrow().stack.last().cloned().unwrap()
-        };
+        // jump (to back) if true [32-bit position]
+        // Jump-Condition JMP
+        if [JNcond as u8, JNcondNoPop as u8].contains(&op) {
+            log_debug!("OP_JNCOND/OP_JNCOND_NO_POP");
+            let val = if JNcond == op {
+                vm.borrow_mut().stack.pop().unwrap()
+            } else {
+                (*vm).borrow().stack.last().unwrap()
+            };
 
-        vm.borrow_mut().ip += 1;
-        let pos = i16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-        ]);
+            vm.borrow_mut().ip += 1;
+            let pos = i16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+            ]);
 
-        if !value_is_true(val) {
-            let ip = ((*vm).borrow().ip as i32 + pos as i32) as u32;
-            vm.borrow_mut().ip = ip;
-        } else {
-            vm.borrow_mut().ip += 2;
+            if !value_is_true(&val) {
+                let ip = ((*vm).borrow().ip as i32 + pos as i32) as u32;
+                vm.borrow_mut().ip = ip;
+            } else {
+                vm.borrow_mut().ip += 2;
+            }
         }
-    }
 
-    if Call == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Call, IP: {}", (*vm).borrow().ip);
-
-        let val = vm.borrow_mut().stack.pop().unwrap();
-        let nargs = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-        vm.borrow_mut().ip += 3;
-        log_debug!("  sum(3), val: {}, nargs: {}", val, nargs);
-
-        debug_assert!((*vm).borrow().stack.len() >= nargs as usize);
-        match val {
-            NativeFn(native) => {
-                vm.borrow_mut().native_call_depth += 1;
-
-                // Call to native function
-                native(Rc::clone(&vm), nargs);
-
-                vm.borrow_mut().native_call_depth -= 1;
-                let call_depth = if (*vm).borrow().exframe_fallthrough.is_some() {
-                    (*vm)
-                        .borrow()
-                        .exframe_fallthrough
-                        .as_ref()
-                        .unwrap()
-                        .unwind_native_call_depth
-                } else {
-                    (*vm).borrow().native_call_depth
-                };
+        if Call == op {
+            log_debug!("Call, IP: {}", (*vm).borrow().ip);
 
-                if call_depth != (*vm).borrow().native_call_depth
-                    || (*vm).borrow().error != ERROR_NO_ERROR
-                {
-                    return;
-                }
-            }
-            Record(ref reco) => {
-                let pctor = unsafe { (*reco.to_raw()).get("new") };
-                if pctor.is_none() {
-                    vm.borrow_mut().error = ERROR_RECORD_NO_CONSTRUCTOR;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32;
-                    return;
+            let val = vm.borrow_mut().stack.pop().unwrap();
+            let nargs = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
+            vm.borrow_mut().ip += 3;
+            log_debug!("  sum(3), val: {}, nargs: {}", val, nargs);
+
+            debug_assert!((*vm).borrow().stack.len() >= nargs as usize);
+            match val {
+                NativeFn(native) => {
+                    vm.borrow_mut().native_call_depth += 1;
+
+                    // Call to native function
+                    native(Rc::clone(&vm), nargs);
+
+                    if native_call_returned(&vm) == NativeReturn::Stop {
+                        return;
+                    }
                 }
-                let ctor = pctor.unwrap();
-                match ctor {
-                    NativeFn(native) => {
+                Record(ref reco) => {
+                    // functions exported by native modules
+                    if let Some(fun) = module::function(reco) {
                         vm.borrow_mut().native_call_depth += 1;
-
-                        // Call to native function
-                        native(Rc::clone(&vm), nargs);
-
-                        vm.borrow_mut().native_call_depth -= 1;
-                        let call_depth = if (*vm).borrow().exframe_fallthrough.is_some() {
-                            (*vm)
-                                .borrow()
-                                .exframe_fallthrough
-                                .as_ref()
-                                .unwrap()
-                                .unwind_native_call_depth
-                        } else {
-                            (*vm).borrow().native_call_depth
-                        };
-
-                        if call_depth != (*vm).borrow().native_call_depth
-                            || (*vm).borrow().error != ERROR_NO_ERROR
-                        {
-                            return;
-                        }
-
-                        if (*vm).borrow().exframe_fallthrough.is_some()
-                            && (*vm)
-                                .borrow()
-                                .exframe_fallthrough
-                                .as_ref()
-                                .unwrap()
-                                .unwind_native_call_depth
-                                != (*vm).borrow().native_call_depth
-                        {
+                        module::call(&vm, fun, nargs);
+                        if native_call_returned(&vm) == NativeReturn::Stop {
                             return;
                         }
+                        continue;
                     }
-                    Fn(ifn) => {
-                        let ifn = ifn.to_raw();
-                        unsafe {
-                            if nargs + 1 != (*ifn).nargs {
-                                vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
-                                vm.borrow_mut().error_expected = (*ifn).nargs as u32;
-                                vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32;
-                                return;
+                    let pctor = unsafe { (*reco.to_raw()).get("constructor") };
+                    if pctor.is_none() {
+                        vm.borrow_mut().error = ERROR_RECORD_NO_CONSTRUCTOR;
+                        vm.borrow_mut().ip -= 3;
+                        return;
+                    }
+                    let ctor = pctor.unwrap();
+                    match ctor {
+                        NativeFn(native) => {
+                            vm.borrow_mut().native_call_depth += 1;
+
+                            // Call to native function
+                            native(Rc::clone(&vm), nargs);
+
+                            if native_call_returned(&vm) == NativeReturn::Stop {
+                                return;
                             }
-                            let mut new_val = record::Record::new();
-                            new_val.insert("prototype", val);
-                            vm.borrow_mut()
-                                .stack
-                                .push(Record((*vm).borrow().malloc(new_val)));
-                            vm.borrow_mut().enter_env(&*ifn);
+                        }
+                        Fn(ifn) => {
+                            let ifn = ifn.to_raw();
+                            unsafe {
+                                if nargs + 1 != (*ifn).nargs {
+                                    vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
+                                    vm.borrow_mut().error_expected = (*ifn).nargs as u32;
+                                    vm.borrow_mut().ip -= 3;
+                                    return;
+                                }
+                                let mut new_val = record::Record::new();
+                                new_val.insert("prototype", val);
+                                let new_val = Record((*vm).borrow().malloc(new_val));
+                                vm.borrow_mut().stack.push(new_val);
+                                if !vm.borrow_mut().enter_env(&*ifn) {
+                                    vm.borrow_mut().ip -= 3;
+                                    if !raise_stack_overflow(&vm)
+                                        || (*vm).borrow().exframe_fallthrough.is_some()
+                                    {
+                                        return;
+                                    }
+                                }
+                            }
+                        }
+                        _ => {
+                            vm.borrow_mut().error = ERROR_CONSTRUCTOR_NOT_FUNCTION;
+                            let ip = ((*vm).borrow().ip as i32 - 3) as u32;
+                            vm.borrow_mut().ip = ip;
+                            return;
                         }
                     }
-                    _ => {
-                        vm.borrow_mut().error = ERROR_CONSTRUCTOR_NOT_FUNCTION;
+                }
+                Fn(hfn) => unsafe {
+                    let ifn = hfn.to_raw();
+                    if nargs != (*ifn).nargs {
+                        vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
                         let ip = ((*vm).borrow().ip as i32 - 3) as u32;
                         vm.borrow_mut().ip = ip;
+                        vm.borrow_mut().error_expected = (*ifn).nargs as u32;
                         return;
                     }
-                }
-            }
-            Fn(hfn) => unsafe {
-                let ifn = hfn.to_raw();
-                if nargs != (*ifn).nargs {
-                    vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
+                    if !vm.borrow_mut().enter_env(&*ifn) {
+                        vm.borrow_mut().ip -= 3;
+                        if !raise_stack_overflow(&vm)
+                            || (*vm).borrow().exframe_fallthrough.is_some()
+                        {
+                            return;
+                        }
+                    }
+                },
+                _ => {
+                    vm.borrow_mut().error = ERROR_EXPECTED_CALLABLE;
                     let ip = ((*vm).borrow().ip as i32 - 3) as u32;
                     vm.borrow_mut().ip = ip;
-                    vm.borrow_mut().error_expected = (*ifn).nargs as u32;
                     return;
                 }
-                vm.borrow_mut().enter_env(&*ifn);
-                // vm.localenv();
-            },
-            _ => {
-                vm.borrow_mut().error = ERROR_EXPECTED_CALLABLE;
-                let ip = ((*vm).borrow().ip as i32 - 3) as u32;
-                vm.borrow_mut().ip = ip;
-                return;
             }
         }
-    }
-
-    // returns from function
-    if Ret == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Ret, IP: {}", vm.ip);
-        let last_entry = Rc::clone((*vm).borrow().localenv.last().unwrap());
 
-        if let Some(env) = (*last_entry).borrow().as_ref() {
-            if env.retip == u32::MAX {
+        // returns from function
+        if Ret == op {
+            log_debug!("Ret, IP: {}", (*vm).borrow().ip);
+            if (*vm).borrow_mut().leave_env() == u32::MAX {
                 //LOG("return from vm_call\n");
                 return;
             }
+
+            //LOG("ip = %d\n", vm->ip);
         }
 
-        (*vm).borrow_mut().leave_env();
+        // dictionaries
+        if DictNew == op {
+            log_debug!("OP_DICT_NEW");
+            vm.borrow_mut().ip += 1;
+            unimplemented!();
+        }
 
-        //LOG("ip = %d\n", vm->ip);
-    }
+        if MemberGet == op || MemberGetNoPop == op {
+            log_debug!("MemberGet/MemberGetNoPop");
+
+            // fast path: the key is where it was found last time
+            let hit = {
+                let vm = (*vm).borrow();
+                vm.inline_caches.get(vm.ip).and_then(|cache| {
+                    let top = vm.stack.slots().last().unwrap().borrow();
+                    let record = member_record(&vm, &top)?;
+                    Some((cache.get(record)?.clone(), cache.next_ip))
+                })
+            };
+            if let Some((result, next_ip)) = hit {
+                let mut vm = vm.borrow_mut();
+                vm.ip = next_ip;
+                if op == MemberGet as u8 {
+                    *vm.stack.slots_mut().last_mut().unwrap() = result.into();
+                } else {
+                    vm.stack.push(result);
+                }
+                continue;
+            }
 
-    // dictionaries
-    if DictNew == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("OP_DICT_NEW");
-        vm.borrow_mut().ip += 1;
-        unimplemented!();
-    }
+            let val = (*vm).borrow().stack.last().unwrap();
 
-    if MemberGet == (*vm).borrow().code[(*vm).borrow().ip as usize]
-        || MemberGetNoPop == (*vm).borrow().code[(*vm).borrow().ip as usize]
-    {
-        log_debug!("MemberGet/MemberGetNoPop");
-        let op = (*vm).borrow().code[(*vm).borrow().ip as usize];
+            let pos = (*vm).borrow().ip;
+            let key = generate_string(Rc::clone(&vm));
 
-        let val = (*vm).borrow().stack.last().cloned().unwrap();
+            let dict;
+            if let Record(reco) = val {
+                dict = Some(reco.clone());
+            } else {
+                dict = get_prototype(Rc::clone(&vm), val.clone());
+                if dict.is_none() {
+                    vm.borrow_mut().error = ERROR_CANNOT_ACCESS_NON_RECORD;
+                    vm.borrow_mut().ip = (pos as i32 - 1) as u32;
+                    return;
+                }
 
-        let pos = (*vm).borrow().ip;
-        let key = generate_string(Rc::clone(&vm));
+                if key == *"prototype" {
+                    if op == MemberGet as u8 {
+                        vm.borrow_mut().stack.pop();
+                    }
 
-        let dict;
-        if let Record(reco) = val {
-            dict = Some(reco.clone());
-            if op == MemberGet as u8 {
-                vm.borrow_mut().stack.pop();
-            }
-        } else {
-            dict = get_prototype(Rc::clone(&vm), val.clone());
-            if dict.is_none() {
-                vm.borrow_mut().error = ERROR_CANNOT_ACCESS_NON_RECORD;
-                vm.borrow_mut().ip = (pos as i32 - 1) as u32;
-                return;
+                    vm.borrow_mut().stack.push(Record(dict.clone().unwrap()));
+                    continue;
+                }
             }
 
-            if key == *"prototype" {
+            let dict = dict.unwrap();
+            let result = dict.as_ref().get(&key).cloned();
+            if let Some(result) = result {
+                let mut vm = vm.borrow_mut();
+                let next_ip = vm.ip;
+                if let Some(cache) = InlineCache::new(dict.as_ref(), &key, next_ip) {
+                    vm.inline_caches.insert(pos, cache);
+                }
                 if op == MemberGet as u8 {
-                    vm.borrow_mut().stack.pop();
+                    vm.stack.pop();
                 }
-
-                vm.borrow_mut().stack.push(Record(dict.clone().unwrap()));
-                return; // Does this go here?
+                vm.stack.push(result);
+            } else {
+                vm.borrow_mut().error = ERROR_UNKNOWN_KEY;
+                vm.borrow_mut().ip = (pos as i32 - 1) as u32; // or? vm.borrow_mut().ip = pos;
+                return;
             }
         }
 
-        let result = dict.unwrap().as_ref().get(&key).cloned();
-        if let Some(result) = result {
-            vm.borrow_mut().stack.push(result);
-        } else {
-            vm.borrow_mut().error = ERROR_UNKNOWN_KEY;
-            vm.borrow_mut().ip = (pos as i32 - 1) as u32; // or? vm.borrow_mut().ip = pos;
-            return;
-        }
-    }
+        if MemberSet == op {
+            log_debug!("MemberSet, IP: {}", (*vm).borrow().ip);
+            // stack: [value][dict], leaves the value like IndexSet
+
+            // fast path: the key already exists where it was found last time
+            let hit = {
+                let mut vm = vm.borrow_mut();
+                let vm = &mut *vm;
+                match (vm.inline_caches.get(vm.ip), vm.stack.slots()) {
+                    (Some(cache), [.., val, reco]) => match &*reco.borrow() {
+                        Record(reco) => {
+                            let mut reco = reco.clone();
+                            let next_ip = cache.next_ip;
+                            cache
+                                .set(reco.inner_mut_ptr(), val.to_value())
+                                .ok()
+                                .map(|_| next_ip)
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            };
+            if let Some(next_ip) = hit {
+                let mut vm = vm.borrow_mut();
+                vm.ip = next_ip;
+                vm.stack.pop();
+                continue;
+            }
 
-    if MemberSet == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("MemberSet, IP: {}", vm.ip);
-        // stack: [value][dict]
-        let pos = (*vm).borrow().ip;
-        let key = generate_string(Rc::clone(&vm));
+            let pos = (*vm).borrow().ip;
+            let key = generate_string(Rc::clone(&vm));
 
-        let dval = vm.borrow_mut().stack.last().cloned().unwrap();
-        match dval {
-            Record(mut reco) => {
-                vm.borrow_mut().stack.pop();
-                let val = vm.borrow_mut().stack.pop().unwrap();
-                reco.inner_mut_ptr().insert(key, val);
-            }
-            _ => {
-                vm.borrow_mut().error = ERROR_CANNOT_ACCESS_NON_RECORD;
-                vm.borrow_mut().ip = pos;
-                return;
+            let dval = vm.borrow_mut().stack.last().unwrap();
+            match dval {
+                Record(mut reco) => {
+                    vm.borrow_mut().stack.pop();
+                    let val = (*vm).borrow().stack.last().unwrap();
+                    let reco = reco.inner_mut_ptr();
+                    // only overwriting a key keeps the shape as it is
+                    if key != "prototype" && matches!(reco.lookup(key.as_str()), Some((0, _))) {
+                        let mut vm = vm.borrow_mut();
+                        let next_ip = vm.ip;
+                        if let Some(cache) = InlineCache::new(reco, key.as_str(), next_ip) {
+                            vm.inline_caches.insert(pos, cache);
+                        }
+                    }
+                    reco.insert(key, val);
+                }
+                _ => {
+                    vm.borrow_mut().error = ERROR_CANNOT_ACCESS_NON_RECORD;
+                    vm.borrow_mut().ip = pos;
+                    return;
+                }
             }
         }
-    }
 
-    if DictLoad == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("DictLoad, IP: {}", vm.ip);
-        // stack: [nil][value][key]
-        vm.borrow_mut().ip += 1;
+        if DictLoad == op {
+            log_debug!("DictLoad, IP: {}", (*vm).borrow().ip);
+            // stack: [nil][value][key]
+            vm.borrow_mut().ip += 1;
 
-        let mut length = {
-            let val = vm.borrow_mut().stack.pop().unwrap();
-            let Int(num) = val else {
-                unreachable!("Expect integer, found {}", val.type_name());
+            let mut length = {
+                let val = vm.borrow_mut().stack.pop().unwrap();
+                let Int(num) = val else {
+                    unreachable!("Expect integer, found {}", val.type_name());
+                };
+
+                num as usize
             };
 
-            num as usize
-        };
+            let mut dval = record::Record::with_capacity(length);
 
-        let mut dval = record::Record::with_capacity(length);
+            while length > 0 {
+                //debug_assert(key.type == TYPE_STR);
+                // key
+                let key = {
+                    let val = vm.borrow_mut().stack.pop().unwrap();
+                    let Str(s) = val else {
+                        unreachable!("Expect string, found {}", val.type_name());
+                    };
 
-        while length > 0 {
-            //debug_assert(key.type == TYPE_STR);
-            // key
-            let key = {
-                let val = vm.borrow_mut().stack.pop().unwrap();
-                let Str(s) = val else {
-                    unreachable!("Expect string, found {}", val.type_name());
+                    s
                 };
+                // val
+                let val = vm.borrow_mut().stack.pop().unwrap();
+                let key = unsafe { (*key.to_raw()).borrow() } as &String;
+                dval.insert(key.clone(), val);
 
-                s
-            };
-            // val
-            let val = vm.borrow_mut().stack.pop().unwrap();
-            let key = unsafe { (*key.to_raw()).borrow() } as &String;
-            dval.insert(key.clone(), val);
+                length -= 1;
+            }
 
-            length -= 1;
+            let dval = Record((*vm).borrow().malloc(dval));
+            vm.borrow_mut().stack.push(dval);
         }
 
-        vm.borrow_mut()
-            .stack
-            .push(Record((*vm).borrow().malloc(dval)));
-    }
+        if ArrayLoad == op {
+            log_debug!("ArrayLoad, IP: {}", (*vm).borrow().ip);
+            vm.borrow_mut().ip += 1;
 
-    if ArrayLoad == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("ArrayLoad, IP: {}", vm.ip);
-        vm.borrow_mut().ip += 1;
+            let mut length = {
+                let val = vm.borrow_mut().stack.pop().unwrap();
+                let Int(num) = val else {
+                    unreachable!("Expect integer, found {}", val.type_name());
+                };
 
-        let mut length = {
-            let val = vm.borrow_mut().stack.pop().unwrap();
-            let Int(num) = val else {
-                unreachable!("Expect integer, found {}", val.type_name());
+                num as usize
             };
 
-            num as usize
-        };
-
-        if length == 0 {
-            vm.borrow_mut()
-                .stack
-                .push(Array((*vm).borrow().malloc(Vec::new())));
-        } else {
-            let mut array = Vec::with_capacity(length);
+            if length == 0 {
+                let array = Array((*vm).borrow().malloc(Vec::new()));
+                vm.borrow_mut().stack.push(array);
+            } else {
+                let mut array = Vec::with_capacity(length);
 
-            while length > 0 {
-                array.insert(0, vm.borrow_mut().stack.pop().unwrap());
-                length -= 1
+                while length > 0 {
+                    array.insert(0, vm.borrow_mut().stack.pop().unwrap());
+                    length -= 1
+                }
+                let array = Array((*vm).borrow().malloc(array));
+                vm.borrow_mut().stack.push(array);
             }
-            vm.borrow_mut()
-                .stack
-                .push(Array((*vm).borrow().malloc(array)));
         }
-    }
 
-    // exceptions
-    if Try == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Try, IP: {}", vm.ip);
-        // stack: [nil][function][error type]
-        //LOG("TRY\n");
-        vm.borrow_mut().ip += 1;
-
-        let frame: *mut _ = vm.borrow_mut().enter_exframe();
-        let mut error;
-        loop {
-            unsafe {
-                error = vm.borrow_mut().stack.last().cloned().unwrap();
-                if let Nil = error {
-                    break;
-                }
+        // exceptions
+        if Try == op {
+            log_debug!("Try, IP: {}", (*vm).borrow().ip);
+            // stack: [nil][function][error type]
+            //LOG("TRY\n");
+            vm.borrow_mut().ip += 1;
+
+            let frame: *mut _ = vm.borrow_mut().enter_exframe();
+            let mut error;
+            loop {
+                unsafe {
+                    error = vm.borrow_mut().stack.last().unwrap();
+                    if let Nil = error {
+                        break;
+                    }
 
-                // error type
-                if let Record(reco) = error {
-                    vm.borrow_mut().stack.pop();
-                    // val
-                    let xfn = (*vm).borrow().stack.last().cloned().unwrap();
-                    let xfn = {
-                        let Fn(f) = xfn else {
-                            unreachable!();
+                    // error type
+                    if let Record(reco) = error {
+                        vm.borrow_mut().stack.pop();
+                        // val
+                        let xfn = (*vm).borrow().stack.last().unwrap();
+                        let xfn = {
+                            let Fn(f) = xfn else {
+                                unreachable!();
+                            };
+                            f.to_raw()
                         };
-                        f.to_raw()
-                    };
-                    //debug_assert!(xfn.type == TYPE_FN);
-                    vm.borrow_mut().stack.pop();
-                    (*frame).set_handler(Some(reco.clone()), (*xfn).clone());
-                } else {
-                    vm.borrow_mut().error = ERROR_CASE_EXPECTS_DICT;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                    return;
+                        //debug_assert!(xfn.type == TYPE_FN);
+                        vm.borrow_mut().stack.pop();
+                        (*frame).set_handler(Some(reco.clone()), (*xfn).clone());
+                    } else {
+                        vm.borrow_mut().error = ERROR_CASE_EXPECTS_DICT;
+                        vm.borrow_mut().ip -= 1;
+                        return;
+                    }
                 }
             }
+
+            vm.borrow_mut().stack.pop(); // pop nil
+            let len = (*vm).borrow().stack.len();
+            unsafe {
+                (*frame).unwind_stack = len;
+            }
         }
 
-        vm.borrow_mut().stack.pop(); // pop nil
-    }
+        if Raise == op {
+            log_debug!("Raise, IP: {}", (*vm).borrow().ip);
+            if !harumachine::vm::raise(Rc::clone(&vm)) {
+                vm.borrow_mut().error = ERROR_UNHANDLED_EXCEPTION;
+                if (*vm).borrow().exframe_fallthrough.is_some()
+                    || (*vm).borrow().native_call_depth != 0
+                {
+                    log_debug!(
+                        "falling through pls wait ({})\n",
+                        (*vm).borrow().native_call_depth
+                    );
+                    return;
+                }
+                return;
+            }
 
-    if Raise == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Raise, IP: {}", vm.ip);
-        if !harumachine::vm::raise(Rc::clone(&vm)) {
-            vm.borrow_mut().error = ERROR_UNHANDLED_EXCEPTION;
-            if (*vm).borrow().exframe_fallthrough.is_some()
-                || (*vm).borrow().native_call_depth != 0
-            {
+            // caught outside of the native function that called into us
+            if (*vm).borrow().exframe_fallthrough.is_some() {
                 log_debug!(
                     "falling through pls wait ({})\n",
                     (*vm).borrow().native_call_depth
                 );
                 return;
             }
-            return;
         }
 
-        if (*vm).borrow().exframe_fallthrough.is_some() || (*vm).borrow().native_call_depth != 0 {
-            log_debug!(
-                "falling through pls wait ({})\n",
-                (*vm).borrow().native_call_depth
-            );
-            return;
+        if ExframeRet == op {
+            log_debug!("ExframeRet, IP: {}", (*vm).borrow().ip);
+            vm.borrow_mut().ip += 1;
+            let pos = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+            ]);
+
+            vm.borrow_mut().ip += pos as u32;
+            vm.borrow_mut().leave_exframe();
         }
-    }
 
-    if ExframeRet == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("ExframeRet, IP: {}", vm.ip);
-        vm.borrow_mut().ip += 1;
-        let pos = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-        ]);
+        if RetCall == op {
+            log_debug!("RetCall, IP: {}", (*vm).borrow().ip);
+            let val = (*vm).borrow().stack.last().unwrap();
+            let nargs = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
+            ]);
 
-        vm.borrow_mut().ip += pos as u32;
-        vm.borrow_mut().leave_exframe();
-    }
+            vm.borrow_mut().ip += 3;
+            debug_assert!((*vm).borrow().stack.len() >= (nargs as usize));
 
-    if RetCall == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("RetCall, IP: {}", (*vm).borrow().ip);
-        let val = (*vm).borrow().stack.last().cloned().unwrap();
-        let nargs = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 2],
-        ]);
-
-        vm.borrow_mut().ip += 3;
-        debug_assert!((*vm).borrow().stack.len() >= (nargs as usize));
-
-        match val {
-            NativeFn(native) => {
-                vm.borrow_mut().stack.pop();
-                vm.borrow_mut().native_call_depth += 1;
-
-                // Call to native function
-                native(Rc::clone(&vm), nargs);
-
-                vm.borrow_mut().native_call_depth -= 1;
-                let call_depth = if (*vm).borrow().exframe_fallthrough.is_some() {
-                    (*vm)
-                        .borrow()
-                        .exframe_fallthrough
-                        .as_ref()
-                        .unwrap()
-                        .unwind_native_call_depth
-                } else {
-                    (*vm).borrow().native_call_depth
-                };
+            match val {
+                NativeFn(native) => {
+                    vm.borrow_mut().stack.pop();
+                    vm.borrow_mut().native_call_depth += 1;
 
-                if call_depth != (*vm).borrow().native_call_depth
-                    || (*vm).borrow().error != ERROR_NO_ERROR
-                {
-                    return;
-                }
+                    // Call to native function
+                    native(Rc::clone(&vm), nargs);
 
-                let last_entry = Rc::clone((*vm).borrow().localenv.last().unwrap());
+                    match native_call_returned(&vm) {
+                        NativeReturn::Stop => return,
+                        NativeReturn::Caught => continue,
+                        NativeReturn::Returned => (),
+                    }
 
-                if let Some(env) = (*last_entry).borrow().as_ref() {
-                    if env.retip == u32::MAX {
+                    if vm.borrow_mut().leave_env() == u32::MAX {
                         return;
                     }
                 }
+                Fn(hfn) => unsafe {
+                    vm.borrow_mut().stack.pop();
+                    let ifn = hfn.to_raw();
+                    if nargs != (*ifn).nargs {
+                        vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
+                        vm.borrow_mut().ip -= 3;
+                        vm.borrow_mut().error_expected = (*ifn).nargs as u32;
+                        return;
+                    }
 
-                vm.borrow_mut().leave_env();
-            }
-            Fn(hfn) => unsafe {
-                vm.borrow_mut().stack.pop();
-                let ifn = hfn.to_raw();
-                if nargs != (*ifn).nargs {
-                    vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32;
-                    vm.borrow_mut().error_expected = (*ifn).nargs as u32;
-                    return;
-                }
-
-                vm.borrow_mut().enter_env_tail(&*ifn);
-                // vm.localenv();
-            },
-            Record(ref reco) => {
-                let pctor = unsafe { (*reco.to_raw()).get("constructor") };
-                if pctor.is_none() {
-                    vm.borrow_mut().error = ERROR_RECORD_NO_CONSTRUCTOR;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32;
-                    return;
-                }
-                let ctor = pctor.unwrap();
-                match ctor {
-                    NativeFn(native) => {
+                    vm.borrow_mut().enter_env_tail(&*ifn);
+                    // vm.localenv();
+                },
+                Record(ref reco) => {
+                    // functions exported by native modules
+                    if let Some(fun) = module::function(reco) {
+                        vm.borrow_mut().stack.pop();
                         vm.borrow_mut().native_call_depth += 1;
-
-                        // Call to native function
-                        native(Rc::clone(&vm), nargs);
-
-                        vm.borrow_mut().native_call_depth -= 1;
-                        let call_depth = if (*vm).borrow().exframe_fallthrough.is_some() {
-                            (*vm)
-                                .borrow()
-                                .exframe_fallthrough
-                                .as_ref()
-                                .unwrap()
-                                .unwind_native_call_depth
-                        } else {
-                            (*vm).borrow().native_call_depth
-                        };
-
-                        if call_depth != (*vm).borrow().native_call_depth
-                            || (*vm).borrow().error != ERROR_NO_ERROR
-                        {
+                        module::call(&vm, fun, nargs);
+                        match native_call_returned(&vm) {
+                            NativeReturn::Stop => return,
+                            NativeReturn::Caught => continue,
+                            NativeReturn::Returned => (),
+                        }
+                        if vm.borrow_mut().leave_env() == u32::MAX {
                             return;
                         }
+                        continue;
+                    }
+                    let pctor = unsafe { (*reco.to_raw()).get("constructor") };
+                    if pctor.is_none() {
+                        vm.borrow_mut().error = ERROR_RECORD_NO_CONSTRUCTOR;
+                        vm.borrow_mut().ip -= 3;
+                        return;
+                    }
+                    let ctor = pctor.unwrap();
+                    vm.borrow_mut().stack.pop();
+                    match ctor {
+                        NativeFn(native) => {
+                            vm.borrow_mut().native_call_depth += 1;
 
-                        if let Some(last_entry) = (*vm).borrow().localenv.last() {
-                            if let Some(env) = (**last_entry).borrow().as_ref() {
-                                if env.retip == u32::MAX {
-                                    //LOG("return from vm_call\n");
-                                    return;
-                                }
+                            // Call to native function
+                            native(Rc::clone(&vm), nargs);
+
+                            match native_call_returned(&vm) {
+                                NativeReturn::Stop => return,
+                                NativeReturn::Caught => continue,
+                                NativeReturn::Returned => (),
                             }
-                        }
-                    }
-                    Fn(ifn) => {
-                        let ifn = ifn.to_raw();
-                        unsafe {
-                            if nargs + 1 != (*ifn).nargs {
-                                vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
-                                vm.borrow_mut().error_expected = (*ifn).nargs as u32;
-                                vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32;
+
+                            if vm.borrow_mut().leave_env() == u32::MAX {
+                                //LOG("return from vm_call\n");
                                 return;
                             }
-                            let mut new_val = record::Record::new();
-                            new_val.insert("prototype", val.clone());
-                            vm.borrow_mut()
-                                .stack
-                                .push(Record((*vm).borrow().malloc(new_val)));
+                        }
+                        Fn(ifn) => {
+                            let ifn = ifn.to_raw();
+                            unsafe {
+                                if nargs + 1 != (*ifn).nargs {
+                                    vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
+                                    vm.borrow_mut().error_expected = (*ifn).nargs as u32;
+                                    vm.borrow_mut().ip -= 3;
+                                    return;
+                                }
+                                let mut new_val = record::Record::new();
+                                new_val.insert("prototype", val.clone());
+                                let new_val = Record((*vm).borrow().malloc(new_val));
+                                vm.borrow_mut().stack.push(new_val);
 
-                            vm.borrow_mut().enter_env_tail(&*ifn);
-                            // vm.localenv();
+                                vm.borrow_mut().enter_env_tail(&*ifn);
+                                // vm.localenv();
+                            }
+                        }
+                        _ => {
+                            vm.borrow_mut().error = ERROR_CONSTRUCTOR_NOT_FUNCTION;
+                            vm.borrow_mut().ip -= 3;
+                            return;
                         }
-                    }
-                    _ => {
-                        vm.borrow_mut().error = ERROR_CONSTRUCTOR_NOT_FUNCTION;
-                        vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32;
-                        return;
                     }
                 }
-            }
-            _ => {
-                vm.borrow_mut().error = ERROR_EXPECTED_CALLABLE;
-                vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32;
-                return;
+                _ => {
+                    vm.borrow_mut().error = ERROR_EXPECTED_CALLABLE;
+                    vm.borrow_mut().ip -= 3;
+                    return;
+                }
             }
         }
-    }
 
-    // Remember the -2
-    if ForIn == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("ForIn, IP: {}", vm.ip);
+        // Remember the -2
+        if ForIn == op {
+            log_debug!("ForIn, IP: {}", (*vm).borrow().ip);
 
-        vm.borrow_mut().ip += 1;
-        let pos = u16::from_be_bytes([
-            (*vm).borrow().code[(*vm).borrow().ip as usize],
-            (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
-        ]);
-        // as usize + 1]);
-        unsafe {
-            //println!("{:?}, {}", (*vm).borrow().code, vm.ip);
-            debug_assert!(!(*vm).borrow().stack.is_empty());
+            vm.borrow_mut().ip += 1;
+            let pos = u16::from_be_bytes([
+                (*vm).borrow().code[(*vm).borrow().ip as usize],
+                (*vm).borrow().code[(*vm).borrow().ip as usize + 1],
+            ]);
+            // as usize + 1]);
+            unsafe {
+                //println!("{:?}, {}", (*vm).borrow().code, vm.ip);
+                debug_assert!(!(*vm).borrow().stack.is_empty());
 
-            let top = (*vm).borrow().stack.last().cloned().unwrap();
+                let top = (*vm).borrow().stack.last().unwrap();
 
-            match top {
-                Str(xstr) => {
-                    let mut vec = (*xstr.to_raw())
-                        .deref()
-                        .chars()
-                        .map(|ch| Str((*vm).borrow().malloc(ch.to_string().into())))
-                        .collect::<Vec<_>>();
+                match top {
+                    Str(xstr) => {
+                        let mut vec = (*xstr.to_raw())
+                            .deref()
+                            .chars()
+                            .map(|ch| Str((*vm).borrow().malloc(ch.to_string().into())))
+                            .collect::<Vec<_>>();
 
-                    vm.borrow_mut().stack.pop();
-                    if vec.is_empty() {
-                        // skip empty
-                        vm.borrow_mut().ip += (pos as i32 - 2) as u32; // -2 sizeof(pos)
-                    } else {
-                        let less = vec.remove(0);
-                        vm.borrow_mut()
-                            .stack
-                            .push(Array((*vm).borrow().malloc(vec)));
-                        vm.borrow_mut().stack.push(Iterator);
-                        vm.borrow_mut().stack.push(less);
-                    }
-                }
-                Array(array) => {
-                    let array = array.to_raw();
-                    if (*array).is_empty() {
-                        // skip empty
-                        vm.borrow_mut().ip += (pos as i32 - 2) as u32; // -2 sizeof(pos)
-                        vm.borrow_mut().stack.pop();
-                    } else {
                         vm.borrow_mut().stack.pop();
-                        let mut clone = (*array).clone();
-                        let less = clone.remove(0);
-
-                        vm.borrow_mut()
-                            .stack
-                            .push(Array((*vm).borrow().malloc(clone))); // De esta manera no consumiremos el original
-                        vm.borrow_mut().stack.push(Iterator);
-                        vm.borrow_mut().stack.push(less);
+                        if vec.is_empty() {
+                            // skip empty
+                            vm.borrow_mut().ip += (pos as i32 - 2) as u32; // -2 sizeof(pos)
+                        } else {
+                            let less = vec.remove(0);
+                            let vec = Array((*vm).borrow().malloc(vec));
+                            vm.borrow_mut().stack.push(vec);
+                            vm.borrow_mut().stack.push(Iterator);
+                            vm.borrow_mut().stack.push(less);
+                        }
                     }
-                }
-                //TYPE_DICT
-                // interation
-                Iterator => {
-                    // There must be at least two values on the stack!
-                    debug_assert!((*vm).borrow().stack.len() >= 2);
-
-                    let len_stack = (*vm).borrow().stack.len();
-                    let iterator = (*vm).borrow().stack[len_stack - 2].clone();
-                    match iterator {
-                        Nil => {
-                            vm.borrow_mut().ip += (pos as i32 - 2) as u32;
+                    Array(array) => {
+                        let array = array.to_raw();
+                        if (*array).is_empty() {
+                            // skip empty
+                            vm.borrow_mut().ip += (pos as i32 - 2) as u32; // -2 sizeof(pos)
+                            vm.borrow_mut().stack.pop();
+                        } else {
+                            vm.borrow_mut().stack.pop();
+                            let mut clone = (*array).clone();
+                            let less = clone.remove(0);
+
+                            let clone = Array((*vm).borrow().malloc(clone));
+                            vm.borrow_mut().stack.push(clone); // De esta manera no consumiremos el original
+                            vm.borrow_mut().stack.push(Iterator);
+                            vm.borrow_mut().stack.push(less);
                         }
+                    }
+                    //TYPE_DICT
+                    // interation
+                    Iterator => {
+                        // There must be at least two values on the stack!
+                        debug_assert!((*vm).borrow().stack.len() >= 2);
+
+                        let len_stack = (*vm).borrow().stack.len();
+                        let iterator = (*vm).borrow().stack.get(len_stack - 2).unwrap();
+                        match iterator {
+                            Nil => {
+                                vm.borrow_mut().ip += (pos as i32 - 2) as u32;
+                            }
 
-                        // NativeValue
-                        Array(arr) => {
-                            let arr = arr.into_raw();
-                            if (*arr).is_empty() {
-                                vm.borrow_mut().stack.pop(); /* iterator */
-                                vm.borrow_mut().stack.pop(); /* array */
-                                vm.borrow_mut().ip += (pos as i32 - 2) as u32; // -2 sizeof(pos)
-                            } else {
-                                log_debug!("CONTINUE\n");
-                                //vm.stack.pop(); /* old iterator */
-                                //array_push(vm->stack, value_pointer(TYPE_INTERPRETER_ITERATOR,
-                                // (void *)(idx + 1)));
-                                // vm.borrow_mut().stack.push(Iterator);
-                                vm.borrow_mut().stack.push((*arr).remove(0));
+                            // NativeValue
+                            Array(arr) => {
+                                let arr = arr.into_raw();
+                                if (*arr).is_empty() {
+                                    vm.borrow_mut().stack.pop(); /* iterator */
+                                    vm.borrow_mut().stack.pop(); /* array */
+                                    // -2 sizeof(pos)
+                                    vm.borrow_mut().ip += (pos as i32 - 2) as u32;
+                                } else {
+                                    log_debug!("CONTINUE\n");
+                                    //vm.stack.pop(); /* old iterator */
+                                    //array_push(vm->stack, value_pointer(TYPE_INTERPRETER_ITERATOR,
+                                    // (void *)(idx + 1)));
+                                    // vm.borrow_mut().stack.push(Iterator);
+                                    vm.borrow_mut().stack.push((*arr).remove(0));
+                                }
                             }
-                        }
 
-                        _ => {
-                            vm.borrow_mut().error = ERROR_EXPECTED_ITERABLE;
-                            // 1 + sizeof(pos) (where 1 is the operator)
-                            vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 3) as u32; // R(siz)()?
-                            return;
+                            _ => {
+                                vm.borrow_mut().error = ERROR_EXPECTED_ITERABLE;
+                                // 1 + sizeof(pos) (where 1 is the operator)
+                                vm.borrow_mut().ip -= 3; // R(siz)()?
+                                return;
+                            }
                         }
                     }
+                    _ => {
+                        log_debug!("NOT ITERABLE\n");
+                        vm.borrow_mut().error = ERROR_EXPECTED_ITERABLE;
+                        // -2 == - sizeof(pos)
+                        vm.borrow_mut().ip -= 2; // R(siz)()?
+                        return;
+                    }
                 }
-                _ => {
-                    log_debug!("NOT ITERABLE\n");
-                    vm.borrow_mut().error = ERROR_EXPECTED_ITERABLE;
-                    // -2 == - sizeof(pos)
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 2) as u32; // R(siz)()?
-                    return;
-                }
-            }
 
-            vm.borrow_mut().ip += 2; // Sera un descuido R(siz)()?
+                vm.borrow_mut().ip += 2; // Sera un descuido R(siz)()?
+            }
         }
-    }
 
-    if IndexGet == (*vm).borrow().code[(*vm).borrow().ip as usize]
-        || MemberGetNoPop == (*vm).borrow().code[(*vm).borrow().ip as usize]
-    {
-        log_debug!("IndexGET/MemberGetNoPop, IP: {}", vm.ip);
-        let index = vm.borrow_mut().stack.pop().unwrap();
-        let dval = if IndexGet == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-            vm.borrow_mut().stack.pop().unwrap()
-        } else {
-            (*vm).borrow().stack.last().cloned().unwrap()
-        };
+        if IndexGet == op || IndexGetNoPop == op {
+            log_debug!("IndexGet/IndexGetNoPop, IP: {}", (*vm).borrow().ip);
+            let index = vm.borrow_mut().stack.pop().unwrap();
+            let dval = if IndexGet == op {
+                vm.borrow_mut().stack.pop().unwrap()
+            } else {
+                (*vm).borrow().stack.last().unwrap()
+            };
 
-        vm.borrow_mut().ip += 1;
-        match dval {
-            Array(array) => {
-                let array = unsafe { &*array.to_raw() };
-                let index = if let Int(num) = index {
-                    if num < 0 {
-                        ((array.len() as i64) + num) as usize
+            vm.borrow_mut().ip += 1;
+            match dval {
+                Array(array) => {
+                    let array = unsafe { &*array.to_raw() };
+                    let index = if let Int(num) = index {
+                        if num < 0 {
+                            ((array.len() as i64) + num) as usize
+                        } else {
+                            num as usize
+                        }
                     } else {
-                        num as usize
+                        vm.borrow_mut().error = ERROR_KEY_NON_INT;
+                        vm.borrow_mut().ip -= 1;
+                        return;
+                    };
+
+                    if index >= array.len() {
+                        vm.borrow_mut().error = ERROR_UNBOUNDED_ACCESS;
+                        vm.borrow_mut().ip -= 1;
+                        vm.borrow_mut().error_expected = (array.len()) as u32;
+                        return;
                     }
-                } else {
-                    vm.borrow_mut().error = ERROR_KEY_NON_INT;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                    return;
-                };
 
-                if index >= array.len() {
-                    vm.borrow_mut().error = ERROR_UNBOUNDED_ACCESS;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                    vm.borrow_mut().error_expected = (array.len()) as u32;
-                    return;
+                    vm.borrow_mut().stack.push(array[index].clone())
                 }
+                Str(xstr) => {
+                    let xstr = unsafe { &*xstr.to_raw() };
+                    let len = xstr.graphemes(true).count() as i64;
 
-                vm.borrow_mut().stack.push(array[index].clone())
-            }
-            Str(xstr) => {
-                let xstr = unsafe { &*xstr.to_raw() };
-                let len = xstr.graphemes(true).count() as i64;
-
-                let index = if let Int(num) = index {
-                    if num < 0 {
-                        //   -
-                        (len + num) as usize
+                    let index = if let Int(num) = index {
+                        if num < 0 {
+                            //   -
+                            (len + num) as usize
+                        } else {
+                            num as usize
+                        }
                     } else {
-                        num as usize
-                    }
-                } else {
-                    vm.borrow_mut().error = ERROR_KEY_NON_INT;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                    return;
-                };
+                        vm.borrow_mut().error = ERROR_KEY_NON_INT;
+                        vm.borrow_mut().ip -= 1;
+                        return;
+                    };
 
-                let left = if let Some(ch) = xstr.graphemes(true).nth(index) {
-                    (*vm).borrow().malloc(ch.to_string().into())
-                } else {
-                    vm.borrow_mut().error = ERROR_UNBOUNDED_ACCESS;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                    vm.borrow_mut().error_expected = len as u32;
+                    let left = if let Some(ch) = xstr.graphemes(true).nth(index) {
+                        (*vm).borrow().malloc(ch.to_string().into())
+                    } else {
+                        vm.borrow_mut().error = ERROR_UNBOUNDED_ACCESS;
+                        vm.borrow_mut().ip -= 1;
+                        vm.borrow_mut().error_expected = len as u32;
+                        return;
+                    };
+                    vm.borrow_mut().stack.push(Str(left));
+                }
+                _ => {
+                    vm.borrow_mut().error = ERROR_CANNOT_ACCESS_NON_RECORD;
+                    vm.borrow_mut().ip -= 1;
                     return;
-                };
-                vm.borrow_mut().stack.push(Str(left));
-            }
-            _ => {
-                vm.borrow_mut().error = ERROR_CANNOT_ACCESS_NON_RECORD;
-                vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                return;
+                }
             }
         }
-    }
 
-    if IndexSet == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("IndexSet, IP: {}", vm.ip);
-        vm.borrow_mut().ip += 1;
-
-        let index = vm.borrow_mut().stack.pop().unwrap();
-        let dval = vm.borrow_mut().stack.pop().unwrap();
-        let val = (*vm).borrow().stack.last().unwrap().clone();
-
-        match dval {
-            Array(array) => {
-                // Note: i64::MAX < usize::MAX
-                let array = unsafe { &mut *(array.to_raw() as *mut Vec<_>) };
-                let index = if let Int(num) = index {
-                    if num < 0 {
-                        ((array.len() as i64) + num) as usize
+        if IndexSet == op {
+            log_debug!("IndexSet, IP: {}", (*vm).borrow().ip);
+            vm.borrow_mut().ip += 1;
+
+            let index = vm.borrow_mut().stack.pop().unwrap();
+            let dval = vm.borrow_mut().stack.pop().unwrap();
+            let val = (*vm).borrow().stack.last().unwrap().clone();
+
+            match dval {
+                Array(array) => {
+                    // Note: i64::MAX < usize::MAX
+                    let array = unsafe { &mut *(array.to_raw() as *mut Vec<_>) };
+                    let index = if let Int(num) = index {
+                        if num < 0 {
+                            ((array.len() as i64) + num) as usize
+                        } else {
+                            num as usize
+                        }
                     } else {
-                        num as usize
+                        vm.borrow_mut().error = ERROR_KEY_NON_INT;
+                        vm.borrow_mut().ip -= 1;
+                        return;
+                    };
+
+                    if index >= array.len() {
+                        vm.borrow_mut().error = ERROR_UNBOUNDED_ACCESS;
+                        vm.borrow_mut().ip -= 1;
+                        vm.borrow_mut().error_expected = (array.len()) as u32;
+                        return;
                     }
-                } else {
-                    vm.borrow_mut().error = ERROR_KEY_NON_INT;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                    return;
-                };
 
-                if index >= array.len() {
-                    vm.borrow_mut().error = ERROR_UNBOUNDED_ACCESS;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                    vm.borrow_mut().error_expected = (array.len()) as u32;
-                    return;
+                    write_barrier(&val);
+                    array[index] = val.clone();
                 }
+                // TODO: the Record's should be more like classes
+                // than dictionaries, in the future, Hana will support dictionaries.
+                // dictionaries, at the moment we will continue, with the old
+                // traditions :(.
+                Record(mut reco) => unsafe {
+                    let index = if let Str(s) = index {
+                        (*s.into_raw()).clone()
+                    } else {
+                        vm.borrow_mut().error = ERROR_RECORD_KEY_NON_STRING;
+                        vm.borrow_mut().ip -= 1;
+                        return;
+                    };
 
-                array[index] = val.clone();
-            }
-            // TODO: the Record's should be more like classes
-            // than dictionaries, in the future, Hana will support dictionaries.
-            // dictionaries, at the moment we will continue, with the old
-            // traditions :(.
-            Record(mut reco) => unsafe {
-                let index = if let Str(s) = index {
-                    (*s.into_raw()).clone()
-                } else {
-                    vm.borrow_mut().error = ERROR_RECORD_KEY_NON_STRING;
-                    vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
+                    reco.inner_mut_ptr().insert(index, val.clone());
+                },
+                _ => {
+                    vm.borrow_mut().error = ERROR_EXPECTED_RECORD_ARRAY;
+                    vm.borrow_mut().ip -= 1;
                     return;
-                };
-
-                reco.inner_mut_ptr().insert(index, val.clone());
-            },
-            _ => {
-                vm.borrow_mut().error = ERROR_EXPECTED_RECORD_ARRAY;
-                vm.borrow_mut().ip = ((*vm).borrow().ip as i32 - 1) as u32;
-                return;
+                }
             }
         }
-    }
 
-    if Swap == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Swap, IP: {}", vm.ip);
-        debug_assert!((*vm).borrow().stack.len() >= 2);
-        vm.borrow_mut().ip += 1;
-        let stack = &mut (*vm).borrow_mut().stack;
-        let len = stack.len();
-        stack.swap(len - 1, len - 2);
-    }
+        if Swap == op {
+            log_debug!("Swap, IP: {}", (*vm).borrow().ip);
+            debug_assert!((*vm).borrow().stack.len() >= 2);
+            vm.borrow_mut().ip += 1;
+            let mut vm = (*vm).borrow_mut();
+            let stack = vm.stack.slots_mut();
+            let len = stack.len();
+            stack.swap(len - 1, len - 2);
+        }
 
-    // modules
-    if Use == (*vm).borrow().code[(*vm).borrow().ip as usize] {
-        log_debug!("Use, IP: {}", vm.ip);
-        let path = generate_string(Rc::clone(&vm));
-        vm.borrow_mut().load_module(&path);
+        // modules
+        if Use == op {
+            log_debug!("Use, IP: {}", (*vm).borrow().ip);
+            let use_ip = (*vm).borrow().ip;
+            let path = generate_string(Rc::clone(&vm));
+            let loaded = vm.borrow_mut().load_module(&path);
+            if let Err(why) = loaded {
+                // the error is reported at the use statement
+                vm.borrow_mut().ip = use_ip;
+                if !raise_error(&vm, |stdlib| &stdlib.io_error, why)
+                    || (*vm).borrow().exframe_fallthrough.is_some()
+                {
+                    return;
+                }
+            }
+        }
     }
-
-    inside_execute(vm);
 }
 
 pub(super) fn vm_call(vm: Rc<RefCell<Vm>>, func: Value, args: &[Value]) -> Value {
-    let ifn: *mut Function = std::ptr::null_mut();
-    let nargs = args.len() as u16;
-
-    if let NativeFn(value_fn) = func {
-        let len = args.len() as isize - 1;
-        for i in -len..1 {
-            vm.borrow_mut().stack.push(args[(-i) as usize].clone());
+    // arguments are pushed from last to first, like the Call instruction
+    let push_args = |vm: &Rc<RefCell<Vm>>| {
+        let mut vm = vm.borrow_mut();
+        for arg in args.iter().rev() {
+            vm.stack.push(arg.clone());
         }
-
-        // call fn
-        value_fn(Rc::clone(&vm), nargs);
-
-        return vm.borrow_mut().stack.pop().clone().unwrap();
-    } else if let Record(pctor) = func.clone() {
-        let pctor = unsafe { (*pctor.to_raw()).get("constructor") };
-
-        if pctor.is_none() {
-            vm.borrow_mut().error = ERROR_RECORD_NO_CONSTRUCTOR;
+    };
+    let call_native = |native: &dyn std::ops::Fn(Rc<RefCell<Vm>>, u16)| {
+        push_args(&vm);
+        native(Rc::clone(&vm), args.len() as u16);
+        if (*vm).borrow().error != ERROR_NO_ERROR || (*vm).borrow().exframe_fallthrough.is_some() {
             return Value::InterpreterError;
         }
+        vm.borrow_mut().stack.pop().unwrap()
+    };
 
-        if let NativeFn(value_fn) = pctor.unwrap() {
-            let len = args.len() as isize - 1;
-            for i in -len..1 {
-                vm.borrow_mut().stack.push(args[(-i) as usize].clone());
+    let (ifn, this): (&'static Function, Option<Value>) = match &func {
+        NativeFn(native) => return call_native(native),
+        Record(reco) => {
+            if let Some(fun) = module::function(reco) {
+                return call_native(&|vm, nargs| module::call(&vm, fun, nargs));
+            }
+            let pctor = unsafe { (*reco.to_raw()).get("constructor") };
+            match pctor {
+                None => {
+                    vm.borrow_mut().error = ERROR_RECORD_NO_CONSTRUCTOR;
+                    return Value::InterpreterError;
+                }
+                Some(NativeFn(native)) => return call_native(native),
+                Some(Fn(ifn)) => {
+                    // constructors get a new record to fill in as their first argument
+                    let mut this = record::Record::new();
+                    this.insert("prototype", func.clone());
+                    let this = Record((*vm).borrow().malloc(this));
+                    (unsafe { &*ifn.to_raw() }, Some(this))
+                }
+                Some(_) => {
+                    vm.borrow_mut().error = ERROR_CONSTRUCTOR_NOT_FUNCTION;
+                    return Value::InterpreterError;
+                }
             }
-
-            // call fn
-            value_fn(Rc::clone(&vm), nargs);
-
-            return vm.borrow_mut().stack.pop().unwrap();
-        } else if let Fn(f) = func {
-            unsafe { *ifn = (*f.into_raw()).clone() };
-        } else {
-            vm.borrow_mut().error = ERROR_CONSTRUCTOR_NOT_FUNCTION;
-            return Value::InterpreterError;
         }
-    } else if let Fn(f) = func {
-        unsafe { *ifn = (*f.into_raw()).clone() };
-    } else {
-        vm.borrow_mut().error = ERROR_EXPECTED_CALLABLE;
-        return Value::InterpreterError;
-    }
-    unsafe {
-        if nargs != (*ifn).nargs {
-            vm.borrow_mut().ip = (*ifn).ip;
-            vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
+        Fn(ifn) => (unsafe { &*ifn.to_raw() }, None),
+        _ => {
+            vm.borrow_mut().error = ERROR_EXPECTED_CALLABLE;
             return Value::InterpreterError;
         }
+    };
+
+    let nargs = args.len() as u16 + this.is_some() as u16;
+    if nargs != ifn.nargs {
+        vm.borrow_mut().error = ERROR_MISMATCH_ARGUMENTS;
+        vm.borrow_mut().error_expected = ifn.nargs as u32;
+        return Value::InterpreterError;
     }
 
     let last = (*vm).borrow().ip;
-    // setup env
+    // setup env, returning to u32::MAX makes Ret stop executing
     let oldenv = (*vm).borrow().localenv.len();
-    vm.borrow_mut().ip = (last as i32 - 1) as u32;
-    unsafe {
-        vm.borrow_mut().enter_env(&*ifn);
+    if native_stack_exhausted(&(*vm).borrow()) {
+        raise_error(
+            &vm,
+            |stdlib| &stdlib.stack_overflow_error,
+            "too many nested calls from native functions".to_string(),
+        );
+        return Value::InterpreterError;
+    }
+    vm.borrow_mut().ip = u32::MAX;
+    if !vm.borrow_mut().enter_env(ifn) {
+        vm.borrow_mut().ip = last;
+        raise_stack_overflow(&vm);
+        return Value::InterpreterError;
     }
-    let curenv = (*vm).borrow().localenv.len();
-    // setup stack/ip
 
-    let len = args.len() as isize - 1;
-    for i in -len..1 {
-        vm.borrow_mut().stack.push(args[(-i) as usize].clone());
+    // setup stack
+    push_args(&vm);
+    if let Some(this) = this {
+        vm.borrow_mut().stack.push(this);
     }
 
     inside_execute(Rc::clone(&vm));
@@ -1677,14 +1786,12 @@ pub(super) fn vm_call(vm: Rc<RefCell<Vm>>, func: Value, args: &[Value]) -> Value
         return Value::InterpreterError;
     }
 
-    if (*vm).borrow().localenv.len() != curenv {
+    if (*vm).borrow().localenv.len() != oldenv {
         // exception occurred outside of function's scope
-        // NOTE: curenv already free'd from unwinding
+        // NOTE: the function's env already free'd from unwinding
         return Value::InterpreterError;
     }
 
-    let localenv = vm.borrow_mut().localenv.drain(..oldenv).collect();
-    vm.borrow_mut().localenv = localenv;
     vm.borrow_mut().ip = last;
 
     vm.borrow_mut().stack.pop().unwrap()
@@ -0,0 +1,167 @@
+//! Pretty printing of values for inspect() and the repl
+
+use super::debug::scopes_at;
+use super::record::Record;
+use super::value::Value;
+use super::vm::Vm;
+
+/// Levels of nested arrays and records shown if no depth is given
+pub const DEFAULT_DEPTH: usize = 4;
+// items shown of an array or record before the rest is left out
+const MAX_ITEMS: usize = 100;
+// longest collection kept on a single line
+const MAX_WIDTH: usize = 72;
+const INDENT: &str = "  ";
+
+/// Pretty prints the value, showing the arrays and records nested in it up
+/// to `depth` levels deep
+///
+/// Records are shown with the name of the global their prototype is, and
+/// collections that contain themselves are printed as `[circular]`.
+pub fn inspect(vm: &Vm, val: &Value, depth: usize) -> String {
+    let mut inspector = Inspector {
+        vm,
+        depth,
+        parents: Vec::new(),
+    };
+    inspector.inspect(val)
+}
+
+struct Inspector<'a> {
+    vm: &'a Vm,
+    depth: usize,
+    // arrays and records being printed, one of them showing up again is a cycle
+    parents: Vec<*const ()>,
+}
+
+impl Inspector<'_> {
+    fn inspect(&mut self, val: &Value) -> String {
+        match val {
+            Value::Nil => "nil".to_string(),
+            Value::True => "true".to_string(),
+            Value::False => "false".to_string(),
+            Value::Int(n) => n.to_string(),
+            Value::Float(n) => format!("{:?}", n),
+            Value::NativeFn(_) => "[native function]".to_string(),
+            Value::Fn(f) => match self.function_name(f.as_ref().ip as usize) {
+                Some(name) => format!("[function {}]", name),
+                None => "[function]".to_string(),
+            },
+            Value::Str(s) => format!("{:?}", s.as_ref() as &str),
+            Value::Array(a) => {
+                let ptr = a.to_raw() as *const ();
+                let array = a.as_ref();
+                if self.parents.contains(&ptr) {
+                    "[circular]".to_string()
+                } else if array.is_empty() {
+                    "[]".to_string()
+                } else if self.parents.len() >= self.depth {
+                    format!("[array of {}]", array.len())
+                } else {
+                    self.parents.push(ptr);
+                    let mut items: Vec<String> = array
+                        .iter()
+                        .take(MAX_ITEMS)
+                        .map(|item| self.inspect(item))
+                        .collect();
+                    self.parents.pop();
+                    if array.len() > MAX_ITEMS {
+                        items.push(format!("... {} more", array.len() - MAX_ITEMS));
+                    }
+                    join("[", &items, "]")
+                }
+            }
+            Value::Record(r) => {
+                let ptr = r.to_raw() as *const ();
+                let record = r.as_ref();
+                let open = match self.prototype_name(record) {
+                    Some(name) => format!("{} {{", name),
+                    None => "{".to_string(),
+                };
+                let mut keys: Vec<&str> = record
+                    .iter()
+                    .map(|(key, _)| key.as_ref() as &str)
+                    .filter(|&key| key != "prototype")
+                    .collect();
+                if self.parents.contains(&ptr) {
+                    "[circular]".to_string()
+                } else if keys.is_empty() {
+                    format!("{}}}", open)
+                } else if self.parents.len() >= self.depth {
+                    format!("{}...}}", open)
+                } else {
+                    keys.sort_unstable();
+                    self.parents.push(ptr);
+                    let mut items: Vec<String> = keys
+                        .iter()
+                        .take(MAX_ITEMS)
+                        .map(|&key| {
+                            let val = record.get(key).unwrap();
+                            format!("{}: {}", show_key(key), self.inspect(val))
+                        })
+                        .collect();
+                    self.parents.pop();
+                    if keys.len() > MAX_ITEMS {
+                        items.push(format!("... {} more", keys.len() - MAX_ITEMS));
+                    }
+                    join(&open, &items, "}")
+                }
+            }
+            Value::InterpreterError | Value::PropagateError | Value::Iterator => {
+                "[unk]".to_string()
+            }
+        }
+    }
+
+    // name of the function starting at the instruction
+    fn function_name(&self, ip: usize) -> Option<String> {
+        let info = self.vm.modules_info.as_ref()?.borrow();
+        scopes_at(&info, ip)
+            .first()
+            .and_then(|scope| scope.name.clone())
+    }
+
+    // name of the global the record's prototype is
+    fn prototype_name(&self, record: &Record) -> Option<String> {
+        let prototype = record.prototype()?;
+        self.vm.global().iter().find_map(|(key, val)| match val {
+            Value::Record(global) if std::ptr::eq(global.to_raw(), prototype) => {
+                Some(key.to_string())
+            }
+            _ => None,
+        })
+    }
+}
+
+// keys that aren't identifiers are quoted
+fn show_key(key: &str) -> String {
+    let is_identifier = key.chars().next().is_some_and(|ch| !ch.is_ascii_digit())
+        && key
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || "$_?!".contains(ch));
+    if is_identifier {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
+}
+
+// puts the items on a single line if they fit, or one per line otherwise
+fn join(open: &str, items: &[String], close: &str) -> String {
+    let width = open.len() + close.len() + items.iter().map(|item| item.len() + 2).sum::<usize>();
+    if width <= MAX_WIDTH && items.iter().all(|item| !item.contains('\n')) {
+        return format!("{}{}{}", open, items.join(", "), close);
+    }
+    let mut out = open.to_string();
+    for (idx, item) in items.iter().enumerate() {
+        out.push('\n');
+        out.push_str(INDENT);
+        out.push_str(&item.replace('\n', &format!("\n{}", INDENT)));
+        if idx + 1 < items.len() {
+            out.push(',');
+        }
+    }
+    out.push('\n');
+    out.push_str(close);
+    out
+}
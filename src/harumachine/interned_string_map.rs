@@ -18,14 +18,10 @@ impl InternedStringMap {
         if !(2..20).contains(&s.len()) {
             return None;
         }
-        let it = self
-            .data
-            .iter()
-            .enumerate()
-            .find(|(_, item)| (*item).1.as_str() == s);
+        let it = self.data.iter().find(|(_, item)| item.as_str() == s);
 
-        if let Some((idx, _)) = it {
-            Some(idx as u16)
+        if let Some((&idx, _)) = it {
+            Some(idx)
         } else if self.data.len() > MAX_LENGTH {
             None
         } else {
@@ -1,15 +1,24 @@
 //! Bindings for the virtual machine.
 
+pub mod convert;
+#[cfg_attr(not(feature = "debuger"), allow(dead_code))]
+pub mod debug;
 pub mod env;
 pub mod exframe;
 pub mod function;
 pub mod gc;
 pub mod hmap;
+pub mod inline_cache;
 mod inside;
+pub mod inspect;
 pub mod interned_string_map;
+pub mod module;
+pub mod nanbox;
 pub mod operations;
 pub mod record;
+pub mod stack;
 pub mod string;
+pub mod upvalue;
 pub mod value;
 pub mod vm;
 pub mod vmerror;
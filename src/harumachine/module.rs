@@ -0,0 +1,492 @@
+//! Provides native modules, shared libraries that `use` can load
+//!
+//! A native module is a `cdylib` depending on `haru` that exports its
+//! entry point with the [haru_module](crate::haru_module) macro:
+//!
+//! ```
+//! use haru::harumachine::module::{ModuleBuilder, ModuleCall, ModuleValue};
+//!
+//! extern "C" fn double(call: &mut ModuleCall) {
+//!     match call.arg(0) {
+//!         ModuleValue::Int(x) => call.ret(ModuleValue::Int(x * 2)),
+//!         _ => call.raise("expected argument x of double to be an Int"),
+//!     }
+//! }
+//!
+//! fn init(module: &mut ModuleBuilder) {
+//!     module.function("double", double);
+//! }
+//!
+//! haru::haru_module!(init);
+//! ```
+//!
+//! `use "libfoo.so"` then makes the module's exports available in the
+//! global record `foo`. Records exported with [ModuleBuilder::record] can
+//! be used as prototypes, their functions get the record they're called
+//! on as their first argument.
+//!
+//! Modules only talk to the interpreter through the C functions of
+//! [ModuleApi], so they don't have to be built by the same compiler, only
+//! against the same [ABI_VERSION].
+
+use std::cell::RefCell;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+use std::path::Path;
+use std::rc::Rc;
+
+use super::gc::Gc;
+use super::record::Record;
+use super::value::Value;
+use super::vm::Vm;
+
+/// Version of the interface between the interpreter and native modules,
+/// changes whenever [ModuleApi] or the types passed through it change
+pub const ABI_VERSION: u32 = 2;
+
+/// Signature of `haru_module_abi_version`, exported by native modules
+pub type ModuleAbiFn = extern "C" fn() -> u32;
+/// Signature of `haru_module_init`, exported by native modules
+pub type ModuleInitFn = extern "C" fn(&mut ModuleBuilder);
+/// Signature of the functions a native module exports
+pub type ModuleFn = extern "C" fn(&mut ModuleCall);
+/// Signature of the callback filling in a record a native module exports,
+/// called with the data pointer given to `export_record`
+pub type ModuleRecordFn = extern "C" fn(&mut ModuleBuilder, *mut c_void);
+
+/// A value passed between the interpreter and a native module
+///
+/// Strings point to memory owned by the side passing them: strings given
+/// to a module live until its function returns, strings given to the
+/// interpreter are copied right away. Values that can't be passed are
+/// `Other`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum ModuleValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(*const u8, usize),
+    Other,
+}
+
+impl ModuleValue {
+    /// Borrows the string
+    pub fn string(s: &str) -> ModuleValue {
+        ModuleValue::Str(s.as_ptr(), s.len())
+    }
+}
+
+/// Functions of the interpreter native modules call
+#[repr(C)]
+pub struct ModuleApi {
+    pub export_function: extern "C" fn(&mut ModuleBuilder, *const u8, usize, ModuleFn),
+    pub export_value: extern "C" fn(&mut ModuleBuilder, *const u8, usize, ModuleValue),
+    pub export_record:
+        extern "C" fn(&mut ModuleBuilder, *const u8, usize, ModuleRecordFn, *mut c_void),
+    pub arg: extern "C" fn(&ModuleCall, u16) -> ModuleValue,
+    pub ret: extern "C" fn(&mut ModuleCall, ModuleValue),
+    pub raise: extern "C" fn(&mut ModuleCall, *const u8, usize),
+    pub field: extern "C" fn(&ModuleCall, u16, *const u8, usize) -> ModuleValue,
+    pub set_field: extern "C" fn(&mut ModuleCall, u16, *const u8, usize, ModuleValue),
+}
+
+/// Collects the functions and values a native module exports
+#[repr(C)]
+pub struct ModuleBuilder {
+    api: *const ModuleApi,
+    state: *mut c_void,
+}
+
+#[allow(dead_code)]
+impl ModuleBuilder {
+    /// Exports the native function, the value it's exported as is a
+    /// record that can be called
+    pub fn function(&mut self, name: &str, fun: ModuleFn) -> &mut Self {
+        let api = unsafe { &*self.api };
+        (api.export_function)(self, name.as_ptr(), name.len(), fun);
+        self
+    }
+
+    /// Exports the value
+    pub fn value(&mut self, name: &str, val: ModuleValue) -> &mut Self {
+        let api = unsafe { &*self.api };
+        (api.export_value)(self, name.as_ptr(), name.len(), val);
+        self
+    }
+
+    /// Exports a record, filled in by the function with another builder
+    ///
+    /// Functions exported into the record are its methods, so the record
+    /// can be used as the prototype of records made by scripts.
+    pub fn record<F: FnOnce(&mut ModuleBuilder)>(&mut self, name: &str, init: F) -> &mut Self {
+        extern "C" fn fill<F: FnOnce(&mut ModuleBuilder)>(
+            builder: &mut ModuleBuilder,
+            data: *mut c_void,
+        ) {
+            let init = unsafe { (*(data as *mut Option<F>)).take() };
+            if let Some(init) = init {
+                init(builder);
+            }
+        }
+        let api = unsafe { &*self.api };
+        let mut init = Some(init);
+        (api.export_record)(
+            self,
+            name.as_ptr(),
+            name.len(),
+            fill::<F>,
+            &mut init as *mut Option<F> as *mut c_void,
+        );
+        self
+    }
+}
+
+/// A call to a function exported by a native module
+#[repr(C)]
+pub struct ModuleCall {
+    api: *const ModuleApi,
+    state: *mut c_void,
+    nargs: u16,
+}
+
+#[allow(dead_code)]
+impl ModuleCall {
+    /// Number of arguments the function was called with
+    pub fn nargs(&self) -> u16 {
+        self.nargs
+    }
+
+    /// The argument, or nil past the last argument
+    pub fn arg(&self, index: u16) -> ModuleValue {
+        let api = unsafe { &*self.api };
+        (api.arg)(self, index)
+    }
+
+    /// The argument if it's a string
+    pub fn str_arg(&self, index: u16) -> Option<&str> {
+        match self.arg(index) {
+            // the interpreter passes its strings, which are valid utf-8
+            ModuleValue::Str(ptr, len) => unsafe {
+                Some(std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+                    ptr, len,
+                )))
+            },
+            _ => None,
+        }
+    }
+
+    /// The field of the argument if it's a record, looking through its
+    /// prototypes, or nil
+    pub fn field(&self, index: u16, name: &str) -> ModuleValue {
+        let api = unsafe { &*self.api };
+        (api.field)(self, index, name.as_ptr(), name.len())
+    }
+
+    /// Sets the field of the argument if it's a record
+    pub fn set_field(&mut self, index: u16, name: &str, val: ModuleValue) {
+        let api = unsafe { &*self.api };
+        (api.set_field)(self, index, name.as_ptr(), name.len(), val)
+    }
+
+    /// Sets the value the function returns, nil if it's never set
+    pub fn ret(&mut self, val: ModuleValue) {
+        let api = unsafe { &*self.api };
+        (api.ret)(self, val)
+    }
+
+    /// Raises an InvalidArgumentError once the function returns
+    pub fn raise(&mut self, why: &str) {
+        let api = unsafe { &*self.api };
+        (api.raise)(self, why.as_ptr(), why.len())
+    }
+}
+
+/// Defines the entry points of a native module, the function is called
+/// with a [ModuleBuilder] when the module is loaded
+#[macro_export]
+macro_rules! haru_module {
+    ($init:path) => {
+        #[no_mangle]
+        pub extern "C" fn haru_module_abi_version() -> u32 {
+            $crate::harumachine::module::ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn haru_module_init(
+            module: &mut $crate::harumachine::module::ModuleBuilder,
+        ) {
+            $init(module);
+        }
+    };
+}
+
+// interpreter side of the api
+
+static API: ModuleApi = ModuleApi {
+    export_function,
+    export_value,
+    export_record,
+    arg,
+    ret,
+    raise,
+    field,
+    set_field,
+};
+
+struct ExportState<'a> {
+    vm: &'a Vm,
+    exports: Gc<Record>,
+}
+
+struct CallState<'a> {
+    vm: &'a Vm,
+    args: Vec<Value>,
+    ret: Value,
+    why: Option<String>,
+}
+
+// Kept in the native field of the records functions are exported as
+struct ModuleFunction(ModuleFn);
+
+unsafe fn to_string(ptr: *const u8, len: usize) -> String {
+    String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len)).into_owned()
+}
+
+fn to_value(vm: &Vm, val: ModuleValue) -> Value {
+    match val {
+        ModuleValue::Bool(true) => Value::True,
+        ModuleValue::Bool(false) => Value::False,
+        ModuleValue::Int(n) => Value::Int(n),
+        ModuleValue::Float(n) => Value::Float(n),
+        ModuleValue::Str(ptr, len) => Value::Str(vm.malloc(unsafe { to_string(ptr, len) }.into())),
+        ModuleValue::Nil | ModuleValue::Other => Value::Nil,
+    }
+}
+
+fn from_value(val: Option<&Value>) -> ModuleValue {
+    match val {
+        None | Some(Value::Nil) => ModuleValue::Nil,
+        Some(Value::True) => ModuleValue::Bool(true),
+        Some(Value::False) => ModuleValue::Bool(false),
+        Some(Value::Int(n)) => ModuleValue::Int(*n),
+        Some(Value::Float(n)) => ModuleValue::Float(*n),
+        Some(Value::Str(s)) => ModuleValue::string(s.as_ref()),
+        Some(_) => ModuleValue::Other,
+    }
+}
+
+extern "C" fn export_function(
+    builder: &mut ModuleBuilder,
+    name: *const u8,
+    len: usize,
+    fun: ModuleFn,
+) {
+    let state = unsafe { &mut *(builder.state as *mut ExportState) };
+    let mut rec = state.vm.malloc(Record::new());
+    rec.inner_mut_ptr().native_field = Some(Box::new(ModuleFunction(fun)));
+    let name = unsafe { to_string(name, len) };
+    state
+        .exports
+        .inner_mut_ptr()
+        .insert(name, Value::Record(rec));
+}
+
+extern "C" fn export_value(
+    builder: &mut ModuleBuilder,
+    name: *const u8,
+    len: usize,
+    val: ModuleValue,
+) {
+    let state = unsafe { &mut *(builder.state as *mut ExportState) };
+    let val = to_value(state.vm, val);
+    let name = unsafe { to_string(name, len) };
+    state.exports.inner_mut_ptr().insert(name, val);
+}
+
+extern "C" fn export_record(
+    builder: &mut ModuleBuilder,
+    name: *const u8,
+    len: usize,
+    init: ModuleRecordFn,
+    data: *mut c_void,
+) {
+    let state = unsafe { &mut *(builder.state as *mut ExportState) };
+    let mut record_state = ExportState {
+        vm: state.vm,
+        exports: state.vm.malloc(Record::new()),
+    };
+    let mut record_builder = ModuleBuilder {
+        api: &API,
+        state: &mut record_state as *mut ExportState as *mut c_void,
+    };
+    init(&mut record_builder, data);
+    let name = unsafe { to_string(name, len) };
+    state
+        .exports
+        .inner_mut_ptr()
+        .insert(name, Value::Record(record_state.exports));
+}
+
+extern "C" fn arg(call: &ModuleCall, index: u16) -> ModuleValue {
+    let state = unsafe { &*(call.state as *const CallState) };
+    from_value(state.args.get(index as usize))
+}
+
+extern "C" fn ret(call: &mut ModuleCall, val: ModuleValue) {
+    let state = unsafe { &mut *(call.state as *mut CallState) };
+    state.ret = to_value(state.vm, val);
+}
+
+extern "C" fn raise(call: &mut ModuleCall, why: *const u8, len: usize) {
+    let state = unsafe { &mut *(call.state as *mut CallState) };
+    state.why = Some(unsafe { to_string(why, len) });
+}
+
+extern "C" fn field(call: &ModuleCall, index: u16, name: *const u8, len: usize) -> ModuleValue {
+    let state = unsafe { &*(call.state as *const CallState) };
+    match state.args.get(index as usize) {
+        Some(Value::Record(rec)) => {
+            let name = unsafe { to_string(name, len) };
+            from_value(rec.as_ref().get(&name))
+        }
+        _ => ModuleValue::Nil,
+    }
+}
+
+extern "C" fn set_field(
+    call: &mut ModuleCall,
+    index: u16,
+    name: *const u8,
+    len: usize,
+    val: ModuleValue,
+) {
+    let state = unsafe { &mut *(call.state as *mut CallState) };
+    if let Some(Value::Record(rec)) = state.args.get(index as usize) {
+        let val = to_value(state.vm, val);
+        let name = unsafe { to_string(name, len) };
+        rec.clone().inner_mut_ptr().insert(name, val);
+    }
+}
+
+/// The function the record was exported as by a native module
+pub(crate) fn function(rec: &Gc<Record>) -> Option<ModuleFn> {
+    let field = rec.as_ref().native_field.as_ref()?;
+    field.downcast_ref::<ModuleFunction>().map(|fun| fun.0)
+}
+
+/// Calls the function exported by a native module like a native function,
+/// popping its arguments then pushing its result or raising its error
+pub(crate) fn call(vm: &Rc<RefCell<Vm>>, fun: ModuleFn, nargs: u16) {
+    let args = {
+        let mut vm = vm.borrow_mut();
+        (0..nargs).map(|_| vm.stack.pop().unwrap()).collect()
+    };
+    let (ret, why) = {
+        let vm = (**vm).borrow();
+        let mut state = CallState {
+            vm: &vm,
+            args,
+            ret: Value::Nil,
+            why: None,
+        };
+        let mut call = ModuleCall {
+            api: &API,
+            state: &mut state as *mut CallState as *mut c_void,
+            nargs,
+        };
+        fun(&mut call);
+        (state.ret, state.why)
+    };
+    match why {
+        Some(why) => {
+            let error = (**vm).borrow().invalid_argument_error(why);
+            Vm::raise_value(vm, error);
+        }
+        None => unsafe { vm.borrow_mut().stack_push_gray(ret) },
+    }
+}
+
+/// Whether the path points to a shared library rather than a source file
+pub fn is_native_module(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("so" | "dylib" | "dll")
+    )
+}
+
+/// Name of the global a native module is loaded into,
+/// `libfoo.so` is loaded into `foo`
+pub fn module_name(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match stem.strip_prefix("lib") {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => stem,
+    }
+}
+
+#[cfg(unix)]
+unsafe fn dlerror() -> String {
+    let err = libc::dlerror();
+    if err.is_null() {
+        "unknown error".to_string()
+    } else {
+        CStr::from_ptr(err).to_string_lossy().into_owned()
+    }
+}
+
+/// Loads the shared library and runs its entry point, returning the
+/// record of its exports
+#[cfg(unix)]
+pub fn load(vm: &Vm, path: &Path) -> Result<Value, String> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|err| err.to_string())?;
+    unsafe {
+        let dl = libc::dlopen(cpath.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if dl.is_null() {
+            return Err(dlerror());
+        }
+        let abi_version = libc::dlsym(dl, c"haru_module_abi_version".as_ptr());
+        let init = libc::dlsym(dl, c"haru_module_init".as_ptr());
+        if abi_version.is_null() || init.is_null() {
+            libc::dlclose(dl);
+            return Err(format!("{} is not a haru module", path.display()));
+        }
+        let abi_version = std::mem::transmute::<*mut c_void, ModuleAbiFn>(abi_version)();
+        if abi_version != ABI_VERSION {
+            libc::dlclose(dl);
+            return Err(format!(
+                "{} was built for version {} of the module interface, expected version {}",
+                path.display(),
+                abi_version,
+                ABI_VERSION
+            ));
+        }
+        // the library is never closed, records can refer to its functions
+        let init = std::mem::transmute::<*mut c_void, ModuleInitFn>(init);
+        let mut state = ExportState {
+            vm,
+            exports: vm.malloc(Record::new()),
+        };
+        let mut builder = ModuleBuilder {
+            api: &API,
+            state: &mut state as *mut ExportState as *mut c_void,
+        };
+        init(&mut builder);
+        Ok(Value::Record(state.exports))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn load(_vm: &Vm, path: &Path) -> Result<Value, String> {
+    Err(format!(
+        "can't load {}, native modules are only supported on unix",
+        path.display()
+    ))
+}
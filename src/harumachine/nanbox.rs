@@ -0,0 +1,298 @@
+//! Provides the compact representation of values on the vm's stack
+//!
+//! A [`NanBox`] packs a [`Value`] into 8 bytes. Floats are stored as
+//! they are, every other value lives in the payload of a negative quiet
+//! NaN, which floats never use since their NaNs are all made positive:
+//!
+//! ```text
+//! 1111 1111 1111 1ttt pppp .... pppp
+//!  sign, exponent and   tag   48 bit payload
+//!  quiet bit all set
+//! ```
+//!
+//! Pointers fit in the payload on the platforms the interpreter runs on.
+//! Integers that don't fit in it are boxed.
+//!
+//! ```
+//! use haru::harumachine::nanbox::NanBox;
+//! use haru::harumachine::value::Value;
+//! assert_eq!(std::mem::size_of::<NanBox>(), 8);
+//! for val in [i64::MIN, -1, 1 << 47, i64::MAX] {
+//!     assert!(matches!(NanBox::from(Value::Int(val)).to_value(), Value::Int(v) if v == val));
+//! }
+//! let nan = NanBox::from(Value::Float(-f64::NAN));
+//! assert!(matches!(nan.to_value(), Value::Float(f) if f.is_nan()));
+//! assert!(matches!(NanBox::from(Value::Float(-0.0)).to_value(), Value::Float(f) if f == 0.0));
+//! ```
+
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use super::function::Function;
+use super::gc::{ref_dec, ref_inc, Gc};
+use super::record::Record;
+use super::string::HaruString;
+use super::value::{NativeFnData, Value};
+
+// bits set in every value that isn't a float
+const TAGGED: u64 = 0xFFF8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0b111 << TAG_SHIFT;
+const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+// the NaN every float NaN is stored as
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+// tags, the payload is:
+// which of the constants below
+const TAG_CONST: u64 = 0;
+// a 48 bit signed integer
+const TAG_INT: u64 = 1;
+// a pointer to a boxed integer
+const TAG_BIG_INT: u64 = 2;
+// a pointer to the native function
+const TAG_NATIVE_FN: u64 = 3;
+// a pointer to gc memory
+const TAG_FN: u64 = 4;
+const TAG_STR: u64 = 5;
+const TAG_RECORD: u64 = 6;
+const TAG_ARRAY: u64 = 7;
+
+const CONST_NIL: u64 = 0;
+const CONST_TRUE: u64 = 1;
+const CONST_FALSE: u64 = 2;
+const CONST_INTERPRETER_ERROR: u64 = 3;
+const CONST_PROPAGATE_ERROR: u64 = 4;
+const CONST_ITERATOR: u64 = 5;
+
+/// A value packed into 8 bytes
+///
+/// Like [`Gc`], a NanBox holding a heap value keeps a reference to it.
+pub struct NanBox(u64);
+
+const _: () = assert!(std::mem::size_of::<NanBox>() == 8);
+
+impl NanBox {
+    pub const NIL: NanBox = NanBox::tagged(TAG_CONST, CONST_NIL);
+
+    const fn tagged(tag: u64, payload: u64) -> NanBox {
+        NanBox(TAGGED | (tag << TAG_SHIFT) | payload)
+    }
+
+    fn pointer(tag: u64, ptr: usize) -> NanBox {
+        assert!(
+            ptr as u64 & !PAYLOAD_MASK == 0,
+            "pointer doesn't fit in 48 bits"
+        );
+        NanBox::tagged(tag, ptr as u64)
+    }
+
+    #[inline]
+    pub fn from_int(i: i64) -> NanBox {
+        if (i << 16) >> 16 == i {
+            NanBox::tagged(TAG_INT, i as u64 & PAYLOAD_MASK)
+        } else {
+            NanBox::pointer(TAG_BIG_INT, Box::into_raw(Box::new(i)) as usize)
+        }
+    }
+
+    #[inline]
+    pub fn from_float(f: f64) -> NanBox {
+        if f.is_nan() {
+            NanBox(CANONICAL_NAN)
+        } else {
+            NanBox(f.to_bits())
+        }
+    }
+
+    #[inline]
+    fn tag(&self) -> Option<u64> {
+        if self.0 < TAGGED {
+            None
+        } else {
+            Some((self.0 & TAG_MASK) >> TAG_SHIFT)
+        }
+    }
+
+    #[inline]
+    fn payload(&self) -> u64 {
+        self.0 & PAYLOAD_MASK
+    }
+
+    /// Gets the integer if it's one
+    #[inline]
+    pub fn as_int(&self) -> Option<i64> {
+        match self.tag() {
+            Some(TAG_INT) => Some(((self.payload() << 16) as i64) >> 16),
+            Some(TAG_BIG_INT) => Some(unsafe { *(self.payload() as *const i64) }),
+            _ => None,
+        }
+    }
+
+    /// Pointer to the gc memory the value refers to, if it's a heap value
+    #[inline]
+    pub fn as_gc_pointer(&self) -> Option<*mut libc::c_void> {
+        match self.tag() {
+            Some(TAG_FN | TAG_STR | TAG_RECORD | TAG_ARRAY) => {
+                Some(self.payload() as *mut libc::c_void)
+            }
+            _ => None,
+        }
+    }
+
+    // reads the value without taking the reference it holds,
+    // numbers are checked for first since they're the most common
+    #[inline]
+    unsafe fn read(&self) -> Value {
+        if self.0 < TAGGED {
+            Value::Float(f64::from_bits(self.0))
+        } else if self.0 & TAG_MASK == TAG_INT << TAG_SHIFT {
+            Value::Int(((self.payload() << 16) as i64) >> 16)
+        } else {
+            self.read_other()
+        }
+    }
+
+    // kept out of line so the number paths stay small where they're inlined
+    #[inline(never)]
+    unsafe fn read_other(&self) -> Value {
+        unsafe fn gc<T: super::gc::GcTraceable>(payload: u64) -> Gc<T> {
+            Gc::from_leaked(NonNull::new_unchecked(payload as *mut T))
+        }
+        let payload = self.payload();
+        match self.tag() {
+            Some(TAG_CONST) => match payload {
+                CONST_NIL => Value::Nil,
+                CONST_TRUE => Value::True,
+                CONST_FALSE => Value::False,
+                CONST_INTERPRETER_ERROR => Value::InterpreterError,
+                CONST_PROPAGATE_ERROR => Value::PropagateError,
+                CONST_ITERATOR => Value::Iterator,
+                _ => unreachable!(),
+            },
+            Some(TAG_BIG_INT) => Value::Int(*(payload as *const i64)),
+            Some(TAG_NATIVE_FN) => {
+                Value::NativeFn(std::mem::transmute::<usize, NativeFnData>(payload as usize))
+            }
+            Some(TAG_FN) => Value::Fn(gc::<Function>(payload)),
+            Some(TAG_STR) => Value::Str(gc::<HaruString>(payload)),
+            Some(TAG_RECORD) => Value::Record(gc::<Record>(payload)),
+            Some(TAG_ARRAY) => Value::Array(gc::<Vec<Value>>(payload)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Borrows the value, without going through the reference counting
+    /// cloning it would
+    #[inline]
+    pub fn borrow(&self) -> BorrowedValue<'_> {
+        BorrowedValue {
+            value: ManuallyDrop::new(unsafe { self.read() }),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Clones the value
+    #[inline]
+    pub fn to_value(&self) -> Value {
+        (*self.borrow()).clone()
+    }
+
+    #[inline(never)]
+    fn from_other(val: Value) -> NanBox {
+        match val {
+            Value::Nil => NanBox::NIL,
+            Value::True => NanBox::tagged(TAG_CONST, CONST_TRUE),
+            Value::False => NanBox::tagged(TAG_CONST, CONST_FALSE),
+            Value::InterpreterError => NanBox::tagged(TAG_CONST, CONST_INTERPRETER_ERROR),
+            Value::PropagateError => NanBox::tagged(TAG_CONST, CONST_PROPAGATE_ERROR),
+            Value::Iterator => NanBox::tagged(TAG_CONST, CONST_ITERATOR),
+            Value::Int(i) => NanBox::from_int(i),
+            Value::Float(f) => NanBox::from_float(f),
+            Value::NativeFn(f) => NanBox::pointer(TAG_NATIVE_FN, f as usize),
+            // the reference held by the handle moves into the box
+            Value::Fn(gc) => NanBox::pointer(TAG_FN, gc.leak().as_ptr() as usize),
+            Value::Str(gc) => NanBox::pointer(TAG_STR, gc.leak().as_ptr() as usize),
+            Value::Record(gc) => NanBox::pointer(TAG_RECORD, gc.leak().as_ptr() as usize),
+            Value::Array(gc) => NanBox::pointer(TAG_ARRAY, gc.leak().as_ptr() as usize),
+        }
+    }
+}
+
+impl From<Value> for NanBox {
+    #[inline]
+    fn from(val: Value) -> NanBox {
+        match val {
+            Value::Int(i) => NanBox::from_int(i),
+            Value::Float(f) => NanBox::from_float(f),
+            val => NanBox::from_other(val),
+        }
+    }
+}
+
+impl From<NanBox> for Value {
+    #[inline]
+    fn from(val: NanBox) -> Value {
+        // the value takes the reference over
+        let val = ManuallyDrop::new(val);
+        let value = unsafe { val.read() };
+        if let Some(TAG_BIG_INT) = val.tag() {
+            drop(unsafe { Box::from_raw(val.payload() as *mut i64) });
+        }
+        value
+    }
+}
+
+impl Clone for NanBox {
+    #[inline]
+    fn clone(&self) -> NanBox {
+        if self.0 < TAGGED {
+            return NanBox(self.0);
+        }
+        match self.tag() {
+            Some(TAG_BIG_INT) => NanBox::from_int(self.as_int().unwrap()),
+            Some(TAG_FN | TAG_STR | TAG_RECORD | TAG_ARRAY) => {
+                unsafe { ref_inc(self.payload() as *mut libc::c_void) };
+                NanBox(self.0)
+            }
+            _ => NanBox(self.0),
+        }
+    }
+}
+
+impl Drop for NanBox {
+    #[inline]
+    fn drop(&mut self) {
+        if self.0 < TAGGED {
+            return;
+        }
+        match self.tag() {
+            Some(TAG_BIG_INT) => drop(unsafe { Box::from_raw(self.payload() as *mut i64) }),
+            Some(TAG_FN | TAG_STR | TAG_RECORD | TAG_ARRAY) => unsafe {
+                ref_dec(self.payload() as *mut libc::c_void)
+            },
+            _ => {}
+        }
+    }
+}
+
+impl std::fmt::Debug for NanBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.borrow().fmt(f)
+    }
+}
+
+/// A value borrowed from a [`NanBox`]
+pub struct BorrowedValue<'a> {
+    value: ManuallyDrop<Value>,
+    phantom: PhantomData<&'a NanBox>,
+}
+
+impl Deref for BorrowedValue<'_> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.value
+    }
+}
@@ -2,8 +2,6 @@
 use crate::harumachine::value::{Value, Value::*};
 use crate::harumachine::{string::HaruString, vm::Vm};
 use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::rc::Rc;
 // Logical and mathematical operations on the values.
 
 // All these operations will be eliminated and I will create a system where the
@@ -11,8 +9,8 @@ use std::rc::Rc;
 // type is added, subtracted ... with the appropriate type instead of primitive
 // functions to take care of it.
 // to take care of it
-pub(crate) fn value_add(left: Value, right: Value, vm: Rc<RefCell<Vm>>) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_add(left: &Value, right: &Value, vm: &Vm) -> Value {
+    match (left, right) {
         (Str(s), Str(s1)) => {
             let st: HaruString = unsafe {
                 format!(
@@ -22,7 +20,7 @@ pub(crate) fn value_add(left: Value, right: Value, vm: Rc<RefCell<Vm>>) -> Value
                 )
             }
             .into();
-            let key = (*vm).borrow().malloc(st);
+            let key = vm.malloc(st);
             Str(key)
         }
         (Int(i), Int(i2)) => Int(i + i2),
@@ -32,8 +30,8 @@ pub(crate) fn value_add(left: Value, right: Value, vm: Rc<RefCell<Vm>>) -> Value
     }
 }
 // Original
-pub(crate) fn value_sub(left: Value, right: Value, _: Rc<RefCell<Vm>>) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_sub(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i2)) => Int(i - i2),
         (Float(f), Float(f2)) => Float(f - f2),
         (Int(i), Float(f)) => Float(*i as f64 - f),
@@ -42,8 +40,8 @@ pub(crate) fn value_sub(left: Value, right: Value, _: Rc<RefCell<Vm>>) -> Value
     }
 }
 
-pub(crate) fn value_mul(left: Value, right: Value, _: Rc<RefCell<Vm>>) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_mul(left: &Value, right: &Value, _: &Vm) -> Value {
+    match (left, right) {
         (Int(i), Int(i2)) => Int(i * i2),
         (Float(f), Float(f2)) => Float(f * f2),
         (Int(i), Float(f)) => Float(*i as f64 * f),
@@ -65,8 +63,8 @@ pub(crate) fn value_pow(left: Value, right: Value, vm: Rc<RefCell<Vm>>) -> Value
 }
 */
 
-pub(crate) fn value_div(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_div(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         /*
         (Int(i), _) if i == &0 => {
             vm.error = ERROR_ZERO_DIVISION;
@@ -95,8 +93,8 @@ pub(crate) fn value_div(left: Value, right: Value) -> Value {
     }
 }
 
-pub(crate) fn value_mod(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_mod(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i2)) => Int(i % i2),
         (Float(f), Float(f2)) => Float(f % f2),
         (Int(i), Float(f)) => Float(*i as f64 % f),
@@ -105,31 +103,31 @@ pub(crate) fn value_mod(left: Value, right: Value) -> Value {
     }
 }
 
-pub(crate) fn value_bitwise_and(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_bitwise_and(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i2)) => Int(i & i2),
         //(Bool(b), Bool(b1)) => Bool(b & b1),
         _ => Value::InterpreterError,
     }
 }
-pub(crate) fn value_bitwise_or(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_bitwise_or(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int(i | i1),
         //(Bool(b), Bool(b1)) => Bool(b | b1),
         _ => Value::InterpreterError,
     }
 }
 
-pub(crate) fn value_bitwise_xor(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_bitwise_xor(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int(i ^ i1),
         //(Bool(b), Bool(b1)) => Bool(b ^ b1),
         _ => Value::InterpreterError,
     }
 }
 
-pub(crate) fn value_lt(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_lt(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int((i < i1) as i64),
         (Float(f), Float(f1)) => Int((f < f1) as i64),
         (Int(i), Float(f)) => Int(((*i as f64) < *f) as i64),
@@ -138,8 +136,8 @@ pub(crate) fn value_lt(left: Value, right: Value) -> Value {
         _ => Value::InterpreterError,
     }
 }
-pub(crate) fn value_leq(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_leq(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int((i <= i1) as i64),
         (Float(f), Float(f1)) => Int((f <= f1) as i64),
         (Int(i), Float(f)) => Int(((*i as f64) <= *f) as i64),
@@ -148,8 +146,8 @@ pub(crate) fn value_leq(left: Value, right: Value) -> Value {
         _ => Value::InterpreterError,
     }
 }
-pub(crate) fn value_gt(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_gt(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int((i > i1) as i64),
         (Float(f), Float(f1)) => Int((f > f1) as i64),
         (Int(i), Float(f)) => Int(((*i as f64) > *f) as i64),
@@ -158,8 +156,8 @@ pub(crate) fn value_gt(left: Value, right: Value) -> Value {
         _ => Value::InterpreterError,
     }
 }
-pub(crate) fn value_geq(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_geq(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int((i >= i1) as i64),
         (Float(f), Float(f1)) => Int((f >= f1) as i64),
         (Int(i), Float(f)) => Int(((*i as f64) >= *f) as i64),
@@ -168,8 +166,8 @@ pub(crate) fn value_geq(left: Value, right: Value) -> Value {
         _ => Value::InterpreterError,
     }
 }
-pub(crate) fn value_eq(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_eq(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int((i == i1) as i64),
         (Float(f), Float(f1)) => Int((f == f1) as i64),
         (Int(i), Float(f)) => Int(((*i as f64) == *f) as i64),
@@ -179,8 +177,8 @@ pub(crate) fn value_eq(left: Value, right: Value) -> Value {
     }
 }
 
-pub(crate) fn value_neq(left: Value, right: Value) -> Value {
-    match (&left, &right) {
+pub(crate) fn value_neq(left: &Value, right: &Value) -> Value {
+    match (left, right) {
         (Int(i), Int(i1)) => Int((i != i1) as i64),
         (Float(f), Float(f1)) => Int((f != f1) as i64),
         (Int(i), Float(f)) => Int(((*i as f64) != *f) as i64),
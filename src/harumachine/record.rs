@@ -1,24 +1,87 @@
 //! Provides a record value in Hana
 
-use super::gc::{push_gray_body, GcNode, GcTraceable};
-use super::hmap::HaruHashMap;
+use super::gc::{push_gray_body, write_barrier, GcNode, GcTraceable};
 use super::string::HaruString;
 use super::value::Value;
 use std::any::Any;
 use std::borrow::Borrow;
 use std::boxed::Box;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::hash::Hash;
 
+// A shape stands for the keys of a record in the order they were added,
+// so records built the same way share a shape and store each key in the
+// same slot. The empty record has shape 0, adding a key to a record of
+// some shape always leads to the same next shape.
+//
+// Records used as dictionaries would make a shape for every key they get,
+// so records with too many keys, or whose next shape would have too many
+// siblings or go past the maximum number of shapes, are put in dictionary
+// mode instead: their shape never changes and their members aren't cached.
+struct Shapes {
+    transitions: HashMap<u64, HashMap<String, u64>>,
+    count: u64,
+}
+
+thread_local! {
+    static SHAPES: RefCell<Shapes> = RefCell::new(Shapes {
+        transitions: HashMap::new(),
+        count: 1,
+    });
+}
+
+/// Shape of the records in dictionary mode
+pub const DICTIONARY_SHAPE: u64 = u64::MAX;
+// keys a record can have before it's put in dictionary mode
+const MAX_SHAPED_KEYS: usize = 64;
+// shapes adding a key to a single shape can lead to
+const MAX_TRANSITIONS: usize = 64;
+const MAX_SHAPES: u64 = 1 << 16;
+
+fn next_shape(shape: u64, nkeys: usize, key: &str) -> u64 {
+    if shape == DICTIONARY_SHAPE || nkeys >= MAX_SHAPED_KEYS {
+        return DICTIONARY_SHAPE;
+    }
+    SHAPES.with(|shapes| {
+        let mut shapes = shapes.borrow_mut();
+        let transitions = shapes.transitions.get(&shape);
+        if let Some(&next) = transitions.and_then(|t| t.get(key)) {
+            return next;
+        }
+        if transitions.map_or(0, |t| t.len()) >= MAX_TRANSITIONS || shapes.count >= MAX_SHAPES {
+            return DICTIONARY_SHAPE;
+        }
+        let next = shapes.count;
+        shapes.count += 1;
+        shapes
+            .transitions
+            .entry(shape)
+            .or_default()
+            .insert(key.to_string(), next);
+        next
+    })
+}
+
 /// A record value in Hana
-#[derive(Default)]
 pub struct Record {
-    data: HaruHashMap,
+    /// Slot of every key in values
+    keys: HashMap<HaruString, usize>,
+    values: Vec<Value>,
     prototype: Option<&'static Record>,
     // it says static but it lasts as long as Record, see below!
+    /// Changes whenever a key is added
+    shape: u64,
     /// Dynamic field for use in native functions
     pub native_field: Option<Box<dyn Any>>,
 }
 
+impl Default for Record {
+    fn default() -> Self {
+        Self::with_capacity(0)
+    }
+}
+
 impl Record {
     pub fn new() -> Self {
         Self::default()
@@ -26,8 +89,10 @@ impl Record {
 
     pub fn with_capacity(n: usize) -> Record {
         Record {
-            data: HaruHashMap::with_capacity(n),
+            keys: HashMap::with_capacity(n),
+            values: Vec::with_capacity(n),
             prototype: None,
+            shape: 0,
             native_field: None,
         }
     }
@@ -37,8 +102,8 @@ impl Record {
         HaruString: Borrow<T>,
         T: Hash + Eq + ?Sized,
     {
-        if let Some(v) = self.data.get(k) {
-            return Some(v);
+        if let Some(&slot) = self.keys.get(k) {
+            return Some(&self.values[slot]);
         } else if let Some(prototype) = self.prototype {
             return prototype.get(k);
         }
@@ -50,6 +115,7 @@ impl Record {
         K: Into<HaruString> + Hash + Eq,
     {
         let k = k.into();
+        write_barrier(&v);
         if (k.borrow() as &String) == "prototype" {
             self.prototype = unsafe {
                 match &v {
@@ -60,11 +126,65 @@ impl Record {
                 }
             };
         }
-        self.data.insert(k, v);
+        match self.keys.get(&k) {
+            Some(&slot) => self.values[slot] = v,
+            None => {
+                self.shape = next_shape(self.shape, self.values.len(), k.borrow() as &str);
+                self.keys.insert(k, self.values.len());
+                self.values.push(v);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&HaruString, &Value)> {
+        self.keys
+            .iter()
+            .map(move |(key, &slot)| (key, &self.values[slot]))
+    }
+
+    /// Current shape of the record, see [Record::lookup]
+    pub fn shape(&self) -> u64 {
+        self.shape
+    }
+
+    /// Whether the record is in dictionary mode, see [DICTIONARY_SHAPE]
+    pub fn is_dictionary(&self) -> bool {
+        self.shape == DICTIONARY_SHAPE
+    }
+
+    pub fn prototype(&self) -> Option<&Record> {
+        self.prototype
+    }
+
+    /// Finds the slot of a key, returning how many prototypes were walked
+    /// through to find it (0 if it belongs to the record itself)
+    ///
+    /// The slot stays valid for every record with the same shape, the
+    /// prototypes walked through must still be the same records. Records
+    /// in dictionary mode all have the same shape, whatever their keys.
+    pub fn lookup<T>(&self, k: &T) -> Option<(usize, usize)>
+    where
+        HaruString: Borrow<T>,
+        T: Hash + Eq + ?Sized,
+    {
+        let mut record = self;
+        let mut depth = 0;
+        loop {
+            if let Some(&slot) = record.keys.get(k) {
+                return Some((depth, slot));
+            }
+            record = record.prototype?;
+            depth += 1;
+        }
     }
 
-    pub fn iter(&self) -> std::collections::hash_map::Iter<HaruString, Value> {
-        self.data.iter()
+    pub fn slot(&self, slot: usize) -> &Value {
+        &self.values[slot]
+    }
+
+    pub fn set_slot(&mut self, slot: usize, v: Value) {
+        write_barrier(&v);
+        self.values[slot] = v;
     }
 
     // But why the prototype of the prototype of the prototype of the...
@@ -84,8 +204,16 @@ impl Record {
 }
 
 impl GcTraceable for Record {
+    const NAME: &'static str = "Record";
+
+    // records get finalized by the __finalize__ method of their prototypes
+    fn needs_finalize(&self) -> bool {
+        self.prototype
+            .is_some_and(|prototype| prototype.get("__finalize__").is_some())
+    }
+
     unsafe fn trace(&self, gray_nodes: &mut Vec<*mut GcNode>) {
-        for (_, val) in self.iter() {
+        for val in self.values.iter() {
             if let Some(ptr) = val.as_gc_pointer() {
                 push_gray_body(gray_nodes, ptr);
             }
@@ -0,0 +1,78 @@
+//! Provides the vm's value stack
+//!
+//! Values get pushed and popped as [`Value`]s, while the stack stores them
+//! as [`NanBox`]es that the interpreter works on in place.
+
+use super::nanbox::NanBox;
+use super::value::Value;
+
+#[derive(Default)]
+pub struct ValueStack {
+    slots: Vec<NanBox>,
+}
+
+impl ValueStack {
+    pub fn with_capacity(capacity: usize) -> ValueStack {
+        ValueStack {
+            slots: Vec::with_capacity(capacity),
+        }
+    }
+
+    #[inline]
+    pub fn push(&mut self, val: Value) {
+        self.slots.push(val.into());
+    }
+
+    #[inline]
+    pub fn pop(&mut self) -> Option<Value> {
+        self.slots.pop().map(Value::from)
+    }
+
+    /// Copy of the value on top of the stack
+    #[inline]
+    pub fn last(&self) -> Option<Value> {
+        self.slots.last().map(NanBox::to_value)
+    }
+
+    /// Copy of the value at the index from the bottom of the stack
+    #[inline]
+    pub fn get(&self, idx: usize) -> Option<Value> {
+        self.slots.get(idx).map(NanBox::to_value)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.slots.truncate(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    /// Copies of the values in the range
+    pub fn to_vec(
+        &self,
+        range: impl std::slice::SliceIndex<[NanBox], Output = [NanBox]>,
+    ) -> Vec<Value> {
+        self.slots[range].iter().map(NanBox::to_value).collect()
+    }
+
+    #[inline]
+    pub fn slots(&self) -> &[NanBox] {
+        &self.slots
+    }
+
+    #[inline]
+    pub fn slots_mut(&mut self) -> &mut Vec<NanBox> {
+        &mut self.slots
+    }
+}
@@ -115,6 +115,16 @@ impl std::ops::DerefMut for HaruString {
 }
 
 impl GcTraceable for HaruString {
+    const NAME: &'static str = "String";
+
+    fn payload_size(&self) -> usize {
+        match &self.data {
+            HaruStringData::String(s) => s.capacity(),
+            // shared with the constants it was made from
+            HaruStringData::CowString(_) => 0,
+        }
+    }
+
     unsafe fn trace(&self, _manager: &mut Vec<*mut GcNode>) {}
 }
 
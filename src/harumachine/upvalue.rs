@@ -0,0 +1,82 @@
+//! Provides the variables functions capture from the ones they're defined in
+//!
+//! Example checking that functions stored in local variables don't keep
+//! the calls they're defined in alive:
+//! ```
+//! use haru::harumachine::value::Value;
+//! use haru::interpreter::Interpreter;
+//! let mut interpreter = Interpreter::new();
+//! interpreter
+//!     .eval_str(
+//!         "func make(n)\n  f = fn()\n    return n\n  end\n  return f()\nend\n\
+//!          func run(times)\n  i = 0\n  while i < times begin\n    make(i)\n    i += 1\n  end\nend\n",
+//!     )
+//!     .unwrap();
+//! let mut heap_after = |times| {
+//!     interpreter.call("run", &[Value::Int(times)]).unwrap();
+//!     let vm = interpreter.vm().borrow();
+//!     // nothing outside of the interpreter holds on to its values
+//!     unsafe {
+//!         vm.gc_collect();
+//!         vm.gc_collect();
+//!     }
+//!     vm.gc_stats().bytes_allocated
+//! };
+//! let before = heap_after(10);
+//! assert_eq!(heap_after(1000), before);
+//! ```
+
+use super::gc::{push_gray_body, write_barrier, GcNode, GcTraceable};
+use super::nanbox::NanBox;
+
+/// Variable captured by a function
+///
+/// While the frame the variable belongs to is running, the upvalue refers
+/// to its slot so that both see the same assignments. The value gets moved
+/// into the upvalue when the frame is left, functions only keep the
+/// variables they use alive rather than the whole frame.
+pub enum Upvalue {
+    /// Index of a slot in the value stack, its frame is still running
+    Open(usize),
+    Closed(NanBox),
+}
+
+impl Upvalue {
+    pub fn get(&self, stack: &[NanBox]) -> NanBox {
+        match self {
+            Upvalue::Open(idx) => stack[*idx].clone(),
+            Upvalue::Closed(val) => val.clone(),
+        }
+    }
+
+    pub fn set(&mut self, stack: &mut [NanBox], val: NanBox) {
+        write_barrier(&val.borrow());
+        match self {
+            Upvalue::Open(idx) => stack[*idx] = val,
+            Upvalue::Closed(closed) => *closed = val,
+        }
+    }
+
+    /// Moves the value out of the stack before its frame is left
+    pub fn close(&mut self, stack: &[NanBox]) {
+        if let Upvalue::Open(idx) = self {
+            let val = stack[*idx].clone();
+            write_barrier(&val.borrow());
+            *self = Upvalue::Closed(val);
+        }
+    }
+}
+
+// gc traceable
+impl GcTraceable for Upvalue {
+    const NAME: &'static str = "Upvalue";
+
+    unsafe fn trace(&self, gray_nodes: &mut Vec<*mut GcNode>) {
+        // open upvalues are traced along with the stack
+        if let Upvalue::Closed(val) = self {
+            if let Some(ptr) = val.as_gc_pointer() {
+                push_gray_body(gray_nodes, ptr);
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 //! Provides an abstraction for native values
 
 use super::function::Function;
-use super::gc::{ref_dec, ref_inc, Gc};
+use super::gc::Gc;
 // use super::nativeval::{NativeValue, NativeValueType};
 use super::record::Record;
 use super::string::HaruString;
@@ -10,6 +10,11 @@ use std::borrow::Borrow;
 
 pub type NativeFnData = fn(Rc<RefCell<Vm>>, u16);
 
+/// A hana value
+///
+/// Primitive values are stored inline, while heap values hold a pointer to
+/// garbage collected memory. The vm's stack stores values packed into a
+/// [`NanBox`](super::nanbox::NanBox) instead.
 #[derive(Clone)]
 pub enum Value {
     Nil,
@@ -78,11 +83,11 @@ impl Ord for Value {
 
 // boolean?
 #[no_mangle]
-pub(super) fn value_is_true(value: Value) -> bool {
+pub(super) fn value_is_true(value: &Value) -> bool {
     match value {
-        Value::Int(i) => i > 0,
-        Value::Float(f) => f > 0.0,
-        Value::Str(s) => unsafe { (*s.to_raw()).is_empty() },
+        Value::Int(i) => *i > 0,
+        Value::Float(f) => *f > 0.0,
+        Value::Str(s) => unsafe { (&(*s.to_raw())).is_empty() },
         _ => false,
     }
 }
@@ -101,27 +106,12 @@ impl Value {
         }
     }
 
-    pub fn ref_inc(&self) {
-        if let Some(ptr) = self.as_gc_pointer() {
-            unsafe {
-                ref_inc(ptr);
-            }
-        }
-    }
-    pub fn ref_dec(&self) {
-        if let Some(ptr) = self.as_gc_pointer() {
-            unsafe {
-                ref_dec(ptr);
-            }
-        }
-    }
-
     pub fn get_prototype(&self, vm: Rc<RefCell<Vm>>) -> Option<Gc<Record>> {
         crate::harumachine::inside::get_prototype(vm, self.clone())
     }
 
     pub fn is_true(&self) -> bool {
-        value_is_true(self.clone())
+        value_is_true(self)
     }
 
     pub fn type_name(&self) -> &str {
@@ -155,23 +145,37 @@ impl fmt::Display for Value {
             Value::Fn(_) => write!(f, "[fn]"),
             Value::Str(p) => write!(f, "{}", p.as_ref().borrow() as &String),
             Value::Record(p) => write!(f, "[record {:p}]", p.to_raw()),
-            Value::Array(a) => unsafe {
-                let a = &*a.to_raw();
-                write!(f, "[")?;
-                if !a.is_empty() {
-                    write!(f, "{}", a[0])?;
-                }
-
-                for item in a.iter().skip(1) {
-                    write!(f, ", {}", item)?;
-                }
-                write!(f, "]")
-            },
+            Value::Array(a) => fmt_array(f, a, &mut Vec::new()),
             _ => unreachable!(),
         }
     }
 }
 
+// arrays being printed are kept in parents, so an array containing itself
+// is printed as [...] rather than forever
+fn fmt_array(
+    f: &mut fmt::Formatter,
+    a: &Gc<Vec<Value>>,
+    parents: &mut Vec<*const Vec<Value>>,
+) -> fmt::Result {
+    if parents.contains(&a.to_raw()) {
+        return write!(f, "[...]");
+    }
+    parents.push(a.to_raw());
+    write!(f, "[")?;
+    for (idx, item) in a.as_ref().iter().enumerate() {
+        if idx > 0 {
+            write!(f, ", ")?;
+        }
+        match item {
+            Value::Array(a) => fmt_array(f, a, parents)?,
+            _ => write!(f, "{}", item)?,
+        }
+    }
+    parents.pop();
+    write!(f, "]")
+}
+
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -1,10 +1,11 @@
 //! Provides an interface for the virtual machine
 
 use std::{
-    cell::RefCell,
-    mem::{transmute, ManuallyDrop},
+    cell::{Cell, RefCell},
+    mem::transmute,
     path::Path,
     rc::Rc,
+    time::Instant,
 };
 
 use super::env::Env;
@@ -12,9 +13,13 @@ use super::exframe::ExFrame;
 use super::function::Function;
 use super::gc::*;
 use super::hmap::HaruHashMap;
+use super::inline_cache::InlineCaches;
 use super::interned_string_map::InternedStringMap;
+use super::module;
 use super::record::Record;
+use super::stack::ValueStack;
 use super::string::HaruString;
+use super::upvalue::Upvalue;
 use super::value::Value;
 
 use super::vmerror::VmError;
@@ -23,6 +28,59 @@ use crate::hanayo::HanayoCtx;
 use crate::harumachine::inside::inside_execute;
 
 const CALL_STACK_SIZE: usize = 512;
+/// Default maximum number of nested function calls, see [Vm::set_max_call_depth]
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+// instructions executed between checks of the execution limits
+const LIMITS_CHECK_INTERVAL: u32 = 1024;
+
+/// Limits on how long scripts are allowed to run, running past either of them
+/// stops the interpreter with [VmError::ERROR_OUT_OF_FUEL] or
+/// [VmError::ERROR_DEADLINE_EXCEEDED]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExecutionLimits {
+    /// Number of instructions that can still be executed
+    pub fuel: Option<u64>,
+    /// Point in time execution must stop at
+    pub deadline: Option<Instant>,
+}
+
+impl ExecutionLimits {
+    /// Limits that satisfy both of the given ones
+    pub fn tightest(self, other: ExecutionLimits) -> ExecutionLimits {
+        fn min<T: Ord>(x: Option<T>, y: Option<T>) -> Option<T> {
+            match (x, y) {
+                (Some(x), Some(y)) => Some(x.min(y)),
+                (x, None) => x,
+                (None, y) => y,
+            }
+        }
+        ExecutionLimits {
+            fuel: min(self.fuel, other.fuel),
+            deadline: min(self.deadline, other.deadline),
+        }
+    }
+}
+
+/// Gets called by the interpreter before every instruction while it's set
+/// with [Vm::set_hook], for debuggers and profilers
+///
+/// The hook is taken out of the virtual machine while it runs, so code
+/// it runs doesn't call it again.
+pub trait VmHook {
+    fn on_instruction(&mut self, vm: &Rc<RefCell<Vm>>);
+}
+
+/// Runs the hooks in order, until one of them stops execution
+impl VmHook for Vec<Box<dyn VmHook>> {
+    fn on_instruction(&mut self, vm: &Rc<RefCell<Vm>>) {
+        for hook in self.iter_mut() {
+            hook.on_instruction(vm);
+            if vm.borrow().error != VmError::ERROR_NO_ERROR {
+                break;
+            }
+        }
+    }
+}
 
 #[repr(transparent)]
 #[allow(dead_code)]
@@ -91,7 +149,7 @@ pub enum VmOpcode {
     SetLocal,
     SetLocalFunctionDef,
     GetLocal,
-    GetLocalUp,
+    GetUpvalue,
     SetGlobal,
     GetGlobal,
     DefFunctionPush,
@@ -125,11 +183,15 @@ pub enum VmOpcode {
     Swap, // 60
     // modules
     Use,
+    // variables
+    SetUpvalue,
+    Capture,
 }
 
+#[allow(dead_code)]
 impl VmOpcode {
-    // NOTE: This variable must be updated if use is no longer the last operator.
-    pub const VM_OPCODE_COUNT: u8 = VmOpcode::Use as u8;
+    // NOTE: This variable must be updated if Capture is no longer the last operator.
+    pub const VM_OPCODE_COUNT: u8 = VmOpcode::Capture as u8;
 
     pub fn from_u8(value: u8) -> Option<Self> {
         if value <= Self::VM_OPCODE_COUNT {
@@ -153,13 +215,15 @@ impl PartialEq<u8> for VmOpcode {
 pub struct Vm {
     pub(super) ip: u32, // current instruction pointer
     // pointer to current stack frame
-    pub(super) localenv: Vec<Rc<RefCell<Option<Env>>>>,
+    pub(super) localenv: Vec<Env>,
+    // upvalues referring to slots of the frames, innermost frame last
+    pub(super) open_upvalues: Vec<Gc<Upvalue>>,
     // global environment, all unscoped variables/variables
     // starting with '$' should also be stored here without '$'
     globalenv: Option<Box<HaruHashMap>>,
     exframes: Option<Vec<ExFrame>>, // exception frame
     pub code: Vec<u8>,              // where all the code is
-    pub stack: Vec<Value>,          // stack
+    pub stack: ValueStack,          // stack
 
     // prototype types for primitive values
     pub(crate) dstr: Option<Gc<Record>>,
@@ -174,6 +238,22 @@ pub struct Vm {
     // for handling exceptions inside of interpreted functions called by native functions
     pub(super) exframe_fallthrough: Option<ExFrame>,
     pub(super) native_call_depth: usize,
+    max_call_depth: usize,
+    // address near the start of the rust stack used by the interpreter
+    pub(super) native_stack_base: usize,
+    limits: ExecutionLimits,
+    // instructions executed since the limits were last checked
+    pub(super) ticks: u32,
+    pub(super) ticks_until_check: u32,
+    // set by the gc when the heap is still too big after a full collection
+    pub(super) out_of_memory: Cell<bool>,
+    // set by the gc when records are waiting for their __finalize__ methods
+    pub(super) finalizers_pending: Cell<bool>,
+    // called before every instruction, the limits are checked every time it's set
+    pub(super) hook: Option<Box<dyn VmHook>>,
+
+    // where MemberGet/MemberSet found their key last time, by ip
+    pub(super) inline_caches: InlineCaches,
 
     // rust-specific fields
     pub interned_strings: Option<InternedStringMap>,
@@ -182,6 +262,18 @@ pub struct Vm {
     gc_manager: Option<RefCell<GcManager>>,
 }
 
+/// State of the code that was running when code got evaluated on top of it
+pub struct ExecCtx {
+    ip: u32,
+    localenv: Vec<Env>,
+    open_upvalues: Vec<Gc<Upvalue>>,
+    exframes: Option<Vec<ExFrame>>,
+    exframe_fallthrough: Option<ExFrame>,
+    native_call_depth: usize,
+    // the evaluated code runs on the same stack, right above its values
+    stack_len: usize,
+}
+
 use super::inside::vm_call;
 impl Vm {
     fn new(
@@ -192,10 +284,11 @@ impl Vm {
         Vm {
             ip: 0,
             localenv: Vec::with_capacity(CALL_STACK_SIZE),
+            open_upvalues: Vec::new(),
             globalenv: Some(Box::new(HaruHashMap::new())),
             exframes: Some(Vec::with_capacity(2)),
             code,
-            stack: Vec::with_capacity(2),
+            stack: ValueStack::with_capacity(2),
             dstr: None,
             dint: None,
             dfloat: None,
@@ -205,6 +298,15 @@ impl Vm {
             error_expected: 0,
             exframe_fallthrough: None,
             native_call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            native_stack_base: 0,
+            limits: ExecutionLimits::default(),
+            ticks: 0,
+            ticks_until_check: LIMITS_CHECK_INTERVAL,
+            out_of_memory: Cell::new(false),
+            finalizers_pending: Cell::new(false),
+            hook: None,
+            inline_caches: InlineCaches::default(),
             interned_strings,
             modules_info,
             stdlib: None,
@@ -222,7 +324,7 @@ impl Vm {
     pub fn print_stack(&self) {
         // TODO: move vm_print_stack here and expose function through C ffi
         eprint!("[");
-        for value in &self.stack {
+        for value in self.stack.slots() {
             eprint!("{:?} ", value);
         }
         eprintln!("]");
@@ -251,6 +353,11 @@ impl Vm {
         self.globalenv.as_mut().unwrap().borrow_mut()
     }
 
+    /// Swaps in another global environment, returning the previous one
+    pub fn replace_global(&mut self, globals: HaruHashMap) -> HaruHashMap {
+        *self.globalenv.replace(Box::new(globals)).unwrap()
+    }
+
     // gc
     pub fn malloc<T: Sized + GcTraceable>(&self, val: T) -> Gc<T> {
         self.gc_manager
@@ -260,7 +367,18 @@ impl Vm {
             .malloc(self, val)
     }
 
-    #[allow(dead_code)]
+    /// Accounts for the value growing or shrinking in place
+    /// since it was allocated
+    pub fn gc_resize<T: Sized + GcTraceable>(&self, val: &Gc<T>) {
+        unsafe {
+            self.gc_manager
+                .as_ref()
+                .unwrap()
+                .borrow_mut()
+                .resize(self, val.to_raw())
+        }
+    }
+
     pub fn gc_disable(&self) {
         self.gc_manager.as_ref().unwrap().borrow_mut().disable()
     }
@@ -269,6 +387,85 @@ impl Vm {
         self.gc_manager.as_ref().unwrap().borrow_mut().enable()
     }
 
+    /// Does a full collection
+    ///
+    /// # Safety
+    ///
+    /// Values only referred to by raw pointers outside of the vm get freed
+    pub unsafe fn gc_collect(&self) {
+        self.gc_manager.as_ref().unwrap().borrow_mut().collect(self)
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_manager.as_ref().unwrap().borrow().stats()
+    }
+
+    /// Creates a reference to the value that doesn't keep it alive
+    pub fn gc_weak_ref(&self, val: &Value) -> WeakRef {
+        self.gc_manager.as_ref().unwrap().borrow_mut().weak_ref(val)
+    }
+
+    /// Takes the records waiting for their __finalize__ methods to run
+    pub fn gc_take_finalizable(&self) -> Vec<Gc<Record>> {
+        let finalizable = self
+            .gc_manager
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .take_finalizable();
+        finalizable
+            .into_iter()
+            .map(|ptr| unsafe {
+                // only records need finalizing, the reference held
+                // for the finalizer moves into the Gc
+                let record = Gc::from_raw(ptr as *mut Record);
+                ref_dec(ptr);
+                record
+            })
+            .collect()
+    }
+
+    /// Sets how big the heap has to grow before a collection starts
+    pub fn gc_set_threshold(&self, threshold: usize) {
+        self.gc_manager
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .set_threshold(threshold)
+    }
+
+    pub fn max_heap_size(&self) -> Option<usize> {
+        self.gc_manager.as_ref().unwrap().borrow().max_heap_size()
+    }
+
+    /// Limits the size of the garbage collected heap, allocating past it
+    /// raises a MemoryError once a full collection couldn't free enough
+    ///
+    /// Example catching the error, strings count with their contents:
+    /// ```
+    /// use haru::interpreter::Interpreter;
+    /// let mut interpreter = Interpreter::new();
+    /// interpreter.vm().borrow().set_max_heap_size(Some(1_000_000));
+    /// interpreter
+    ///     .eval_str(
+    ///         "func grow()\n  s = \"ab\"\n  while 1 begin\n    s = s + s\n  end\nend\n\
+    ///          try\n  grow()\ncase MemoryError as e\n  why = e.why\nend\n",
+    ///     )
+    ///     .unwrap();
+    /// let why = interpreter.get_global("why").unwrap();
+    /// assert_eq!(
+    ///     why.to_string(),
+    ///     "heap grew past its maximum size of 1000000 bytes"
+    /// );
+    /// ```
+    pub fn set_max_heap_size(&self, max_heap_size: Option<usize>) {
+        self.gc_manager
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .set_max_heap_size(max_heap_size)
+    }
+
     /// # Safety
     ///
     /// This function calls gc (which is unsafe)
@@ -286,41 +483,165 @@ impl Vm {
 
     // call stack
     // We take a function f, we divert our current ip to the ip of f
-    pub fn enter_env(&mut self, fun: &'static Function) {
-        if self.localenv.len() > CALL_STACK_SIZE {
-            panic!("maximum stack depth exceeded");
+    /// Enters the function's scope, returns false without entering it
+    /// if the maximum call depth has been reached
+    #[must_use]
+    pub fn enter_env(&mut self, fun: &'static Function) -> bool {
+        if self.localenv.len() >= self.max_call_depth {
+            return false;
         }
 
-        self.localenv.push(Rc::new(RefCell::new(Some(Env::new(
+        // native functions push the arguments after entering, the
+        // frame's base is set once they're all there on EnvNew
+        self.localenv.push(Env::new(
             self.ip,
-            fun.get_bound(),
+            fun.get_upvalues(),
             fun.nargs,
-        )))));
+            self.stack.len(),
+        ));
 
         self.ip = fun.ip;
+        true
     }
 
+    /// Replaces the current frame with the function's, moving the
+    /// arguments on top of the stack down to the start of the frame
     pub fn enter_env_tail(&mut self, fun: &'static Function) {
-        if let Some(localenv) = self.localenv.last() {
-            if let Some(mut env) = localenv.take() {
-                env.nargs = fun.nargs;
-                env.lexical_parent = fun.get_bound();
-                *localenv.borrow_mut() = Some(env);
-                self.ip = fun.ip;
+        let Some(env) = self.localenv.last() else {
+            return;
+        };
+        let (retip, base) = (env.retip, env.base);
+        self.close_upvalues(self.localenv.len() - 1);
+
+        let args = self.stack.len() - fun.nargs as usize;
+        debug_assert!(base <= args);
+        self.stack.slots_mut().drain(base..args);
+        *self.localenv.last_mut().unwrap() = Env::new(retip, fun.get_upvalues(), fun.nargs, base);
+        self.ip = fun.ip;
+    }
+
+    /// Leaves the current frame, dropping everything it left on the stack
+    /// but the return value on top, returns the ip it went back to
+    pub fn leave_env(&mut self) -> u32 {
+        self.close_upvalues(self.localenv.len().saturating_sub(1));
+        if let Some(env) = self.localenv.pop() {
+            let slots = self.stack.slots_mut();
+            let val = slots.pop().unwrap();
+            slots.truncate(env.base);
+            slots.push(val);
+            self.ip = env.retip;
+        }
+        self.ip
+    }
+
+    /// Drops the frames from the depth on, the caller
+    /// unwinds the value stack
+    pub(super) fn truncate_envs(&mut self, depth: usize) {
+        self.close_upvalues(depth);
+        self.localenv.truncate(depth);
+    }
+
+    // moves the variables captured from the frames from the depth on
+    // out of the stack, before the frames get dropped
+    fn close_upvalues(&mut self, depth: usize) {
+        let Some(env) = self.localenv.get(depth) else {
+            return;
+        };
+        let base = env.base;
+        while let Some(upvalue) = self.open_upvalues.last_mut() {
+            match *upvalue.as_ref() {
+                Upvalue::Open(idx) if idx >= base => {}
+                _ => break,
             }
+            upvalue.inner_mut_ptr().close(self.stack.slots());
+            self.open_upvalues.pop();
         }
     }
 
-    pub fn leave_env(&mut self) {
-        if let Some(localenv) = self.localenv.pop().take() {
-            if let Some(localenv) = localenv.borrow_mut().take() {
-                self.ip = localenv.retip;
+    /// Gets the upvalue referring to the slot of the current frame,
+    /// every function capturing the variable shares the same one
+    pub(super) fn capture_local(&mut self, slot: u16) -> Gc<Upvalue> {
+        let base = self.localenv.last().unwrap().base;
+        let idx = base + slot as usize;
+        // the current frame's upvalues are the last ones
+        for upvalue in self.open_upvalues.iter().rev() {
+            match *upvalue.as_ref() {
+                Upvalue::Open(open) if open == idx => return upvalue.clone(),
+                Upvalue::Open(open) if open >= base => (),
+                _ => break,
             }
         }
+        let upvalue = self.malloc(Upvalue::Open(idx));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth
+    }
+
+    /// Sets how deeply functions can be nested before a StackOverflowError
+    /// is raised, including calls from native functions
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Current execution limits, with the fuel that's left
+    pub fn execution_limits(&mut self) -> ExecutionLimits {
+        self.charge_ticks();
+        self.limits
+    }
+
+    pub fn set_execution_limits(&mut self, limits: ExecutionLimits) {
+        self.charge_ticks();
+        self.limits = limits;
+        self.ticks_until_check = match limits.fuel {
+            _ if self.hook.is_some() => 0,
+            Some(fuel) => fuel.min(LIMITS_CHECK_INTERVAL as u64) as u32,
+            None => LIMITS_CHECK_INTERVAL,
+        };
+    }
+
+    /// Sets the hook called before every instruction, returning the previous one
+    #[allow(dead_code)]
+    pub fn set_hook(&mut self, hook: Option<Box<dyn VmHook>>) -> Option<Box<dyn VmHook>> {
+        self.ticks_until_check = 0;
+        std::mem::replace(&mut self.hook, hook)
+    }
+
+    fn charge_ticks(&mut self) {
+        if let Some(fuel) = self.limits.fuel.as_mut() {
+            *fuel = fuel.saturating_sub(self.ticks as u64);
+        }
+        self.ticks = 0;
+    }
+
+    /// Called by the interpreter every once in a while, sets the error
+    /// and returns false once execution has to stop
+    pub(super) fn check_limits(&mut self) -> bool {
+        self.charge_ticks();
+        self.ticks_until_check = LIMITS_CHECK_INTERVAL;
+        if let Some(fuel) = self.limits.fuel {
+            if fuel == 0 {
+                self.error = VmError::ERROR_OUT_OF_FUEL;
+                return false;
+            }
+            self.ticks_until_check = fuel.min(LIMITS_CHECK_INTERVAL as u64) as u32;
+        }
+        if let Some(deadline) = self.limits.deadline {
+            if Instant::now() >= deadline {
+                self.error = VmError::ERROR_DEADLINE_EXCEEDED;
+                return false;
+            }
+        }
+        if self.hook.is_some() {
+            self.ticks_until_check = 0;
+        }
+        true
     }
 
     // accessors
-    pub fn localenv(&self) -> &[Rc<RefCell<Option<Env>>>] {
+    pub fn localenv(&self) -> &[Env] {
         &self.localenv[..]
     }
 
@@ -329,17 +650,17 @@ impl Vm {
         self.exframes.as_ref().unwrap()
     }
 
-    fn mut_exframes(&mut self) -> &mut Vec<ExFrame> {
+    pub(super) fn mut_exframes(&mut self) -> &mut Vec<ExFrame> {
         self.exframes.as_mut().unwrap()
     }
 
     pub fn enter_exframe(&mut self) -> &mut ExFrame {
-        let localenv = self.localenv.last().map(Rc::clone);
+        let depth = self.localenv.len();
         let len = self.stack.len() - 1;
         let native_call_depth = self.native_call_depth;
 
         self.mut_exframes()
-            .push(ExFrame::new(localenv, len, native_call_depth));
+            .push(ExFrame::new(depth, len, native_call_depth));
         self.mut_exframes().last_mut().unwrap()
     }
 
@@ -347,96 +668,77 @@ impl Vm {
         self.mut_exframes().pop();
     }
 
-    // execution context for eval
-    pub fn new_exec_ctx(&mut self) -> ManuallyDrop<Vm> {
-        // prevent context's local variables from being freed
+    /// Stops the vm because a function got the wrong number of arguments
+    pub fn mismatch_arguments(&mut self, expected: u32) {
+        self.error = VmError::ERROR_MISMATCH_ARGUMENTS;
+        self.error_expected = expected;
+    }
 
-        // stack
-        let stack = &self.stack;
-        for val in stack.iter() {
-            val.ref_inc();
+    /// Creates an InvalidArgumentError record saying why an argument was rejected
+    pub fn invalid_argument_error(&self, why: String) -> Value {
+        let mut rec = self.malloc(Record::new());
+        if let Some(stdlib) = self.stdlib.as_ref() {
+            rec.inner_mut_ptr().insert(
+                "prototype",
+                Value::Record(stdlib.invalid_argument_error.clone()),
+            );
         }
-        // call stack
-        // if let Some(localenv) = self.localenv {
-        //     let mut env = self.localenv_bp;
-        //     let localenv = localenv.as_ptr();
-
-        for env in self.localenv.iter() {
-            let env_some = env.borrow_mut().take();
-
-            if let Some(ref env) = env_some {
-                for val in env.slots.values() {
-                    (*val).ref_inc();
-                }
-            }
+        rec.inner_mut_ptr()
+            .insert("why", Value::Str(self.malloc(why.into())));
+        Value::Record(rec)
+    }
 
-            *env.borrow_mut() = env_some;
+    /// Raises the value from a native function, which then has to return
+    /// the PropagateError given back right away
+    pub fn raise_value(vm: &Rc<RefCell<Vm>>, val: Value) -> Value {
+        vm.borrow_mut().stack.push(val);
+        if !raise(Rc::clone(vm)) {
+            vm.borrow_mut().error = VmError::ERROR_UNHANDLED_EXCEPTION;
         }
-        
+        Value::PropagateError
+    }
+
+    /// Clears the error the vm stopped with, dropping the frames, exception
+    /// handlers and stack values left behind by the code that was running
+    #[allow(dead_code)]
+    pub fn reset(&mut self) {
+        self.error = VmError::ERROR_NO_ERROR;
+        self.truncate_envs(0);
+        self.mut_exframes().clear();
+        self.exframe_fallthrough = None;
+        self.native_call_depth = 0;
+        self.stack.clear();
+    }
 
-        // save current ctx
-        let current_ctx = Vm {
+    // execution context for eval
+    /// Sets the code that's running aside, so that code can be evaluated
+    /// on top of it with frames and exception handlers of its own
+    pub fn new_exec_ctx(&mut self) -> ExecCtx {
+        let ctx = ExecCtx {
             ip: self.ip,
-            localenv: self.localenv.drain(..).collect(),
-            globalenv: None, // shared
-            exframes: self.exframes.take(),
-            code: Vec::new(), // shared
-            stack: std::mem::replace(&mut self.stack, Vec::with_capacity(2)),
-            // types don't need to be saved:
-            dstr: None,
-            dint: None,
-            dfloat: None,
-            darray: None,
-            drec: None,
-            // shared
-            error: VmError::ERROR_NO_ERROR,
-            error_expected: 0,
-            interned_strings: None,
+            localenv: std::mem::take(&mut self.localenv),
+            open_upvalues: std::mem::take(&mut self.open_upvalues),
+            exframes: self.exframes.replace(Vec::new()),
             exframe_fallthrough: self.exframe_fallthrough.take(),
             native_call_depth: self.native_call_depth,
-            modules_info: None,
-            stdlib: None,
-            gc_manager: None,
+            stack_len: self.stack.len(),
         };
-        // create new ctx
         self.ip = 0;
-        self.exframes = Some(Vec::new());
-        ManuallyDrop::new(current_ctx)
+        ctx
     }
 
-    pub fn restore_exec_ctx(&mut self, ctx: ManuallyDrop<Vm>) {
-        let mut ctx: Vm = ManuallyDrop::into_inner(ctx);
-
-        self.localenv = Vec::with_capacity(CALL_STACK_SIZE);
+    /// Goes back to the code that was running before [Vm::new_exec_ctx],
+    /// dropping whatever the evaluated code left behind
+    pub fn restore_exec_ctx(&mut self, ctx: ExecCtx) {
+        self.truncate_envs(0);
+        self.stack.truncate(ctx.stack_len);
 
-        // fill in
         self.ip = ctx.ip;
-        self.localenv = ctx.localenv.drain(..).collect();
-        self.exframes = ctx.exframes.take();
-        self.exframe_fallthrough = ctx.exframe_fallthrough.take();
+        self.localenv = ctx.localenv;
+        self.open_upvalues = ctx.open_upvalues;
+        self.exframes = ctx.exframes;
+        self.exframe_fallthrough = ctx.exframe_fallthrough;
         self.native_call_depth = ctx.native_call_depth;
-        self.stack = std::mem::take(&mut ctx.stack);
-
-        // release context's local variables
-
-        // stack
-        let stack = &self.stack;
-        for val in stack.iter() {
-            val.ref_dec();
-        }
-
-        // call stack
-        for val in self.localenv.iter() {
-            let env_some = val.borrow_mut().take();
-
-            if let Some(ref env) = env_some {
-                for val in env.slots.values() {
-                    (*val).ref_dec();
-                }
-            }
-
-            *val.borrow_mut() = env_some;
-        }
     }
 
     // instruction pointer
@@ -449,18 +751,24 @@ impl Vm {
     }
 
     // imports
-    pub fn load_module(&mut self, path: &str) {
-        // loads module, jumps to the module then jump back to OP_USE
-        use std::io::Read;
-
+    /// Loads the module, jumping to it so that it jumps back to the Use
+    /// instruction afterwards, or loads a native module right away
+    ///
+    /// Returns why the module couldn't be loaded.
+    pub fn load_module(&mut self, path: &str) -> Result<(), String> {
         let rc = self.modules_info.clone().unwrap();
 
-        let pathobj = if path.starts_with("./") {
+        let pathobj = if let Some(relative) = path.strip_prefix("./") {
             let c = rc.borrow_mut();
-            let last_path = c.files.last().unwrap();
-            let curpath = Path::new(&last_path);
-            let mut pathobj = if let Some(parent) = curpath.parent() {
-                parent.join(Path::new(path))
+            let curpath = Path::new(&c.files[c.fileno]);
+            // a script run by its bare file name has an empty parent, keep the
+            // ./ since the dynamic linker only looks in the current directory
+            // for paths with a slash
+            let parent = curpath
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty());
+            let mut pathobj = if let Some(parent) = parent {
+                parent.join(relative)
             } else {
                 Path::new(path).to_path_buf()
             };
@@ -476,8 +784,8 @@ impl Vm {
             pathobj
         } else {
             use std::env;
-            match env::var_os("HANA_PATH") {
-                Some(parent) => env::split_paths(&parent)
+            let found = env::var_os("HANA_PATH").map(|parent| {
+                env::split_paths(&parent)
                     .map(|x| {
                         let mut pathobj = Path::new(&x).join(path);
                         if pathobj.extension().is_none() {
@@ -486,48 +794,64 @@ impl Vm {
                         pathobj
                     })
                     .find(|x| x.as_path().is_file())
-                    .unwrap(),
-                None => panic!("HANA_PATH not set!"),
+            });
+            match found {
+                Some(Some(pathobj)) => pathobj,
+                // the dynamic linker searches its own paths for libraries
+                _ if module::is_native_module(Path::new(path)) => Path::new(path).to_path_buf(),
+                Some(None) => return Err(format!("module {} not found in HANA_PATH", path)),
+                None => return Err(format!("can't find module {}, HANA_PATH not set", path)),
             }
         };
 
         if rc.borrow_mut().modules_loaded.contains(&pathobj) {
-            return;
+            return Ok(());
         } else {
             rc.borrow_mut().modules_loaded.insert(pathobj.clone());
         }
 
-        if let Ok(mut file) = std::fs::File::open(pathobj) {
-            // WARNING: There is no control of the errors generated when importing.
-            let mut s = String::new();
-            file.read_to_string(&mut s).unwrap();
-            let prog = crate::grammar::parser_start(&s).unwrap();
-            rc.borrow_mut().files.push(path.to_string());
-            rc.borrow_mut().sources.push(s);
-
-            let importer_ip = self.ip;
-            let imported_ip = self.code.len();
-            {
-                let mut c = Compiler::new_append(
-                    self.code.clone(),
-                    rc,
-                    // TODO(xyz): error ↓
-                    if self.interned_strings.is_some() {
-                        self.interned_strings.take().unwrap()
-                    } else {
-                        InternedStringMap::new()
-                    },
-                );
-                for stmt in prog {
-                    stmt.emit(&mut c).unwrap();
-                }
-                c.cpushop(VmOpcode::JmpLong);
-                c.cpush32(importer_ip);
-                self.interned_strings = c.interned_strings.take();
-                self.code = c.into_code();
+        if module::is_native_module(&pathobj) {
+            let exports = module::load(self, &pathobj).inspect_err(|_| {
+                // so that the module can be loaded again once it's fixed
+                rc.borrow_mut().modules_loaded.remove(&pathobj);
+            })?;
+            let name = module::module_name(&pathobj);
+            self.mut_global().insert(name.into(), exports);
+            return Ok(());
+        }
+
+        let s = std::fs::read_to_string(&pathobj).map_err(|err| {
+            rc.borrow_mut().modules_loaded.remove(&pathobj);
+            format!("can't read module {}: {}", pathobj.display(), err)
+        })?;
+        // WARNING: There is no control of the errors generated when importing.
+        let prog = crate::grammar::parser_start(&s).unwrap();
+        rc.borrow_mut()
+            .add_file(pathobj.to_string_lossy().into_owned(), s);
+
+        let importer_ip = self.ip;
+        let imported_ip = self.code.len();
+        {
+            let mut c = Compiler::new_append(
+                self.code.clone(),
+                rc,
+                // TODO(xyz): error ↓
+                if self.interned_strings.is_some() {
+                    self.interned_strings.take().unwrap()
+                } else {
+                    InternedStringMap::new()
+                },
+            );
+            for stmt in prog {
+                stmt.emit(&mut c).unwrap();
             }
-            self.ip = imported_ip as u32;
+            c.cpushop(VmOpcode::JmpLong);
+            c.cpush32(importer_ip);
+            self.interned_strings = c.interned_strings.take();
+            self.code = c.into_code();
         }
+        self.ip = imported_ip as u32;
+        Ok(())
     }
 }
 
@@ -548,12 +872,125 @@ pub fn call(vm: Rc<RefCell<Vm>>, fun: Value, args: &[Value]) -> Option<Value> {
         Some(val)
     }
 }
+/// Calls the function with limits on top of the ones the vm already has
+///
+/// If the function runs past them, the vm is left as it was before the call
+/// with its error set, so a native function can clear the error and go on.
+#[allow(dead_code)]
+pub fn call_limited(
+    vm: Rc<RefCell<Vm>>,
+    fun: Value,
+    args: &[Value],
+    limits: ExecutionLimits,
+) -> Option<Value> {
+    let outer = vm.borrow_mut().execution_limits();
+    let (ip, nenvs, nexframes, nstack) = {
+        let vm = vm.borrow();
+        (
+            vm.ip,
+            vm.localenv.len(),
+            vm.exframes().len(),
+            vm.stack.len(),
+        )
+    };
+    let inner = outer.tightest(limits);
+    vm.borrow_mut().set_execution_limits(inner);
+
+    let val = vm_call(Rc::clone(&vm), fun, args);
+
+    let mut vm = vm.borrow_mut();
+    let fuel_left = vm.execution_limits().fuel;
+    vm.set_execution_limits(ExecutionLimits {
+        // the call used up as much of the caller's fuel as of its own
+        fuel: outer
+            .fuel
+            .map(|fuel| fuel - (inner.fuel.unwrap() - fuel_left.unwrap())),
+        ..outer
+    });
+    if vm.error == VmError::ERROR_OUT_OF_FUEL || vm.error == VmError::ERROR_DEADLINE_EXCEEDED {
+        vm.ip = ip;
+        vm.truncate_envs(nenvs);
+        vm.mut_exframes().truncate(nexframes);
+        vm.stack.truncate(nstack);
+        return None;
+    }
+    if let Value::InterpreterError = val {
+        None
+    } else {
+        Some(val)
+    }
+}
+
+/// Runs the __finalize__ methods of the records the gc found dead
+///
+/// Finalizers run in between two instructions, exceptions they don't handle
+/// themselves are ignored rather than raised in the code that was running.
+/// Returns false if a finalizer stopped the vm with an error.
+// Prints the error a __finalize__ method stopped with, along with where
+fn report_finalizer_error(vm: &Rc<RefCell<Vm>>) {
+    let error = &(**vm).borrow().error;
+    if matches!(
+        error,
+        VmError::ERROR_OUT_OF_FUEL | VmError::ERROR_DEADLINE_EXCEEDED
+    ) {
+        return;
+    }
+    let location = match (**vm).borrow().modules_info.as_ref() {
+        Some(info) => (**info).borrow().location((**vm).borrow().ip as usize),
+        None => format!("bytecode index {}", (**vm).borrow().ip),
+    };
+    eprintln!("error in __finalize__ at {}: {}", location, error);
+    if let Some(hint) = unsafe { error.hint(Rc::clone(vm)) } {
+        eprintln!("hint: {}", hint);
+    }
+}
+
+pub(super) fn run_finalizers(vm: &Rc<RefCell<Vm>>) -> bool {
+    let records = (**vm).borrow().gc_take_finalizable();
+    for record in records {
+        let finalize = match record.as_ref().get("__finalize__") {
+            Some(finalize) => finalize.clone(),
+            None => continue,
+        };
+        let (ip, nenvs, nstack) = {
+            let vm = (**vm).borrow();
+            (vm.ip, vm.localenv.len(), vm.stack.len())
+        };
+        let exframes = vm.borrow_mut().exframes.replace(Vec::new());
+        let val = vm_call(Rc::clone(vm), finalize, &[Value::Record(record)]);
+        if let Value::InterpreterError = val {
+            report_finalizer_error(vm);
+        }
+        let mut vm = vm.borrow_mut();
+        vm.exframes = exframes;
+        if let Value::InterpreterError = val {
+            // running out of fuel or time still stops the script, other
+            // errors are reported and the script keeps running
+            if matches!(
+                vm.error,
+                VmError::ERROR_OUT_OF_FUEL | VmError::ERROR_DEADLINE_EXCEEDED
+            ) {
+                return false;
+            }
+            vm.error = VmError::ERROR_NO_ERROR;
+            vm.ip = ip;
+            vm.truncate_envs(nenvs);
+            vm.stack.truncate(nstack);
+        }
+    }
+    true
+}
+
 // TODO: Use error instance panic
 pub fn execute_vm(vm: Rc<RefCell<Vm>>) {
     if vm.borrow().code.is_empty() {
         panic!("calling with nil code");
     }
     //unsafe {}
+    if vm.borrow().native_call_depth == 0 {
+        let base = 0u8;
+        vm.borrow_mut().native_stack_base = std::hint::black_box(&base) as *const u8 as usize;
+    }
     inside_execute(vm);
 }
 
@@ -562,14 +999,23 @@ pub fn raise(vm: Rc<RefCell<Vm>>) -> bool {
         return false;
     }
     let val = vm.borrow().stack.last().unwrap().clone();
-    for exframe in vm.borrow().exframes.as_ref().unwrap().iter() {
+    // innermost try statement gets the first chance at handling the value
+    let exframes = vm.borrow().exframes().clone();
+    for (idx, exframe) in exframes.iter().enumerate().rev() {
         if let Some(handler) = exframe.get_handler(Rc::clone(&vm), &val) {
-            vm.borrow_mut().ip = handler.ip;
-            if handler.nargs == 0 {
-                vm.borrow_mut().stack.pop();
+            let mut vm_mut = vm.borrow_mut();
+            // unwind the call stack back to the frame the try statement was in
+            vm_mut.truncate_envs(exframe.unwind_depth);
+            // the handling frame is left too, so that raising from
+            // inside of the case goes to the enclosing try statement
+            vm_mut.mut_exframes().truncate(idx);
+            vm_mut.stack.truncate(exframe.unwind_stack);
+            if handler.nargs != 0 {
+                vm_mut.stack.push(val);
             }
-            if exframe.unwind_native_call_depth != vm.borrow().native_call_depth {
-                vm.borrow_mut().exframe_fallthrough = Some(exframe.clone());
+            vm_mut.ip = handler.ip;
+            if exframe.unwind_native_call_depth != vm_mut.native_call_depth {
+                vm_mut.exframe_fallthrough = Some(exframe.clone());
             }
             return true;
         }
@@ -585,24 +1031,17 @@ impl GcTraceable for Vm {
             }
         }
         // stack
-        let stack = &self.stack;
-        for val in stack.iter() {
+        for val in self.stack.slots().iter() {
             if let Some(ptr) = val.as_gc_pointer() {
                 push_gray_body(vec, ptr);
             }
         }
 
-        // call stack
-        for env in self.localenv.iter().filter(|x| x.borrow().is_some()) {
-            let env_some = env.borrow_mut().take().unwrap();
-
-            for val in env_some.slots.values() {
-                if let Some(ptr) = (*val).as_gc_pointer() {
-                    push_gray_body(vec, ptr);
-                }
+        // call stack, the local variables are on the stack
+        for env in self.localenv.iter() {
+            for upvalue in env.upvalues.iter() {
+                push_gray_body(vec, upvalue.to_raw() as *mut c_void);
             }
-
-            *env.borrow_mut() = Some(env_some);
         }
     }
 }
@@ -42,6 +42,8 @@ pub enum VmError {
     ERROR_EXPECTED_ITERABLE,
     ERROR_EXPECTED_RECORD_OF_EXPR,
     ERROR_UNKNOWN_KEY,
+    ERROR_OUT_OF_FUEL,
+    ERROR_DEADLINE_EXCEEDED,
 }
 
 impl VmError {
@@ -82,8 +84,9 @@ impl VmError {
             | VmError::ERROR_OP_GEQ
             | VmError::ERROR_OP_EQ
             | VmError::ERROR_OP_NEQ => {
-                let right = (*vm).borrow().stack[(*vm).borrow().stack.len() - 2].clone();
-                let left = (*vm).borrow().stack[(*vm).borrow().stack.len() - 1].clone();
+                let len = (*vm).borrow().stack.len();
+                let left = (*vm).borrow().stack.get(len - 2).unwrap();
+                let right = (*vm).borrow().stack.get(len - 1).unwrap();
                 Some(format!(
                     "Can't perform {} between {} and {}",
                     self.method_for_op(),
@@ -100,7 +103,7 @@ impl VmError {
                 (*vm).borrow().error_expected
             )),
             VmError::ERROR_UNHANDLED_EXCEPTION => {
-                let top = (*vm).borrow().stack.last().cloned().unwrap();
+                let top = (*vm).borrow().stack.last().unwrap();
                 Some(match top {
                     Value::Record(rec) => {
                         let rec = rec.as_ref();
@@ -179,6 +182,8 @@ impl std::fmt::Display for VmError {
             VmError::ERROR_UNHANDLED_EXCEPTION => write!(f, "Unhandled exception"),
             VmError::ERROR_EXPECTED_ITERABLE => write!(f, "Expected iterable record or array"),
             VmError::ERROR_UNKNOWN_KEY => write!(f, "Unknown key"),
+            VmError::ERROR_OUT_OF_FUEL => write!(f, "Ran out of fuel"),
+            VmError::ERROR_DEADLINE_EXCEEDED => write!(f, "Ran past the deadline"),
             _ => write!(f, "[vmerror]"),
         }
     }
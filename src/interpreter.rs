@@ -0,0 +1,263 @@
+//! Provides an interpreter for embedding Hana in Rust programs
+//!
+//! Example for evaluating code and calling a function defined in it:
+//! ```
+//! use haru::harumachine::value::Value;
+//! use haru::interpreter::Interpreter;
+//! let mut interpreter = Interpreter::new();
+//! interpreter.eval_str("func double(x)\n  return x * 2\nend\n").unwrap();
+//! let result = interpreter.call("double", &[Value::Int(21)]).unwrap();
+//! assert_eq!(result, Value::Int(42));
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::ast;
+use crate::compiler::{append_source, ModulesInfo, SourceError};
+use crate::hanayo;
+use crate::harumachine::value::{NativeFnData, Value};
+use crate::harumachine::vm::{self, execute_vm, initialize_vm, Vm, VmOpcode};
+use crate::harumachine::vmerror::VmError;
+
+const STRING_FILENAME: &str = "[string]";
+
+/// Position in a source file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Error from running code in an [Interpreter]
+#[derive(Debug)]
+pub enum HanaError {
+    /// The file couldn't be read
+    Io(std::io::Error),
+    /// The source couldn't be parsed
+    Syntax { message: String, location: Location },
+    /// The source was parsed but bytecode couldn't be generated for it
+    Compile {
+        error: ast::CodeGenError,
+        location: Location,
+    },
+    /// The virtual machine stopped with an error
+    Runtime {
+        error: VmError,
+        /// More details about the error, if there are any
+        hint: Option<String>,
+        location: Option<Location>,
+    },
+    /// A value was raised and no try statement handled it
+    Exception {
+        value: Value,
+        location: Option<Location>,
+    },
+}
+
+impl fmt::Display for HanaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HanaError::Io(err) => write!(f, "{}", err),
+            HanaError::Syntax { message, location } => write!(f, "{} at {}", message, location),
+            HanaError::Compile { error, location } => write!(f, "{} at {}", error, location),
+            HanaError::Runtime {
+                error,
+                hint,
+                location,
+            } => {
+                write!(f, "{}", error)?;
+                if let Some(location) = location {
+                    write!(f, " at {}", location)?;
+                }
+                if let Some(hint) = hint {
+                    write!(f, " ({})", hint)?;
+                }
+                Ok(())
+            }
+            HanaError::Exception { value, location } => {
+                write!(f, "unhandled exception {:?}", value)?;
+                if let Some(location) = location {
+                    write!(f, " at {}", location)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for HanaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HanaError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for HanaError {
+    fn from(err: std::io::Error) -> Self {
+        HanaError::Io(err)
+    }
+}
+
+/// Virtual machine with the standard library loaded, that keeps its
+/// globals in between runs
+///
+/// **Note:** values returned by the interpreter are allocated by its
+/// garbage collector, they must not outlive it.
+pub struct Interpreter {
+    vm: Rc<RefCell<Vm>>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        let modules_info = Rc::new(RefCell::new(ModulesInfo::new()));
+        let vm = initialize_vm(Vec::new(), Some(modules_info), Some(Default::default()));
+        hanayo::init(Rc::clone(&vm));
+        vm.borrow_mut().gc_enable();
+        Interpreter { vm }
+    }
+
+    /// The virtual machine, for allocating values and setting its limits
+    pub fn vm(&self) -> &Rc<RefCell<Vm>> {
+        &self.vm
+    }
+
+    /// Evaluates the source, returning the value of its last expression
+    /// (nil if the last statement isn't an expression)
+    pub fn eval_str(&mut self, src: &str) -> Result<Value, HanaError> {
+        self.eval_source(src, STRING_FILENAME.to_string())
+    }
+
+    /// Runs the script at the path, returning the value of its last expression
+    pub fn run_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Value, HanaError> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)?;
+        self.modules_info()
+            .borrow_mut()
+            .modules_loaded
+            .insert(path.to_path_buf());
+        self.eval_source(&src, path.to_string_lossy().into_owned())
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.vm.borrow().global().get(name).cloned()
+    }
+
+    pub fn set_global(&mut self, name: &str, val: Value) {
+        self.vm
+            .borrow_mut()
+            .mut_global()
+            .insert(name.to_string().into(), val);
+    }
+
+    /// Makes the native function available as a global
+    pub fn register_fn(&mut self, name: &str, fun: NativeFnData) {
+        self.set_global(name, Value::NativeFn(fun));
+    }
+
+    /// Calls the function (or record constructor) stored in the global
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, HanaError> {
+        let fun = match self.get_global(name) {
+            Some(fun) => fun,
+            None => {
+                return Err(HanaError::Runtime {
+                    error: VmError::ERROR_UNDEFINED_GLOBAL_VAR,
+                    hint: Some(format!("{} is not defined", name)),
+                    location: None,
+                })
+            }
+        };
+        match vm::call(Rc::clone(&self.vm), fun, args) {
+            Some(val) => Ok(val),
+            None => Err(self.take_error()),
+        }
+    }
+
+    fn modules_info(&self) -> Rc<RefCell<ModulesInfo>> {
+        self.vm.borrow().modules_info.clone().unwrap()
+    }
+
+    // Appends the source's bytecode to the vm's code and runs it
+    fn eval_source(&mut self, src: &str, file: String) -> Result<Value, HanaError> {
+        let target_ip = append_source(&self.vm, src, file.clone(), VmOpcode::Halt).map_err(
+            |err| match err {
+                SourceError::Syntax {
+                    message,
+                    line,
+                    column,
+                } => HanaError::Syntax {
+                    message,
+                    location: Location { file, line, column },
+                },
+                SourceError::CodeGen {
+                    error,
+                    line,
+                    column,
+                } => HanaError::Compile {
+                    error,
+                    location: Location { file, line, column },
+                },
+            },
+        )?;
+
+        self.vm.borrow_mut().jmp(target_ip);
+        execute_vm(Rc::clone(&self.vm));
+        if self.vm.borrow().error != VmError::ERROR_NO_ERROR {
+            return Err(self.take_error());
+        }
+        Ok(self.vm.borrow_mut().stack.pop().unwrap())
+    }
+
+    // Turns the error the vm stopped with into a HanaError, leaving the vm
+    // ready to run code again
+    fn take_error(&mut self) -> HanaError {
+        let location = {
+            let vm = self.vm.borrow();
+            let modules_info = vm.modules_info.as_ref().unwrap().borrow();
+            modules_info.lookup_smap(vm.ip() as usize).map(|smap| {
+                let (line, column) =
+                    ast::pos_to_line(&modules_info.sources[smap.fileno], smap.file.0);
+                Location {
+                    file: modules_info.files[smap.fileno].clone(),
+                    line,
+                    column,
+                }
+            })
+        };
+        let error = if self.vm.borrow().error == VmError::ERROR_UNHANDLED_EXCEPTION {
+            HanaError::Exception {
+                value: self.vm.borrow().stack.last().unwrap(),
+                location,
+            }
+        } else {
+            let hint = unsafe {
+                let error = &self.vm.borrow().error;
+                error.hint(Rc::clone(&self.vm))
+            };
+            HanaError::Runtime {
+                error: std::mem::replace(&mut self.vm.borrow_mut().error, VmError::ERROR_NO_ERROR),
+                hint,
+                location,
+            }
+        };
+        self.vm.borrow_mut().reset();
+        error
+    }
+}
@@ -11,3 +11,4 @@ pub mod compiler;
 pub mod grammar;
 pub mod hanayo;
 pub mod harumachine;
+pub mod interpreter;
@@ -23,17 +23,52 @@ mod consts;
 mod hanayo;
 mod harumachine;
 
-use cli::CliArgs;
+use std::time::Duration;
+
+use cli::{CliArgs, Command};
 use execution::{run_cmd, run_repl, run_script, ParserFlag};
 
 fn main() {
     let cli_args = CliArgs::parse_args();
 
+    if let Some(Command::Fmt { check, files }) = cli_args.command {
+        if !execution::formatter::run_fmt(files, check) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let flags = ParserFlag {
         dump_bytecode: cli_args.dump_bytecode,
         print_ast: cli_args.print_ast,
+        max_call_depth: cli_args.max_call_depth,
+        fuel: cli_args.fuel,
+        time_limit: cli_args.time_limit.map(Duration::from_millis),
+        max_heap_size: cli_args.max_heap_size,
+        profile: cli_args.profile,
+        profile_time: cli_args.profile_time,
+        profile_report: cli_args.profile_report,
+        coverage: cli_args.coverage,
+        trace: cli_args.trace,
+        trace_functions: cli_args.trace_functions,
+        trace_files: cli_args.trace_files,
+        trace_stack: cli_args.trace_stack,
+        #[cfg(feature = "debuger")]
+        debug: cli_args.debug,
     };
 
+    #[cfg(feature = "debuger")]
+    if cli_args.dap {
+        execution::dap::run_dap(flags);
+        return;
+    }
+
+    #[cfg(feature = "lsp")]
+    if cli_args.lsp {
+        execution::lsp::run_lsp();
+        return;
+    }
+
     if let Some(instructions) = cli_args.cmd {
         run_cmd(instructions, flags);
     } else if let Some(filename) = cli_args.filename {
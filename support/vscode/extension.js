@@ -0,0 +1,34 @@
+const vscode = require('vscode');
+const { LanguageClient } = require('vscode-languageclient');
+
+let client;
+
+function haruPath() {
+    return vscode.workspace.getConfiguration('hana').get('haruPath');
+}
+
+function activate(context) {
+    // `haru --lsp` checks the sources as they're edited
+    client = new LanguageClient(
+        'hana',
+        'Hana',
+        { command: haruPath(), args: ['--lsp'] },
+        { documentSelector: [{ scheme: 'file', language: 'hana' }] }
+    );
+    context.subscriptions.push(client.start());
+
+    // and `haru --dap` runs every debug session
+    context.subscriptions.push(
+        vscode.debug.registerDebugAdapterDescriptorFactory('hana', {
+            createDebugAdapterDescriptor() {
+                return new vscode.DebugAdapterExecutable(haruPath(), ['--dap']);
+            }
+        })
+    );
+}
+
+function deactivate() {
+    return client ? client.stop() : undefined;
+}
+
+module.exports = { activate, deactivate };